@@ -0,0 +1,514 @@
+// aggregation.rs
+// Sketches behind a Reduce that aggregates its field other than by summing: exact
+// per-key state, a mean as a sum sketch over a count sketch, Count-Min shaped registers
+// for the minimum, maximum, first and last value, and per-key histograms of value buckets
+// for quantiles.
+
+use std::collections::{BTreeMap, HashMap};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use crate::bobhash32::BOBHash32;
+use crate::query_plan::Aggregation;
+use crate::sketch::Sketch;
+
+// Rows of the BOBHash prime table the registers use, clear of the Count-Min rows from 750.
+const REGISTER_HASH_BASE: u32 = 800;
+const FINGERPRINT_HASH: u32 = 900;
+
+// Values below 8 get a bucket each; above, every power of two is split into 4 buckets,
+// so a bucket's midpoint is within 12.5% of any value in it.
+pub const QUANTILE_BUCKETS: usize = 8 + 29 * 4;
+
+fn bucket(value: u32) -> u8 {
+    if value < 8 {
+        return value as u8;
+    }
+    let exponent = value.ilog2();
+    (8 + (exponent - 3) * 4 + ((value >> (exponent - 2)) & 3)) as u8
+}
+
+// Midpoint of the values falling into `bucket`.
+fn bucket_value(bucket: u8) -> u64 {
+    if bucket < 8 {
+        return bucket as u64;
+    }
+    let exponent = (bucket as u32 - 8) / 4 + 3;
+    let width = 1u64 << (exponent - 2);
+    (4 + (bucket as u64 - 8) % 4) * width + width / 2
+}
+
+// Nearest-rank quantile of a histogram of (value, count) in ascending order.
+fn nearest_rank(q: f64, histogram: impl Iterator<Item = (u64, u64)> + Clone) -> u64 {
+    let total: u64 = histogram.clone().map(|(_, count)| count).sum();
+    if total == 0 {
+        return 0;
+    }
+    let rank = ((q * total as f64).ceil() as u64).clamp(1, total);
+    let mut seen = 0;
+    for (value, count) in histogram {
+        seen += count;
+        if seen >= rank {
+            return value;
+        }
+    }
+    0
+}
+
+// A key's value folded into its exact minimum, maximum, first or last value.
+fn fold_value(aggregation: Aggregation, values: &mut HashMap<Vec<u8>, u64>, item: &[u8], value: u64) {
+    match values.get_mut(item) {
+        Some(current) => match aggregation {
+            Aggregation::Min => *current = (*current).min(value),
+            Aggregation::Max => *current = (*current).max(value),
+            Aggregation::Last => *current = value,
+            _ => {}
+        },
+        None => {
+            values.insert(item.to_vec(), value);
+        }
+    }
+}
+
+// Stored as (key, value) pairs, as `DeterministicSketch` does.
+fn serialize_pairs<S: Serializer, V: Serialize>(map: &HashMap<Vec<u8>, V>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(map.iter())
+}
+
+fn deserialize_pairs<'de, D: Deserializer<'de>, V: DeserializeOwned>(deserializer: D) -> Result<HashMap<Vec<u8>, V>, D::Error> {
+    let pairs: Vec<(Vec<u8>, V)> = Vec::deserialize(deserializer)?;
+    Ok(pairs.into_iter().collect())
+}
+
+/// Depth × width registers updated like a Count-Min sketch. `Max` keeps the largest value
+/// hashed to a cell and reads the smallest of the key's cells, `Min` the reverse, so
+/// collisions can only push an estimate past the true value. `First` and `Last` store the
+/// value with a fingerprint of its key; `First` only writes empty cells, `Last` always
+/// writes, and a key reads the first of its cells still holding its fingerprint, or 0.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Registers {
+    aggregation: Aggregation,
+    depth: usize,
+    width: usize,
+    // Per cell the key's fingerprint (0 when empty, unused by Min and Max) and the value.
+    cells: Vec<Vec<(u32, u32)>>,
+    hashes: Vec<BOBHash32>,
+    fingerprint: BOBHash32,
+}
+
+impl Registers {
+    fn new(aggregation: Aggregation, memory_in_bytes: usize, depth: usize) -> Self {
+        let depth = depth.max(1);
+        let mut registers = Self {
+            aggregation,
+            depth,
+            width: 0,
+            cells: Vec::new(),
+            hashes: (0..depth).map(|i| BOBHash32::new(REGISTER_HASH_BASE + i as u32)).collect(),
+            fingerprint: BOBHash32::new(FINGERPRINT_HASH),
+        };
+        registers.width = (memory_in_bytes / registers.cell_bytes() / depth).max(1);
+        registers.cells = vec![vec![registers.empty_cell(); registers.width]; depth];
+        registers
+    }
+
+    fn cell_bytes(&self) -> usize {
+        match self.aggregation {
+            Aggregation::First | Aggregation::Last => 2 * size_of::<u32>(),
+            _ => size_of::<u32>(),
+        }
+    }
+
+    fn empty_cell(&self) -> (u32, u32) {
+        match self.aggregation {
+            Aggregation::Min => (0, u32::MAX),
+            _ => (0, 0),
+        }
+    }
+
+    fn index(&self, row: usize, item: &[u8]) -> usize {
+        self.hashes[row].run(item) as usize % self.width
+    }
+
+    // Never 0, which marks an empty cell.
+    fn key_fingerprint(&self, item: &[u8]) -> u32 {
+        self.fingerprint.run(item) | 1
+    }
+
+    // `other` (a cell of a later stream, when merging) folded into `cell`.
+    fn combine(aggregation: Aggregation, cell: &mut (u32, u32), other: (u32, u32)) {
+        match aggregation {
+            Aggregation::Min => cell.1 = cell.1.min(other.1),
+            Aggregation::First if cell.0 == 0 => *cell = other,
+            Aggregation::Last if other.0 != 0 => *cell = other,
+            Aggregation::First | Aggregation::Last => {}
+            _ => cell.1 = cell.1.max(other.1),
+        }
+    }
+
+    fn insert(&mut self, item: &[u8], value: u32) {
+        let fingerprint = self.key_fingerprint(item);
+        for row in 0..self.depth {
+            let index = self.index(row, item);
+            Self::combine(self.aggregation, &mut self.cells[row][index], (fingerprint, value));
+        }
+    }
+
+    fn query(&self, item: &[u8]) -> u64 {
+        let mut cells = (0..self.depth).map(|row| self.cells[row][self.index(row, item)]);
+        let value = match self.aggregation {
+            Aggregation::Min => cells.map(|(_, value)| value).max(),
+            Aggregation::First | Aggregation::Last => {
+                let fingerprint = self.key_fingerprint(item);
+                cells.find(|(cell_fingerprint, _)| *cell_fingerprint == fingerprint).map(|(_, value)| value)
+            }
+            _ => cells.map(|(_, value)| value).min(),
+        };
+        value.unwrap_or(0) as u64
+    }
+
+    fn merge(&mut self, other: &Registers) -> Result<(), String> {
+        if self.aggregation != other.aggregation || self.depth != other.depth || self.width != other.width {
+            return Err(format!(
+                "Register parameters differ: {:?} {}x{} vs {:?} {}x{}",
+                self.aggregation, self.depth, self.width, other.aggregation, other.depth, other.width
+            ));
+        }
+        for (row, other_row) in self.cells.iter_mut().zip(&other.cells) {
+            for (cell, other_cell) in row.iter_mut().zip(other_row) {
+                Self::combine(self.aggregation, cell, *other_cell);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// State of an aggregating `Reduce`; `estimate` reads the aggregate of a key.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum AggregateSketch {
+    /// The minimum, maximum, first or last value of every key.
+    ExactValue {
+        aggregation: Aggregation,
+        #[serde(serialize_with = "serialize_pairs", deserialize_with = "deserialize_pairs")]
+        values: HashMap<Vec<u8>, u64>,
+    },
+    /// How often each key saw each value.
+    ExactQuantile {
+        q: f64,
+        #[serde(serialize_with = "serialize_pairs", deserialize_with = "deserialize_pairs")]
+        counts: HashMap<Vec<u8>, BTreeMap<u32, u64>>,
+    },
+    Mean { sum: Box<Sketch>, count: Box<Sketch> },
+    Registers(Registers),
+    /// Packet counts per key and value bucket, in a sketch keyed by the key followed by
+    /// the bucket. `max_bucket` bounds the buckets a query reads.
+    Quantile { q: f64, buckets: Box<Sketch>, max_bucket: u8 },
+}
+
+impl AggregateSketch {
+    /// Exact state for `Min`, `Max`, `First`, `Last` or a quantile.
+    pub fn exact(aggregation: Aggregation) -> Self {
+        match aggregation {
+            Aggregation::Quantile(q) => AggregateSketch::ExactQuantile { q, counts: HashMap::new() },
+            aggregation => AggregateSketch::ExactValue { aggregation, values: HashMap::new() },
+        }
+    }
+
+    pub fn mean(sum: Sketch, count: Sketch) -> Self {
+        AggregateSketch::Mean { sum: Box::new(sum), count: Box::new(count) }
+    }
+
+    /// Registers for `Min`, `Max`, `First` or `Last` in `memory_in_bytes`.
+    pub fn registers(aggregation: Aggregation, memory_in_bytes: usize, depth: usize) -> Self {
+        AggregateSketch::Registers(Registers::new(aggregation, memory_in_bytes, depth))
+    }
+
+    pub fn quantile(q: f64, buckets: Sketch) -> Self {
+        AggregateSketch::Quantile { q, buckets: Box::new(buckets), max_bucket: 0 }
+    }
+
+    fn bucket_key(item: &[u8], bucket: u8) -> Vec<u8> {
+        let mut key = item.to_vec();
+        key.push(bucket);
+        key
+    }
+
+    pub fn insert(&mut self, item: &[u8], value: u32) {
+        match self {
+            AggregateSketch::ExactValue { aggregation, values } => fold_value(*aggregation, values, item, value as u64),
+            AggregateSketch::ExactQuantile { counts, .. } => {
+                *counts.entry(item.to_vec()).or_default().entry(value).or_insert(0) += 1;
+            }
+            AggregateSketch::Mean { sum, count } => {
+                sum.increment(item, value as u64);
+                count.increment(item, 1);
+            }
+            AggregateSketch::Registers(registers) => registers.insert(item, value),
+            AggregateSketch::Quantile { buckets, max_bucket, .. } => {
+                let bucket = bucket(value);
+                *max_bucket = (*max_bucket).max(bucket);
+                buckets.increment(&Self::bucket_key(item, bucket), 1);
+            }
+        }
+    }
+
+    /// The mean rounds down; a quantile from buckets is the midpoint of its bucket.
+    pub fn query(&self, item: &[u8]) -> u64 {
+        match self {
+            AggregateSketch::ExactValue { values, .. } => values.get(item).copied().unwrap_or(0),
+            AggregateSketch::ExactQuantile { q, counts } => match counts.get(item) {
+                Some(values) => nearest_rank(*q, values.iter().map(|(value, count)| (*value as u64, *count))),
+                None => 0,
+            },
+            AggregateSketch::Mean { sum, count } => sum.estimate(item).checked_div(count.estimate(item)).unwrap_or(0),
+            AggregateSketch::Registers(registers) => registers.query(item),
+            AggregateSketch::Quantile { q, buckets, max_bucket } => {
+                let histogram: Vec<(u64, u64)> = (0..=*max_bucket)
+                    .map(|bucket| (bucket_value(bucket), buckets.estimate(&Self::bucket_key(item, bucket))))
+                    .collect();
+                nearest_rank(*q, histogram.into_iter())
+            }
+        }
+    }
+
+    /// Whether every estimate is the true aggregate.
+    pub fn is_exact(&self) -> bool {
+        match self {
+            AggregateSketch::ExactValue { .. } | AggregateSketch::ExactQuantile { .. } => true,
+            AggregateSketch::Mean { sum, count } => {
+                matches!((sum.as_ref(), count.as_ref()), (Sketch::DeterministicSketch(_), Sketch::DeterministicSketch(_)))
+            }
+            AggregateSketch::Registers(_) | AggregateSketch::Quantile { .. } => false,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        match self {
+            AggregateSketch::ExactValue { values, .. } => values.is_empty(),
+            AggregateSketch::ExactQuantile { counts, .. } => counts.is_empty(),
+            AggregateSketch::Mean { sum, count } => sum.is_empty() && count.is_empty(),
+            AggregateSketch::Registers(registers) => {
+                let empty = registers.empty_cell();
+                registers.cells.iter().all(|row| row.iter().all(|&cell| cell == empty))
+            }
+            AggregateSketch::Quantile { buckets, .. } => buckets.is_empty(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        match self {
+            AggregateSketch::ExactValue { values, .. } => values.clear(),
+            AggregateSketch::ExactQuantile { counts, .. } => counts.clear(),
+            AggregateSketch::Mean { sum, count } => {
+                sum.clear();
+                count.clear();
+            }
+            AggregateSketch::Registers(registers) => {
+                let empty = registers.empty_cell();
+                registers.cells.iter_mut().for_each(|row| row.fill(empty));
+            }
+            AggregateSketch::Quantile { buckets, max_bucket, .. } => {
+                buckets.clear();
+                *max_bucket = 0;
+            }
+        }
+    }
+
+    /// Exact state counts each key with an 8-byte value, or 12 bytes per distinct value
+    /// for quantiles.
+    pub fn get_memory_usage(&self) -> usize {
+        match self {
+            AggregateSketch::ExactValue { values, .. } => values.keys().map(|key| key.len() + size_of::<u64>()).sum(),
+            AggregateSketch::ExactQuantile { counts, .. } => counts
+                .iter()
+                .map(|(key, values)| key.len() + values.len() * (size_of::<u32>() + size_of::<u64>()))
+                .sum(),
+            AggregateSketch::Mean { sum, count } => sum.get_memory_usage() + count.get_memory_usage(),
+            AggregateSketch::Registers(registers) => registers.depth * registers.width * registers.cell_bytes(),
+            AggregateSketch::Quantile { buckets, .. } => buckets.get_memory_usage(),
+        }
+    }
+
+    /// Folds in the state of another stream. For `First` and `Last`, `other` is taken to
+    /// come after this one.
+    pub fn merge(&mut self, other: &AggregateSketch) -> Result<(), String> {
+        match (self, other) {
+            (
+                AggregateSketch::ExactValue { aggregation, values },
+                AggregateSketch::ExactValue { aggregation: other_aggregation, values: other_values },
+            ) if aggregation == other_aggregation => {
+                for (item, value) in other_values {
+                    fold_value(*aggregation, values, item, *value);
+                }
+                Ok(())
+            }
+            (AggregateSketch::ExactQuantile { q, counts }, AggregateSketch::ExactQuantile { q: other_q, counts: other_counts })
+                if q == other_q =>
+            {
+                for (item, other_values) in other_counts {
+                    let values = counts.entry(item.clone()).or_default();
+                    for (value, count) in other_values {
+                        *values.entry(*value).or_insert(0) += count;
+                    }
+                }
+                Ok(())
+            }
+            (AggregateSketch::Mean { sum, count }, AggregateSketch::Mean { sum: other_sum, count: other_count }) => {
+                sum.merge(other_sum)?;
+                count.merge(other_count)
+            }
+            (AggregateSketch::Registers(registers), AggregateSketch::Registers(other)) => registers.merge(other),
+            (
+                AggregateSketch::Quantile { q, buckets, max_bucket },
+                AggregateSketch::Quantile { q: other_q, buckets: other_buckets, max_bucket: other_max },
+            ) if q == other_q => {
+                *max_bucket = (*max_bucket).max(*other_max);
+                buckets.merge(other_buckets)
+            }
+            _ => Err("Cannot merge aggregates of different kinds".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merged(mut sketch: AggregateSketch, other: AggregateSketch) -> AggregateSketch {
+        sketch.merge(&other).unwrap();
+        sketch
+    }
+
+    fn exact(aggregation: Aggregation, values: &[(&[u8], u32)]) -> AggregateSketch {
+        let mut sketch = AggregateSketch::exact(aggregation);
+        values.iter().for_each(|(item, value)| sketch.insert(item, *value));
+        sketch
+    }
+
+    // One register cell, so that every key collides.
+    fn one_cell(aggregation: Aggregation, item: &[u8], value: u32) -> AggregateSketch {
+        let mut sketch = AggregateSketch::registers(aggregation, 4, 1);
+        sketch.insert(item, value);
+        sketch
+    }
+
+    fn registers(aggregation: Aggregation, item: &[u8], value: u32) -> AggregateSketch {
+        let mut sketch = AggregateSketch::registers(aggregation, 4096, 2);
+        sketch.insert(item, value);
+        sketch
+    }
+
+    #[test]
+    fn bucket_midpoints_are_within_an_eighth_of_their_values() {
+        let values = (0..100_000).chain((0..32).map(|shift| 1u32 << shift)).chain([u32::MAX, u32::MAX / 3 * 2]);
+        for value in values {
+            let index = bucket(value);
+            assert!((index as usize) < QUANTILE_BUCKETS, "{}", value);
+            let midpoint = bucket_value(index);
+            assert_eq!(bucket(midpoint as u32), index, "{}", value);
+            if value < 8 {
+                assert_eq!(midpoint, value as u64);
+            } else {
+                assert!(midpoint.abs_diff(value as u64) * 8 <= value as u64, "{} in bucket {} at {}", value, index, midpoint);
+            }
+        }
+        assert_eq!(bucket(u32::MAX) as usize, QUANTILE_BUCKETS - 1);
+    }
+
+    #[test]
+    fn nearest_rank_picks_the_first_value_reaching_the_rank() {
+        let uniform = [(1, 1), (2, 1), (3, 1), (4, 1)];
+        assert_eq!(nearest_rank(0.0, uniform.into_iter()), 1);
+        assert_eq!(nearest_rank(0.5, uniform.into_iter()), 2);
+        assert_eq!(nearest_rank(0.51, uniform.into_iter()), 3);
+        assert_eq!(nearest_rank(1.0, uniform.into_iter()), 4);
+
+        let weighted = [(10, 3), (20, 1)];
+        assert_eq!(nearest_rank(0.75, weighted.into_iter()), 10);
+        assert_eq!(nearest_rank(0.9, weighted.into_iter()), 20);
+        assert_eq!(nearest_rank(0.5, [(10, 0)].into_iter()), 0);
+        assert_eq!(nearest_rank(0.5, std::iter::empty()), 0);
+    }
+
+    #[test]
+    fn exact_values_merge_as_one_stream() {
+        let first: &[(&[u8], u32)] = &[(b"a", 5), (b"b", 1)];
+        let second: &[(&[u8], u32)] = &[(b"a", 3), (b"c", 9)];
+        for (aggregation, a) in [(Aggregation::Min, 3), (Aggregation::Max, 5), (Aggregation::First, 5), (Aggregation::Last, 3)] {
+            let sketch = merged(exact(aggregation, first), exact(aggregation, second));
+            assert_eq!(sketch.query(b"a"), a, "{:?}", aggregation);
+            assert_eq!(sketch.query(b"b"), 1, "{:?}", aggregation);
+            assert_eq!(sketch.query(b"c"), 9, "{:?}", aggregation);
+        }
+
+        let quantiles = merged(
+            exact(Aggregation::Quantile(0.5), &[(b"a", 1), (b"a", 2)]),
+            exact(Aggregation::Quantile(0.5), &[(b"a", 3), (b"a", 4), (b"a", 2)]),
+        );
+        assert_eq!(quantiles.query(b"a"), 2);
+    }
+
+    #[test]
+    fn register_collisions_only_move_min_down_and_max_up() {
+        let min = merged(one_cell(Aggregation::Min, b"a", 10), one_cell(Aggregation::Min, b"b", 5));
+        assert_eq!(min.query(b"a"), 5);
+        let min = merged(one_cell(Aggregation::Min, b"a", 10), one_cell(Aggregation::Min, b"b", 50));
+        assert_eq!(min.query(b"a"), 10);
+
+        let max = merged(one_cell(Aggregation::Max, b"a", 10), one_cell(Aggregation::Max, b"b", 20));
+        assert_eq!(max.query(b"a"), 20);
+        let max = merged(one_cell(Aggregation::Max, b"a", 10), one_cell(Aggregation::Max, b"b", 1));
+        assert_eq!(max.query(b"a"), 10);
+    }
+
+    #[test]
+    fn register_first_and_last_take_the_merged_stream_as_later() {
+        let first = merged(registers(Aggregation::First, b"a", 1), registers(Aggregation::First, b"a", 2));
+        assert_eq!(first.query(b"a"), 1);
+        let first = merged(AggregateSketch::registers(Aggregation::First, 4096, 2), registers(Aggregation::First, b"a", 2));
+        assert_eq!(first.query(b"a"), 2);
+
+        let last = merged(registers(Aggregation::Last, b"a", 1), registers(Aggregation::Last, b"a", 2));
+        assert_eq!(last.query(b"a"), 2);
+        let last = merged(registers(Aggregation::Last, b"a", 1), AggregateSketch::registers(Aggregation::Last, 4096, 2));
+        assert_eq!(last.query(b"a"), 1);
+
+        // A key whose cells were all taken by another reads 0.
+        let taken = merged(one_cell(Aggregation::Last, b"a", 1), one_cell(Aggregation::Last, b"b", 2));
+        assert_eq!(taken.query(b"a"), 0);
+    }
+
+    #[test]
+    fn mean_merges_sums_and_counts_and_reads_0_without_packets() {
+        let mean = || AggregateSketch::mean(Sketch::new_deterministic_sketch(), Sketch::new_deterministic_sketch());
+        let mut first = mean();
+        first.insert(b"a", 10);
+        first.insert(b"a", 20);
+        let mut second = mean();
+        second.insert(b"a", 31);
+        let sketch = merged(first, second);
+        assert_eq!(sketch.query(b"a"), 20);
+        assert_eq!(sketch.query(b"b"), 0);
+        assert!(sketch.is_exact());
+    }
+
+    #[test]
+    fn bucket_quantiles_merge_their_histograms() {
+        let quantile = |values: &[u32]| {
+            let mut sketch = AggregateSketch::quantile(0.5, Sketch::new_deterministic_sketch());
+            values.iter().for_each(|value| sketch.insert(b"a", *value));
+            sketch
+        };
+        let sketch = merged(quantile(&[3, 1000]), quantile(&[5000, 6000, 1000]));
+        assert_eq!(sketch.query(b"a"), bucket_value(bucket(1000)));
+        assert_eq!(merged(quantile(&[1]), quantile(&[100_000])).query(b"a"), 1);
+    }
+
+    #[test]
+    fn aggregates_of_different_kinds_do_not_merge() {
+        let mut min = AggregateSketch::exact(Aggregation::Min);
+        assert!(min.merge(&AggregateSketch::exact(Aggregation::Max)).is_err());
+        assert!(min.merge(&AggregateSketch::registers(Aggregation::Min, 64, 1)).is_err());
+        let mut registers = AggregateSketch::registers(Aggregation::Min, 64, 1);
+        assert!(registers.merge(&AggregateSketch::registers(Aggregation::Min, 128, 1)).is_err());
+    }
+}
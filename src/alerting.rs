@@ -0,0 +1,361 @@
+// alerting.rs
+// Alert events raised from a query's FilterResult output, deduplicated per key across
+// epochs and delivered to one or more sinks.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+use serde_json::json;
+use crate::flow_key::format_key;
+use crate::query_executor::{passes_filter, CompiledPlan};
+use crate::record::Record;
+use crate::sketch::{Estimate, Sketch, ThresholdStatus};
+
+const DEFAULT_SYSLOG_SOCKET: &str = "/dev/log";
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Info,
+    Warning,
+    Critical,
+}
+
+impl Severity {
+    fn syslog_level(&self) -> u8 {
+        match self {
+            Severity::Info => 6,
+            Severity::Warning => 4,
+            Severity::Critical => 2,
+        }
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Info => write!(f, "info"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Critical => write!(f, "critical"),
+        }
+    }
+}
+
+pub struct AlertEvent {
+    pub query: String,
+    pub key: String,
+    pub value: u64,
+    pub threshold: u64,
+    pub epoch: usize,
+    pub epoch_end: u64,
+    pub severity: Severity,
+    /// Whether the key is above the threshold for certain, given the sketch's bounds.
+    pub status: ThresholdStatus,
+}
+
+impl AlertEvent {
+    fn to_json(&self) -> String {
+        json!({
+            "query": self.query,
+            "epoch": self.epoch,
+            "epoch_end": self.epoch_end,
+            "key": self.key,
+            "value": self.value,
+            "threshold": self.threshold,
+            "severity": self.severity.to_string(),
+            "status": self.status.to_string(),
+        })
+        .to_string()
+    }
+}
+
+impl fmt::Display for AlertEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[{}] query {} epoch {}: {} = {} (threshold {}, {})",
+            self.severity, self.query, self.epoch, self.key, self.value, self.threshold, self.status
+        )
+    }
+}
+
+/// Where alerts are delivered, parsed from an `ALERT_SINKS` entry.
+pub enum AlertSink {
+    /// `stdout`
+    Stdout,
+    /// `file:<path>`, one JSON object per line.
+    File(File),
+    /// `syslog` or `syslog:<socket>`, RFC 3164 messages on a Unix datagram socket.
+    Syslog(UnixDatagram),
+    /// `webhook:http://<host>:<port>/<path>`, each event POSTed as JSON. Delivery is a
+    /// blocking round-trip per event, with a 2 s timeout per connect, write and read, made
+    /// at the end of the epoch on the thread that reads packets.
+    Webhook { host: String, path: String },
+    /// Events kept in memory, for tests.
+    #[cfg(test)]
+    Memory(Vec<(usize, String, Severity)>),
+}
+
+impl AlertSink {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let (kind, target) = spec.split_once(':').unwrap_or((spec, ""));
+        match kind {
+            "stdout" => Ok(AlertSink::Stdout),
+            "file" if !target.is_empty() => OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(target)
+                .map(AlertSink::File)
+                .map_err(|e| format!("Cannot open alert file {}: {}", target, e)),
+            "syslog" => {
+                let socket_path = if target.is_empty() { DEFAULT_SYSLOG_SOCKET } else { target };
+                let socket = UnixDatagram::unbound().map_err(|e| e.to_string())?;
+                socket
+                    .connect(socket_path)
+                    .map_err(|e| format!("Cannot connect to syslog socket {}: {}", socket_path, e))?;
+                Ok(AlertSink::Syslog(socket))
+            }
+            "webhook" => {
+                let rest = target
+                    .strip_prefix("http://")
+                    .ok_or_else(|| format!("Webhook must be an http:// URL: {}", target))?;
+                let (host, path) = match rest.find('/') {
+                    Some(i) => (&rest[..i], &rest[i..]),
+                    None => (rest, "/"),
+                };
+                Ok(AlertSink::Webhook { host: host.to_string(), path: path.to_string() })
+            }
+            _ => Err(format!("Unknown alert sink '{}' (expected stdout, file:<path>, syslog[:<socket>] or webhook:<url>)", spec)),
+        }
+    }
+
+    fn send(&mut self, event: &AlertEvent) -> Result<(), String> {
+        match self {
+            AlertSink::Stdout => {
+                println!("ALERT {}", event);
+                Ok(())
+            }
+            AlertSink::File(file) => writeln!(file, "{}", event.to_json()).map_err(|e| e.to_string()),
+            AlertSink::Syslog(socket) => {
+                // Facility 1 (user-level messages).
+                let message = format!("<{}>telemetry: {}", 8 + event.severity.syslog_level(), event);
+                socket.send(message.as_bytes()).map(|_| ()).map_err(|e| e.to_string())
+            }
+            AlertSink::Webhook { host, path } => post_json(host, path, &event.to_json()),
+            #[cfg(test)]
+            AlertSink::Memory(events) => {
+                events.push((event.epoch, event.key.clone(), event.severity));
+                Ok(())
+            }
+        }
+    }
+}
+
+fn post_json(host: &str, path: &str, body: &str) -> Result<(), String> {
+    let mut stream = TcpStream::connect(host).map_err(|e| format!("Cannot reach {}: {}", host, e))?;
+    stream.set_read_timeout(Some(WEBHOOK_TIMEOUT)).map_err(|e| e.to_string())?;
+    stream.set_write_timeout(Some(WEBHOOK_TIMEOUT)).map_err(|e| e.to_string())?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        host,
+        body.len(),
+        body
+    )
+    .map_err(|e| e.to_string())?;
+
+    let mut response = [0u8; 64];
+    let n = stream.read(&mut response).map_err(|e| e.to_string())?;
+    let status_line = String::from_utf8_lossy(&response[..n]);
+    match status_line.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(format!("{} answered: {}", host, status_line.lines().next().unwrap_or(""))),
+    }
+}
+
+#[derive(Clone)]
+pub struct AlertConfig {
+    pub query: String,
+    pub sinks: Vec<String>,
+    /// Epochs during which a key that already alerted does not alert again at the same
+    /// or a lower severity.
+    pub suppress_epochs: usize,
+    /// Value-to-threshold ratios from which an alert is a warning, or critical.
+    pub warning_ratio: f64,
+    pub critical_ratio: f64,
+}
+
+/// Turns the keys that pass a query's `FilterResult` into alerts, one per key unless it
+/// alerted recently.
+pub struct AlertManager {
+    config: AlertConfig,
+    sinks: Vec<AlertSink>,
+    /// Epoch and severity of the last alert fired for each key.
+    fired: HashMap<Vec<u8>, (usize, Severity)>,
+}
+
+impl AlertManager {
+    pub fn new(config: &AlertConfig) -> Result<Self, String> {
+        let sinks = config.sinks.iter().map(|spec| AlertSink::parse(spec)).collect::<Result<Vec<_>, String>>()?;
+        Ok(AlertManager { config: config.clone(), sinks, fired: HashMap::new() })
+    }
+
+    fn severity(&self, value: u64, threshold: u64) -> Severity {
+        let ratio = value as f64 / threshold.max(1) as f64;
+        if ratio >= self.config.critical_ratio {
+            Severity::Critical
+        } else if ratio >= self.config.warning_ratio {
+            Severity::Warning
+        } else {
+            Severity::Info
+        }
+    }
+
+    /// Raises an alert for every key of the epoch's result map that passes the plan's last
+    /// `FilterResult`. Plans without one never alert. Alerts are delivered before this
+    /// returns, so a slow webhook holds up packet processing for up to its timeouts per
+    /// alert.
+    pub fn process_epoch(
+        &mut self,
+        epoch: usize,
+        epoch_end: u64,
+        plan: &CompiledPlan,
+        result_map: &HashMap<Vec<u8>, Record>,
+        sketches: &HashMap<String, Sketch>,
+    ) {
+        let (threshold, value_slot, mode) = match plan.filter_result() {
+            Some(filter) => filter,
+            None => return,
+        };
+        let bound = plan.value_error_bound(value_slot, sketches);
+        let mut passing: Vec<(&Vec<u8>, Estimate)> = result_map
+            .iter()
+            .filter_map(|(key, record)| {
+                let estimate = Estimate::new(record.get(value_slot)?.as_u64()?, bound);
+                passes_filter(&estimate, threshold, mode).then_some((key, estimate))
+            })
+            .collect();
+        passing.sort_by(|a, b| b.1.value.cmp(&a.1.value).then_with(|| a.0.cmp(b.0)));
+
+        let suppress_epochs = self.config.suppress_epochs;
+        self.fired.retain(|_, (last, _)| epoch - *last < suppress_epochs);
+
+        let (mut raised, mut suppressed) = (0, 0);
+        for (key, estimate) in passing {
+            let severity = self.severity(estimate.value, threshold);
+            // Repeats are held back until the window expires; escalations go out at once.
+            if matches!(self.fired.get(key), Some((_, last_severity)) if severity <= *last_severity) {
+                suppressed += 1;
+                continue;
+            }
+            self.fired.insert(key.clone(), (epoch, severity));
+            let event = AlertEvent {
+                query: self.config.query.clone(),
+                key: format_key(&plan.result_key, key),
+                value: estimate.value,
+                threshold,
+                epoch,
+                epoch_end,
+                severity,
+                status: estimate.status(threshold),
+            };
+            for sink in &mut self.sinks {
+                if let Err(e) = sink.send(&event) {
+                    eprintln!("Failed to deliver alert: {}", e);
+                }
+            }
+            raised += 1;
+        }
+        if raised + suppressed > 0 {
+            println!("Raised {} alerts ({} suppressed as repeats)", raised, suppressed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use crate::query_executor::{compile_plan, execute_query, PacketField};
+    use crate::query_plan::{Aggregation, FilterMode, Operation, QueryPlan, ReduceType};
+
+    const THRESHOLD: u64 = 10;
+
+    fn plan() -> CompiledPlan {
+        compile_plan(&QueryPlan {
+            operations: vec![
+                Operation::Map("(dst_ip, count = 1)".to_string()),
+                Operation::Reduce {
+                    keys: vec!["dst_ip".to_string()],
+                    reduce_type: ReduceType::DeterministicReduce,
+                    field_name: "count".to_string(),
+                    aggregation: Aggregation::Sum,
+                },
+                Operation::FilterResult { threshold: THRESHOLD, field_name: "count".to_string(), mode: FilterMode::Estimate },
+            ],
+        })
+        .unwrap()
+    }
+
+    // Warnings from twice the threshold, critical from five times; repeats are held back
+    // for 3 epochs.
+    fn manager() -> AlertManager {
+        let config = AlertConfig {
+            query: "test".to_string(),
+            sinks: Vec::new(),
+            suppress_epochs: 3,
+            warning_ratio: 2.0,
+            critical_ratio: 5.0,
+        };
+        AlertManager { config, sinks: vec![AlertSink::Memory(Vec::new())], fired: HashMap::new() }
+    }
+
+    // Runs an epoch with `packets` packets to each 10.0.0.<host>, returning the alerts it raised.
+    fn epoch(manager: &mut AlertManager, plan: &CompiledPlan, epoch: usize, packets: &[(u8, u32)]) -> Vec<(usize, String, Severity)> {
+        let mut sketches = HashMap::new();
+        let mut result_map = HashMap::new();
+        for (host, count) in packets {
+            for _ in 0..*count {
+                let mut record = Record::default();
+                record.set(1, PacketField::Ipv4(Ipv4Addr::new(10, 0, 0, *host)));
+                execute_query(plan, record, &mut sketches, &mut result_map, 100, &mut Some(0), 1);
+            }
+        }
+        manager.process_epoch(epoch, epoch as u64 * 100, plan, &result_map, &sketches);
+        match &mut manager.sinks[0] {
+            AlertSink::Memory(events) => std::mem::take(events),
+            _ => unreachable!(),
+        }
+    }
+
+    fn alert(epoch: usize, host: u8, severity: Severity) -> (usize, String, Severity) {
+        (epoch, format!("dst_ip: 10.0.0.{}", host), severity)
+    }
+
+    #[test]
+    fn repeats_are_suppressed_until_the_window_expires() {
+        let (plan, mut manager) = (plan(), manager());
+        assert_eq!(epoch(&mut manager, &plan, 1, &[(1, 12), (2, 5)]), vec![alert(1, 1, Severity::Info)]);
+        assert_eq!(epoch(&mut manager, &plan, 2, &[(1, 12)]), vec![]);
+        assert_eq!(epoch(&mut manager, &plan, 3, &[(1, 15), (2, 11)]), vec![alert(3, 2, Severity::Info)]);
+        // Epoch 4 is 3 epochs after the first alert for .1, but within the window of .2.
+        assert_eq!(epoch(&mut manager, &plan, 4, &[(1, 12), (2, 11)]), vec![alert(4, 1, Severity::Info)]);
+        assert_eq!(epoch(&mut manager, &plan, 6, &[(1, 12), (2, 11)]), vec![alert(6, 2, Severity::Info)]);
+    }
+
+    #[test]
+    fn escalations_are_raised_at_once() {
+        let (plan, mut manager) = (plan(), manager());
+        assert_eq!(epoch(&mut manager, &plan, 1, &[(1, 12)]), vec![alert(1, 1, Severity::Info)]);
+        assert_eq!(epoch(&mut manager, &plan, 2, &[(1, 25)]), vec![alert(2, 1, Severity::Warning)]);
+        // Back down to info, and a warning again, are both repeats of the warning.
+        assert_eq!(epoch(&mut manager, &plan, 3, &[(1, 12)]), vec![]);
+        assert_eq!(epoch(&mut manager, &plan, 3, &[(1, 30)]), vec![]);
+        assert_eq!(epoch(&mut manager, &plan, 4, &[(1, 50)]), vec![alert(4, 1, Severity::Critical)]);
+    }
+}
@@ -3,13 +3,16 @@ use rand::rngs::StdRng;
 use bitvec::prelude::*;
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
+use serde::{Deserialize, Serialize};
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct BeauCoupSketch {
     num_rows: usize,          // m
     num_coupons: usize,       // w
     d: usize,                 // number of hash functions
     max_coupons_per_packet: usize,
     tables: Vec<BitVec>,
+    #[serde(skip, default = "StdRng::from_entropy")]
     rng: StdRng,
     hash_seeds: Vec<u64>,
 }
@@ -31,14 +34,14 @@ impl BeauCoupSketch {
         }
     }
 
-    fn hash(&self, key: &str, seed: u64) -> usize {
+    fn hash(&self, key: &[u8], seed: u64) -> usize {
         let mut hasher = DefaultHasher::new();
         key.hash(&mut hasher);
         seed.hash(&mut hasher);
         (hasher.finish() as usize) % self.num_rows
     }
 
-    pub fn insert(&mut self, key: &str) {
+    pub fn insert(&mut self, key: &[u8]) {
         for i in 0..self.d {
             let row_index = self.hash(key, self.hash_seeds[i]);
             let bv = &mut self.tables[row_index];
@@ -50,7 +53,7 @@ impl BeauCoupSketch {
         }
     }
 
-    pub fn estimate(&self, key: &str) -> u64 {
+    pub fn estimate(&self, key: &[u8]) -> u64 {
         let mut max_k = 0;
 
         for i in 0..self.d {
@@ -72,13 +75,37 @@ impl BeauCoupSketch {
         }
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.tables.iter().all(|bv| bv.not_any())
+    }
+
     pub fn clear(&mut self) {
         for bv in self.tables.iter_mut() {
             bv.fill(false);
         }
     }
 
-pub fn contains(&self, key: &str) -> bool {
+    pub fn get_memory_usage(&self) -> usize {
+        self.tables.iter().map(|table| table.len().div_ceil(8)).sum()
+    }
+
+    pub fn merge(&mut self, other: &BeauCoupSketch) -> Result<(), String> {
+        if self.num_rows != other.num_rows
+            || self.num_coupons != other.num_coupons
+            || self.hash_seeds != other.hash_seeds
+        {
+            return Err(format!(
+                "BeauCoup parameters differ: {} rows/{} coupons vs {} rows/{} coupons",
+                self.num_rows, self.num_coupons, other.num_rows, other.num_coupons
+            ));
+        }
+        for (table, other_table) in self.tables.iter_mut().zip(&other.tables) {
+            *table |= other_table;
+        }
+        Ok(())
+    }
+
+pub fn contains(&self, key: &[u8]) -> bool {
     for i in 0..self.d {
         let row_index = self.hash(key, self.hash_seeds[i]);
         let bv = &self.tables[row_index];
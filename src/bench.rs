@@ -0,0 +1,312 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::time::Instant;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use crate::query_executor::{PacketField, CompiledPlan, execute_query, reduce_sketch_key, new_reduce_sketch};
+use crate::query_plan::{QueryPlan, Operation, Field, ReduceType, Aggregation, FilterMode};
+use crate::record::Record;
+use crate::sketch::Sketch;
+
+const BENCH_EPOCH_PACKETS: usize = 100_000;
+const BENCH_EPOCH_SIZE: u64 = 30;
+
+// src, dst, src_port, dst_port, tcp_flags, total_len
+type Tuple = ([u8; 4], [u8; 4], u16, u16, u8, u32);
+
+type StringPacket = HashMap<String, PacketField>;
+
+/// Runs a query over synthetic TCP packets twice, on the string-keyed path the executor
+/// used before plans were compiled and on the compiled plan, and reports the throughput
+/// of each. Packet construction is timed too, as it is part of the per-packet cost in
+/// `process_pcap`.
+pub fn run_benchmark(query: &QueryPlan, plan: &CompiledPlan, num_packets: usize) {
+    let tuples = synthetic_tuples(num_packets);
+
+    let baseline = match baseline_supports(query) {
+        Ok(()) => {
+            let elapsed = time_baseline(query, &tuples);
+            report("Baseline (string-keyed packets)", num_packets, elapsed);
+            Some(elapsed)
+        }
+        Err(e) => {
+            println!("Baseline skipped: {}", e);
+            None
+        }
+    };
+
+    let (elapsed, key_allocations) = time_compiled(plan, &tuples);
+    report("Compiled plan (typed records)", num_packets, elapsed);
+    println!(
+        "Result keys allocated: {} ({:.4} per packet)",
+        key_allocations,
+        key_allocations as f64 / num_packets as f64
+    );
+    if let Some(baseline) = baseline {
+        println!("Speedup: {:.2}x", baseline / elapsed);
+    }
+}
+
+fn report(label: &str, num_packets: usize, elapsed: f64) {
+    println!(
+        "{}: {} packets in {:.3} s ({:.0} packets/s)",
+        label,
+        num_packets,
+        elapsed,
+        num_packets as f64 / elapsed
+    );
+}
+
+fn synthetic_tuples(num_packets: usize) -> Vec<Tuple> {
+    let mut rng = StdRng::seed_from_u64(42);
+    (0..num_packets)
+        .map(|_| {
+            let src = [rng.gen_range(1..224), rng.gen(), rng.gen(), rng.gen_range(1..255)];
+            let dst = if rng.gen_bool(0.2) {
+                [192, 168, 1, 1]
+            } else {
+                [172, 16, rng.gen_range(0..4), rng.gen_range(1..255)]
+            };
+            (
+                src,
+                dst,
+                rng.gen_range(1024..65535),
+                [80u16, 443, 22, 53][rng.gen_range(0..4)],
+                [2u8, 16, 17, 24][rng.gen_range(0..4)],
+                rng.gen_range(40..1500),
+            )
+        })
+        .collect()
+}
+
+/// Times the compiled plan, and counts the result keys it inserted: the only allocation
+/// `execute_query` makes per packet for plans without string fields, once per key and epoch.
+fn time_compiled(plan: &CompiledPlan, tuples: &[Tuple]) -> (f64, usize) {
+    let mut sketches: HashMap<String, Sketch> = HashMap::new();
+    let mut result_map: HashMap<Vec<u8>, Record> = HashMap::new();
+    let mut epoch_start = Some(0u64);
+    let mut key_allocations = 0;
+
+    let start = Instant::now();
+    for (i, (src, dst, src_port, dst_port, flags, len)) in tuples.iter().enumerate() {
+        let timestamp = (i / BENCH_EPOCH_PACKETS) as u64 * BENCH_EPOCH_SIZE;
+        let mut record = Record::default();
+        record.set(0, PacketField::Ipv4(Ipv4Addr::from(*src)));
+        record.set(1, PacketField::Ipv4(Ipv4Addr::from(*dst)));
+        record.set(2, PacketField::U16(*src_port));
+        record.set(3, PacketField::U16(*dst_port));
+        record.set(4, PacketField::U8(*flags));
+        record.set(5, PacketField::U32(*len));
+        record.set(6, PacketField::U8(6));
+        record.set(7, PacketField::OptionU16(None));
+        execute_query(plan, record, &mut sketches, &mut result_map, BENCH_EPOCH_SIZE, &mut epoch_start, timestamp + 1);
+
+        if (i + 1) % BENCH_EPOCH_PACKETS == 0 {
+            key_allocations += result_map.len();
+            sketches.values_mut().for_each(|sketch| sketch.clear());
+            result_map.clear();
+            epoch_start = Some(timestamp + BENCH_EPOCH_SIZE);
+        }
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    (elapsed, key_allocations + result_map.len())
+}
+
+fn time_baseline(query: &QueryPlan, tuples: &[Tuple]) -> f64 {
+    let mut sketches: HashMap<String, Sketch> = HashMap::new();
+    let mut result_map: HashMap<Vec<u8>, StringPacket> = HashMap::new();
+    let mut epoch_start = 0u64;
+
+    let start = Instant::now();
+    for (i, (src, dst, src_port, dst_port, flags, len)) in tuples.iter().enumerate() {
+        let timestamp = (i / BENCH_EPOCH_PACKETS) as u64 * BENCH_EPOCH_SIZE;
+        let mut packet = StringPacket::new();
+        packet.insert("src_ip".to_string(), PacketField::String(Ipv4Addr::from(*src).to_string()));
+        packet.insert("dst_ip".to_string(), PacketField::String(Ipv4Addr::from(*dst).to_string()));
+        packet.insert("src_port".to_string(), PacketField::U16(*src_port));
+        packet.insert("dst_port".to_string(), PacketField::U16(*dst_port));
+        packet.insert("tcp_flags".to_string(), PacketField::U8(*flags));
+        packet.insert("total_len".to_string(), PacketField::U32(*len));
+        packet.insert("protocol".to_string(), PacketField::U8(6));
+        packet.insert("dns_ns_type".to_string(), PacketField::OptionU16(None));
+        execute_baseline(query, packet, &mut sketches, &mut result_map, epoch_start, timestamp + 1);
+
+        if (i + 1) % BENCH_EPOCH_PACKETS == 0 {
+            sketches.values_mut().for_each(|sketch| sketch.clear());
+            result_map.clear();
+            epoch_start = timestamp + BENCH_EPOCH_SIZE;
+        }
+    }
+    start.elapsed().as_secs_f64()
+}
+
+/// The baseline covers the per-packet operators the string-keyed executor had.
+fn baseline_supports(query: &QueryPlan) -> Result<(), String> {
+    for op in &query.operations {
+        match op {
+            Operation::Filter(_) => {}
+            Operation::Map(expr) if !map_operations(expr).iter().any(|item| item.contains('(')) => {}
+            Operation::Reduce { reduce_type, aggregation: Aggregation::Sum, .. } if reduce_sketch_key(reduce_type).is_some() => {}
+            Operation::FilterResult { mode: FilterMode::Estimate, .. } => {}
+            Operation::Distinct { distinct_type: ReduceType::BloomFilter { .. } | ReduceType::DeterministicReduce, .. } => {}
+            other => return Err(format!("the string-keyed executor has no equivalent of {:?}", other)),
+        }
+    }
+    Ok(())
+}
+
+fn map_operations(expr: &str) -> Vec<&str> {
+    expr.trim_matches(|c| c == '(' || c == ')').split(',').map(|s| s.trim()).collect()
+}
+
+// The per-packet work of the executor before plans were compiled: packets are maps from
+// field names to values, IPs are strings, every Map builds a new map and every Reduce
+// and Distinct formats its sketch name and encodes its key from the named fields.
+fn execute_baseline(
+    query: &QueryPlan,
+    packet: StringPacket,
+    sketches: &mut HashMap<String, Sketch>,
+    result_map: &mut HashMap<Vec<u8>, StringPacket>,
+    epoch_start: u64,
+    timestamp: u64,
+) -> Option<StringPacket> {
+    let mut current_packet = packet;
+
+    for op in &query.operations {
+        match op {
+            Operation::Filter(conditions) => {
+                for (field, value) in conditions {
+                    let field_key = match field {
+                        Field::TcpFlag => "tcp_flags",
+                        Field::SourceIp => "src_ip",
+                        Field::DestIp => "dst_ip",
+                        Field::SourcePort => "src_port",
+                        Field::DestPort => "dst_port",
+                        Field::Protocol => "protocol",
+                        Field::DnsNsType => "dns_ns_type",
+                    };
+                    let pass = match current_packet.get(field_key) {
+                        Some(PacketField::String(s)) => s == value,
+                        Some(PacketField::U16(v)) => v.to_string() == *value,
+                        Some(PacketField::U8(v)) => v.to_string() == *value,
+                        Some(PacketField::OptionU16(Some(v))) => v.to_string() == *value,
+                        _ => false,
+                    };
+                    if !pass {
+                        return None;
+                    }
+                }
+            }
+            Operation::Map(expr) => {
+                let mut new_packet = StringPacket::new();
+                for operation in map_operations(expr) {
+                    if let Some((key, value)) = operation.split_once('=') {
+                        let (key, value) = (key.trim(), value.trim());
+                        match value.parse::<u32>() {
+                            Ok(parsed_value) => new_packet.insert(key.to_string(), PacketField::U32(parsed_value)),
+                            Err(_) => new_packet.insert(key.to_string(), PacketField::String(value.to_string())),
+                        };
+                    } else if let Some(value) = current_packet.get(operation) {
+                        new_packet.insert(operation.to_string(), value.clone());
+                    }
+                }
+                current_packet = new_packet;
+            }
+            Operation::Reduce { keys, reduce_type, field_name, .. } => {
+                let key = encode_baseline_key(keys, &current_packet);
+                let sketch_key = reduce_sketch_key(reduce_type)?;
+                let sketch = sketches.entry(sketch_key).or_insert_with(|| new_reduce_sketch(reduce_type));
+                let Some(PacketField::U32(value)) = current_packet.get(field_name) else {
+                    return None;
+                };
+                sketch.increment(&key, *value as u64);
+                let estimated_count = sketch.estimate(&key);
+                current_packet.insert(field_name.to_string(), PacketField::U32(estimated_count as u32));
+                result_map.insert(key, current_packet.clone());
+            }
+            Operation::FilterResult { threshold, field_name, .. } => {
+                if timestamp - epoch_start >= BENCH_EPOCH_SIZE {
+                    result_map.retain(|_, fields| {
+                        matches!(fields.get(field_name), Some(PacketField::U32(value)) if *value as u64 >= *threshold)
+                    });
+                }
+            }
+            Operation::Distinct { keys, distinct_type } => {
+                let key = encode_baseline_key(keys, &current_packet);
+                let sketch = match distinct_type {
+                    ReduceType::BloomFilter { size, num_hashes, seed } => sketches
+                        .entry(format!("DistinctBloomFilter_{}_{}", size, num_hashes))
+                        .or_insert_with(|| Sketch::new_bloom_filter(*size, *num_hashes, *seed)),
+                    _ => sketches
+                        .entry("DistinctDeterministicSketch".to_string())
+                        .or_insert_with(Sketch::new_deterministic_sketch),
+                };
+                match distinct_type {
+                    ReduceType::BloomFilter { .. } => {
+                        if sketch.contains(&key) {
+                            return None;
+                        }
+                        sketch.insert(&key);
+                    }
+                    _ => {
+                        if sketch.estimate(&key) > 0 {
+                            return None;
+                        }
+                        sketch.increment(&key, 1);
+                    }
+                }
+            }
+            _ => return None,
+        }
+    }
+    Some(current_packet)
+}
+
+// Key layout of the string-keyed executor: addresses parsed back from their text, ports,
+// flags and lengths big-endian, and other fields tagged with their type.
+fn encode_baseline_key(keys: &[String], packet: &StringPacket) -> Vec<u8> {
+    let mut out = Vec::with_capacity(keys.len() * 4);
+    for k in keys {
+        let value = packet.get(k);
+        match k.as_str() {
+            "src_ip" | "dst_ip" => {
+                let ip = match value {
+                    Some(PacketField::String(s)) => s.parse::<Ipv4Addr>().unwrap_or(Ipv4Addr::UNSPECIFIED),
+                    _ => Ipv4Addr::UNSPECIFIED,
+                };
+                out.extend_from_slice(&ip.octets());
+            }
+            "src_port" | "dst_port" => match value {
+                Some(PacketField::U16(v)) | Some(PacketField::OptionU16(Some(v))) => out.extend_from_slice(&v.to_be_bytes()),
+                _ => out.extend_from_slice(&[0, 0]),
+            },
+            "tcp_flags" | "protocol" => match value {
+                Some(PacketField::U8(v)) => out.push(*v),
+                _ => out.push(0),
+            },
+            "total_len" => match value {
+                Some(PacketField::U32(v)) => out.extend_from_slice(&v.to_be_bytes()),
+                _ => out.extend_from_slice(&[0; 4]),
+            },
+            _ => match value {
+                Some(PacketField::U8(v)) => out.extend_from_slice(&[1, *v]),
+                Some(PacketField::U16(v)) => {
+                    out.push(2);
+                    out.extend_from_slice(&v.to_be_bytes());
+                }
+                Some(PacketField::U32(v)) => {
+                    out.push(3);
+                    out.extend_from_slice(&v.to_be_bytes());
+                }
+                Some(PacketField::String(s)) => {
+                    let bytes = &s.as_bytes()[..s.len().min(u8::MAX as usize)];
+                    out.push(4);
+                    out.push(bytes.len() as u8);
+                    out.extend_from_slice(bytes);
+                }
+                _ => out.push(0),
+            },
+        }
+    }
+    out
+}
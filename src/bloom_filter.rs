@@ -1,5 +1,7 @@
 use crate::bobhash32::BOBHash32;
+use serde::{Deserialize, Serialize};
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct BloomFilter {
     bit_vector: Vec<bool>,
     hash_functions: Vec<BOBHash32>,
@@ -20,16 +22,16 @@ impl BloomFilter {
         }
     }
 
-    pub fn insert(&mut self, item: &str) {
+    pub fn insert(&mut self, item: &[u8]) {
         for hash_function in &self.hash_functions {
-            let index = (hash_function.run(item.as_bytes()) as usize) % self.size;
+            let index = (hash_function.run(item) as usize) % self.size;
             self.bit_vector[index] = true;
         }
     }
 
-    pub fn contains(&self, item: &str) -> bool {
+    pub fn contains(&self, item: &[u8]) -> bool {
         for hash_function in &self.hash_functions {
-            let index = (hash_function.run(item.as_bytes()) as usize) % self.size;
+            let index = (hash_function.run(item) as usize) % self.size;
             if !self.bit_vector[index] {
                 return false; 
             }
@@ -37,7 +39,31 @@ impl BloomFilter {
         true 
     }
 
+    pub fn is_empty(&self) -> bool {
+        !self.bit_vector.contains(&true)
+    }
+
     pub fn clear(&mut self) {
         self.bit_vector.fill(false);
     }
+
+    pub fn get_memory_usage(&self) -> usize {
+        self.size.div_ceil(8)
+    }
+
+    pub fn merge(&mut self, other: &BloomFilter) -> Result<(), String> {
+        if self.size != other.size || self.hash_functions != other.hash_functions {
+            return Err(format!(
+                "BloomFilter parameters differ: {} bits/{} hashes vs {} bits/{} hashes",
+                self.size,
+                self.hash_functions.len(),
+                other.size,
+                other.hash_functions.len()
+            ));
+        }
+        for (bit, other_bit) in self.bit_vector.iter_mut().zip(&other.bit_vector) {
+            *bit |= *other_bit;
+        }
+        Ok(())
+    }
 }
\ No newline at end of file
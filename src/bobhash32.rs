@@ -1,5 +1,6 @@
 use rand::Rng;
 use std::collections::HashSet;
+use serde::{Deserialize, Serialize};
 
 const MAX_PRIME32: usize = 1229;
 const PRIME32: [u32; MAX_PRIME32] = [
@@ -129,6 +130,7 @@ const PRIME32: [u32; MAX_PRIME32] = [
 	9973
 ];
 
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct BOBHash32 {
     prime32_num: u32,
 }
@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::hash::Hasher;
+use std::io::{BufReader, BufWriter, Read, Write};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use twox_hash::XxHash64;
+use crate::pcap_processor::EPOCH_RESULTS;
+use crate::query_executor::{CompiledPlan, LEFT_RESULTS, RIGHT_RESULTS};
+use crate::record::Record;
+use crate::sketch::Sketch;
+
+/// Version of the binary layout of checkpoints and sketch snapshots. Bump it whenever a
+/// serialized type changes; files written by another version are rejected.
+pub const FORMAT_VERSION: u32 = 1;
+
+const CHECKPOINT_MAGIC: [u8; 4] = *b"SKCP";
+
+/// Writes `value` as a 4-byte magic, the format version and a bincode payload. The file
+/// is written next to `path` and renamed into place, so a crash never leaves it truncated.
+pub fn write_versioned<T: Serialize>(path: &str, magic: [u8; 4], value: &T) -> Result<(), String> {
+    let tmp_path = format!("{}.tmp", path);
+    let file = File::create(&tmp_path).map_err(|e| format!("Cannot create {}: {}", tmp_path, e))?;
+    let mut writer = BufWriter::new(file);
+    writer
+        .write_all(&magic)
+        .and_then(|_| writer.write_all(&FORMAT_VERSION.to_le_bytes()))
+        .map_err(|e| format!("Cannot write {}: {}", tmp_path, e))?;
+    bincode::serialize_into(&mut writer, value).map_err(|e| format!("Cannot write {}: {}", tmp_path, e))?;
+    writer.flush().map_err(|e| format!("Cannot write {}: {}", tmp_path, e))?;
+    drop(writer);
+    fs::rename(&tmp_path, path).map_err(|e| format!("Cannot rename {} to {}: {}", tmp_path, path, e))
+}
+
+pub fn read_versioned<T: DeserializeOwned>(path: &str, magic: [u8; 4]) -> Result<T, String> {
+    let file = File::open(path).map_err(|e| format!("Cannot open {}: {}", path, e))?;
+    let mut reader = BufReader::new(file);
+    let mut header = [0u8; 8];
+    reader.read_exact(&mut header).map_err(|e| format!("Cannot read {}: {}", path, e))?;
+    if header[..4] != magic {
+        return Err(format!("{} is not a {} file", path, String::from_utf8_lossy(&magic)));
+    }
+    let version = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    if version != FORMAT_VERSION {
+        return Err(format!("{} has format version {}, expected {}", path, version, FORMAT_VERSION));
+    }
+    bincode::deserialize_from(reader).map_err(|e| format!("Cannot read {}: {}", path, e))
+}
+
+/// Position of a run in its trace. `total_packets` counts every pcap record read,
+/// including those the parser skipped, so it is also the offset to resume from.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct RunProgress {
+    pub total_packets: usize,
+    pub epoch_packets: usize,
+    pub epoch_count: usize,
+    pub current_epoch_start: Option<u64>,
+}
+
+/// Everything needed to continue a run: progress, the open epoch's sketches and result
+/// map, and the join and epoch buffers held by the executor.
+#[derive(Deserialize)]
+pub struct Checkpoint {
+    pub pcap_file: String,
+    pub plan: String,
+    pub progress: RunProgress,
+    pub sketches: HashMap<String, Sketch>,
+    pub result_map: HashMap<Vec<u8>, Record>,
+    left_results: HashMap<Vec<u8>, Record>,
+    right_results: HashMap<Vec<u8>, Record>,
+    epoch_results: Vec<Record>,
+}
+
+// Borrowed form of `Checkpoint` with the same field order, so saving does not copy state.
+#[derive(Serialize)]
+struct CheckpointRef<'a> {
+    pcap_file: &'a str,
+    plan: &'a str,
+    progress: &'a RunProgress,
+    sketches: &'a HashMap<String, Sketch>,
+    result_map: &'a HashMap<Vec<u8>, Record>,
+    left_results: &'a HashMap<Vec<u8>, Record>,
+    right_results: &'a HashMap<Vec<u8>, Record>,
+    epoch_results: &'a Vec<Record>,
+}
+
+// A plan is identified by a hash of the bincode encoding of the query it was compiled
+// from, which holds every sketch parameter. Unlike `Debug` output, that encoding only
+// changes when the plan types do, and those changes bump FORMAT_VERSION.
+fn plan_fingerprint(plan: &CompiledPlan) -> Result<String, String> {
+    let bytes = bincode::serialize(&plan.query).map_err(|e| format!("Cannot encode the query plan: {}", e))?;
+    let mut hasher = XxHash64::with_seed(0);
+    hasher.write(&bytes);
+    Ok(format!("{:016x}", hasher.finish()))
+}
+
+pub fn save_checkpoint(
+    path: &str,
+    pcap_file: &str,
+    plan: &CompiledPlan,
+    progress: &RunProgress,
+    sketches: &HashMap<String, Sketch>,
+    result_map: &HashMap<Vec<u8>, Record>,
+) -> Result<(), String> {
+    let left_results = LEFT_RESULTS.lock().unwrap();
+    let right_results = RIGHT_RESULTS.lock().unwrap();
+    let epoch_results = EPOCH_RESULTS.lock().unwrap();
+    let plan = plan_fingerprint(plan)?;
+    let checkpoint = CheckpointRef {
+        pcap_file,
+        plan: &plan,
+        progress,
+        sketches,
+        result_map,
+        left_results: &left_results,
+        right_results: &right_results,
+        epoch_results: &epoch_results,
+    };
+    write_versioned(path, CHECKPOINT_MAGIC, &checkpoint)
+}
+
+/// Loads a checkpoint, refusing it if it was taken on another trace or with another plan.
+pub fn load_checkpoint(path: &str, pcap_file: &str, plan: &CompiledPlan) -> Result<Checkpoint, String> {
+    let checkpoint: Checkpoint = read_versioned(path, CHECKPOINT_MAGIC)?;
+    if checkpoint.pcap_file != pcap_file {
+        return Err(format!("checkpoint was taken on {}, not {}", checkpoint.pcap_file, pcap_file));
+    }
+    if checkpoint.plan != plan_fingerprint(plan)? {
+        return Err("checkpoint was taken with a different query plan or sketch parameters".to_string());
+    }
+    Ok(checkpoint)
+}
+
+impl Checkpoint {
+    /// True if the checkpoint holds no per-epoch state, i.e. was taken right after an
+    /// epoch was summarized. Single-threaded runs keep their cleared sketches, so those
+    /// count as no state too.
+    pub fn at_epoch_boundary(&self) -> bool {
+        self.result_map.is_empty()
+            && self.left_results.is_empty()
+            && self.right_results.is_empty()
+            && self.sketches.values().all(Sketch::is_empty)
+    }
+
+    /// Moves the join and epoch buffers back into the executor and returns the rest.
+    pub fn restore(self) -> (RunProgress, HashMap<String, Sketch>, HashMap<Vec<u8>, Record>) {
+        *LEFT_RESULTS.lock().unwrap() = self.left_results;
+        *RIGHT_RESULTS.lock().unwrap() = self.right_results;
+        *EPOCH_RESULTS.lock().unwrap() = self.epoch_results;
+        (self.progress, self.sketches, self.result_map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queries::query_1;
+    use crate::query_executor::compile_plan;
+    use crate::query_plan::{Operation, ReduceType};
+
+    fn checkpoint(sketches: HashMap<String, Sketch>) -> Checkpoint {
+        Checkpoint {
+            pcap_file: String::new(),
+            plan: String::new(),
+            progress: RunProgress::default(),
+            sketches,
+            result_map: HashMap::new(),
+            left_results: HashMap::new(),
+            right_results: HashMap::new(),
+            epoch_results: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn cleared_sketches_are_at_an_epoch_boundary() {
+        let mut sketch = Sketch::new_cm_sketch(4096, 3, 1);
+        sketch.increment(b"key", 1);
+        let mut sketches = HashMap::from([("CMSketch".to_string(), sketch)]);
+        assert!(!checkpoint(sketches).at_epoch_boundary());
+
+        let mut sketch = Sketch::new_cm_sketch(4096, 3, 1);
+        sketch.increment(b"key", 1);
+        sketch.clear();
+        sketches = HashMap::from([("CMSketch".to_string(), sketch)]);
+        assert!(checkpoint(sketches).at_epoch_boundary());
+        assert!(checkpoint(HashMap::new()).at_epoch_boundary());
+    }
+
+    #[test]
+    fn plan_fingerprint_follows_sketch_parameters() {
+        let query = query_1();
+        let fingerprint = plan_fingerprint(&compile_plan(&query).unwrap()).unwrap();
+        assert_eq!(fingerprint, plan_fingerprint(&compile_plan(&query).unwrap()).unwrap());
+
+        let mut other = query.clone();
+        for operation in &mut other.operations {
+            if let Operation::Reduce { reduce_type, .. } = operation {
+                *reduce_type = ReduceType::CMReduce { memory_in_bytes: 4096, depth: 3, seed: 7 };
+            }
+        }
+        assert_ne!(fingerprint, plan_fingerprint(&compile_plan(&other).unwrap()).unwrap());
+    }
+}
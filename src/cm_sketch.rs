@@ -1,5 +1,7 @@
 use crate::bobhash32::BOBHash32;
+use serde::{Deserialize, Serialize};
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct CMSketch {
     pub depth: usize,
     pub width: usize,
@@ -41,6 +43,21 @@ impl CMSketch {
         ret
     }
 
+    pub fn merge(&mut self, other: &CMSketch) -> Result<(), String> {
+        if self.depth != other.depth || self.width != other.width || self.hashes != other.hashes {
+            return Err(format!(
+                "CMSketch parameters differ: {}x{} vs {}x{}",
+                self.depth, self.width, other.depth, other.width
+            ));
+        }
+        for (row, other_row) in self.counters.iter_mut().zip(&other.counters) {
+            for (counter, other_counter) in row.iter_mut().zip(other_row) {
+                *counter = counter.saturating_add(*other_counter);
+            }
+        }
+        Ok(())
+    }
+
     // pub fn clear(&mut self) {
     //     for counters_row in &mut self.counters {
     //         counters_row.fill(0);
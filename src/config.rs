@@ -1,5 +1,9 @@
 // config.rs
-use crate::query_plan::ReduceType;
+use crate::alerting::AlertConfig;
+use crate::dataplane::TargetModel;
+use crate::output_sink::OutputFormat;
+use crate::query_plan::{FilterMode, ReduceType, StatisticType};
+use crate::sizing::{bloom_from_accuracy, cm_from_accuracy, fcm_from_memory};
 use std::env;
 
 #[derive(Debug, Clone)]
@@ -19,22 +23,57 @@ fn parse_env<T: std::str::FromStr>(key: &str, default: T) -> T {
         .unwrap_or(default)
 }
 
+fn parse_env_opt<T: std::str::FromStr>(key: &str) -> Option<T> {
+    env::var(key).ok().and_then(|s| s.parse::<T>().ok())
+}
+
+/// CM memory and depth: sized from `CM_EPSILON`/`CM_DELTA` when both are set, otherwise
+/// taken from `CM_MEMORY`/`CM_DEPTH`.
+fn cm_dimensions_from_env() -> (usize, usize) {
+    if let (Some(epsilon), Some(delta)) = (parse_env_opt("CM_EPSILON"), parse_env_opt("CM_DELTA")) {
+        match cm_from_accuracy(epsilon, delta) {
+            Some(dimensions) => return dimensions,
+            None => eprintln!("Ignoring CM_EPSILON/CM_DELTA: both must be in (0, 1)"),
+        }
+    }
+    (parse_env("CM_MEMORY", 524288), parse_env("CM_DEPTH", 3))
+}
+
+/// FCM layer widths: sized from the `FCM_MEMORY` budget when set, otherwise taken from
+/// `FCM_WIDTH_L1`..`FCM_WIDTH_L3`.
+fn fcm_widths_from_env(depth: usize) -> (usize, usize, usize) {
+    if let Some(memory) = parse_env_opt("FCM_MEMORY") {
+        match fcm_from_memory(memory, depth) {
+            Some(widths) => return widths,
+            None => eprintln!("Ignoring FCM_MEMORY: {} bytes is too small for {} trees", memory, depth),
+        }
+    }
+    (
+        parse_env("FCM_WIDTH_L1", 524288),
+        parse_env("FCM_WIDTH_L2", 65536),
+        parse_env("FCM_WIDTH_L3", 8192),
+    )
+}
+
+/// Bloom filter size and hashes: sized from `BF_EXPECTED_ITEMS`/`BF_FP_RATE` when both
+/// are set, otherwise taken from `BF_SIZE`/`BF_HASHES`.
+fn bloom_dimensions_from_env() -> (usize, usize) {
+    if let (Some(items), Some(rate)) = (parse_env_opt("BF_EXPECTED_ITEMS"), parse_env_opt("BF_FP_RATE")) {
+        match bloom_from_accuracy(items, rate) {
+            Some(dimensions) => return dimensions,
+            None => eprintln!("Ignoring BF_EXPECTED_ITEMS/BF_FP_RATE: need items > 0 and a rate in (0, 1)"),
+        }
+    }
+    (parse_env("BF_SIZE", 300000), parse_env("BF_HASHES", 5))
+}
+
 pub fn get_reduce_type_from_env() -> ReduceType {
     match env::var("REDUCE_TYPE").unwrap_or_else(|_| "deterministic".to_string()).as_str() {
-        "cms" => ReduceType::CMReduce {
-            memory_in_bytes: parse_env("CM_MEMORY", 524288),
-            depth: parse_env("CM_DEPTH", 3),
-            seed: parse_env("CM_SEED", 42),
-        },
-        "fcm" => ReduceType::FCMReduce {
-            depth: parse_env("FCM_DEPTH", 2),
-            width_l1: parse_env("FCM_WIDTH_L1", 524288),
-            width_l2: parse_env("FCM_WIDTH_L2", 65536),
-            width_l3: parse_env("FCM_WIDTH_L3", 8192),
-            threshold_l1: parse_env("FCM_THRESHOLD_L1", 254),
-            threshold_l2: parse_env("FCM_THRESHOLD_L2", 65534),
-            seed: parse_env("FCM_SEED", 42),
-        },
+        "cms" => {
+            let (memory_in_bytes, depth) = cm_dimensions_from_env();
+            ReduceType::CMReduce { memory_in_bytes, depth, seed: parse_env("CM_SEED", 42) }
+        }
+        "fcm" => get_fcm_reduce_type_from_env(),
         "beaucoup" => ReduceType::BeauCoupReduce {
             num_rows: parse_env("BC_ROWS", 8),
             num_coupons: parse_env("BC_COUPONS", 32768),
@@ -46,13 +85,175 @@ pub fn get_reduce_type_from_env() -> ReduceType {
     }
 }
 
+/// FCM parameters, used both for `REDUCE_TYPE=fcm` and by queries that need FCM regardless.
+pub fn get_fcm_reduce_type_from_env() -> ReduceType {
+    let depth = parse_env("FCM_DEPTH", 2);
+    let (width_l1, width_l2, width_l3) = fcm_widths_from_env(depth);
+    ReduceType::FCMReduce {
+        depth,
+        width_l1,
+        width_l2,
+        width_l3,
+        threshold_l1: parse_env("FCM_THRESHOLD_L1", 254),
+        threshold_l2: parse_env("FCM_THRESHOLD_L2", 65534),
+        seed: parse_env("FCM_SEED", 42),
+    }
+}
+
 pub fn get_distinct_type_from_env() -> DistinctType {
     match env::var("DISTINCT_TYPE").unwrap_or_else(|_| "deterministic".to_string()).as_str() {
-        "bloom" => DistinctType::BloomFilter {
-            size: parse_env("BF_SIZE", 300000),
-            num_hashes: parse_env("BF_HASHES", 5),
-            seed: parse_env("BF_SEED", 42),
-        },
+        "bloom" => {
+            let (size, num_hashes) = bloom_dimensions_from_env();
+            DistinctType::BloomFilter { size, num_hashes, seed: parse_env("BF_SEED", 42) }
+        }
         _ => DistinctType::DeterministicReduce,
     }
-}
\ No newline at end of file
+}
+
+pub fn get_statistic_type_from_env() -> StatisticType {
+    match env::var("STATISTIC_TYPE").unwrap_or_else(|_| "exact".to_string()).as_str() {
+        "lightpart" => StatisticType::LightPart {
+            memory_in_bytes: parse_env("LP_MEMORY", 524288),
+            seed: parse_env("LP_SEED", 42),
+        },
+        "fcm" => {
+            let depth = parse_env("FCM_DEPTH", 2);
+            let (width_l1, width_l2, width_l3) = fcm_widths_from_env(depth);
+            StatisticType::FCMSketch {
+                depth,
+                width_l1,
+                width_l2,
+                width_l3,
+                threshold_l1: parse_env("FCM_THRESHOLD_L1", 254),
+                threshold_l2: parse_env("FCM_THRESHOLD_L2", 65534),
+                seed: parse_env("FCM_SEED", 42),
+                em_iterations: parse_env("FCM_EM_ITERATIONS", 10),
+            }
+        }
+        _ => StatisticType::Exact,
+    }
+}
+
+/// Bits each level of a hierarchical heavy hitter lattice adds to its prefixes.
+pub fn get_hhh_prefix_step_from_env() -> u8 {
+    parse_env("HHH_PREFIX_STEP", 8)
+}
+
+/// Whether hierarchical heavy hitters update one random lattice node per packet (RHHH).
+pub fn get_hhh_randomized_from_env() -> bool {
+    parse_env("HHH_RANDOMIZED", false)
+}
+
+/// How `FilterResult` treats sketch error: `estimate`, `definitely` (lower bound) or
+/// `possibly` (upper bound).
+pub fn get_filter_mode_from_env() -> FilterMode {
+    match env::var("FILTER_MODE").unwrap_or_else(|_| "estimate".to_string()).as_str() {
+        "definitely" => FilterMode::Definitely,
+        "possibly" => FilterMode::Possibly,
+        _ => FilterMode::Estimate,
+    }
+}
+
+/// Number of worker threads for `process_pcap`; 1 runs the single-threaded loop.
+pub fn get_num_workers_from_env() -> usize {
+    parse_env("NUM_WORKERS", 1).max(1)
+}
+
+/// Directory where each epoch's sketches are archived for `merge` and `archive`; unset disables saving.
+pub fn get_sketch_dir_from_env() -> Option<String> {
+    env::var("SKETCH_DIR").ok().filter(|dir| !dir.is_empty())
+}
+
+/// Checkpoint file for long runs and `--resume`; unset disables checkpointing.
+pub fn get_checkpoint_path_from_env() -> Option<String> {
+    env::var("CHECKPOINT_PATH").ok().filter(|path| !path.is_empty())
+}
+
+/// Packets processed between two checkpoints.
+pub fn get_checkpoint_interval_from_env() -> usize {
+    parse_env("CHECKPOINT_INTERVAL", 1_000_000).max(1)
+}
+
+/// Format of the per-epoch results: `text` (the default), `csv`, `jsonl` or `parquet`.
+pub fn get_output_format_from_env() -> OutputFormat {
+    let name = env::var("OUTPUT_FORMAT").unwrap_or_else(|_| "text".to_string());
+    OutputFormat::parse(&name).unwrap_or_else(|| {
+        eprintln!("Unknown OUTPUT_FORMAT '{}', writing text", name);
+        OutputFormat::Text
+    })
+}
+
+/// File the per-epoch results are written to; defaults to one named after the format.
+pub fn get_output_path_from_env(format: OutputFormat) -> String {
+    env::var("OUTPUT_PATH")
+        .ok()
+        .filter(|path| !path.is_empty())
+        .unwrap_or_else(|| format.default_path().to_string())
+}
+
+/// Address of the Prometheus endpoint, e.g. `127.0.0.1:9898`; unset disables it.
+pub fn get_metrics_addr_from_env() -> Option<String> {
+    env::var("METRICS_ADDR").ok().filter(|addr| !addr.is_empty())
+}
+
+/// Keys of the latest epoch exposed on the metrics endpoint.
+pub fn get_metrics_top_k_from_env() -> usize {
+    parse_env("METRICS_TOP_K", 10)
+}
+
+/// Seconds the metrics endpoint stays up once processing has finished.
+pub fn get_metrics_linger_from_env() -> u64 {
+    parse_env("METRICS_LINGER", 0)
+}
+
+/// Alerting for `query`: comma-separated `ALERT_SINKS` (`stdout`, `file:<path>`,
+/// `syslog[:<socket>]`, `webhook:<url>`); unset disables it. Webhooks are called
+/// synchronously at the end of each epoch.
+pub fn get_alert_config_from_env(query: &str) -> Option<AlertConfig> {
+    let sinks: Vec<String> = env::var("ALERT_SINKS")
+        .ok()?
+        .split(',')
+        .map(|sink| sink.trim().to_string())
+        .filter(|sink| !sink.is_empty())
+        .collect();
+    if sinks.is_empty() {
+        return None;
+    }
+    Some(AlertConfig {
+        query: query.to_string(),
+        sinks,
+        suppress_epochs: parse_env("ALERT_SUPPRESS_EPOCHS", 3),
+        warning_ratio: parse_env("ALERT_WARNING_RATIO", 2.0),
+        critical_ratio: parse_env("ALERT_CRITICAL_RATIO", 10.0),
+    })
+}
+
+/// Switch resources the data-plane planner fits queries into.
+pub fn get_target_model_from_env() -> TargetModel {
+    TargetModel {
+        stages: parse_env("DATAPLANE_STAGES", 12),
+        sram_per_stage: parse_env("DATAPLANE_SRAM_PER_STAGE", 1_310_720),
+        stateful_alus_per_stage: parse_env("DATAPLANE_STATEFUL_ALUS", 4),
+        hash_units_per_stage: parse_env("DATAPLANE_HASH_UNITS", 6),
+        phv_bits: parse_env("DATAPLANE_PHV_BITS", 4096),
+    }
+}
+
+/// Address field the `refine` command truncates.
+pub fn get_refinement_field_from_env() -> String {
+    env::var("REFINEMENT_FIELD").ok().filter(|field| !field.is_empty()).unwrap_or_else(|| "dst_ip".to_string())
+}
+
+/// Prefix lengths `refine` steps through, coarsest first: comma-separated `REFINEMENT_LEVELS`.
+pub fn get_refinement_levels_from_env() -> Vec<u8> {
+    let default = vec![8, 16, 32];
+    let Ok(text) = env::var("REFINEMENT_LEVELS") else { return default };
+    let levels: Option<Vec<u8>> = text.split(',').map(|level| level.trim().parse().ok()).collect();
+    match levels {
+        Some(levels) if !levels.is_empty() => levels,
+        _ => {
+            eprintln!("Ignoring REFINEMENT_LEVELS '{}': expected prefix lengths such as 8,16,32", text);
+            default
+        }
+    }
+}
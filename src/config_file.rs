@@ -0,0 +1,122 @@
+// config_file.rs
+// TOML or YAML run configuration. Parameters are exported as the environment variables
+// the `config` getters read, so a file can set anything the environment can.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use serde::Deserialize;
+use serde_json::Value;
+
+/// ```toml
+/// [source]
+/// pcap = "trace.pcap"
+/// epoch_size = 10
+///
+/// [query]
+/// id = 1            # or plan = "plans/syn_flood.yaml"
+/// threshold = 50
+///
+/// [sketch]
+/// REDUCE_TYPE = "cms"
+/// CM_MEMORY = 65536
+///
+/// [output]
+/// format = "csv"
+///
+/// [alert]
+/// sinks = ["stdout", "file:alerts.jsonl"]
+/// ```
+///
+/// `[sketch]` and `[execution]` take variable names as they are (`NUM_WORKERS`,
+/// `SKETCH_DIR`, ...); keys of `[output]`, `[alert]`, `[metrics]`, `[checkpoint]`,
+/// `[dataplane]` and `[refinement]` are prefixed with the section name, so `[metrics] addr`
+/// sets `METRICS_ADDR`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    pub source: SourceConfig,
+    pub query: QueryConfig,
+    sketch: BTreeMap<String, Value>,
+    execution: BTreeMap<String, Value>,
+    output: BTreeMap<String, Value>,
+    alert: BTreeMap<String, Value>,
+    metrics: BTreeMap<String, Value>,
+    checkpoint: BTreeMap<String, Value>,
+    dataplane: BTreeMap<String, Value>,
+    refinement: BTreeMap<String, Value>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SourceConfig {
+    pub pcap: Option<String>,
+    pub epoch_size: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueryConfig {
+    pub id: Option<u8>,
+    /// JSON or YAML plan file, instead of a built-in `id`.
+    pub plan: Option<String>,
+    pub threshold: Option<u64>,
+}
+
+impl ConfigFile {
+    /// Reads a `.toml`, `.yaml` or `.yml` file.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Cannot read {}: {}", path, e))?;
+        if path.ends_with(".toml") {
+            toml::from_str(&text).map_err(|e| format!("Invalid config {}: {}", path, e))
+        } else if path.ends_with(".yaml") || path.ends_with(".yml") {
+            serde_yaml::from_str(&text).map_err(|e| format!("Invalid config {}: {}", path, e))
+        } else {
+            Err(format!("Config file {} must end in .toml, .yaml or .yml", path))
+        }
+    }
+
+    /// Parameters as environment variable names and values.
+    pub fn settings(&self) -> Result<Vec<(String, String)>, String> {
+        let sections = [
+            ("", &self.sketch),
+            ("", &self.execution),
+            ("OUTPUT_", &self.output),
+            ("ALERT_", &self.alert),
+            ("METRICS_", &self.metrics),
+            ("CHECKPOINT_", &self.checkpoint),
+            ("DATAPLANE_", &self.dataplane),
+            ("REFINEMENT_", &self.refinement),
+        ];
+        let mut settings = Vec::new();
+        for (prefix, section) in sections {
+            for (key, value) in section {
+                let name = format!("{}{}", prefix, key.to_uppercase());
+                settings.push((name.clone(), setting_value(value).ok_or_else(|| format!("Unsupported value for {}", name))?));
+            }
+        }
+        Ok(settings)
+    }
+
+    /// Exports the file's parameters that are not already set in the environment, which
+    /// therefore takes precedence.
+    pub fn apply_to_env(&self) -> Result<(), String> {
+        for (name, value) in self.settings()? {
+            if env::var_os(&name).is_none() {
+                env::set_var(name, value);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Scalars as written; lists (such as alert sinks) comma-separated.
+fn setting_value(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        Value::Array(items) => items.iter().map(setting_value).collect::<Option<Vec<_>>>().map(|items| items.join(",")),
+        Value::Null | Value::Object(_) => None,
+    }
+}
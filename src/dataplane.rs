@@ -0,0 +1,394 @@
+// dataplane.rs
+// Resource model of a programmable switch pipeline, and the planner that splits a query
+// between the switch and the stream processor as Sonata does: the longest prefix of
+// operators the pipeline can hold runs in the data plane, the rest on the collected tuples.
+
+use crate::flow_key::encoded_width;
+use crate::functions::parse_call;
+use crate::query_executor::{compile_plan, map_items, CompiledPlan};
+use crate::query_plan::{Aggregation, Field, Operation, QueryPlan, ReduceType, StatisticType};
+use crate::record::Schema;
+
+/// What the switch offers. The defaults resemble a Tofino-class pipeline.
+#[derive(Clone, Copy, Debug)]
+pub struct TargetModel {
+    pub stages: usize,
+    /// Register memory of one stage, in bytes.
+    pub sram_per_stage: usize,
+    /// Register arrays a stage can update per packet, one per stateful ALU.
+    pub stateful_alus_per_stage: usize,
+    pub hash_units_per_stage: usize,
+    /// Packet header vector: headers plus the metadata the operators carry between stages.
+    pub phv_bits: usize,
+}
+
+// One register array and whether its index comes from a hash unit (FCM's upper layers
+// are indexed by dividing the leaf index).
+#[derive(Clone, Copy)]
+struct RegisterArray {
+    bytes: usize,
+    hashed: bool,
+}
+
+// Work of one operator: steps that depend on each other and so go to successive stages,
+// each a set of register arrays that may share a stage. An empty step is a stateless
+// table, such as a filter or a comparison. `metadata_bits` is what the operator adds to
+// the PHV.
+struct Requirement {
+    steps: Vec<Vec<RegisterArray>>,
+    metadata_bits: usize,
+}
+
+fn schema_bits(schema: &Schema) -> usize {
+    schema.fields().iter().map(|(_, field_type)| encoded_width(*field_type) * 8).sum()
+}
+
+fn rows(count: usize, bytes: usize, hashed: bool) -> Vec<RegisterArray> {
+    vec![RegisterArray { bytes, hashed }; count]
+}
+
+// Rows updated in parallel, then a table taking the minimum of their estimates.
+fn count_min(depth: usize, row_bytes: usize) -> Requirement {
+    let mut steps = vec![rows(depth, row_bytes, true)];
+    if depth > 1 {
+        steps.push(Vec::new());
+    }
+    Requirement { steps, metadata_bits: 32 * (depth + 1) }
+}
+
+// Each FCM layer needs the overflow of the one below it, so the three layers of all trees
+// take three steps.
+fn fcm(depth: usize, width_l1: usize, width_l2: usize, width_l3: usize) -> Requirement {
+    let mut steps = vec![rows(depth, width_l1, true), rows(depth, width_l2 * 2, false), rows(depth, width_l3 * 4, false)];
+    if depth > 1 {
+        steps.push(Vec::new());
+    }
+    Requirement { steps, metadata_bits: 32 * (depth + 1) }
+}
+
+// Function a Map item calls, whether named (`net = prefix(src_ip, 24)`) or not.
+fn map_call(item: &str) -> Option<&str> {
+    let value = item.split_once('=').map_or(item, |(_, value)| value);
+    parse_call(value).map(|(name, _)| name)
+}
+
+fn requirement(op: &Operation, output_schema: &Schema) -> Result<Requirement, String> {
+    let stateless = |steps: usize| Requirement { steps: vec![Vec::new(); steps], metadata_bits: 0 };
+    match op {
+        Operation::Filter(conditions) if conditions.iter().any(|(field, _)| matches!(field, Field::DnsNsType)) => {
+            Err("the switch parser stops at the TCP header, before the DNS fields".to_string())
+        }
+        Operation::Filter(_) => Ok(stateless(1)),
+        // Truncating an address is a mask in the table action; the other functions have no
+        // switch counterpart.
+        Operation::Map(expr) if map_items(expr).iter().any(|item| map_call(item).is_some_and(|name| name != "prefix")) => {
+            Err("only the prefix() function of a Map runs on the switch".to_string())
+        }
+        Operation::Map(expr) if map_items(expr).iter().any(|item| {
+            map_call(item).is_none()
+                && item.split_once('=').is_some_and(|(_, value)| value.trim().parse::<u32>().is_err())
+        }) => {
+            Err("string constants have no representation in switch metadata".to_string())
+        }
+        // Rewriting fields is an action of the next table; the new record lives in metadata.
+        Operation::Map(_) => Ok(Requirement { steps: Vec::new(), metadata_bits: schema_bits(output_schema) }),
+        Operation::Reduce { keys, .. } if keys.iter().any(|key| parse_call(key).is_some()) => {
+            Err("keys named after function calls are not generated for the switch; name them in a Map".to_string())
+        }
+        Operation::Reduce { aggregation, .. } if !matches!(aggregation, Aggregation::Sum | Aggregation::Count) => {
+            Err(format!("a {:?} Reduce is not generated for the switch, which only adds to counters", aggregation))
+        }
+        Operation::Reduce { reduce_type, .. } => match reduce_type {
+            ReduceType::CMReduce { memory_in_bytes, depth, .. } => {
+                Ok(count_min(*depth, memory_in_bytes / (*depth).max(1) / 4 * 4))
+            }
+            ReduceType::FCMFirstLayerOnly { depth, width_l1, .. } => Ok(count_min(*depth, width_l1 * 4)),
+            ReduceType::FCMReduce { depth, width_l1, width_l2, width_l3, .. } => {
+                Ok(fcm(*depth, *width_l1, *width_l2, *width_l3))
+            }
+            ReduceType::ElasticReduce { depth, width, .. } => {
+                let mut requirement = count_min(*depth, width * 4);
+                requirement.steps.insert(0, rows(1, width * 4, true));
+                requirement.metadata_bits += 32;
+                Ok(requirement)
+            }
+            // Every hash picks a row of the same table, so the switch needs one copy per hash.
+            ReduceType::BeauCoupReduce { num_rows, num_coupons, d, .. } => Ok(Requirement {
+                steps: vec![rows(*d, num_rows * num_coupons.div_ceil(8), true)],
+                metadata_bits: 32 * (d + 1),
+            }),
+            ReduceType::DeterministicReduce => {
+                Err("an exact Reduce keeps an entry per key, which switch memory cannot grow to hold".to_string())
+            }
+            ReduceType::BloomFilter { .. } => Err("BloomFilter cannot back a Reduce".to_string()),
+        },
+        // A register array allows one access per packet, so the filter is split into one
+        // array per hash; a table then combines their bits.
+        Operation::Distinct { distinct_type: ReduceType::BloomFilter { size, num_hashes, .. }, .. } => Ok(Requirement {
+            steps: vec![rows(*num_hashes, size.div_ceil((*num_hashes).max(1)).div_ceil(8), true), Vec::new()],
+            metadata_bits: 32 * num_hashes + 1,
+        }),
+        Operation::Distinct { .. } => {
+            Err("an exact Distinct keeps every key seen, which switch memory cannot grow to hold".to_string())
+        }
+        Operation::FilterResult { .. } => Ok(stateless(1)),
+        // A longest-prefix-match table whose action truncates the address.
+        Operation::RefinePrefix { .. } => Ok(stateless(1)),
+        Operation::Cardinality { statistic_type, .. }
+        | Operation::Entropy { statistic_type, .. }
+        | Operation::FlowSizeDistribution { statistic_type, .. } => match statistic_type {
+            StatisticType::LightPart { memory_in_bytes, .. } => Ok(Requirement {
+                steps: vec![rows(1, *memory_in_bytes, true)],
+                metadata_bits: 32,
+            }),
+            StatisticType::FCMSketch { depth, width_l1, width_l2, width_l3, .. } => {
+                Ok(fcm(*depth, *width_l1, *width_l2, *width_l3))
+            }
+            StatisticType::Exact => {
+                Err("an exact statistic counts every key, which switch memory cannot grow to hold".to_string())
+            }
+        },
+        Operation::HierarchicalHeavyHitters { .. } => {
+            Err("the planner does not model a sketch per prefix lattice node".to_string())
+        }
+        Operation::FCMEstimate { .. } => {
+            Err("the EM estimate runs over all counters at the end of the epoch".to_string())
+        }
+        Operation::Join { .. } | Operation::MapJoin(_) | Operation::FilterJoin { .. } => {
+            Err("joins combine the results of a whole epoch".to_string())
+        }
+    }
+}
+
+/// What an operator placed in the data plane occupies.
+#[derive(Clone, Copy, Debug)]
+pub struct StageUse {
+    /// First and last stage, counted from 1; `None` for operators folded into another
+    /// table's action.
+    pub stages: Option<(usize, usize)>,
+    pub register_arrays: usize,
+    pub sram_bytes: usize,
+    pub hash_units: usize,
+}
+
+#[derive(Debug)]
+pub enum Location {
+    DataPlane(StageUse),
+    /// The stream processor, and why the operator could not stay on the switch.
+    StreamProcessor(String),
+}
+
+#[derive(Debug)]
+pub struct Placement {
+    /// Join nesting: 0 for the operators of the query itself.
+    pub depth: usize,
+    /// Position in its plan, counted from 1.
+    pub position: usize,
+    pub operation: String,
+    pub location: Location,
+}
+
+/// The split of a query between the switch and the stream processor.
+#[derive(Debug)]
+pub struct Partition {
+    pub placements: Vec<Placement>,
+    pub stages_used: usize,
+    pub sram_bytes: usize,
+    pub phv_bits: usize,
+    /// What the data plane sends to the stream processor: for the query, or for each side
+    /// of a join, the records leaving its last operator on the switch, or `None` when it
+    /// starts on the stream processor and needs every packet.
+    pub boundary: Vec<(String, Option<Schema>)>,
+}
+
+struct Planner {
+    model: TargetModel,
+    // Stages before this one are taken.
+    next_stage: usize,
+    sram_bytes: usize,
+    phv_bits: usize,
+    placements: Vec<Placement>,
+}
+
+impl Planner {
+    // Packs an operator's steps into the stages following those already used.
+    fn allocate(&mut self, requirement: &Requirement) -> Result<StageUse, String> {
+        let model = self.model;
+        let phv_bits = self.phv_bits + requirement.metadata_bits;
+        if phv_bits > model.phv_bits {
+            return Err(format!("needs {} PHV bits, the target has {}", phv_bits, model.phv_bits));
+        }
+        let (mut stage, mut register_arrays, mut sram_bytes, mut hash_units) = (self.next_stage, 0, 0, 0);
+        for step in &requirement.steps {
+            let (mut alus, mut hashes, mut sram) = (0, 0, 0);
+            for array in step {
+                if array.bytes > model.sram_per_stage {
+                    return Err(format!(
+                        "a {} byte register array exceeds the {} bytes of SRAM in a stage",
+                        array.bytes, model.sram_per_stage
+                    ));
+                }
+                if model.stateful_alus_per_stage == 0 || (array.hashed && model.hash_units_per_stage == 0) {
+                    return Err("the target has no stateful ALUs or hash units".to_string());
+                }
+                let hashed = usize::from(array.hashed);
+                if alus == model.stateful_alus_per_stage
+                    || hashes + hashed > model.hash_units_per_stage
+                    || sram + array.bytes > model.sram_per_stage
+                {
+                    stage += 1;
+                    (alus, hashes, sram) = (0, 0, 0);
+                }
+                alus += 1;
+                hashes += hashed;
+                sram += array.bytes;
+                register_arrays += 1;
+                hash_units += hashed;
+                sram_bytes += array.bytes;
+            }
+            stage += 1;
+        }
+
+        let stages = (stage > self.next_stage).then_some((self.next_stage, stage - 1));
+        if let Some((first, last)) = stages {
+            if last > model.stages {
+                return Err(format!("needs {}, beyond the {} of the pipeline", stage_range(first, last), model.stages));
+            }
+        }
+        self.next_stage = stage;
+        self.sram_bytes += sram_bytes;
+        self.phv_bits = phv_bits;
+        Ok(StageUse { stages, register_arrays, sram_bytes, hash_units })
+    }
+
+    // Places the operators of `query` in order until one does not fit; it and everything
+    // after it go to the stream processor. Returns what crosses to the stream processor.
+    fn place(&mut self, query: &QueryPlan, plan: &CompiledPlan, depth: usize, name: &str) -> Vec<(String, Option<Schema>)> {
+        let mut boundary = None;
+        // Filled by the sides of a join, which then make up the whole crossing.
+        let mut crossing = Vec::new();
+        let mut offloaded = false;
+        for (i, (op, profile)) in query.operations.iter().zip(plan.operator_profiles()).enumerate() {
+            let operation = match op {
+                Operation::Join { left_keys, right_keys, .. } => {
+                    format!("Join on {} = {}", left_keys.join(", "), right_keys.join(", "))
+                }
+                other => format!("{:?}", other),
+            };
+            if offloaded {
+                let reason = "follows an operator on the stream processor".to_string();
+                self.placements.push(Placement { depth, position: i + 1, operation, location: Location::StreamProcessor(reason) });
+                continue;
+            }
+            let location = match requirement(op, profile.output_schema).and_then(|requirement| self.allocate(&requirement)) {
+                Ok(stage_use) => {
+                    boundary = Some(profile.output_schema.clone());
+                    Location::DataPlane(stage_use)
+                }
+                Err(reason) => {
+                    offloaded = true;
+                    Location::StreamProcessor(reason)
+                }
+            };
+            self.placements.push(Placement { depth, position: i + 1, operation, location });
+
+            // The join itself runs on the stream processor, but each side still filters and
+            // aggregates on the switch as far as it fits.
+            if let (Operation::Join { left_query, right_query, .. }, Some((left_plan, right_plan))) = (op, profile.sub_plans) {
+                crossing.extend(self.place(left_query, left_plan, depth + 1, &format!("{}left", name)));
+                crossing.extend(self.place(right_query, right_plan, depth + 1, &format!("{}right", name)));
+            }
+        }
+        if crossing.is_empty() {
+            crossing.push((name.to_string(), boundary));
+        }
+        crossing
+    }
+}
+
+fn stage_range(first: usize, last: usize) -> String {
+    if first == last {
+        format!("stage {}", first)
+    } else {
+        format!("stages {}-{}", first, last)
+    }
+}
+
+/// Splits `query` between a switch described by `model` and the stream processor.
+pub fn partition(query: &QueryPlan, model: TargetModel) -> Result<Partition, String> {
+    let plan = compile_plan(query)?;
+    let mut planner = Planner {
+        model,
+        next_stage: 1,
+        sram_bytes: 0,
+        phv_bits: schema_bits(&Schema::packet()),
+        placements: Vec::new(),
+    };
+    let boundary = planner.place(query, &plan, 0, "");
+    Ok(Partition {
+        placements: planner.placements,
+        stages_used: planner.next_stage - 1,
+        sram_bytes: planner.sram_bytes,
+        phv_bits: planner.phv_bits,
+        boundary,
+    })
+}
+
+pub fn run_partition(query: &QueryPlan, model: TargetModel) {
+    println!(
+        "Target: {} stages; per stage {} bytes of SRAM, {} stateful ALUs and {} hash units; {} PHV bits",
+        model.stages, model.sram_per_stage, model.stateful_alus_per_stage, model.hash_units_per_stage, model.phv_bits
+    );
+    let partition = match partition(query, model) {
+        Ok(partition) => partition,
+        Err(e) => {
+            println!("Invalid plan: {}", e);
+            return;
+        }
+    };
+
+    println!("Operators:");
+    for placement in &partition.placements {
+        let pad = " ".repeat(2 + 6 * placement.depth);
+        println!("{}{}. {}", pad, placement.position, placement.operation);
+        match &placement.location {
+            Location::DataPlane(stage_use) => {
+                let stages = match stage_use.stages {
+                    Some((first, last)) => stage_range(first, last),
+                    None => "no stage of its own".to_string(),
+                };
+                if stage_use.register_arrays > 0 {
+                    println!(
+                        "{}     data plane, {}: {} register arrays, {} bytes of SRAM, {} hash units",
+                        pad, stages, stage_use.register_arrays, stage_use.sram_bytes, stage_use.hash_units
+                    );
+                } else {
+                    println!("{}     data plane, {}", pad, stages);
+                }
+            }
+            Location::StreamProcessor(reason) => println!("{}     stream processor: {}", pad, reason),
+        }
+    }
+
+    let on_switch = partition
+        .placements
+        .iter()
+        .filter(|placement| matches!(placement.location, Location::DataPlane(_)))
+        .count();
+    println!("Partition: {} of {} operators in the data plane", on_switch, partition.placements.len());
+    println!(
+        "Data plane uses {} of {} stages, {} bytes of SRAM and {} of {} PHV bits",
+        partition.stages_used, model.stages, partition.sram_bytes, partition.phv_bits, model.phv_bits
+    );
+    for (side, schema) in &partition.boundary {
+        let side = if side.is_empty() { String::new() } else { format!(" ({})", side) };
+        match schema {
+            Some(schema) => println!(
+                "Sent to the stream processor{}: {}",
+                side,
+                schema.fields().iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>().join(", ")
+            ),
+            None => println!("Sent to the stream processor{}: every packet", side),
+        }
+    }
+}
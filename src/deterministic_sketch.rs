@@ -1,7 +1,21 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use crate::fcm_em::FlowSizeEstimate;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct DeterministicSketch {
-    counts: HashMap<String, u64>,
+    // Stored as (key, count) pairs, so formats without binary map keys can hold it.
+    #[serde(serialize_with = "serialize_counts", deserialize_with = "deserialize_counts")]
+    counts: HashMap<Vec<u8>, u64>,
+}
+
+fn serialize_counts<S: Serializer>(counts: &HashMap<Vec<u8>, u64>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(counts.iter())
+}
+
+fn deserialize_counts<'de, D: Deserializer<'de>>(deserializer: D) -> Result<HashMap<Vec<u8>, u64>, D::Error> {
+    let pairs: Vec<(Vec<u8>, u64)> = Vec::deserialize(deserializer)?;
+    Ok(pairs.into_iter().collect())
 }
 
 impl DeterministicSketch {
@@ -11,15 +25,43 @@ impl DeterministicSketch {
         }
     }
 
-    pub fn insert(&mut self, item: &str, count: u64) {
-        *self.counts.entry(item.to_string()).or_insert(0) += count;
+    pub fn insert(&mut self, item: &[u8], count: u64) {
+        match self.counts.get_mut(item) {
+            Some(total) => *total += count,
+            None => {
+                self.counts.insert(item.to_vec(), count);
+            }
+        }
     }
 
-    pub fn query(&self, item: &str) -> u64 {
+    pub fn query(&self, item: &[u8]) -> u64 {
         *self.counts.get(item).unwrap_or(&0)
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+
     pub fn clear(&mut self) {
         self.counts.clear();
     }
-}
\ No newline at end of file
+
+    pub fn get_memory_usage(&self) -> usize {
+        self.counts.keys().map(|key| key.len() + size_of::<u64>()).sum()
+    }
+
+    pub fn merge(&mut self, other: &DeterministicSketch) {
+        for (item, count) in &other.counts {
+            *self.counts.entry(item.clone()).or_insert(0) += count;
+        }
+    }
+
+    /// Exact flow size histogram of everything inserted this epoch.
+    pub fn get_distribution(&self) -> FlowSizeEstimate {
+        let mut distribution: BTreeMap<u32, f64> = BTreeMap::new();
+        for count in self.counts.values().filter(|c| **c > 0) {
+            *distribution.entry(*count as u32).or_insert(0.0) += 1.0;
+        }
+        FlowSizeEstimate::from_distribution(distribution)
+    }
+}
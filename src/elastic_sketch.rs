@@ -1,6 +1,8 @@
 use std::collections::HashSet;
 use std::convert::TryInto;
+use serde::{Deserialize, Serialize};
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct ElasticSketch {
     pub depth: usize,
     pub width: usize,
@@ -50,6 +52,25 @@ impl ElasticSketch {
         }
     }
 
+    pub fn merge(&mut self, other: &ElasticSketch) -> Result<(), String> {
+        if self.depth != other.depth || self.width != other.width || self.hash_seeds != other.hash_seeds {
+            return Err(format!(
+                "ElasticSketch parameters differ: {}x{} vs {}x{}",
+                self.depth, self.width, other.depth, other.width
+            ));
+        }
+        for (row, other_row) in self.light_counters.iter_mut().zip(&other.light_counters) {
+            for (counter, other_counter) in row.iter_mut().zip(other_row) {
+                *counter = counter.saturating_add(*other_counter);
+            }
+        }
+        for (counter, other_counter) in self.heavy_counters.iter_mut().zip(&other.heavy_counters) {
+            *counter = counter.saturating_add(*other_counter);
+        }
+        self.hh_candidates.extend(&other.hh_candidates);
+        Ok(())
+    }
+
     pub fn query(&self, item: &[u8]) -> u32 {
         let mut hash_index = vec![0; self.depth];
         let mut ret_val = vec![0; self.depth];
@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use pcap::Capture;
+use crate::pcap_processor::extract_packet_tuple;
+use crate::query_executor::{compile_plan, execute_query, CompiledPlan};
+use crate::query_plan::QueryPlan;
+use crate::record::Record;
+use crate::sketch::Sketch;
+
+/// Accuracy of one epoch's thresholded keys against the exact plan.
+struct EpochAccuracy {
+    true_keys: usize,
+    reported_keys: usize,
+    true_positives: usize,
+    precision: f64,
+    recall: f64,
+    f1: f64,
+    /// Average relative error over the true heavy hitters.
+    are: f64,
+    /// Average absolute error over the true heavy hitters.
+    aae: f64,
+}
+
+/// One plan's state while evaluating.
+pub struct PlanRun {
+    pub plan: CompiledPlan,
+    pub sketches: HashMap<String, Sketch>,
+    pub result_map: HashMap<Vec<u8>, Record>,
+    epoch_start: Option<u64>,
+}
+
+impl PlanRun {
+    pub fn new(plan: CompiledPlan) -> Self {
+        PlanRun { plan, sketches: HashMap::new(), result_map: HashMap::new(), epoch_start: None }
+    }
+
+    pub fn execute(&mut self, record: Record, epoch_size: u64, timestamp: u64) {
+        self.epoch_start.get_or_insert(timestamp);
+        execute_query(&self.plan, record, &mut self.sketches, &mut self.result_map, epoch_size, &mut self.epoch_start, timestamp);
+    }
+
+    // Keys whose value exceeds the threshold, as listed in the epoch summary.
+    fn thresholded(&self, threshold: u64) -> HashMap<&[u8], u64> {
+        let value_slot = self.plan.value_slot();
+        self.result_map
+            .iter()
+            .filter_map(|(key, record)| {
+                let value = record.get(value_slot?)?.as_u64()?;
+                (value > threshold).then_some((key.as_slice(), value))
+            })
+            .collect()
+    }
+
+    pub fn end_epoch(&mut self, timestamp: u64) {
+        self.sketches.values_mut().for_each(|sketch| sketch.clear());
+        self.result_map.clear();
+        self.epoch_start = Some(timestamp);
+    }
+}
+
+fn compare(exact: &HashMap<&[u8], u64>, estimated: &HashMap<&[u8], u64>, sketch: &PlanRun) -> EpochAccuracy {
+    let true_positives = estimated.keys().filter(|key| exact.contains_key(*key)).count();
+    // An empty set has no false positives (or no misses), so it scores 1.
+    let precision = if estimated.is_empty() { 1.0 } else { true_positives as f64 / estimated.len() as f64 };
+    let recall = if exact.is_empty() { 1.0 } else { true_positives as f64 / exact.len() as f64 };
+    let f1 = if precision + recall > 0.0 { 2.0 * precision * recall / (precision + recall) } else { 0.0 };
+
+    // Errors use the sketch's value for every true heavy hitter, reported or not.
+    let value_slot = sketch.plan.value_slot();
+    let (mut relative, mut absolute) = (0.0, 0.0);
+    for (key, truth) in exact {
+        let estimate = value_slot
+            .and_then(|slot| sketch.result_map.get(*key)?.get(slot)?.as_u64())
+            .unwrap_or(0) as f64;
+        let error = (estimate - *truth as f64).abs();
+        absolute += error;
+        relative += error / *truth as f64;
+    }
+    let n = exact.len().max(1) as f64;
+
+    EpochAccuracy {
+        true_keys: exact.len(),
+        reported_keys: estimated.len(),
+        true_positives,
+        precision,
+        recall,
+        f1,
+        are: relative / n,
+        aae: absolute / n,
+    }
+}
+
+/// A candidate plan's accuracy in every epoch, and the most memory its sketches used.
+pub struct CandidateResult {
+    epochs: Vec<(u64, EpochAccuracy)>,
+    pub peak_memory: usize,
+}
+
+impl CandidateResult {
+    /// Mean precision, recall, F1, ARE and AAE over all epochs.
+    pub fn mean(&self) -> (f64, f64, f64, f64, f64) {
+        let n = self.epochs.len().max(1) as f64;
+        let mean = |metric: fn(&EpochAccuracy) -> f64| self.epochs.iter().map(|(_, a)| metric(a)).sum::<f64>() / n;
+        (mean(|a| a.precision), mean(|a| a.recall), mean(|a| a.f1), mean(|a| a.are), mean(|a| a.aae))
+    }
+}
+
+/// Runs every group's exact plan and its candidate plans over the trace in one pass, so
+/// all of them see the same packets and epochs. Returns one result per candidate, by group.
+pub fn evaluate_plans(
+    file_path: &str,
+    epoch_size: u64,
+    threshold: u64,
+    groups: Vec<(CompiledPlan, Vec<CompiledPlan>)>,
+) -> Vec<Vec<CandidateResult>> {
+    let mut runs: Vec<(PlanRun, Vec<(PlanRun, CandidateResult)>)> = groups
+        .into_iter()
+        .map(|(exact, candidates)| {
+            let candidates = candidates
+                .into_iter()
+                .map(|plan| (PlanRun::new(plan), CandidateResult { epochs: Vec::new(), peak_memory: 0 }))
+                .collect();
+            (PlanRun::new(exact), candidates)
+        })
+        .collect();
+    let mut cap = Capture::from_file(file_path).expect("Failed to open PCAP file");
+    let mut current_epoch_start: Option<u64> = None;
+    let mut epoch_packets = 0;
+
+    let end_epoch = |runs: &mut Vec<(PlanRun, Vec<(PlanRun, CandidateResult)>)>, epoch_end: u64| {
+        for (exact_run, candidates) in runs.iter_mut() {
+            let exact = exact_run.thresholded(threshold);
+            for (run, result) in candidates.iter_mut() {
+                result.epochs.push((epoch_end, compare(&exact, &run.thresholded(threshold), run)));
+                result.peak_memory = result.peak_memory.max(run.sketches.values().map(|s| s.get_memory_usage()).sum());
+            }
+        }
+    };
+
+    while let Ok(packet) = cap.next_packet() {
+        let packet_timestamp = packet.header.ts.tv_sec as u64;
+        current_epoch_start.get_or_insert(packet_timestamp);
+
+        if let Some(record) = extract_packet_tuple(&packet) {
+            for (exact_run, candidates) in runs.iter_mut() {
+                exact_run.execute(record.clone(), epoch_size, packet_timestamp);
+                for (run, _) in candidates.iter_mut() {
+                    run.execute(record.clone(), epoch_size, packet_timestamp);
+                }
+            }
+        }
+
+        if packet_timestamp - current_epoch_start.unwrap() >= epoch_size {
+            end_epoch(&mut runs, packet_timestamp);
+            for (exact_run, candidates) in runs.iter_mut() {
+                exact_run.end_epoch(packet_timestamp);
+                candidates.iter_mut().for_each(|(run, _)| run.end_epoch(packet_timestamp));
+            }
+            current_epoch_start = Some(packet_timestamp);
+            epoch_packets = 0;
+        }
+        epoch_packets += 1;
+    }
+    if let (Some(epoch_start), true) = (current_epoch_start, epoch_packets > 0) {
+        end_epoch(&mut runs, epoch_start);
+    }
+
+    runs.into_iter()
+        .map(|(_, candidates)| candidates.into_iter().map(|(_, result)| result).collect())
+        .collect()
+}
+
+/// Compiles a query and its exact counterpart for `evaluate_plans`. Joins keep their
+/// buffers in executor-wide statics, which two plans cannot share, so they are refused.
+pub fn compile_with_exact(query: &QueryPlan) -> Result<(CompiledPlan, CompiledPlan), String> {
+    if query.has_join() {
+        return Err("evaluation does not support queries with joins".to_string());
+    }
+    Ok((compile_plan(query)?, compile_plan(&query.exact())?))
+}
+
+/// `evaluate` mode: runs the configured plan and its exact counterpart over the trace in
+/// one pass and writes per-epoch precision, recall, F1, ARE and AAE of the keys above
+/// `threshold` to `report_path` as CSV.
+pub fn run_evaluation(file_path: &str, epoch_size: u64, threshold: u64, query: &QueryPlan, report_path: &str) {
+    let (sketch_plan, exact_plan) = match compile_with_exact(query) {
+        Ok(plans) => plans,
+        Err(e) => {
+            eprintln!("Cannot evaluate query: {}", e);
+            return;
+        }
+    };
+    let mut report = match File::create(report_path) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("Cannot create {}: {}", report_path, e);
+            return;
+        }
+    };
+
+    println!("Evaluating against the exact plan...");
+    let result = match evaluate_plans(file_path, epoch_size, threshold, vec![(exact_plan, vec![sketch_plan])])
+        .pop()
+        .and_then(|mut group| group.pop())
+    {
+        Some(result) => result,
+        None => return,
+    };
+
+    let mut csv = String::from("epoch,epoch_end,true_keys,reported_keys,true_positives,precision,recall,f1,are,aae\n");
+    for (i, (epoch_end, a)) in result.epochs.iter().enumerate() {
+        csv.push_str(&format!(
+            "{},{},{},{},{},{:.6},{:.6},{:.6},{:.6},{:.4}\n",
+            i + 1, epoch_end, a.true_keys, a.reported_keys, a.true_positives, a.precision, a.recall, a.f1, a.are, a.aae
+        ));
+    }
+    let (precision, recall, f1, are, aae) = result.mean();
+    csv.push_str(&format!("mean,,,,,{:.6},{:.6},{:.6},{:.6},{:.4}\n", precision, recall, f1, are, aae));
+
+    if let Err(e) = report.write_all(csv.as_bytes()) {
+        eprintln!("Failed to write {}: {}", report_path, e);
+        return;
+    }
+    println!("Evaluated {} epochs (threshold {})", result.epochs.len(), threshold);
+    println!("Mean precision: {:.4}, recall: {:.4}, F1: {:.4}", precision, recall, f1);
+    println!("Mean ARE: {:.4}, AAE: {:.2}", are, aae);
+    println!("Report written to {}", report_path);
+}
@@ -0,0 +1,122 @@
+// explain.rs
+// `explain` mode: a query's operators, the records passed between them, the sketches they
+// update and what the plan costs in memory and per-packet work.
+
+use std::collections::BTreeMap;
+use crate::flow_key::key_names;
+use crate::query_executor::{compile_plan, CompiledPlan, Sharding, SketchCost};
+use crate::query_plan::{Operation, QueryPlan};
+use crate::record::Schema;
+
+fn schema_fields(schema: &Schema) -> String {
+    schema
+        .fields()
+        .iter()
+        .map(|(name, field_type)| format!("{} ({:?})", name, field_type))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn range(range: (usize, usize)) -> String {
+    if range.0 == range.1 {
+        range.0.to_string()
+    } else {
+        format!("{}-{}", range.0, range.1)
+    }
+}
+
+fn memory(cost: &SketchCost) -> String {
+    match cost.memory_bytes {
+        Some(bytes) => format!("{} bytes", bytes),
+        None => "exact, grows with the keys".to_string(),
+    }
+}
+
+// Prints the operator tree and collects every sketch updated per packet, with its cost.
+fn print_operations<'a>(query: &QueryPlan, plan: &'a CompiledPlan, indent: usize, sketches: &mut Vec<(&'a str, SketchCost)>) {
+    let pad = " ".repeat(indent);
+    for (i, (op, profile)) in query.operations.iter().zip(plan.operator_profiles()).enumerate() {
+        match (op, profile.sub_plans) {
+            (Operation::Join { left_query, right_query, left_keys, right_keys }, Some((left_plan, right_plan))) => {
+                println!("{}{}. Join on {} = {}", pad, i + 1, left_keys.join(", "), right_keys.join(", "));
+                println!("{}   left:", pad);
+                print_operations(left_query, left_plan, indent + 6, sketches);
+                println!("{}   right:", pad);
+                print_operations(right_query, right_plan, indent + 6, sketches);
+            }
+            (other, _) => println!("{}{}. {:?}", pad, i + 1, other),
+        }
+        if let Some((sketch_key, cost)) = profile.sketch {
+            println!(
+                "{}     sketch {}: {}, {} hashes and {} counter accesses per packet",
+                pad,
+                sketch_key,
+                memory(&cost),
+                range(cost.hashes),
+                range(cost.memory_accesses)
+            );
+            sketches.push((sketch_key, cost));
+        }
+        println!("{}     -> {}", pad, schema_fields(profile.output_schema));
+    }
+}
+
+pub fn run_explain(query: &QueryPlan) {
+    let plan = match compile_plan(query) {
+        Ok(plan) => plan,
+        Err(e) => {
+            println!("Operators:");
+            for (i, op) in query.operations.iter().enumerate() {
+                println!("  {}. {:?}", i + 1, op);
+            }
+            println!("Invalid plan: {}", e);
+            return;
+        }
+    };
+    println!("Operators:");
+    let mut updated = Vec::new();
+    print_operations(query, &plan, 2, &mut updated);
+
+    let packet = Schema::packet();
+    println!("Result key: {}", key_names(&plan.result_key));
+    println!("Result fields: {}", schema_fields(&plan.result_schema));
+    match plan.value_slot() {
+        Some(slot) => println!("Reported value: {}", plan.result_schema.fields()[slot].0),
+        None => println!("Reported value: none"),
+    }
+    if let Some((threshold, slot, mode)) = plan.filter_result() {
+        println!("FilterResult: {} >= {} ({:?})", plan.result_schema.fields()[slot].0, threshold, mode);
+    }
+    match plan.sharding() {
+        Ok(Sharding::ByKey(key)) => {
+            let names: Vec<&str> = key.iter().map(|(slot, _)| packet.fields()[*slot].0.as_str()).collect();
+            println!("Sharding: by {}", names.join(", "));
+        }
+        Ok(Sharding::RoundRobin) => println!("Sharding: round robin"),
+        Err(e) => println!("Sharding: single-threaded ({})", e),
+    }
+    let mut sketches = plan.epoch_sketch_keys();
+    sketches.sort();
+    sketches.dedup();
+    if !sketches.is_empty() {
+        println!("Sketches read per epoch: {}", sketches.join(", "));
+    }
+
+    // Operators naming the same sketch share it, so its memory is counted once.
+    let instances: BTreeMap<&str, SketchCost> = updated.iter().copied().collect();
+    let configured: usize = instances.values().filter_map(|cost| cost.memory_bytes).sum();
+    let exact = instances.values().filter(|cost| cost.memory_bytes.is_none()).count();
+    println!("Resources:");
+    println!("  Configured sketch memory: {} bytes", configured);
+    if exact > 0 {
+        println!("  Exact sketches, growing with the keys: {}", exact);
+    }
+    let total = |field: fn(&SketchCost) -> (usize, usize)| {
+        updated.iter().fold((0, 0), |(min, max), (_, cost)| (min + field(cost).0, max + field(cost).1))
+    };
+    println!(
+        "  Per packet reaching every sketch: {} hashes, {} counter accesses",
+        range(total(|cost| cost.hashes)),
+        range(total(|cost| cost.memory_accesses))
+    );
+}
@@ -0,0 +1,259 @@
+use std::collections::{BTreeMap, HashMap};
+
+// Counters up to this value are split into at most EM_MAX_PARTS_SMALL flows,
+// counters up to the layer-1 threshold into at most two flows, and anything
+// larger is treated as a single flow (as in MRAC / FCM-Sketch).
+const EM_SMALL_VALUE: u32 = 32;
+const EM_MAX_PARTS_SMALL: usize = 4;
+const EM_MAX_PARTS_LARGE: usize = 2;
+
+/// A counter after collapsing an FCM counter tree: `value` is the sum along the
+/// overflow path and `degree` is the number of layer-1 leaves merged into it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct VirtualCounter {
+    pub value: u32,
+    pub degree: u32,
+}
+
+/// Flow size distribution estimated from one epoch of sketch state.
+#[derive(Clone, Debug, Default)]
+pub struct FlowSizeEstimate {
+    /// flow size -> estimated number of flows with that size
+    pub distribution: BTreeMap<u32, f64>,
+    pub cardinality: f64,
+    pub entropy: f64,
+}
+
+impl FlowSizeEstimate {
+    pub fn from_distribution(distribution: BTreeMap<u32, f64>) -> Self {
+        let cardinality: f64 = distribution.values().sum();
+        let total: f64 = distribution.iter().map(|(size, n)| *size as f64 * n).sum();
+
+        let mut entropy = 0.0;
+        if total > 0.0 {
+            for (size, n) in &distribution {
+                let p = *size as f64 / total;
+                if p > 0.0 {
+                    entropy -= n * p * p.log2();
+                }
+            }
+        }
+
+        Self { distribution, cardinality, entropy }
+    }
+
+    /// Averages the estimates of several independent counter arrays (one per FCM tree).
+    pub fn average(estimates: &[FlowSizeEstimate]) -> Self {
+        if estimates.is_empty() {
+            return Self::default();
+        }
+        let mut distribution: BTreeMap<u32, f64> = BTreeMap::new();
+        for estimate in estimates {
+            for (size, n) in &estimate.distribution {
+                *distribution.entry(*size).or_insert(0.0) += n / estimates.len() as f64;
+            }
+        }
+        Self::from_distribution(distribution)
+    }
+}
+
+/// Expectation-maximization over virtual counters. Each counter is assumed to hold the
+/// sum of a Poisson number of flows whose sizes follow the current distribution estimate.
+pub fn estimate_flow_sizes(
+    counters: &[VirtualCounter],
+    num_leaves: usize,
+    threshold_l1: u32,
+    iterations: usize,
+) -> FlowSizeEstimate {
+    let mut grouped: HashMap<VirtualCounter, f64> = HashMap::new();
+    for counter in counters.iter().filter(|c| c.value > 0) {
+        *grouped.entry(*counter).or_insert(0.0) += 1.0;
+    }
+
+    // Initial guess: every non-empty counter holds exactly one flow.
+    let mut ns: BTreeMap<u32, f64> = BTreeMap::new();
+    for (counter, count) in &grouped {
+        for (size, flows) in split_merged_counter(counter) {
+            *ns.entry(size).or_insert(0.0) += count * flows;
+        }
+    }
+
+    for _ in 0..iterations {
+        let n: f64 = ns.values().sum();
+        if n == 0.0 {
+            break;
+        }
+        let lambda = n / num_leaves.max(1) as f64;
+        let mut next: BTreeMap<u32, f64> = BTreeMap::new();
+
+        for (counter, count) in &grouped {
+            let max_parts = if counter.degree > 1 {
+                1
+            } else if counter.value <= EM_SMALL_VALUE {
+                EM_MAX_PARTS_SMALL
+            } else if counter.value <= threshold_l1 {
+                EM_MAX_PARTS_LARGE
+            } else {
+                1
+            };
+
+            if max_parts == 1 {
+                for (size, flows) in split_merged_counter(counter) {
+                    *next.entry(size).or_insert(0.0) += count * flows;
+                }
+                continue;
+            }
+
+            let lambda_k = lambda * counter.degree as f64;
+            let mut weighted: Vec<(Vec<u32>, f64)> = Vec::new();
+            let mut total_weight = 0.0;
+            let mut parts = Vec::new();
+            for_each_partition(counter.value, counter.value, max_parts, &mut parts, &mut |partition| {
+                let weight = partition_weight(partition, lambda_k, n, &ns);
+                if weight > 0.0 {
+                    total_weight += weight;
+                    weighted.push((partition.to_vec(), weight));
+                }
+            });
+
+            if total_weight == 0.0 {
+                *next.entry(counter.value).or_insert(0.0) += count;
+                continue;
+            }
+
+            for (partition, weight) in weighted {
+                let share = count * weight / total_weight;
+                for size in partition {
+                    *next.entry(size).or_insert(0.0) += share;
+                }
+            }
+        }
+
+        ns = next;
+    }
+
+    FlowSizeEstimate::from_distribution(ns)
+}
+
+// Merged counters cover `degree` leaves that each overflowed, so each leaf carried at
+// least one large flow; the value is split evenly across them.
+fn split_merged_counter(counter: &VirtualCounter) -> Vec<(u32, f64)> {
+    if counter.degree <= 1 {
+        return vec![(counter.value, 1.0)];
+    }
+    let share = counter.value / counter.degree;
+    let remainder = counter.value % counter.degree;
+    let mut splits = vec![(share, (counter.degree - 1) as f64)];
+    splits.push((share + remainder, 1.0));
+    splits
+}
+
+// P(partition | value) up to a constant: prod over distinct sizes s of (lambda * phi_s)^m_s / m_s!
+fn partition_weight(partition: &[u32], lambda: f64, n: f64, ns: &BTreeMap<u32, f64>) -> f64 {
+    let mut weight = 1.0;
+    let mut i = 0;
+    while i < partition.len() {
+        let size = partition[i];
+        let mut multiplicity = 0;
+        while i < partition.len() && partition[i] == size {
+            multiplicity += 1;
+            i += 1;
+        }
+        let phi = ns.get(&size).copied().unwrap_or(0.0) / n;
+        for m in 1..=multiplicity {
+            weight *= lambda * phi / m as f64;
+        }
+    }
+    weight
+}
+
+// Enumerates partitions of `remaining` into non-increasing parts no larger than `max_part`.
+fn for_each_partition<F: FnMut(&[u32])>(
+    remaining: u32,
+    max_part: u32,
+    max_parts: usize,
+    parts: &mut Vec<u32>,
+    visit: &mut F,
+) {
+    if remaining == 0 {
+        visit(parts);
+        return;
+    }
+    if parts.len() == max_parts || remaining > max_part.saturating_mul((max_parts - parts.len()) as u32) {
+        return;
+    }
+    if parts.len() + 1 == max_parts {
+        parts.push(remaining);
+        visit(parts);
+        parts.pop();
+        return;
+    }
+    let mut part = max_part.min(remaining);
+    while part > 0 {
+        parts.push(part);
+        for_each_partition(remaining - part, part, max_parts, parts, visit);
+        parts.pop();
+        part -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::deterministic_sketch::DeterministicSketch;
+    use crate::fcm_sketch::FCMSketch;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    // A heavy-tailed population: most flows have a few packets, a few have thousands.
+    fn synthetic_flows(num_flows: u32, seed: u64) -> Vec<(Vec<u8>, u32)> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        (0..num_flows)
+            .map(|flow| {
+                let size = (1.0 / rng.gen_range(0.0005f64..1.0)).powf(1.2).ceil() as u32;
+                (flow.to_be_bytes().to_vec(), size.min(20_000))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn em_matches_ground_truth_on_synthetic_trace() {
+        let flows = synthetic_flows(20_000, 7);
+        let mut fcm = FCMSketch::new(2, 65536, 8192, 1024, 254, 65534, 42);
+        let mut exact = DeterministicSketch::new();
+        // Packets arrive one at a time, interleaving the flows.
+        let longest = flows.iter().map(|(_, size)| *size).max().unwrap();
+        for round in 0..longest {
+            for (key, _) in flows.iter().filter(|(_, size)| *size > round) {
+                fcm.insert(key, 1);
+                exact.insert(key, 1);
+            }
+        }
+
+        let truth = exact.get_distribution();
+        let estimate = fcm.get_distribution(10);
+        assert_eq!(truth.cardinality, flows.len() as f64);
+
+        // Within 2% on cardinality and entropy.
+        let relative = |estimate: f64, truth: f64| (estimate - truth).abs() / truth;
+        assert!(
+            relative(estimate.cardinality, truth.cardinality) < 0.02,
+            "cardinality {} against {}",
+            estimate.cardinality,
+            truth.cardinality
+        );
+        assert!(relative(estimate.entropy, truth.entropy) < 0.02, "entropy {} against {}", estimate.entropy, truth.entropy);
+
+        // Weighted mean relative error of the distribution, as reported for FCM-Sketch.
+        let sizes: std::collections::BTreeSet<u32> =
+            truth.distribution.keys().chain(estimate.distribution.keys()).copied().collect();
+        let (mut difference, mut total) = (0.0, 0.0);
+        for size in sizes {
+            let n = truth.distribution.get(&size).copied().unwrap_or(0.0);
+            let n_hat = estimate.distribution.get(&size).copied().unwrap_or(0.0);
+            difference += (n - n_hat).abs();
+            total += (n + n_hat) / 2.0;
+        }
+        let wmre = difference / total;
+        assert!(wmre < 0.1, "WMRE {}", wmre);
+    }
+}
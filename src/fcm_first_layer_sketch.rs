@@ -1,11 +1,13 @@
 use std::collections::HashSet;
 use crate::bobhash32::BOBHash32;
+use serde::{Deserialize, Serialize};
 
 
 type FCMSK_C1 = u32; // counter type is 32 bits like Count-Min sketch
 
 
 // This FCM only uses the first layer of the FCM sketch and does not have any pormotion.
+#[derive(Clone, Serialize, Deserialize)]
 pub struct FCMFirstLayerOnly {
     pub depth: usize,
     pub width_l1: usize,
@@ -35,6 +37,21 @@ impl FCMFirstLayerOnly {
         }
     }
 
+    pub fn merge(&mut self, other: &FCMFirstLayerOnly) -> Result<(), String> {
+        if self.depth != other.depth || self.width_l1 != other.width_l1 || self.hash_functions != other.hash_functions {
+            return Err(format!(
+                "FCMFirstLayerOnly parameters differ: {}x{} vs {}x{}",
+                self.depth, self.width_l1, other.depth, other.width_l1
+            ));
+        }
+        for (row, other_row) in self.counters_l1.iter_mut().zip(&other.counters_l1) {
+            for (counter, other_counter) in row.iter_mut().zip(other_row) {
+                *counter = counter.saturating_add(*other_counter);
+            }
+        }
+        Ok(())
+    }
+
     pub fn query(&self, item: &[u8]) -> u32 {
         let mut ret = u32::MAX;
         for d in 0..self.depth {
@@ -1,5 +1,7 @@
 use std::collections::HashSet;
 use crate::bobhash32::BOBHash32;
+use serde::{Deserialize, Serialize};
+use crate::fcm_em::{estimate_flow_sizes, FlowSizeEstimate, VirtualCounter};
 
 const FCMSK_K_ARY: usize = 8; // k-ary tree
 const FCMSK_K_POW: usize = 3; // 2^3 = 8
@@ -9,6 +11,7 @@ const HH_THRESHOLD: u32 = 10000; // Heavy hitter threshold
     type FCMSK_C2 = u32; // 16-bit
     type FCMSK_C3 = u32; // 32-bit
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct FCMSketch {
     pub depth: usize,
     pub width_l1: usize,
@@ -50,175 +53,123 @@ impl FCMSketch {
     }
 
     pub fn insert(&mut self, item: &[u8], count: u32) {
-        let mut hash_index = vec![0; self.depth];
-        let mut ret_val = vec![0; self.depth];
         let mut hh_flag = true;
-    
-        // Define the target item
-        let target_item = "dst_ip: 35.26.185.176, src_ip: 163.27.199.6".as_bytes();
-        let is_target_item = item == target_item;
-    
-        // if is_target_item {
-        //     println!("[DEBUG] Target item detected: dst_ip: 35.26.185.176, src_ip: 163.27.199.6 with count {:?}", count);
-        // }
-    
-        // Compute hash indices for each depth
-        for d in 0..self.depth {
-            hash_index[d] = self.hash_functions[d].run(item) as usize % self.width_l1;
-    
-            // if is_target_item {
-            //     println!(
-            //         "[DEBUG] Depth {}: Hash index for L1 = {}",
-            //         d, hash_index[d]
-            //     );
-            // }
-        }
-    
+
         for d in 0..self.depth {
-            let mut idx = hash_index[d];
-    
+            let mut idx = self.hash_functions[d].run(item) as usize % self.width_l1;
+
             // Stage 1: L1
-            ret_val[d] = self.increment_counter_l1(d, idx, count);
-    
-            // if is_target_item {
-            //     println!(
-            //         "[DEBUG] Depth {}: L1 Counter[{}] = {} after increment",
-            //         d, idx, self.counters_l1[d][idx]
-            //     );
-            // }
-    
-            if ret_val[d] <= self.threshold_l1 {
-                // Did not overflow, done
-                continue;
-            }
-    
-            // Stage 2: L2
-            idx = idx / FCMSK_K_ARY;
-            ret_val[d] = self.increment_counter_l2(d, idx, count) + self.cumul_l2;
-    
-            // if is_target_item {
-            //     println!(
-            //         "[DEBUG] Depth {}: L2 Counter[{}] = {} after increment",
-            //         d, idx, self.counters_l2[d][idx]
-            //     );
-            // }
-    
-            if ret_val[d] <= self.threshold_l2 {
-                continue;
+            let mut ret_val = self.increment_counter_l1(d, idx, count);
+
+            if ret_val > self.threshold_l1 {
+                // Stage 2: L2
+                idx /= FCMSK_K_ARY;
+                ret_val = self.increment_counter_l2(d, idx, count) + self.cumul_l2;
+
+                if ret_val > self.threshold_l2 {
+                    // Stage 3: L3
+                    idx /= FCMSK_K_ARY;
+                    ret_val = self.increment_counter_l3(d, idx, count) + self.cumul_l3;
+                }
             }
-    
-            // Stage 3: L3
-            idx = idx / FCMSK_K_ARY;
-            ret_val[d] = self.increment_counter_l3(d, idx, count) + self.cumul_l3;
-    
-            // if is_target_item {
-            //     println!(
-            //         "[DEBUG] Depth {}: L3 Counter[{}] = {} after increment",
-            //         d, idx, self.counters_l3[d][idx]
-            //     );
-            // }
-        }
-    
-        // Check if the item qualifies as a heavy hitter
-        for d in 0..self.depth {
-            if ret_val[d] <= HH_THRESHOLD {
+
+            // Heavy hitter only if every tree is past the threshold
+            if ret_val <= HH_THRESHOLD {
                 hh_flag = false;
-                break;
             }
         }
-    
-        if hh_flag {
-            if item.len() == 4 {
-                let item_u32 = u32::from_be_bytes(item.try_into().unwrap());
-                self.hh_candidates.insert(item_u32);
-    
-                // if is_target_item {
-                //     println!("[DEBUG] Heavy hitter detected: {:?}", item_u32);
-                // }
-            }
+
+        if hh_flag && item.len() == 4 {
+            let item_u32 = u32::from_be_bytes(item.try_into().unwrap());
+            self.hh_candidates.insert(item_u32);
         }
     }
-    
+
     pub fn query(&self, item: &[u8]) -> u32 {
-        let mut hash_index = vec![0; self.depth];
-        let mut ret_val = vec![0; self.depth];
         let mut count_query = u32::MAX;
-    
-        // Define the target item
-        let target_item = "dst_ip: 35.26.185.176, src_ip: 163.27.199.6".as_bytes();
-        let is_target_item = item == target_item;
-    
-        // if is_target_item {
-        //     println!("[DEBUG] Querying target item: dst_ip: 35.26.185.176, src_ip: 163.27.199.6");
-        // }
-    
-        // Compute hash indices for each depth
+
         for d in 0..self.depth {
-            hash_index[d] = self.hash_functions[d].run(item) as usize % self.width_l1;
-    
-            // if is_target_item {
-            //     println!(
-            //         "[DEBUG] Depth {}: Hash index for L1 = {}",
-            //         d, hash_index[d]
-            //     );
-            // }
+            let mut idx = self.hash_functions[d].run(item) as usize % self.width_l1;
+
+            // Stage 1: L1
+            let mut ret_val = self.query_counter_l1(d, idx);
+
+            if ret_val > self.threshold_l1 {
+                // Stage 2: L2
+                idx /= FCMSK_K_ARY;
+                ret_val = self.query_counter_l2(d, idx) + self.cumul_l2;
+
+                if ret_val > self.threshold_l2 {
+                    // Stage 3: L3
+                    idx /= FCMSK_K_ARY;
+                    ret_val = self.query_counter_l3(d, idx) + self.cumul_l3;
+                }
+            }
+
+            count_query = count_query.min(ret_val);
         }
+
+        count_query
+    }
     
+
+    /// Register memory of the three layers, at the 8, 16 and 32-bit widths their overflow
+    /// thresholds are chosen for, rather than the u32s they are stored in.
+    pub fn get_memory_usage(&self) -> usize {
+        self.depth * (self.width_l1 + self.width_l2 * 2 + self.width_l3 * 4)
+    }
+
+    /// Adds another FCM sketch tree by tree, as if its packets were inserted after this
+    /// sketch's. Its leaf counts are replayed as unit inserts: every one that leaves a leaf
+    /// above `threshold_l1` also counts in the layer-2 parent, and likewise from layer 2
+    /// into layer 3, so the upper layers of `other` are not read. This is exact for unit
+    /// counts; with larger ones an insert crossing a threshold carries its whole count,
+    /// so the layers above can end lower than inserting would have left them.
+    pub fn merge(&mut self, other: &FCMSketch) -> Result<(), String> {
+        if self.depth != other.depth
+            || self.width_l1 != other.width_l1
+            || self.width_l2 != other.width_l2
+            || self.width_l3 != other.width_l3
+            || self.threshold_l1 != other.threshold_l1
+            || self.threshold_l2 != other.threshold_l2
+            || self.hash_functions != other.hash_functions
+        {
+            return Err(format!(
+                "FCMSketch parameters differ: {}x{}/{}/{} vs {}x{}/{}/{}",
+                self.depth, self.width_l1, self.width_l2, self.width_l3,
+                other.depth, other.width_l1, other.width_l2, other.width_l3
+            ));
+        }
+
+        // Layer-2 values overflow once they pass threshold_l2 - cumul_l2.
+        let overflow_l2 = self.threshold_l2.saturating_sub(self.cumul_l2);
+        let mut carry_l2 = vec![0u32; self.width_l2];
+        let mut carry_l3 = vec![0u32; self.width_l3];
         for d in 0..self.depth {
-            let mut idx = hash_index[d];
-    
-            // Stage 1: L1
-            ret_val[d] = self.query_counter_l1(d, idx);
-    
-            // if is_target_item {
-            //     println!(
-            //         "[DEBUG] Depth {}: L1 Counter[{}] = {}",
-            //         d, idx, self.counters_l1[d][idx]
-            //     );
-            // }
-    
-            if ret_val[d] <= self.threshold_l1 {
-                count_query = count_query.min(ret_val[d]);
-                continue;
+            carry_l2.fill(0);
+            for i in 0..self.width_l1 {
+                let (a, b) = (self.counters_l1[d][i], other.counters_l1[d][i]);
+                let merged = a.saturating_add(b);
+                let carry = &mut carry_l2[i / FCMSK_K_ARY];
+                *carry = carry.saturating_add(merged.saturating_sub(self.threshold_l1).min(b));
+                self.counters_l1[d][i] = merged;
             }
-    
-            // Stage 2: L2
-            idx = idx / FCMSK_K_ARY;
-            ret_val[d] = self.query_counter_l2(d, idx) + self.cumul_l2;
-    
-            // if is_target_item {
-            //     println!(
-            //         "[DEBUG] Depth {}: L2 Counter[{}] = {}",
-            //         d, idx, self.counters_l2[d][idx]
-            //     );
-            // }
-    
-            if ret_val[d] <= self.threshold_l2 {
-                count_query = count_query.min(ret_val[d]);
-                continue;
+
+            carry_l3.fill(0);
+            for (j, &carried) in carry_l2.iter().enumerate() {
+                let merged = self.counters_l2[d][j].saturating_add(carried);
+                let carry = &mut carry_l3[j / FCMSK_K_ARY];
+                *carry = carry.saturating_add(merged.saturating_sub(overflow_l2).min(carried));
+                self.counters_l2[d][j] = merged;
+            }
+
+            for (counter, &carried) in self.counters_l3[d].iter_mut().zip(&carry_l3) {
+                *counter = counter.saturating_add(carried);
             }
-    
-            // Stage 3: L3
-            idx = idx / FCMSK_K_ARY;
-            ret_val[d] = self.query_counter_l3(d, idx) + self.cumul_l3;
-    
-            // if is_target_item {
-            //     println!(
-            //         "[DEBUG] Depth {}: L3 Counter[{}] = {}",
-            //         d, idx, self.counters_l3[d][idx]
-            //     );
-            // }
-    
-            count_query = count_query.min(ret_val[d]);
         }
-    
-        // if is_target_item {
-        //     println!("[DEBUG] Final query result for target item: {}", count_query);
-        // }
-    
-        count_query
+        self.hh_candidates.extend(&other.hh_candidates);
+        Ok(())
     }
-    
 
     pub fn get_cardinality(&self) -> i32 {
         let mut avgnum_empty_counter = 0;
@@ -228,6 +179,57 @@ impl FCMSketch {
         (self.width_l1 as f64 * (self.width_l1 as f64 / avgnum_empty_counter as f64).ln()) as i32
     }
 
+    /// Collapses every counter tree of one hash row into virtual counters: leaves that
+    /// overflowed into the same parent are merged and their values summed along the path.
+    pub fn get_virtual_counters(&self, depth: usize) -> Vec<VirtualCounter> {
+        let mut virtual_counters = Vec::with_capacity(self.width_l1);
+        let mut merged_l3: Vec<VirtualCounter> = vec![VirtualCounter { value: 0, degree: 0 }; self.width_l3];
+
+        for idx_l2 in 0..self.width_l2 {
+            let mut merged = VirtualCounter { value: 0, degree: 0 };
+            for idx_l1 in idx_l2 * FCMSK_K_ARY..((idx_l2 + 1) * FCMSK_K_ARY).min(self.width_l1) {
+                let value = self.counters_l1[depth][idx_l1];
+                if value <= self.threshold_l1 {
+                    virtual_counters.push(VirtualCounter { value, degree: 1 });
+                } else {
+                    merged.value = merged.value.saturating_add(self.threshold_l1);
+                    merged.degree += 1;
+                }
+            }
+            if merged.degree == 0 {
+                continue;
+            }
+
+            let value_l2 = self.counters_l2[depth][idx_l2];
+            if value_l2 <= self.threshold_l2 {
+                merged.value = merged.value.saturating_add(value_l2);
+                virtual_counters.push(merged);
+            } else {
+                let parent = &mut merged_l3[idx_l2 / FCMSK_K_ARY];
+                parent.value = parent.value.saturating_add(merged.value).saturating_add(self.threshold_l2);
+                parent.degree += merged.degree;
+            }
+        }
+
+        for (idx_l3, mut merged) in merged_l3.into_iter().enumerate() {
+            if merged.degree > 0 {
+                merged.value = merged.value.saturating_add(self.counters_l3[depth][idx_l3]);
+                virtual_counters.push(merged);
+            }
+        }
+        virtual_counters
+    }
+
+    /// Runs the EM flow size estimator on every hash row and averages the results.
+    pub fn get_distribution(&self, iterations: usize) -> FlowSizeEstimate {
+        let estimates: Vec<FlowSizeEstimate> = (0..self.depth)
+            .map(|d| {
+                estimate_flow_sizes(&self.get_virtual_counters(d), self.width_l1, self.threshold_l1, iterations)
+            })
+            .collect();
+        FlowSizeEstimate::average(&estimates)
+    }
+
     fn increment_counter_l1(&mut self, depth: usize, index: usize, count: u32) -> u32 {
         let old_val = self.counters_l1[depth][index];
         let new_val = old_val.saturating_add(count as FCMSK_C1);
@@ -260,4 +262,56 @@ impl FCMSketch {
     fn query_counter_l3(&self, depth: usize, index: usize) -> u32 {
         self.counters_l3[depth][index] as u32
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    // Small layers and thresholds, so that a few thousand packets overflow into layer 3.
+    fn small_sketch() -> FCMSketch {
+        FCMSketch::new(2, 64, 8, 1, 10, 100, 1)
+    }
+
+    fn stream(seed: u64, packets: usize) -> Vec<[u8; 4]> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        (0..packets).map(|_| rng.gen_range(0u32..300).to_be_bytes()).collect()
+    }
+
+    #[test]
+    fn merge_matches_inserting_the_union_stream() {
+        let (first, second) = (stream(1, 3000), stream(2, 3000));
+        let mut merged = small_sketch();
+        let mut other = small_sketch();
+        let mut union = small_sketch();
+        for item in &first {
+            merged.insert(item, 1);
+            union.insert(item, 1);
+        }
+        for item in &second {
+            other.insert(item, 1);
+            union.insert(item, 1);
+        }
+        merged.merge(&other).unwrap();
+
+        assert!(union.counters_l3.iter().flatten().any(|&counter| counter > 0));
+        assert_eq!(merged.counters_l1, union.counters_l1);
+        assert_eq!(merged.counters_l2, union.counters_l2);
+        assert_eq!(merged.counters_l3, union.counters_l3);
+        for item in first.iter().chain(&second) {
+            assert_eq!(merged.query(item), union.query(item));
+        }
+    }
+
+    #[test]
+    fn merge_saturates_instead_of_overflowing() {
+        let mut merged = small_sketch();
+        let mut other = small_sketch();
+        merged.insert(b"key", u32::MAX - 5);
+        other.insert(b"key", 10);
+        merged.merge(&other).unwrap();
+        assert_eq!(merged.counters_l1.iter().flatten().max(), Some(&u32::MAX));
+    }
+}
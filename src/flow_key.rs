@@ -0,0 +1,170 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+use crate::query_executor::PacketField;
+use crate::record::{FieldType, Record};
+
+/// Longest encoded key. Plans whose keys could be longer are rejected when compiled.
+pub const MAX_KEY_BYTES: usize = 64;
+/// Longest string a plan can produce, so that string keys have a bounded width.
+pub const MAX_KEY_STRING_BYTES: usize = 31;
+
+/// Canonical binary encoding of a flow key, built on the stack.
+///
+/// Fields are packed back to back in key order using their schema type: IPv4 addresses
+/// as 4 bytes, IPv6 as 16, integers big-endian at their natural width, and strings as a
+/// length byte followed by the bytes. A key on a single IPv4 address is exactly 4 bytes.
+/// Fields that are missing from the record encode as zeros.
+pub struct FlowKey {
+    bytes: [u8; MAX_KEY_BYTES],
+    len: usize,
+}
+
+impl FlowKey {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        self.bytes[self.len..self.len + data.len()].copy_from_slice(data);
+        self.len += data.len();
+    }
+}
+
+/// Encoded size of a field, or the minimum size for variable-length strings.
+pub fn encoded_width(field_type: FieldType) -> usize {
+    match field_type {
+        FieldType::String | FieldType::U8 | FieldType::Bool => 1,
+        FieldType::U16 => 2,
+        FieldType::OptionU16 => 3,
+        FieldType::U32 | FieldType::Ipv4 => 4,
+        FieldType::OptionTupleU16 => 5,
+        FieldType::U64 => 8,
+        FieldType::Ipv6 => 16,
+    }
+}
+
+/// Largest encoded size of a field, which `compile_plan` keeps keys within.
+pub fn max_encoded_width(field_type: FieldType) -> usize {
+    match field_type {
+        FieldType::String => 1 + MAX_KEY_STRING_BYTES,
+        other => encoded_width(other),
+    }
+}
+
+pub fn encode_key(key_fields: &[(usize, FieldType)], record: &Record) -> FlowKey {
+    let mut key = FlowKey { bytes: [0; MAX_KEY_BYTES], len: 0 };
+    for (slot, field_type) in key_fields {
+        encode_field(*field_type, record.get(*slot), &mut key);
+    }
+    key
+}
+
+fn encode_field(field_type: FieldType, value: Option<&PacketField>, key: &mut FlowKey) {
+    match (field_type, value) {
+        (FieldType::Ipv4, Some(PacketField::Ipv4(ip))) => key.push(&ip.octets()),
+        (FieldType::Ipv6, Some(PacketField::Ipv6(ip))) => key.push(&ip.octets()),
+        (FieldType::U8, Some(PacketField::U8(v))) => key.push(&[*v]),
+        (FieldType::Bool, Some(PacketField::Bool(v))) => key.push(&[*v as u8]),
+        (FieldType::U16, Some(PacketField::U16(v))) => key.push(&v.to_be_bytes()),
+        (FieldType::U32, Some(PacketField::U32(v))) => key.push(&v.to_be_bytes()),
+        (FieldType::U64, Some(PacketField::U64(v))) => key.push(&v.to_be_bytes()),
+        (FieldType::OptionU16, Some(PacketField::OptionU16(Some(v)))) => {
+            key.push(&[1]);
+            key.push(&v.to_be_bytes());
+        }
+        (FieldType::OptionTupleU16, Some(PacketField::OptionTupleU16(Some((a, b))))) => {
+            key.push(&[1]);
+            key.push(&a.to_be_bytes());
+            key.push(&b.to_be_bytes());
+        }
+        (FieldType::String, Some(PacketField::String(s))) => {
+            key.push(&[s.len() as u8]);
+            key.push(s.as_bytes());
+        }
+        (field_type, _) => key.push(&[0; 16][..encoded_width(field_type)]),
+    }
+}
+
+/// Decodes a key produced by `encode_key` back into named fields.
+pub fn decode_key(key_fields: &[(String, FieldType)], bytes: &[u8]) -> Option<Vec<(String, PacketField)>> {
+    let mut fields = Vec::with_capacity(key_fields.len());
+    let mut rest = bytes;
+    for (name, field_type) in key_fields {
+        let (value, remaining) = decode_field(*field_type, rest)?;
+        if let Some(value) = value {
+            fields.push((name.clone(), value));
+        }
+        rest = remaining;
+    }
+    if rest.is_empty() {
+        Some(fields)
+    } else {
+        None
+    }
+}
+
+fn decode_field(field_type: FieldType, bytes: &[u8]) -> Option<(Option<PacketField>, &[u8])> {
+    let width = match field_type {
+        FieldType::String => 1 + *bytes.first()? as usize,
+        other => encoded_width(other),
+    };
+    let data = bytes.get(..width)?;
+    let value = match field_type {
+        FieldType::Ipv4 => Some(PacketField::Ipv4(Ipv4Addr::from(<[u8; 4]>::try_from(data).ok()?))),
+        FieldType::Ipv6 => Some(PacketField::Ipv6(Ipv6Addr::from(<[u8; 16]>::try_from(data).ok()?))),
+        FieldType::U8 => Some(PacketField::U8(data[0])),
+        FieldType::Bool => Some(PacketField::Bool(data[0] != 0)),
+        FieldType::U16 => Some(PacketField::U16(u16::from_be_bytes(data.try_into().ok()?))),
+        FieldType::U32 => Some(PacketField::U32(u32::from_be_bytes(data.try_into().ok()?))),
+        FieldType::U64 => Some(PacketField::U64(u64::from_be_bytes(data.try_into().ok()?))),
+        FieldType::OptionU16 => Some(PacketField::OptionU16(if data[0] == 0 {
+            None
+        } else {
+            Some(u16::from_be_bytes([data[1], data[2]]))
+        })),
+        FieldType::OptionTupleU16 => Some(PacketField::OptionTupleU16(if data[0] == 0 {
+            None
+        } else {
+            Some((u16::from_be_bytes([data[1], data[2]]), u16::from_be_bytes([data[3], data[4]])))
+        })),
+        FieldType::String => Some(PacketField::String(std::str::from_utf8(&data[1..]).ok()?.to_string())),
+    };
+    Some((value, &bytes[width..]))
+}
+
+/// Human-readable form of a binary key, e.g. `dst_ip: 10.0.0.1, dst_port: 443`.
+pub fn format_key(key_fields: &[(String, FieldType)], bytes: &[u8]) -> String {
+    match decode_key(key_fields, bytes) {
+        Some(fields) => fields
+            .iter()
+            .map(|(k, v)| format!("{}: {}", k, v))
+            .collect::<Vec<_>>()
+            .join(", "),
+        None => format!("{:02x?}", bytes),
+    }
+}
+
+/// Encodes a key typed on the command line, given as comma-separated values in key order,
+/// e.g. `10.0.0.1,443` for a key on `dst_ip, dst_port`.
+pub fn parse_key(key_fields: &[(String, FieldType)], text: &str) -> Result<Vec<u8>, String> {
+    let values: Vec<&str> = text.split(',').map(str::trim).collect();
+    if values.len() != key_fields.len() {
+        return Err(format!("expected {} value(s) for key ({})", key_fields.len(), key_names(key_fields)));
+    }
+    let mut record = Record::default();
+    let mut slots = Vec::with_capacity(key_fields.len());
+    for (slot, ((name, field_type), value)) in key_fields.iter().zip(values).enumerate() {
+        if *field_type == FieldType::String && value.len() > MAX_KEY_STRING_BYTES {
+            return Err(format!("{} is longer than {} bytes", name, MAX_KEY_STRING_BYTES));
+        }
+        let field = field_type
+            .parse(value)
+            .ok_or_else(|| format!("invalid {:?} value for {}: {}", field_type, name, value))?;
+        record.set(slot, field);
+        slots.push((slot, *field_type));
+    }
+    Ok(encode_key(&slots, &record).as_bytes().to_vec())
+}
+
+pub fn key_names(key_fields: &[(String, FieldType)]) -> String {
+    key_fields.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>().join(", ")
+}
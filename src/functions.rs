@@ -0,0 +1,213 @@
+// functions.rs
+// Functions deriving a field from others, usable as Map items and Reduce keys:
+// prefix(src_ip, 24), port_range(dst_port), log2(total_len) and hash(src_ip, dst_ip, 1024).
+
+use std::net::Ipv4Addr;
+use crate::bobhash32::BOBHash32;
+use crate::flow_key::encode_key;
+use crate::query_executor::{prefix_mask, resolve, resolve_key, PacketField};
+use crate::record::{FieldType, Record, Schema};
+
+// Row of the BOBHash prime table `hash` uses.
+const HASH_PRIME: u32 = 0;
+
+/// Splits "prefix(src_ip, 24)" into the function name and its arguments; `None` for
+/// anything that is not a call.
+pub fn parse_call(text: &str) -> Option<(&str, Vec<&str>)> {
+    let (name, rest) = text.trim().split_once('(')?;
+    let args = rest.strip_suffix(')')?;
+    let name = name.trim();
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return None;
+    }
+    Some((name, args.split(',').map(str::trim).filter(|arg| !arg.is_empty()).collect()))
+}
+
+/// Name a call gets as a field when the Map item does not name it: the call with its
+/// spacing normalized.
+pub fn call_name(text: &str) -> String {
+    match parse_call(text) {
+        Some((name, args)) => format!("{}({})", name, args.join(", ")),
+        None => text.trim().to_string(),
+    }
+}
+
+/// Port range of a port: `well_known` below 1024, `registered` up to 49151 and
+/// `ephemeral` above.
+pub fn port_range(port: u16) -> &'static str {
+    match port {
+        0..=1023 => "well_known",
+        1024..=49151 => "registered",
+        _ => "ephemeral",
+    }
+}
+
+/// A function call resolved against the record it reads.
+#[derive(Debug)]
+pub enum FieldFunction {
+    /// The address truncated to a prefix.
+    Prefix { slot: usize, mask: u32 },
+    PortRange { slot: usize },
+    /// Power-of-two bucket of an integer: floor(log2(value)), 0 for 0 and 1.
+    Log2 { slot: usize },
+    /// BOBHash of the fields' key encoding, modulo `buckets` when given.
+    Hash { key: Vec<(usize, FieldType)>, buckets: Option<u32> },
+}
+
+impl FieldFunction {
+    /// Resolves the call in `text` against `schema`, with the type of the field it produces.
+    pub fn compile(text: &str, schema: &Schema) -> Result<(FieldFunction, FieldType), String> {
+        let (name, args) = parse_call(text).ok_or_else(|| format!("'{}' is not a function call", text))?;
+        let op = format!("{}()", name);
+        let typed_slot = |arg: &str, allowed: &[FieldType]| {
+            let slot = resolve(schema, arg, &op)?;
+            if !allowed.contains(&schema.field_type(slot)) {
+                return Err(format!("{}: field '{}' has type {:?}", op, arg, schema.field_type(slot)));
+            }
+            Ok(slot)
+        };
+        match (name, args.as_slice()) {
+            ("prefix", [field, len]) => {
+                let slot = typed_slot(field, &[FieldType::Ipv4])?;
+                let len: u8 = len
+                    .parse()
+                    .ok()
+                    .filter(|len| *len <= 32)
+                    .ok_or_else(|| format!("prefix(): '{}' is not a prefix length up to 32", len))?;
+                Ok((FieldFunction::Prefix { slot, mask: prefix_mask(len) }, FieldType::Ipv4))
+            }
+            ("port_range", [field]) => {
+                let slot = typed_slot(field, &[FieldType::U16])?;
+                Ok((FieldFunction::PortRange { slot }, FieldType::String))
+            }
+            ("log2", [field]) => {
+                let slot = typed_slot(field, &[FieldType::U8, FieldType::U16, FieldType::U32, FieldType::U64])?;
+                Ok((FieldFunction::Log2 { slot }, FieldType::U32))
+            }
+            ("hash", [fields @ .., last]) => {
+                // A trailing number is the bucket count rather than a field.
+                let (fields, buckets) = match last.parse::<u32>() {
+                    Ok(0) => return Err("hash(): the number of buckets must be positive".to_string()),
+                    Ok(buckets) => (fields, Some(buckets)),
+                    Err(_) => (args.as_slice(), None),
+                };
+                if fields.is_empty() {
+                    return Err("hash() needs at least one field".to_string());
+                }
+                let fields: Vec<String> = fields.iter().map(|field| field.to_string()).collect();
+                Ok((FieldFunction::Hash { key: resolve_key(schema, &fields, &op)?, buckets }, FieldType::U32))
+            }
+            ("prefix", _) => Err("prefix() takes an address field and a prefix length".to_string()),
+            ("port_range", _) | ("log2", _) => Err(format!("{} takes one field", op)),
+            ("hash", _) => Err("hash() takes one or more fields and optionally a number of buckets".to_string()),
+            _ => Err(format!("unknown function '{}'; expected prefix, port_range, log2 or hash", name)),
+        }
+    }
+
+    pub fn evaluate(&self, record: &Record) -> Option<PacketField> {
+        match self {
+            FieldFunction::Prefix { slot, mask } => match record.get(*slot)? {
+                PacketField::Ipv4(address) => Some(PacketField::Ipv4(Ipv4Addr::from(u32::from(*address) & mask))),
+                _ => None,
+            },
+            FieldFunction::PortRange { slot } => match record.get(*slot)? {
+                PacketField::U16(port) => Some(PacketField::String(port_range(*port).to_string())),
+                _ => None,
+            },
+            FieldFunction::Log2 { slot } => {
+                let value = record.get(*slot)?.as_u64()?;
+                Some(PacketField::U32(value.max(1).ilog2()))
+            }
+            FieldFunction::Hash { key, buckets } => {
+                let hash = BOBHash32::new(HASH_PRIME).run(encode_key(key, record).as_bytes());
+                Some(PacketField::U32(buckets.map_or(hash, |buckets| hash % buckets)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet() -> Record {
+        let mut record = Record::default();
+        record.set(0, PacketField::Ipv4(Ipv4Addr::new(10, 1, 2, 3)));
+        record.set(1, PacketField::Ipv4(Ipv4Addr::new(192, 168, 7, 9)));
+        record.set(2, PacketField::U16(1023));
+        record.set(3, PacketField::U16(49152));
+        record.set(5, PacketField::U32(0));
+        record
+    }
+
+    fn evaluate(text: &str, record: &Record) -> Result<Option<PacketField>, String> {
+        let (function, _) = FieldFunction::compile(text, &Schema::packet())?;
+        Ok(function.evaluate(record))
+    }
+
+    #[test]
+    fn calls_are_parsed_with_any_spacing() {
+        assert_eq!(parse_call("prefix(src_ip,24)"), Some(("prefix", vec!["src_ip", "24"])));
+        assert_eq!(parse_call("  prefix ( src_ip ,  24 ) "), Some(("prefix", vec!["src_ip", "24"])));
+        assert_eq!(parse_call("log2()"), Some(("log2", vec![])));
+        assert_eq!(call_name(" hash( src_ip,dst_ip , 16 )"), "hash(src_ip, dst_ip, 16)");
+        assert_eq!(call_name(" dst_ip "), "dst_ip");
+    }
+
+    #[test]
+    fn invalid_calls_are_rejected() {
+        for text in ["dst_ip", "(src_ip)", "prefix(src_ip, 24", "src-ip(x)", "a b(x)", "prefix(src_ip, 24) + 1"] {
+            assert_eq!(parse_call(text), None, "{}", text);
+        }
+        // Calls do not nest: the inner call is taken as field names.
+        assert!(FieldFunction::compile("hash(prefix(src_ip, 24))", &Schema::packet()).is_err());
+        let error = FieldFunction::compile("sqrt(total_len)", &Schema::packet()).unwrap_err();
+        assert!(error.contains("unknown function 'sqrt'"), "{}", error);
+    }
+
+    #[test]
+    fn prefix_lengths_range_from_0_to_32() {
+        let record = packet();
+        assert_eq!(evaluate("prefix(src_ip, 0)", &record), Ok(Some(PacketField::Ipv4(Ipv4Addr::UNSPECIFIED))));
+        assert_eq!(evaluate("prefix(src_ip, 24)", &record), Ok(Some(PacketField::Ipv4(Ipv4Addr::new(10, 1, 2, 0)))));
+        assert_eq!(evaluate("prefix(src_ip, 32)", &record), Ok(Some(PacketField::Ipv4(Ipv4Addr::new(10, 1, 2, 3)))));
+        assert!(evaluate("prefix(src_ip, 33)", &record).is_err());
+        assert!(evaluate("prefix(src_port, 8)", &record).is_err());
+    }
+
+    #[test]
+    fn port_ranges_split_at_1024_and_49152() {
+        assert_eq!(port_range(1023), "well_known");
+        assert_eq!(port_range(1024), "registered");
+        assert_eq!(port_range(49151), "registered");
+        assert_eq!(port_range(49152), "ephemeral");
+
+        let record = packet();
+        assert_eq!(evaluate("port_range(src_port)", &record), Ok(Some(PacketField::String("well_known".to_string()))));
+        assert_eq!(evaluate("port_range(dst_port)", &record), Ok(Some(PacketField::String("ephemeral".to_string()))));
+    }
+
+    #[test]
+    fn log2_of_0_and_1_is_0() {
+        let mut record = packet();
+        for (value, log2) in [(0, 0), (1, 0), (2, 1), (3, 1), (1500, 10), (u32::MAX, 31)] {
+            record.set(5, PacketField::U32(value));
+            assert_eq!(evaluate("log2(total_len)", &record), Ok(Some(PacketField::U32(log2))), "{}", value);
+        }
+    }
+
+    #[test]
+    fn hash_takes_an_optional_bucket_count() {
+        let record = packet();
+        let full = match evaluate("hash(src_ip, dst_ip)", &record) {
+            Ok(Some(PacketField::U32(hash))) => hash,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(evaluate("hash(src_ip, dst_ip, 16)", &record), Ok(Some(PacketField::U32(full % 16))));
+        assert_ne!(evaluate("hash(dst_ip, src_ip)", &record), Ok(Some(PacketField::U32(full))));
+
+        assert!(evaluate("hash(src_ip, 0)", &record).is_err());
+        assert!(evaluate("hash(16)", &record).is_err());
+        assert!(evaluate("hash()", &record).is_err());
+    }
+}
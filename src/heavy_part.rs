@@ -1,7 +1,16 @@
 use crate::param::*;
 use std::mem::size_of;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+
+// _MM_SHUFFLE(0, 0, 3, 2) and _MM_SHUFFLE(0, 0, 0, 1); the macro is not stable as a const fn.
+#[cfg(target_arch = "x86_64")]
+const SHUFFLE_HIGH_HALF: i32 = 0b00_00_11_10;
+#[cfg(target_arch = "x86_64")]
+const SHUFFLE_SECOND_LANE: i32 = 0b00_00_00_01;
 
 #[repr(align(64))]
+#[derive(Debug, PartialEq)]
 pub struct Bucket {
     key: [u32; COUNTER_PER_BUCKET],
     val: [u32; COUNTER_PER_BUCKET],
@@ -9,12 +18,26 @@ pub struct Bucket {
 
 pub struct HeavyPart<const BUCKET_NUM: usize> {
     buckets: [Bucket; BUCKET_NUM],
+    // Whether `probe` uses AVX2, detected once when the part is built.
+    use_avx2: bool,
+}
+
+fn avx2_available() -> bool {
+    #[cfg(target_arch = "x86_64")]
+    {
+        is_x86_feature_detected!("avx2")
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    {
+        false
+    }
 }
 
 impl<const BUCKET_NUM: usize> HeavyPart<BUCKET_NUM> {
     pub fn new() -> Self {
         Self {
             buckets: unsafe { std::mem::zeroed() },
+            use_avx2: avx2_available(),
         }
     }
 
@@ -29,38 +52,13 @@ impl<const BUCKET_NUM: usize> HeavyPart<BUCKET_NUM> {
         let fp = u32::from_ne_bytes(key.try_into().unwrap());
         let pos = calculate_bucket_pos(fp) % BUCKET_NUM;
 
-        let item = std::arch::x86_64::_mm256_set1_epi32(fp as i32);
-        let keys_p = unsafe { &*(self.buckets[pos].key.as_ptr() as *const __m256i) };
-        let matched = unsafe { std::arch::x86_64::_mm256_cmpeq_epi32(item, *keys_p) };
-        let matched_mask = unsafe { std::arch::x86_64::_mm256_movemask_ps(std::mem::transmute(matched)) };
+        let (matched, min_counter_val, min_counter) = self.probe(pos, fp);
 
-        if matched_mask != 0 {
-            let matched_index = matched_mask.trailing_zeros() as usize;
+        if let Some(matched_index) = matched {
             self.buckets[pos].val[matched_index] += f;
             return 0;
         }
 
-        let mask_base = 0x7FFFFFFF;
-        let counters = unsafe { &*(self.buckets[pos].val.as_ptr() as *const __m256i) };
-        let masks = unsafe { std::arch::x86_64::_mm256_set1_epi32(mask_base as i32) };
-        let results = unsafe { std::arch::x86_64::_mm256_and_ps(std::mem::transmute(*counters), std::mem::transmute(masks)) };
-        let mask2 = unsafe { std::arch::x86_64::_mm256_set_epi32(mask_base as i32, 0, 0, 0, 0, 0, 0, 0) };
-        let results = unsafe { std::arch::x86_64::_mm256_or_ps(results, std::mem::transmute(mask2)) };
-
-        let low_part = unsafe { std::arch::x86_64::_mm256_extractf128_ps(results, 0) };
-        let high_part = unsafe { std::arch::x86_64::_mm256_extractf128_ps(results, 1) };
-        let x = unsafe { std::arch::x86_64::_mm_min_epi32(std::mem::transmute(low_part), std::mem::transmute(high_part)) };
-        let min1 = unsafe { std::arch::x86_64::_mm_shuffle_epi32(x, std::arch::x86_64::_MM_SHUFFLE(0, 0, 3, 2)) };
-        let min2 = unsafe { std::arch::x86_64::_mm_min_epi32(x, min1) };
-        let min3 = unsafe { std::arch::x86_64::_mm_shuffle_epi32(min2, std::arch::x86_64::_MM_SHUFFLE(0, 0, 0, 1)) };
-        let min4 = unsafe { std::arch::x86_64::_mm_min_epi32(min2, min3) };
-        let min_counter_val = unsafe { std::arch::x86_64::_mm_cvtsi128_si32(min4) };
-
-        let ct_item = unsafe { std::arch::x86_64::_mm256_set1_epi32(min_counter_val) };
-        let ct_matched = unsafe { std::arch::x86_64::_mm256_cmpeq_epi32(ct_item, std::mem::transmute(results)) };
-        let matched = unsafe { std::arch::x86_64::_mm256_movemask_ps(std::mem::transmute(ct_matched)) };
-        let min_counter = matched.trailing_zeros() as usize;
-
         if min_counter_val == 0 {
             self.buckets[pos].key[min_counter] = fp;
             self.buckets[pos].val[min_counter] = f;
@@ -85,59 +83,73 @@ impl<const BUCKET_NUM: usize> HeavyPart<BUCKET_NUM> {
         return 1;
     }
 
-    pub fn quick_insert(&mut self, key: &[u8], f: u32) -> i32 {
-        let fp = u32::from_ne_bytes(key.try_into().unwrap());
-        let pos = calculate_bucket_pos(fp) % BUCKET_NUM;
-
-        let item = std::arch::x86_64::_mm256_set1_epi32(fp as i32);
-        let keys_p = unsafe { &*(self.buckets[pos].key.as_ptr() as *const __m256i) };
-        let matched = unsafe { std::arch::x86_64::_mm256_cmpeq_epi32(item, *keys_p) };
-        let matched_mask = unsafe { std::arch::x86_64::_mm256_movemask_ps(std::mem::transmute(matched)) };
-
-        if matched_mask != 0 {
-            let matched_index = matched_mask.trailing_zeros() as usize;
-            self.buckets[pos].val[matched_index] += f;
-            return 0;
+    /// Looks up `fp` in bucket `pos` and finds its smallest counter (ignoring the flag bit
+    /// and the guard slot). Returns (matching slot, smallest counter value, its slot).
+    fn probe(&self, pos: usize, fp: u32) -> (Option<usize>, u32, usize) {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if self.use_avx2 {
+                return unsafe { self.probe_avx2(pos, fp) };
+            }
         }
+        self.probe_scalar(pos, fp)
+    }
 
-        let mask_base = 0x7FFFFFFF;
-        let counters = unsafe { &*(self.buckets[pos].val.as_ptr() as *const __m256i) };
-        let masks = unsafe { std::arch::x86_64::_mm256_set1_epi32(mask_base as i32) };
-        let results = unsafe { std::arch::x86_64::_mm256_and_ps(std::mem::transmute(*counters), std::mem::transmute(masks)) };
-        let mask2 = unsafe { std::arch::x86_64::_mm256_set_epi32(mask_base as i32, 0, 0, 0, 0, 0, 0, 0) };
-        let results = unsafe { std::arch::x86_64::_mm256_or_ps(results, std::mem::transmute(mask2)) };
-
-        let low_part = unsafe { std::arch::x86_64::_mm256_extractf128_ps(results, 0) };
-        let high_part = unsafe { std::arch::x86_64::_mm256_extractf128_ps(results, 1) };
-        let x = unsafe { std::arch::x86_64::_mm_min_epi32(std::mem::transmute(low_part), std::mem::transmute(high_part)) };
-        let min1 = unsafe { std::arch::x86_64::_mm_shuffle_epi32(x, std::arch::x86_64::_MM_SHUFFLE(0, 0, 3, 2)) };
-        let min2 = unsafe { std::arch::x86_64::_mm_min_epi32(x, min1) };
-        let min3 = unsafe { std::arch::x86_64::_mm_shuffle_epi32(min2, std::arch::x86_64::_MM_SHUFFLE(0, 0, 0, 1)) };
-        let min4 = unsafe { std::arch::x86_64::_mm_min_epi32(min2, min3) };
-        let min_counter_val = unsafe { std::arch::x86_64::_mm_cvtsi128_si32(min4) };
-
-        let ct_item = unsafe { std::arch::x86_64::_mm256_set1_epi32(min_counter_val) };
-        let ct_matched = unsafe { std::arch::x86_64::_mm256_cmpeq_epi32(ct_item, std::mem::transmute(results)) };
-        let matched = unsafe { std::arch::x86_64::_mm256_movemask_ps(std::mem::transmute(ct_matched)) };
-        let min_counter = matched.trailing_zeros() as usize;
-
-        if min_counter_val == 0 {
-            self.buckets[pos].key[min_counter] = fp;
-            self.buckets[pos].val[min_counter] = f;
-            return 0;
+    pub fn probe_scalar(&self, pos: usize, fp: u32) -> (Option<usize>, u32, usize) {
+        let bucket = &self.buckets[pos];
+        let matched = bucket.key.iter().position(|&k| k == fp);
+
+        let mut min_counter_val = u32::MAX;
+        let mut min_counter = 0;
+        for i in 0..COUNTER_PER_BUCKET {
+            let val = if i == MAX_VALID_COUNTER { 0x7FFFFFFF } else { get_counter_val(bucket.val[i]) };
+            if val < min_counter_val {
+                min_counter_val = val;
+                min_counter = i;
+            }
         }
+        (matched, min_counter_val, min_counter)
+    }
 
-        let guard_val = self.buckets[pos].val[MAX_VALID_COUNTER];
-        let guard_val = update_guard_val(guard_val);
+    /// # Safety
+    /// The CPU must support AVX2.
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn probe_avx2(&self, pos: usize, fp: u32) -> (Option<usize>, u32, usize) {
+        let bucket = &self.buckets[pos];
+
+        let item = _mm256_set1_epi32(fp as i32);
+        let keys_p = _mm256_load_si256(bucket.key.as_ptr() as *const __m256i);
+        let matched = _mm256_cmpeq_epi32(item, keys_p);
+        let matched_mask = _mm256_movemask_ps(_mm256_castsi256_ps(matched));
+        let matched_index = if matched_mask != 0 {
+            Some(matched_mask.trailing_zeros() as usize)
+        } else {
+            None
+        };
 
-        if !judge_if_swap(min_counter_val, guard_val) {
-            self.buckets[pos].val[MAX_VALID_COUNTER] = guard_val;
-            return 2;
-        }
+        let mask_base = 0x7FFFFFFF;
+        let counters = _mm256_load_si256(bucket.val.as_ptr() as *const __m256i);
+        let masks = _mm256_set1_epi32(mask_base);
+        let results = _mm256_and_si256(counters, masks);
+        let mask2 = _mm256_set_epi32(mask_base, 0, 0, 0, 0, 0, 0, 0);
+        let results = _mm256_or_si256(results, mask2);
+
+        let low_part = _mm256_extracti128_si256(results, 0);
+        let high_part = _mm256_extracti128_si256(results, 1);
+        let x = _mm_min_epi32(low_part, high_part);
+        let min1 = _mm_shuffle_epi32(x, SHUFFLE_HIGH_HALF);
+        let min2 = _mm_min_epi32(x, min1);
+        let min3 = _mm_shuffle_epi32(min2, SHUFFLE_SECOND_LANE);
+        let min4 = _mm_min_epi32(min2, min3);
+        let min_counter_val = _mm_cvtsi128_si32(min4);
+
+        let ct_item = _mm256_set1_epi32(min_counter_val);
+        let ct_matched = _mm256_cmpeq_epi32(ct_item, results);
+        let matched = _mm256_movemask_ps(_mm256_castsi256_ps(ct_matched));
+        let min_counter = matched.trailing_zeros() as usize;
 
-        self.buckets[pos].val[MAX_VALID_COUNTER] = 0;
-        self.buckets[pos].key[min_counter] = fp;
-        return 1;
+        (matched_index, min_counter_val as u32, min_counter)
     }
 
     pub fn query(&self, key: &[u8]) -> u32 {
@@ -159,4 +171,73 @@ impl<const BUCKET_NUM: usize> HeavyPart<BUCKET_NUM> {
     pub fn get_bucket_num(&self) -> usize {
         BUCKET_NUM
     }
-}
\ No newline at end of file
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn pair<const BUCKET_NUM: usize>() -> Option<(HeavyPart<BUCKET_NUM>, HeavyPart<BUCKET_NUM>)> {
+        if !avx2_available() {
+            eprintln!("AVX2 unavailable; skipping the differential test");
+            return None;
+        }
+        let simd = HeavyPart::new();
+        let mut scalar = HeavyPart::new();
+        scalar.use_avx2 = false;
+        Some((simd, scalar))
+    }
+
+    // Skewed keys over few buckets, so that matches, empty slots, votes and evictions all occur.
+    fn workload(seed: u64, length: usize) -> Vec<(u32, u32)> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        (0..length)
+            .map(|_| {
+                let rank = (1.0 / rng.gen_range(0.001f64..1.0)) as u32;
+                (rank.wrapping_mul(0x9E37_79B1), rng.gen_range(1..=4))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn probes_agree_on_random_buckets() {
+        let Some((mut part, _)) = pair::<64>() else { return };
+        let mut rng = StdRng::seed_from_u64(1);
+        for bucket in &mut part.buckets {
+            for i in 0..COUNTER_PER_BUCKET {
+                // Few distinct keys and values, with flag bits, so ties and matches occur.
+                bucket.key[i] = rng.gen_range(0..6);
+                bucket.val[i] = rng.gen_range(0..4) | if rng.gen_bool(0.3) { 0x80000000 } else { 0 };
+            }
+        }
+        for pos in 0..part.get_bucket_num() {
+            for fp in 0..8 {
+                assert_eq!(unsafe { part.probe_avx2(pos, fp) }, part.probe_scalar(pos, fp), "bucket {} key {}", pos, fp);
+            }
+        }
+    }
+
+    #[test]
+    fn insert_paths_produce_identical_buckets() {
+        for seed in 0..8 {
+            let Some((mut simd, mut scalar)) = pair::<16>() else { return };
+            for (step, (fp, f)) in workload(seed, 20_000).into_iter().enumerate() {
+                let key = fp.to_ne_bytes();
+                let (mut simd_swap, mut scalar_swap) = ([0u8; 4], [0u8; 4]);
+                let (mut simd_val, mut scalar_val) = (0, 0);
+                let simd_result = simd.insert(&key, &mut simd_swap, &mut simd_val, f);
+                let scalar_result = scalar.insert(&key, &mut scalar_swap, &mut scalar_val, f);
+                assert_eq!(
+                    (simd_result, simd_swap, simd_val),
+                    (scalar_result, scalar_swap, scalar_val),
+                    "seed {} step {}",
+                    seed,
+                    step
+                );
+            }
+            assert_eq!(simd.buckets, scalar.buckets, "seed {}", seed);
+        }
+    }
+}
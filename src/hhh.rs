@@ -0,0 +1,330 @@
+// hhh.rs
+// Hierarchical heavy hitters over the prefix lattice of one or two IPv4 addresses. Each
+// node of the lattice (a prefix length per address) counts the truncated addresses in a
+// sketch of its own; as in RHHH (Ben Basat et al., SIGCOMM 2017), a randomized lattice
+// updates a single random node per packet and scales its estimates by the node count.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::net::Ipv4Addr;
+use serde::{Deserialize, Serialize};
+use crate::query_executor::prefix_mask;
+use crate::sketch::Sketch;
+
+/// A lattice node reported as a hierarchical heavy hitter: a prefix per address of the
+/// key, its estimated count, and what is left of it once the heavy hitters below it are
+/// discounted.
+#[derive(Clone, Debug)]
+pub struct HeavyPrefix {
+    pub prefixes: Vec<(Ipv4Addr, u8)>,
+    pub estimate: u64,
+    pub discounted: u64,
+}
+
+impl fmt::Display for HeavyPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let prefixes: Vec<String> = self.prefixes.iter().map(|(network, len)| format!("{}/{}", network, len)).collect();
+        write!(f, "{}", prefixes.join(", "))
+    }
+}
+
+// A prefix per dimension; the second is unused in one dimension.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct Node {
+    lens: [u8; 2],
+    networks: [u32; 2],
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HHHSketch {
+    dimensions: usize,
+    prefix_step: u8,
+    // One sketch per node, indexed by the prefix length of each dimension in steps.
+    nodes: Vec<Sketch>,
+    randomized: bool,
+    rng_state: u64,
+}
+
+impl HHHSketch {
+    /// A lattice over `dimensions` addresses whose prefixes grow by `prefix_step` bits,
+    /// which must divide 32, with a sketch from `new_node` at every node.
+    pub fn new(dimensions: usize, prefix_step: u8, randomized: bool, new_node: impl Fn() -> Sketch) -> Self {
+        let levels = 32 / prefix_step as usize + 1;
+        Self {
+            dimensions,
+            prefix_step,
+            nodes: (0..levels.pow(dimensions as u32)).map(|_| new_node()).collect(),
+            randomized,
+            rng_state: 0x9E37_79B9_7F4A_7C15,
+        }
+    }
+
+    fn levels(&self) -> usize {
+        32 / self.prefix_step as usize + 1
+    }
+
+    fn node_index(&self, node: &Node) -> usize {
+        node.lens[..self.dimensions]
+            .iter()
+            .fold(0, |index, len| index * self.levels() + (len / self.prefix_step) as usize)
+    }
+
+    fn node_at(&self, index: usize, addresses: [u32; 2]) -> Node {
+        let mut node = Node { lens: [0; 2], networks: [0; 2] };
+        let mut rest = index;
+        for dimension in (0..self.dimensions).rev() {
+            let len = (rest % self.levels()) as u8 * self.prefix_step;
+            rest /= self.levels();
+            node.lens[dimension] = len;
+            node.networks[dimension] = addresses[dimension] & prefix_mask(len);
+        }
+        node
+    }
+
+    fn node_key(&self, node: &Node) -> Vec<u8> {
+        node.networks[..self.dimensions].iter().flat_map(|network| network.to_be_bytes()).collect()
+    }
+
+    // `item` holds the addresses as `encode_key` writes them, 4 bytes each.
+    fn addresses(&self, item: &[u8]) -> [u32; 2] {
+        let mut addresses = [0; 2];
+        for (address, bytes) in addresses.iter_mut().zip(item.chunks_exact(4)).take(self.dimensions) {
+            *address = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        addresses
+    }
+
+    // xorshift64*, kept in the sketch so a checkpointed run resumes the same sequence.
+    fn next_random(&mut self) -> u64 {
+        self.rng_state ^= self.rng_state >> 12;
+        self.rng_state ^= self.rng_state << 25;
+        self.rng_state ^= self.rng_state >> 27;
+        self.rng_state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    pub fn insert(&mut self, item: &[u8], count: u64) {
+        let addresses = self.addresses(item);
+        if self.randomized {
+            let index = (self.next_random() % self.nodes.len() as u64) as usize;
+            let key = self.node_key(&self.node_at(index, addresses));
+            self.nodes[index].increment(&key, count);
+        } else {
+            for index in 0..self.nodes.len() {
+                let key = self.node_key(&self.node_at(index, addresses));
+                self.nodes[index].increment(&key, count);
+            }
+        }
+    }
+
+    fn estimate(&self, node: &Node) -> u64 {
+        let estimate = self.nodes[self.node_index(node)].estimate(&self.node_key(node));
+        if self.randomized {
+            estimate.saturating_mul(self.nodes.len() as u64)
+        } else {
+            estimate
+        }
+    }
+
+    /// Estimate for the full addresses in `item`.
+    pub fn query(&self, item: &[u8]) -> u64 {
+        self.estimate(&self.node_at(self.nodes.len() - 1, self.addresses(item)))
+    }
+
+    // Whether `node` lies within `ancestor` in every dimension.
+    fn within(&self, node: &Node, ancestor: &Node) -> bool {
+        (0..self.dimensions).all(|d| {
+            node.lens[d] >= ancestor.lens[d] && node.networks[d] & prefix_mask(ancestor.lens[d]) == ancestor.networks[d]
+        })
+    }
+
+    // Most general node within both, if the two overlap.
+    fn greatest_lower_bound(&self, a: &Node, b: &Node) -> Option<Node> {
+        let mut node = *a;
+        for d in 0..self.dimensions {
+            let (longer, shorter) = if a.lens[d] >= b.lens[d] { (a, b) } else { (b, a) };
+            if longer.networks[d] & prefix_mask(shorter.lens[d]) != shorter.networks[d] {
+                return None;
+            }
+            node.lens[d] = longer.lens[d];
+            node.networks[d] = longer.networks[d];
+        }
+        Some(node)
+    }
+
+    /// Nodes whose discounted count reaches `threshold`, most general first. Candidates
+    /// are found top-down, expanding only nodes whose estimate reaches the threshold.
+    /// Going bottom-up, a node's count is discounted by the closest heavy hitters below
+    /// it, adding back their pairwise overlaps in two dimensions.
+    pub fn heavy_prefixes(&self, threshold: u64) -> Vec<HeavyPrefix> {
+        let root = Node { lens: [0; 2], networks: [0; 2] };
+        let mut candidates = Vec::new();
+        let mut frontier = vec![root];
+        let mut seen = HashSet::from([root]);
+        while let Some(node) = frontier.pop() {
+            let estimate = self.estimate(&node);
+            if estimate < threshold.max(1) {
+                continue;
+            }
+            candidates.push((node, estimate));
+            for d in 0..self.dimensions {
+                if node.lens[d] == 32 {
+                    continue;
+                }
+                let len = node.lens[d] + self.prefix_step;
+                for suffix in 0..1u32 << self.prefix_step {
+                    let mut child = node;
+                    child.lens[d] = len;
+                    child.networks[d] |= suffix << (32 - len as u32);
+                    if seen.insert(child) {
+                        frontier.push(child);
+                    }
+                }
+            }
+        }
+
+        // A node's descendants all have more prefix bits in total.
+        let specificity = |node: &Node| node.lens[..self.dimensions].iter().map(|len| *len as u32).sum::<u32>();
+        candidates.sort_by_key(|(node, _)| std::cmp::Reverse(specificity(node)));
+        let mut heavy: Vec<(Node, u64, u64)> = Vec::new();
+        for (node, estimate) in candidates {
+            let below: Vec<&Node> = heavy
+                .iter()
+                .map(|(other, _, _)| other)
+                .filter(|other| self.within(other, &node))
+                .collect();
+            let closest: Vec<&Node> = below
+                .iter()
+                .filter(|other| !below.iter().any(|between| between != *other && self.within(other, between)))
+                .copied()
+                .collect();
+            let mut discounted = estimate as i128;
+            for (i, a) in closest.iter().enumerate() {
+                discounted -= self.estimate(a) as i128;
+                for b in &closest[i + 1..] {
+                    if let Some(overlap) = self.greatest_lower_bound(a, b) {
+                        discounted += self.estimate(&overlap) as i128;
+                    }
+                }
+            }
+            if discounted >= threshold as i128 {
+                heavy.push((node, estimate, discounted as u64));
+            }
+        }
+
+        heavy.sort_by_key(|(node, _, _)| (specificity(node), node.networks, node.lens));
+        heavy
+            .into_iter()
+            .map(|(node, estimate, discounted)| HeavyPrefix {
+                prefixes: (0..self.dimensions).map(|d| (Ipv4Addr::from(node.networks[d]), node.lens[d])).collect(),
+                estimate,
+                discounted,
+            })
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.iter().all(Sketch::is_empty)
+    }
+
+    pub fn clear(&mut self) {
+        self.nodes.iter_mut().for_each(Sketch::clear);
+    }
+
+    pub fn get_memory_usage(&self) -> usize {
+        self.nodes.iter().map(Sketch::get_memory_usage).sum()
+    }
+
+    /// Merges the lattices node by node.
+    pub fn merge(&mut self, other: &HHHSketch) -> Result<(), String> {
+        if self.dimensions != other.dimensions
+            || self.prefix_step != other.prefix_step
+            || self.randomized != other.randomized
+            || self.nodes.len() != other.nodes.len()
+        {
+            return Err(format!(
+                "HHH lattices differ: {}-D in /{} steps vs {}-D in /{} steps",
+                self.dimensions, self.prefix_step, other.dimensions, other.prefix_step
+            ));
+        }
+        for (node, other_node) in self.nodes.iter_mut().zip(&other.nodes) {
+            node.merge(other_node)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insert(sketch: &mut HHHSketch, addresses: &[Ipv4Addr], count: u64) {
+        let item: Vec<u8> = addresses.iter().flat_map(|address| address.octets()).collect();
+        sketch.insert(&item, count);
+    }
+
+    fn heavy(sketch: &HHHSketch, threshold: u64) -> Vec<(String, u64, u64)> {
+        sketch
+            .heavy_prefixes(threshold)
+            .into_iter()
+            .map(|heavy| (heavy.to_string(), heavy.estimate, heavy.discounted))
+            .collect()
+    }
+
+    #[test]
+    fn prefixes_are_discounted_by_the_closest_heavy_hitters_below() {
+        let mut sketch = HHHSketch::new(1, 8, false, Sketch::new_deterministic_sketch);
+        // One heavy host, and a /24 that is heavy only once its other hosts are added up.
+        insert(&mut sketch, &[Ipv4Addr::new(10, 0, 0, 1)], 150);
+        for host in 2..62 {
+            insert(&mut sketch, &[Ipv4Addr::new(10, 0, 0, host)], 2);
+        }
+        for host in 0..30 {
+            insert(&mut sketch, &[Ipv4Addr::new(20, 0, host, 1)], 1);
+        }
+
+        // Above the /24, nothing is left once it is discounted: 270 - 270 under 10.0.0.0/16
+        // and 300 - 270 at the root.
+        assert_eq!(
+            heavy(&sketch, 100),
+            vec![("10.0.0.0/24".to_string(), 270, 120), ("10.0.0.1/32".to_string(), 150, 150)]
+        );
+        // At 30, 20.0.0.0/16 is heavy too, and the root is discounted by both it and the /24.
+        assert_eq!(
+            heavy(&sketch, 30),
+            vec![
+                ("20.0.0.0/16".to_string(), 30, 30),
+                ("10.0.0.0/24".to_string(), 270, 120),
+                ("10.0.0.1/32".to_string(), 150, 150),
+            ]
+        );
+    }
+
+    #[test]
+    fn overlaps_of_two_dimensional_hitters_are_added_back() {
+        let mut sketch = HHHSketch::new(2, 8, false, Sketch::new_deterministic_sketch);
+        // 1.1.1.0/24 to anywhere and anything to 2.2.2.0/24, 170 packets each, 50 of them
+        // from 1.1.1.0/24 to 2.2.2.0/24.
+        for i in 0..120u8 {
+            insert(&mut sketch, &[Ipv4Addr::new(1, 1, 1, i % 60 + 1), Ipv4Addr::new(i + 10, 0, 0, 1)], 1);
+            insert(&mut sketch, &[Ipv4Addr::new(i + 10, 0, 0, 1), Ipv4Addr::new(2, 2, 2, i % 60 + 1)], 1);
+        }
+        for i in 0..50u8 {
+            insert(&mut sketch, &[Ipv4Addr::new(1, 1, 1, i + 100), Ipv4Addr::new(2, 2, 2, i + 100)], 1);
+        }
+        // Background traffic only the root adds up.
+        for i in 0..150u8 {
+            insert(&mut sketch, &[Ipv4Addr::new(i % 50 + 200, i, 0, 1), Ipv4Addr::new(i % 50 + 140, i, 0, 1)], 1);
+        }
+
+        // The root keeps 440 - 170 - 170 + 50: the overlap is subtracted twice, so it is
+        // added back once.
+        assert_eq!(
+            heavy(&sketch, 100),
+            vec![
+                ("0.0.0.0/0, 0.0.0.0/0".to_string(), 440, 150),
+                ("0.0.0.0/0, 2.2.2.0/24".to_string(), 170, 170),
+                ("1.1.1.0/24, 0.0.0.0/0".to_string(), 170, 170),
+            ]
+        );
+    }
+}
@@ -1,32 +1,56 @@
 use crate::bobhash32::BOBHash32;
-use crate::param::*;
-use std::mem::size_of;
-
-pub struct LightPart<const INIT_MEM_IN_BYTES: usize> {
-    counters: [u8; INIT_MEM_IN_BYTES],
-    mice_dist: [i32; 256],
+use crate::fcm_em::FlowSizeEstimate;
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct LightPart {
+    counters: Vec<u8>,
+    mice_dist: Vec<i32>,
     bobhash: BOBHash32,
 }
 
-impl<const INIT_MEM_IN_BYTES: usize> LightPart<INIT_MEM_IN_BYTES> {
-    pub fn new() -> Self {
-        let mut rng = rand::thread_rng();
-        let bobhash = BOBHash32::new(rng.gen_range(0..MAX_PRIME32 as u32));
+impl LightPart {
+    pub fn new(memory_in_bytes: usize, seed: u64) -> Self {
+        let mut mice_dist = vec![0; 256];
+        mice_dist[0] = memory_in_bytes as i32;
         Self {
-            counters: [0; INIT_MEM_IN_BYTES],
-            mice_dist: [0; 256],
-            bobhash,
+            counters: vec![0; memory_in_bytes],
+            mice_dist,
+            bobhash: BOBHash32::new(seed as u32),
         }
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.counters.iter().all(|&counter| counter == 0)
+    }
+
     pub fn clear(&mut self) {
         self.counters.fill(0);
         self.mice_dist.fill(0);
+        self.mice_dist[0] = self.counters.len() as i32;
+    }
+
+    /// Adds another light part counter by counter, saturating at 255 like `insert`.
+    pub fn merge(&mut self, other: &LightPart) -> Result<(), String> {
+        if self.counters.len() != other.counters.len() || self.bobhash != other.bobhash {
+            return Err(format!(
+                "LightPart parameters differ: {} vs {} counters",
+                self.counters.len(),
+                other.counters.len()
+            ));
+        }
+        self.mice_dist.fill(0);
+        for (counter, other_counter) in self.counters.iter_mut().zip(&other.counters) {
+            *counter = counter.saturating_add(*other_counter);
+            self.mice_dist[*counter as usize] += 1;
+        }
+        Ok(())
     }
 
     pub fn insert(&mut self, key: &[u8], f: i32) {
         let hash_val = self.bobhash.run(key);
-        let pos = (hash_val % INIT_MEM_IN_BYTES as u32) as usize;
+        let pos = (hash_val % self.counters.len() as u32) as usize;
 
         let old_val = self.counters[pos] as i32;
         let new_val = (self.counters[pos] as i32 + f).min(255);
@@ -38,7 +62,7 @@ impl<const INIT_MEM_IN_BYTES: usize> LightPart<INIT_MEM_IN_BYTES> {
 
     pub fn swap_insert(&mut self, key: &[u8], f: i32) {
         let hash_val = self.bobhash.run(key);
-        let pos = (hash_val % INIT_MEM_IN_BYTES as u32) as usize;
+        let pos = (hash_val % self.counters.len() as u32) as usize;
 
         let f = f.min(255);
         if self.counters[pos] < f as u8 {
@@ -53,15 +77,15 @@ impl<const INIT_MEM_IN_BYTES: usize> LightPart<INIT_MEM_IN_BYTES> {
 
     pub fn query(&self, key: &[u8]) -> i32 {
         let hash_val = self.bobhash.run(key);
-        let pos = (hash_val % INIT_MEM_IN_BYTES as u32) as usize;
+        let pos = (hash_val % self.counters.len() as u32) as usize;
         self.counters[pos] as i32
     }
 
     pub fn compress(&self, ratio: usize, dst: &mut [u8]) {
         let width = self.get_compress_width(ratio);
-        for i in 0..width.min(INIT_MEM_IN_BYTES) {
+        for i in 0..width.min(self.counters.len()) {
             let mut max_val = 0;
-            for j in (i..INIT_MEM_IN_BYTES).step_by(width) {
+            for j in (i..self.counters.len()).step_by(width) {
                 max_val = max_val.max(self.counters[j]);
             }
             dst[i] = max_val;
@@ -70,26 +94,30 @@ impl<const INIT_MEM_IN_BYTES: usize> LightPart<INIT_MEM_IN_BYTES> {
 
     pub fn query_compressed_part(&self, key: &[u8], compress_part: &[u8], compress_counter_num: usize) -> i32 {
         let hash_val = self.bobhash.run(key);
-        let pos = (hash_val % INIT_MEM_IN_BYTES as u32) as usize % compress_counter_num;
+        let pos = (hash_val % self.counters.len() as u32) as usize % compress_counter_num;
         compress_part[pos] as i32
     }
 
     pub fn get_compress_width(&self, ratio: usize) -> usize {
-        INIT_MEM_IN_BYTES / ratio
+        self.counters.len() / ratio
     }
 
     pub fn get_compress_memory(&self, ratio: usize) -> usize {
-        INIT_MEM_IN_BYTES / ratio
+        self.counters.len() / ratio
     }
 
     pub fn get_memory_usage(&self) -> usize {
-        INIT_MEM_IN_BYTES
+        self.counters.len()
     }
 
+    /// Linear counting over the counters. With every counter taken the estimate diverges,
+    /// so it is capped at `width * ln(width)`, the estimate with one counter left empty.
     pub fn get_cardinality(&self) -> i32 {
+        let width = self.counters.len();
         let mice_card = self.mice_dist.iter().skip(1).sum::<i32>();
-        let rate = (INIT_MEM_IN_BYTES as i32 - mice_card) as f64 / INIT_MEM_IN_BYTES as f64;
-        (INIT_MEM_IN_BYTES as f64 * (1.0 / rate).ln()) as i32
+        let empty = (width as i32 - mice_card).max(1);
+        let rate = empty as f64 / width as f64;
+        (width as f64 * (1.0 / rate).ln()) as i32
     }
 
     pub fn get_entropy(&self) -> (i32, f64) {
@@ -109,4 +137,51 @@ impl<const INIT_MEM_IN_BYTES: usize> LightPart<INIT_MEM_IN_BYTES> {
         }
         dist
     }
-}
\ No newline at end of file
+
+    /// Summarizes the counter histogram: linear counting for the cardinality and
+    /// `get_entropy` for the entropy of the flow sizes.
+    pub fn get_flow_size_estimate(&self) -> FlowSizeEstimate {
+        let distribution: BTreeMap<u32, f64> = self
+            .get_distribution()
+            .into_iter()
+            .enumerate()
+            .skip(1)
+            .filter(|(_, flows)| *flows > 0.0)
+            .map(|(size, flows)| (size as u32, flows))
+            .collect();
+
+        let mut estimate = FlowSizeEstimate::from_distribution(distribution);
+        estimate.cardinality = self.get_cardinality() as f64;
+        let (tot, entr) = self.get_entropy();
+        if tot > 0 {
+            estimate.entropy = (tot as f64).log2() - entr / tot as f64;
+        }
+        estimate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cardinality_follows_linear_counting() {
+        let mut light = LightPart::new(1024, 1);
+        assert_eq!(light.get_cardinality(), 0);
+        for key in 0u32..200 {
+            light.insert(&key.to_be_bytes(), 1);
+        }
+        let empty = light.counters.iter().filter(|&&counter| counter == 0).count();
+        assert_eq!(light.get_cardinality(), (1024.0 * (1024.0 / empty as f64).ln()) as i32);
+    }
+
+    #[test]
+    fn cardinality_is_capped_when_every_counter_is_taken() {
+        let mut light = LightPart::new(16, 1);
+        for key in 0u32..10_000 {
+            light.insert(&key.to_be_bytes(), 1);
+        }
+        assert!(light.counters.iter().all(|&counter| counter > 0));
+        assert_eq!(light.get_cardinality(), (16.0 * 16f64.ln()) as i32);
+    }
+}
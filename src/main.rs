@@ -5,51 +5,452 @@ mod queries;
 mod pcap_processor;
 mod cm_sketch;
 mod fcm_sketch;
+mod fcm_em;
+mod flow_key;
+mod record;
+mod bench;
 mod elastic_sketch;
 mod fcm_first_layer_sketch;
 mod deterministic_sketch;
 mod bloom_filter;
+mod light_part;
+mod heavy_part;
+mod param;
 pub mod bobhash32;
 pub mod beaucoup;
 mod config;
-use config::{get_reduce_type_from_env, get_distinct_type_from_env};
+mod sizing;
+mod sketch_store;
+mod checkpoint;
+mod evaluation;
+mod sweep;
+mod output_sink;
+mod metrics_server;
+mod alerting;
+mod config_file;
+mod explain;
+mod plan_file;
+mod dataplane;
+mod p4gen;
+mod refinement;
+mod hhh;
+mod functions;
+mod aggregation;
+use config::{get_reduce_type_from_env, get_distinct_type_from_env, get_statistic_type_from_env, get_num_workers_from_env, get_sketch_dir_from_env, get_checkpoint_path_from_env, get_checkpoint_interval_from_env, get_output_format_from_env, get_output_path_from_env, get_metrics_addr_from_env, get_metrics_top_k_from_env, get_metrics_linger_from_env, get_alert_config_from_env, get_target_model_from_env, get_refinement_field_from_env, get_refinement_levels_from_env};
 
 use std::env;
-use pcap_processor::process_pcap;
-// use queries::{query_1, query_2, query_3, query_4, query_5, query_8, query_8_1, query_11};
-use queries::{query_1, query_2, query_3, query_4, query_5, query_6 ,query_8};
+use clap::{Args, Parser, Subcommand};
+use config_file::ConfigFile;
+use pcap_processor::{process_pcap, RunOptions};
+use query_executor::compile_plan;
+use query_plan::QueryPlan;
+use queries::{query_by_id, QUERIES};
 
+/// Streaming telemetry queries over packet traces, backed by sketches.
+///
+/// Parameters are read, from highest to lowest precedence, from command-line flags
+/// (including --set), environment variables (including .env) and the --config file.
+#[derive(Parser)]
+#[command(name = "test2")]
+struct Cli {
+    /// TOML or YAML file with the source, query, sketch parameters and sinks.
+    #[arg(long, global = true)]
+    config: Option<String>,
+    /// Sets a parameter as its environment variable would, e.g. --set CM_MEMORY=65536.
+    #[arg(long = "set", value_name = "NAME=VALUE", global = true)]
+    set: Vec<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Args)]
+struct SourceArgs {
+    /// PCAP trace to read.
+    #[arg(long)]
+    pcap: Option<String>,
+    /// Epoch length in seconds.
+    #[arg(long)]
+    epoch_size: Option<u64>,
+    /// Threshold for reported keys; also replaces the thresholds of the query's FilterResult and heavy hitter lattices.
+    #[arg(long)]
+    threshold: Option<u64>,
+}
+
+#[derive(Args)]
+struct QueryArgs {
+    /// Built-in query id (see list-queries).
+    #[arg(long, conflicts_with = "plan")]
+    query: Option<u8>,
+    /// JSON or YAML plan file (see export-plan).
+    #[arg(long)]
+    plan: Option<String>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Runs a query over a trace.
+    Run {
+        #[command(flatten)]
+        source: SourceArgs,
+        #[command(flatten)]
+        query: QueryArgs,
+        /// Worker threads (NUM_WORKERS).
+        #[arg(long)]
+        workers: Option<usize>,
+        /// text, csv, jsonl or parquet (OUTPUT_FORMAT).
+        #[arg(long)]
+        output_format: Option<String>,
+        /// Results file (OUTPUT_PATH).
+        #[arg(long)]
+        output: Option<String>,
+        /// Continues from CHECKPOINT_PATH instead of starting over.
+        #[arg(long)]
+        resume: bool,
+    },
+    /// Compares a query's sketches with the exact reduce, epoch by epoch.
+    Eval {
+        #[command(flatten)]
+        source: SourceArgs,
+        #[command(flatten)]
+        query: QueryArgs,
+        #[arg(long, default_value = "accuracy_report.csv")]
+        report: String,
+    },
+    /// Runs a query over IP prefixes refined from epoch to epoch, against the unrefined query.
+    Refine {
+        #[command(flatten)]
+        source: SourceArgs,
+        #[command(flatten)]
+        query: QueryArgs,
+        /// IPv4 field of the result key to refine (REFINEMENT_FIELD).
+        #[arg(long)]
+        field: Option<String>,
+        /// Comma-separated prefix lengths, coarsest first (REFINEMENT_LEVELS).
+        #[arg(long)]
+        levels: Option<String>,
+        #[arg(long, default_value = "refinement_report.csv")]
+        report: String,
+    },
+    /// Evaluates every sketch configuration of a grid file for memory against accuracy.
+    Sweep {
+        #[command(flatten)]
+        source: SourceArgs,
+        /// Comma-separated query ids.
+        #[arg(long, value_delimiter = ',', required = true)]
+        queries: Vec<u8>,
+        #[arg(long)]
+        grid: String,
+        #[arg(long, default_value = "sweep_report.csv")]
+        report: String,
+    },
+    /// Shows a query's operators and how it compiles.
+    Explain {
+        #[command(flatten)]
+        query: QueryArgs,
+        #[arg(long)]
+        threshold: Option<u64>,
+    },
+    /// Splits a query between a programmable switch (DATAPLANE_*) and the stream processor.
+    Partition {
+        #[command(flatten)]
+        query: QueryArgs,
+        #[arg(long)]
+        threshold: Option<u64>,
+    },
+    /// Generates P4_16 (v1model) for the part of a query the switch can run.
+    P4gen {
+        #[command(flatten)]
+        query: QueryArgs,
+        #[arg(long)]
+        threshold: Option<u64>,
+        /// P4 source file; stdout when omitted.
+        #[arg(long)]
+        output: Option<String>,
+        /// JSON file describing the digests, mirror sessions and registers for the control plane.
+        #[arg(long)]
+        spec: Option<String>,
+        /// Compares with the existing --output and --spec files instead of writing them.
+        #[arg(long)]
+        check: bool,
+    },
+    /// Writes a query as a plan file that --plan can load.
+    ExportPlan {
+        #[command(flatten)]
+        query: QueryArgs,
+        #[arg(long)]
+        threshold: Option<u64>,
+        /// .json, .yaml or .yml file; JSON on stdout when omitted.
+        #[arg(long)]
+        output: Option<String>,
+    },
+    /// Lists the built-in queries.
+    ListQueries,
+    /// Merges epoch snapshots taken at several vantage points and prints the heavy hitters.
+    Merge {
+        #[arg(long)]
+        threshold: u64,
+        /// Writes the merged snapshot to this file.
+        #[arg(long)]
+        output: Option<String>,
+        #[arg(required = true)]
+        inputs: Vec<String>,
+    },
+    /// Queries the per-epoch sketch archive.
+    Archive {
+        /// Archive directory; defaults to SKETCH_DIR.
+        #[arg(long)]
+        dir: Option<String>,
+        #[command(subcommand)]
+        command: ArchiveCommand,
+    },
+    /// Measures executor throughput on synthetic packets, against the string-keyed baseline.
+    Bench {
+        #[arg(long, default_value_t = 1)]
+        query: u8,
+        #[arg(long, default_value_t = 1_000_000)]
+        packets: usize,
+    },
+}
+
+#[derive(Subcommand)]
+enum ArchiveCommand {
+    /// Estimated count of one key in each archived epoch of a range.
+    Count {
+        /// Key values in key order, comma-separated, e.g. 10.0.0.1,443.
+        #[arg(long)]
+        key: String,
+        #[arg(long)]
+        from: usize,
+        /// Last epoch; defaults to --from.
+        #[arg(long)]
+        to: Option<usize>,
+    },
+    /// Candidate keys with the largest estimates in one epoch.
+    Top {
+        #[arg(long, default_value_t = 10)]
+        k: usize,
+        #[arg(long)]
+        epoch: usize,
+    },
+}
 
 fn main() {
     dotenv::dotenv().ok();
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 5 {
-        eprintln!("Usage: {} <pcap_file> <epoch_size_seconds> <threshold> <query_id>", args[0]);
-                return;
+    let cli = Cli::parse();
+    if let Err(e) = run(cli) {
+        eprintln!("{}", e);
+        std::process::exit(1);
     }
+}
+
+fn run(cli: Cli) -> Result<(), String> {
+    // Flags go into the environment first, so the config file only fills what is unset.
+    for setting in &cli.set {
+        let (name, value) = setting.split_once('=').ok_or_else(|| format!("--set expects NAME=VALUE, found '{}'", setting))?;
+        env::set_var(name, value);
+    }
+    if let Command::Run { workers, output_format, output, .. } = &cli.command {
+        set_env_flag("NUM_WORKERS", workers);
+        set_env_flag("OUTPUT_FORMAT", output_format);
+        set_env_flag("OUTPUT_PATH", output);
+    }
+    if let Command::Refine { field, levels, .. } = &cli.command {
+        set_env_flag("REFINEMENT_FIELD", field);
+        set_env_flag("REFINEMENT_LEVELS", levels);
+    }
+    let file = match &cli.config {
+        Some(path) => ConfigFile::load(path)?,
+        None => ConfigFile::default(),
+    };
+    file.apply_to_env()?;
 
-    let pcap_file = &args[1];
-    let epoch_size: u64 = args[2].parse().expect("Invalid epoch size");
-    let threshold: usize = args[3].parse().expect("Invalid threshold");
-    let query_id: u8 = args[4].parse().expect("Invalid query ID");
+    match cli.command {
+        Command::Run { source, query, resume, .. } => {
+            let (pcap_file, epoch_size) = resolve_source(&source, &file)?;
+            let threshold = source.threshold.or(file.query.threshold);
+            let (query_name, query) = resolve_query(query, &file, threshold)?;
+            run_query(&pcap_file, epoch_size, threshold, &query_name, query, resume)
+        }
+        Command::Eval { source, query, report } => {
+            let (pcap_file, epoch_size) = resolve_source(&source, &file)?;
+            let threshold = source.threshold.or(file.query.threshold);
+            let (query_name, query) = resolve_query(query, &file, threshold)?;
+            let threshold = threshold.unwrap_or_else(|| default_threshold(&query));
+            println!("Evaluating Query {} with REDUCE_TYPE {:?}", query_name, get_reduce_type_from_env());
+            evaluation::run_evaluation(&pcap_file, epoch_size, threshold, &query, &report);
+            Ok(())
+        }
+        Command::Refine { source, query, report, .. } => {
+            let (pcap_file, epoch_size) = resolve_source(&source, &file)?;
+            let threshold = source.threshold.or(file.query.threshold);
+            let (query_name, query) = resolve_query(query, &file, threshold)?;
+            println!("Refining Query {} with REDUCE_TYPE {:?}", query_name, get_reduce_type_from_env());
+            let (field, levels) = (get_refinement_field_from_env(), get_refinement_levels_from_env());
+            refinement::run_refinement(&pcap_file, epoch_size, &query, &field, &levels, &report);
+            Ok(())
+        }
+        Command::Sweep { source, queries, grid, report } => {
+            let (pcap_file, epoch_size) = resolve_source(&source, &file)?;
+            let threshold = source
+                .threshold
+                .or(file.query.threshold)
+                .ok_or_else(|| "sweep needs --threshold, as its queries may differ".to_string())?;
+            sweep::run_sweep(&pcap_file, epoch_size, threshold, &queries, &grid, &report, |id| {
+                query_by_id(id).map(|query| query.with_threshold(threshold))
+            });
+            Ok(())
+        }
+        Command::Explain { query, threshold } => {
+            let (_, query) = resolve_query(query, &file, threshold.or(file.query.threshold))?;
+            explain::run_explain(&query);
+            Ok(())
+        }
+        Command::Partition { query, threshold } => {
+            let (_, query) = resolve_query(query, &file, threshold.or(file.query.threshold))?;
+            dataplane::run_partition(&query, get_target_model_from_env());
+            Ok(())
+        }
+        Command::P4gen { query, threshold, output, spec, check } => {
+            let (name, query) = resolve_query(query, &file, threshold.or(file.query.threshold))?;
+            p4gen::run_p4gen(&query, &name, get_target_model_from_env(), output.as_deref(), spec.as_deref(), check)
+        }
+        Command::ExportPlan { query, threshold, output } => {
+            let (_, query) = resolve_query(query, &file, threshold.or(file.query.threshold))?;
+            match output {
+                Some(path) => {
+                    plan_file::save_plan(&path, &query)?;
+                    println!("Plan written to {}", path);
+                }
+                None => print!("{}", plan_file::plan_to_string(&query, None)?),
+            }
+            Ok(())
+        }
+        Command::ListQueries => {
+            for (id, description) in QUERIES {
+                println!("{:>3}  {}", id, description);
+            }
+            Ok(())
+        }
+        Command::Merge { threshold, output, inputs } => {
+            sketch_store::run_merge(&inputs, threshold, output.as_deref());
+            Ok(())
+        }
+        Command::Archive { dir, command } => {
+            let dir = dir
+                .or_else(get_sketch_dir_from_env)
+                .ok_or_else(|| "archive needs --dir or SKETCH_DIR".to_string())?;
+            match command {
+                ArchiveCommand::Count { key, from, to } => {
+                    let to = to.unwrap_or(from);
+                    if from > to {
+                        return Err(format!("Invalid epoch range {}-{}", from, to));
+                    }
+                    sketch_store::run_archive_count(&dir, &key, from, to);
+                }
+                ArchiveCommand::Top { k, epoch } => sketch_store::run_archive_top(&dir, k, epoch),
+            }
+            Ok(())
+        }
+        Command::Bench { query, packets } => {
+            let query_plan = builtin_query(query)?;
+            let plan = compile_plan(&query_plan).map_err(|e| format!("Invalid query plan: {}", e))?;
+            bench::run_benchmark(&query_plan, &plan, packets);
+            Ok(())
+        }
+    }
+}
+
+fn set_env_flag<T: ToString>(name: &str, value: &Option<T>) {
+    if let Some(value) = value {
+        env::set_var(name, value.to_string());
+    }
+}
+
+fn resolve_source(source: &SourceArgs, file: &ConfigFile) -> Result<(String, u64), String> {
+    let pcap_file = source
+        .pcap
+        .clone()
+        .or_else(|| file.source.pcap.clone())
+        .ok_or_else(|| "No trace given (--pcap or [source] pcap)".to_string())?;
+    let epoch_size = source
+        .epoch_size
+        .or(file.source.epoch_size)
+        .ok_or_else(|| "No epoch size given (--epoch-size or [source] epoch_size)".to_string())?;
+    Ok((pcap_file, epoch_size))
+}
+
+fn builtin_query(query_id: u8) -> Result<QueryPlan, String> {
+    query_by_id(query_id).ok_or_else(|| format!("Invalid query ID: {} (see list-queries)", query_id))
+}
+
+/// The query given by the flags, else by the config file, with its `FilterResult`
+/// thresholds replaced when `threshold` is given, and the name it is reported under:
+/// the id of a built-in query or the path of a plan file.
+fn resolve_query(flags: QueryArgs, file: &ConfigFile, threshold: Option<u64>) -> Result<(String, QueryPlan), String> {
+    let (query_id, plan_path) = match (flags.query, flags.plan) {
+        (None, None) => (file.query.id, file.query.plan.clone()),
+        flags => flags,
+    };
+    let (name, query) = match (query_id, plan_path) {
+        (Some(query_id), None) => (query_id.to_string(), builtin_query(query_id)?),
+        (None, Some(path)) => {
+            let query = plan_file::load_plan(&path)?;
+            (path, query)
+        }
+        (Some(_), Some(_)) => return Err("[query] takes either id or plan, not both".to_string()),
+        (None, None) => return Err("No query given (--query, --plan, or [query] id or plan)".to_string()),
+    };
+    Ok(match threshold {
+        Some(threshold) => (name, query.with_threshold(threshold)),
+        None => (name, query),
+    })
+}
+
+// Without a threshold, keys are reported against the query's own FilterResult.
+fn default_threshold(query: &QueryPlan) -> u64 {
+    compile_plan(query).ok().and_then(|plan| plan.filter_result()).map_or(0, |(threshold, _, _)| threshold)
+}
+
+fn run_query(
+    pcap_file: &str,
+    epoch_size: u64,
+    threshold: Option<u64>,
+    query_name: &str,
+    query: QueryPlan,
+    resume: bool,
+) -> Result<(), String> {
+    let threshold = threshold.unwrap_or_else(|| default_threshold(&query));
     let reduce_type = get_reduce_type_from_env();
     let distinct_type = get_distinct_type_from_env();
-    println!("Running Query {} with config:", query_id);
+    println!("Running Query {} with config:", query_name);
+    println!("  THRESHOLD: {}", threshold);
     println!("  REDUCE_TYPE: {:?}", reduce_type);
     println!("  DISTINCT_TYPE: {:?}", distinct_type);
+    println!("  STATISTIC_TYPE: {:?}", get_statistic_type_from_env());
+    let output_format = get_output_format_from_env();
+    let options = RunOptions {
+        num_workers: get_num_workers_from_env(),
+        sketch_dir: get_sketch_dir_from_env(),
+        checkpoint_path: get_checkpoint_path_from_env(),
+        checkpoint_interval: get_checkpoint_interval_from_env(),
+        resume,
+        output_format,
+        output_path: get_output_path_from_env(output_format),
+        alerts: get_alert_config_from_env(query_name),
+    };
+    println!("  NUM_WORKERS: {}", options.num_workers);
+    println!("  SKETCH_DIR: {:?}", options.sketch_dir);
+    println!("  CHECKPOINT_PATH: {:?} (every {} packets)", options.checkpoint_path, options.checkpoint_interval);
+    println!("  OUTPUT: {:?} to {}", options.output_format, options.output_path);
+    println!("  ALERT_SINKS: {:?}", options.alerts.as_ref().map(|alerts| &alerts.sinks));
 
-    let query = match query_id {
-        1 => query_1(),
-        2 => query_2(),
-        3 => query_3(),
-        4 => query_4(),
-        5 => query_5(),
-        6 => query_6(),
-        8 => query_8(),
-        _ => {
-            eprintln!("Invalid query ID: {}", query_id);
-            return;
+    let plan = compile_plan(&query).map_err(|e| format!("Invalid query plan: {}", e))?;
+    if let Some(addr) = get_metrics_addr_from_env() {
+        if let Err(e) = metrics_server::start(&addr, query_name, get_metrics_top_k_from_env()) {
+            eprintln!("{}", e);
         }
-    };
-    process_pcap(pcap_file, epoch_size, threshold,query);
-}
\ No newline at end of file
+    }
+    process_pcap(pcap_file, epoch_size, threshold as usize, plan, &options);
+    metrics_server::linger(get_metrics_linger_from_env());
+    Ok(())
+}
@@ -0,0 +1,244 @@
+// metrics_server.rs
+// Optional HTTP endpoint serving the latest epoch's results, processing metrics and
+// sketch health in the Prometheus text exposition format.
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use lazy_static::lazy_static;
+use crate::flow_key::decode_key;
+use crate::output_sink::{EpochReport, PerformanceMetrics};
+use crate::query_executor::EpochStatistic;
+use crate::sketch::Sketch;
+
+/// What the endpoint labels its series with and how many keys it exposes.
+struct Exposition {
+    query: String,
+    top_k: usize,
+    started: Instant,
+    /// Rendered at the end of each epoch, so scrapes do not touch the executor's state.
+    latest_epoch: String,
+    packets_per_second: Option<f64>,
+}
+
+lazy_static! {
+    static ref EXPOSITION: Mutex<Option<Exposition>> = Mutex::new(None);
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static PACKETS_TOTAL: AtomicU64 = AtomicU64::new(0);
+static PARSE_FAILURES: AtomicU64 = AtomicU64::new(0);
+static EPOCHS_TOTAL: AtomicU64 = AtomicU64::new(0);
+
+/// Binds `addr` and serves `GET /metrics` from a background thread for the rest of the run.
+pub fn start(addr: &str, query: &str, top_k: usize) -> Result<(), String> {
+    let listener = TcpListener::bind(addr).map_err(|e| format!("Cannot bind metrics endpoint {}: {}", addr, e))?;
+    *EXPOSITION.lock().unwrap() = Some(Exposition {
+        query: query.to_string(),
+        top_k,
+        started: Instant::now(),
+        latest_epoch: String::new(),
+        packets_per_second: None,
+    });
+    ENABLED.store(true, Ordering::Relaxed);
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if let Err(e) = serve(stream) {
+                eprintln!("Metrics request failed: {}", e);
+            }
+        }
+    });
+    println!("Serving Prometheus metrics on http://{}/metrics", addr);
+    Ok(())
+}
+
+/// Keeps the endpoint up after processing so the final epoch can still be scraped.
+pub fn linger(seconds: u64) {
+    if ENABLED.load(Ordering::Relaxed) && seconds > 0 {
+        println!("Serving metrics for another {} seconds...", seconds);
+        thread::sleep(Duration::from_secs(seconds));
+    }
+}
+
+/// Counts a packet read by `process_pcap`; `parsed` is false when it was not a TCP/IPv4 packet.
+pub fn record_packet(parsed: bool) {
+    PACKETS_TOTAL.fetch_add(1, Ordering::Relaxed);
+    if !parsed {
+        PARSE_FAILURES.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Replaces the exposed epoch with `report`.
+pub fn publish_epoch(report: &EpochReport, sketches: &HashMap<String, Sketch>) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    EPOCHS_TOTAL.store(report.epoch as u64, Ordering::Relaxed);
+    let mut exposition = EXPOSITION.lock().unwrap();
+    if let Some(exposition) = exposition.as_mut() {
+        exposition.latest_epoch = render_epoch(&exposition.query, exposition.top_k, report, sketches);
+    }
+}
+
+/// Records the run's final throughput, which replaces the running rate.
+pub fn publish_run_metrics(metrics: &PerformanceMetrics) {
+    if let Some(exposition) = EXPOSITION.lock().unwrap().as_mut() {
+        exposition.packets_per_second = Some(metrics.packets_per_second);
+    }
+}
+
+fn serve(mut stream: TcpStream) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut request = [0u8; 4096];
+    let n = stream.read(&mut request)?;
+    let request_line = String::from_utf8_lossy(&request[..n]);
+    let path = request_line.split_whitespace().nth(1).unwrap_or("");
+
+    let (status, body) = match path {
+        "/metrics" => ("200 OK", render()),
+        _ => ("404 Not Found", "Not found; metrics are at /metrics\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+fn render() -> String {
+    let exposition = EXPOSITION.lock().unwrap();
+    let exposition = match exposition.as_ref() {
+        Some(exposition) => exposition,
+        None => return String::new(),
+    };
+    let query = label("query", &exposition.query);
+    let packets = PACKETS_TOTAL.load(Ordering::Relaxed);
+    let packets_per_second = exposition
+        .packets_per_second
+        .unwrap_or_else(|| packets as f64 / exposition.started.elapsed().as_secs_f64().max(1e-9));
+
+    let mut out = String::new();
+    metric(&mut out, "telemetry_packets_total", "counter", "Packets read from the trace.", &[(query.clone(), packets as f64)]);
+    metric(
+        &mut out,
+        "telemetry_parse_failures_total",
+        "counter",
+        "Packets skipped because they are not TCP over IPv4.",
+        &[(query.clone(), PARSE_FAILURES.load(Ordering::Relaxed) as f64)],
+    );
+    metric(
+        &mut out,
+        "telemetry_epochs_total",
+        "counter",
+        "Epochs closed so far.",
+        &[(query.clone(), EPOCHS_TOTAL.load(Ordering::Relaxed) as f64)],
+    );
+    metric(&mut out, "telemetry_packets_per_second", "gauge", "Average processing rate of the run.", &[(query.clone(), packets_per_second)]);
+    out.push_str(&exposition.latest_epoch);
+    out
+}
+
+fn render_epoch(query: &str, top_k: usize, report: &EpochReport, sketches: &HashMap<String, Sketch>) -> String {
+    let query = label("query", query);
+    let mut out = String::new();
+    metric(&mut out, "telemetry_epoch_end_timestamp_seconds", "gauge", "End of the latest epoch.", &[(query.clone(), report.epoch_end as f64)]);
+    metric(&mut out, "telemetry_epoch_packets", "gauge", "Packets in the latest epoch.", &[(query.clone(), report.epoch_packets as f64)]);
+    metric(
+        &mut out,
+        "telemetry_epoch_keys",
+        "gauge",
+        "Keys above the threshold in the latest epoch.",
+        &[(query.clone(), report.rows.len() as f64)],
+    );
+
+    let top: Vec<(String, f64)> = report
+        .rows
+        .iter()
+        .take(top_k)
+        .map(|(key, _, estimate)| {
+            let mut labels = vec![query.clone()];
+            if let Some(fields) = decode_key(report.result_key, key) {
+                labels.extend(fields.iter().map(|(name, value)| label(name, &value.to_string())));
+            }
+            (labels.join(","), estimate.value as f64)
+        })
+        .collect();
+    metric(&mut out, "telemetry_top_key_value", "gauge", "Largest values of the latest epoch, by key.", &top);
+
+    if let Some(bound) = report.bound {
+        metric(
+            &mut out,
+            "telemetry_value_max_overestimate",
+            "gauge",
+            "How far reported values may exceed the true ones.",
+            &[(query.clone(), bound.overestimate as f64)],
+        );
+        metric(
+            &mut out,
+            "telemetry_value_bound_confidence",
+            "gauge",
+            "Probability that the overestimate bound holds.",
+            &[(query.clone(), bound.confidence)],
+        );
+    }
+
+    let mut names: Vec<&String> = sketches.keys().collect();
+    names.sort();
+    let memory: Vec<(String, f64)> = names
+        .iter()
+        .map(|name| (format!("{},{}", query, label("sketch", name)), sketches[*name].get_memory_usage() as f64))
+        .collect();
+    metric(&mut out, "telemetry_sketch_memory_bytes", "gauge", "Memory used by each sketch.", &memory);
+
+    let (mut cardinality, mut entropy, mut heavy_prefixes) = (Vec::new(), Vec::new(), Vec::new());
+    for (name, statistic) in report.statistics {
+        let labels = format!("{},{}", query, label("statistic", name));
+        match statistic {
+            EpochStatistic::Cardinality(value) => cardinality.push((labels, *value)),
+            EpochStatistic::Entropy(value) => entropy.push((labels, *value)),
+            EpochStatistic::FlowSizeDistribution(estimate) => {
+                cardinality.push((labels.clone(), estimate.cardinality));
+                entropy.push((labels, estimate.entropy));
+            }
+            EpochStatistic::HierarchicalHeavyHitters(heavy) => {
+                for prefix in heavy {
+                    let labels = format!("{},{}", labels, label("prefix", &prefix.to_string()));
+                    heavy_prefixes.push((labels, prefix.discounted as f64));
+                }
+            }
+        }
+    }
+    metric(&mut out, "telemetry_epoch_cardinality", "gauge", "Estimated distinct keys in the latest epoch.", &cardinality);
+    metric(&mut out, "telemetry_epoch_entropy", "gauge", "Estimated entropy of the latest epoch.", &entropy);
+    metric(
+        &mut out,
+        "telemetry_epoch_heavy_prefix",
+        "gauge",
+        "Discounted count of each hierarchical heavy hitter prefix in the latest epoch.",
+        &heavy_prefixes,
+    );
+    out
+}
+
+// Appends one metric family; families without samples are left out.
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(String, f64)]) {
+    if samples.is_empty() {
+        return;
+    }
+    out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind));
+    for (labels, value) in samples {
+        out.push_str(&format!("{}{{{}}} {}\n", name, labels, value));
+    }
+}
+
+fn label(name: &str, value: &str) -> String {
+    let name: String = name.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+    let value = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+    format!("{}=\"{}\"", name, value)
+}
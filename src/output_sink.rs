@@ -0,0 +1,435 @@
+// output_sink.rs
+// Writers for the per-epoch results: the human-readable log, or one row per key per epoch
+// as CSV, JSON Lines or Parquet, with a typed column for every field of the result schema.
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::sync::Arc;
+use parquet::basic::{LogicalType, Repetition, Type as PhysicalType};
+use parquet::column::writer::{ColumnWriter, ColumnWriterImpl};
+use parquet::data_type::{BoolType, ByteArray, ByteArrayType, DataType, Int32Type, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::types::Type;
+use serde_json::{json, Value};
+use crate::flow_key::format_key;
+use crate::query_executor::{EpochStatistic, PacketField};
+use crate::record::{FieldType, Record, Schema};
+use crate::sketch::{ErrorBound, Estimate};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    Csv,
+    JsonLines,
+    Parquet,
+}
+
+impl OutputFormat {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "text" => Some(OutputFormat::Text),
+            "csv" => Some(OutputFormat::Csv),
+            "jsonl" => Some(OutputFormat::JsonLines),
+            "parquet" => Some(OutputFormat::Parquet),
+            _ => None,
+        }
+    }
+
+    /// File written when `OUTPUT_PATH` is not set. The text log keeps its historical name.
+    pub fn default_path(&self) -> &'static str {
+        match self {
+            OutputFormat::Text => "telemetry_log.csv",
+            OutputFormat::Csv => "telemetry.csv",
+            OutputFormat::JsonLines => "telemetry.jsonl",
+            OutputFormat::Parquet => "telemetry.parquet",
+        }
+    }
+}
+
+/// One epoch's results as handed to a sink.
+pub struct EpochReport<'a> {
+    pub epoch: usize,
+    pub epoch_end: u64,
+    pub epoch_packets: usize,
+    pub total_packets: usize,
+    pub threshold: u64,
+    /// Bound on the thresholded value; `None` if the sketch behind it gives no guarantee.
+    pub bound: Option<ErrorBound>,
+    pub result_key: &'a [(String, FieldType)],
+    /// Keys whose value exceeds the threshold, largest first.
+    pub rows: Vec<(&'a [u8], &'a Record, Estimate)>,
+    pub statistics: &'a [(String, EpochStatistic)],
+}
+
+pub struct PerformanceMetrics {
+    pub total_packets: usize,
+    pub elapsed_seconds: f64,
+    pub packets_per_second: f64,
+    pub average_packets_per_epoch: f64,
+}
+
+/// Destination of the per-epoch results. CSV and Parquet hold a single table of keys;
+/// epoch statistics and performance metrics only go to the text log and JSON Lines.
+pub enum OutputSink {
+    Text(File),
+    Csv { file: BufWriter<File>, schema: Schema },
+    JsonLines { file: BufWriter<File>, schema: Schema },
+    Parquet { writer: SerializedFileWriter<File>, schema: Schema },
+}
+
+fn open_file(path: &str, append: bool) -> Result<File, String> {
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)
+        .map_err(|e| format!("Cannot open {}: {}", path, e))
+}
+
+impl OutputSink {
+    /// Opens `path` for results laid out as `schema`; a resumed run appends to it.
+    pub fn open(format: OutputFormat, path: &str, schema: &Schema, append: bool) -> Result<Self, String> {
+        let schema = schema.clone();
+        match format {
+            OutputFormat::Text => Ok(OutputSink::Text(open_file(path, append)?)),
+            OutputFormat::Csv => {
+                let file = open_file(path, append)?;
+                let empty = file.metadata().map(|m| m.len() == 0).unwrap_or(true);
+                let mut file = BufWriter::new(file);
+                if empty {
+                    let mut header = vec!["epoch".to_string(), "epoch_end".to_string()];
+                    header.extend(schema.fields().iter().map(|(name, _)| csv_field(name)));
+                    header.extend(["lower", "upper", "status"].map(str::to_string));
+                    writeln!(file, "{}", header.join(",")).map_err(|e| format!("Cannot write {}: {}", path, e))?;
+                }
+                Ok(OutputSink::Csv { file, schema })
+            }
+            OutputFormat::JsonLines => Ok(OutputSink::JsonLines { file: BufWriter::new(open_file(path, append)?), schema }),
+            OutputFormat::Parquet => {
+                // A Parquet file ends with its footer, so a resumed run cannot add to it.
+                if append && std::fs::metadata(path).is_ok_and(|m| m.len() > 0) {
+                    return Err(format!("Cannot append to Parquet file {}; set OUTPUT_PATH to a new file", path));
+                }
+                let file = open_file(path, false)?;
+                let writer = SerializedFileWriter::new(file, parquet_schema(&schema)?, Arc::new(WriterProperties::builder().build()))
+                    .map_err(|e| format!("Cannot write {}: {}", path, e))?;
+                Ok(OutputSink::Parquet { writer, schema })
+            }
+        }
+    }
+
+    pub fn write_epoch(&mut self, report: &EpochReport) -> Result<(), String> {
+        match self {
+            OutputSink::Text(file) => writeln!(file, "{}", text_summary(report).trim_end()).map_err(|e| e.to_string()),
+            OutputSink::Csv { file, schema } => {
+                for (_, record, estimate) in &report.rows {
+                    let mut row = vec![report.epoch.to_string(), report.epoch_end.to_string()];
+                    row.extend((0..schema.len()).map(|slot| match record.get(slot).and_then(present) {
+                        Some(value) => csv_field(&value.to_string()),
+                        None => String::new(),
+                    }));
+                    let (lower, upper) = estimate.bounds.map_or((String::new(), String::new()), |(l, u)| (l.to_string(), u.to_string()));
+                    row.extend([lower, upper, estimate.status(report.threshold).to_string()]);
+                    writeln!(file, "{}", row.join(",")).map_err(|e| e.to_string())?;
+                }
+                file.flush().map_err(|e| e.to_string())
+            }
+            OutputSink::JsonLines { file, schema } => {
+                for line in json_lines(report, schema) {
+                    writeln!(file, "{}", line).map_err(|e| e.to_string())?;
+                }
+                file.flush().map_err(|e| e.to_string())
+            }
+            OutputSink::Parquet { writer, schema } => write_row_group(writer, schema, report).map_err(|e| e.to_string()),
+        }
+    }
+
+    /// Writes the run's metrics; returns whether this sink keeps them.
+    pub fn write_metrics(&mut self, metrics: &PerformanceMetrics) -> Result<bool, String> {
+        match self {
+            OutputSink::Text(file) => writeln!(
+                file,
+                "\n=== PERFORMANCE METRICS ===\nTotal packets processed: {}\nElapsed time: {:.2} seconds\nAverage packets per second: {:.2}\nAverage packets per epoch: {:.2}\n",
+                metrics.total_packets, metrics.elapsed_seconds, metrics.packets_per_second, metrics.average_packets_per_epoch
+            )
+            .map(|_| true)
+            .map_err(|e| e.to_string()),
+            OutputSink::JsonLines { file, .. } => {
+                let line = json_object(vec![
+                    ("type", json!("metrics")),
+                    ("total_packets", json!(metrics.total_packets)),
+                    ("elapsed_seconds", json!(metrics.elapsed_seconds)),
+                    ("packets_per_second", json!(metrics.packets_per_second)),
+                    ("average_packets_per_epoch", json!(metrics.average_packets_per_epoch)),
+                ]);
+                writeln!(file, "{}", line).and_then(|_| file.flush()).map(|_| true).map_err(|e| e.to_string())
+            }
+            OutputSink::Csv { .. } | OutputSink::Parquet { .. } => Ok(false),
+        }
+    }
+
+    /// Flushes buffered rows; for Parquet, writes the footer that makes the file readable.
+    pub fn finish(self) -> Result<(), String> {
+        match self {
+            OutputSink::Text(_) => Ok(()),
+            OutputSink::Csv { mut file, .. } | OutputSink::JsonLines { mut file, .. } => file.flush().map_err(|e| e.to_string()),
+            OutputSink::Parquet { writer, .. } => writer.close().map(|_| ()).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+// Empty optional fields are written as missing values rather than as `-`.
+fn present(value: &PacketField) -> Option<&PacketField> {
+    match value {
+        PacketField::OptionU16(None) | PacketField::OptionTupleU16(None) => None,
+        value => Some(value),
+    }
+}
+
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+fn text_summary(report: &EpochReport) -> String {
+    let mut summary = format!(
+        "\n=== EPOCH SUMMARY ===\nEpoch end timestamp: {}\nPackets processed this epoch: {}\nTotal packets processed: {}\n",
+        report.epoch_end, report.epoch_packets, report.total_packets
+    );
+    match report.bound {
+        Some(bound) if bound.overestimate > 0 => summary.push_str(&format!(
+            "Error bound: estimates exceed true counts by at most {} (confidence {:.4})\n",
+            bound.overestimate, bound.confidence
+        )),
+        Some(_) => summary.push_str("Error bound: exact\n"),
+        None => summary.push_str("Error bound: none (the sketch gives no guarantee)\n"),
+    }
+
+    summary.push_str("flow_key, value, lower, upper, status\n");
+    for (key, _, estimate) in &report.rows {
+        let (lower, upper) = estimate.bound_strings();
+        summary.push_str(&format!(
+            "{}, {}, {}, {}, {}\n",
+            format_key(report.result_key, key), estimate.value, lower, upper, estimate.status(report.threshold)
+        ));
+    }
+    if report.rows.is_empty() {
+        summary.push_str("No entries exceeded the threshold.\n");
+    }
+
+    for (name, statistic) in report.statistics {
+        match statistic {
+            EpochStatistic::Cardinality(value) => summary.push_str(&format!("\n{}: {:.0}\n", name, value)),
+            EpochStatistic::Entropy(value) => summary.push_str(&format!("\n{}: {:.4}\n", name, value)),
+            EpochStatistic::FlowSizeDistribution(estimate) => {
+                summary.push_str(&format!(
+                    "\n--- FLOW SIZE ESTIMATE ({}) ---\nEstimated cardinality: {:.0}\nEstimated entropy: {:.4}\nflow_size, flows\n",
+                    name, estimate.cardinality, estimate.entropy
+                ));
+                for (size, flows) in &estimate.distribution {
+                    if *flows >= 0.5 {
+                        summary.push_str(&format!("{}, {:.1}\n", size, flows));
+                    }
+                }
+            }
+            EpochStatistic::HierarchicalHeavyHitters(heavy) => {
+                summary.push_str(&format!("\n--- HIERARCHICAL HEAVY HITTERS ({}) ---\nprefix, estimate, discounted\n", name));
+                for prefix in heavy {
+                    summary.push_str(&format!("{}, {}, {}\n", prefix, prefix.estimate, prefix.discounted));
+                }
+                if heavy.is_empty() {
+                    summary.push_str("No prefixes exceeded the threshold.\n");
+                }
+            }
+        }
+    }
+    summary
+}
+
+fn json_value(value: &PacketField) -> Value {
+    match value {
+        PacketField::U8(v) => json!(v),
+        PacketField::U16(v) | PacketField::OptionU16(Some(v)) => json!(v),
+        PacketField::U32(v) => json!(v),
+        PacketField::U64(v) => json!(v),
+        PacketField::Bool(v) => json!(v),
+        PacketField::OptionTupleU16(Some((a, b))) => json!([a, b]),
+        PacketField::OptionU16(None) | PacketField::OptionTupleU16(None) => Value::Null,
+        other => json!(other.to_string()),
+    }
+}
+
+// Serializes entries in the order given, so every line lists its columns in schema order.
+fn json_object(entries: Vec<(&str, Value)>) -> String {
+    let fields: Vec<String> = entries
+        .iter()
+        .map(|(name, value)| format!("{}:{}", Value::from(*name), value))
+        .collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+/// One `epoch` line, a `key` line per reported key and a `statistic` line per statistic.
+fn json_lines(report: &EpochReport, schema: &Schema) -> Vec<String> {
+    let epoch = || vec![("epoch", json!(report.epoch)), ("epoch_end", json!(report.epoch_end))];
+    let mut lines = Vec::with_capacity(report.rows.len() + report.statistics.len() + 1);
+
+    let mut header = vec![("type", json!("epoch"))];
+    header.extend(epoch());
+    header.extend([
+        ("epoch_packets", json!(report.epoch_packets)),
+        ("total_packets", json!(report.total_packets)),
+        ("threshold", json!(report.threshold)),
+        ("max_overestimate", json!(report.bound.map(|b| b.overestimate))),
+        ("confidence", json!(report.bound.map(|b| b.confidence))),
+    ]);
+    lines.push(json_object(header));
+
+    for (_, record, estimate) in &report.rows {
+        let mut entries = vec![("type", json!("key"))];
+        entries.extend(epoch());
+        for (slot, (name, _)) in schema.fields().iter().enumerate() {
+            entries.push((name, record.get(slot).map_or(Value::Null, json_value)));
+        }
+        entries.extend([
+            ("lower", json!(estimate.bounds.map(|(lower, _)| lower))),
+            ("upper", json!(estimate.bounds.map(|(_, upper)| upper))),
+            ("status", json!(estimate.status(report.threshold).to_string())),
+        ]);
+        lines.push(json_object(entries));
+    }
+
+    for (name, statistic) in report.statistics {
+        let mut entries = vec![("type", json!("statistic"))];
+        entries.extend(epoch());
+        entries.push(("name", json!(name)));
+        match statistic {
+            EpochStatistic::Cardinality(value) => entries.push(("cardinality", json!(value))),
+            EpochStatistic::Entropy(value) => entries.push(("entropy", json!(value))),
+            EpochStatistic::FlowSizeDistribution(estimate) => entries.extend([
+                ("cardinality", json!(estimate.cardinality)),
+                ("entropy", json!(estimate.entropy)),
+                ("distribution", json!(estimate.distribution.iter().filter(|(_, flows)| **flows >= 0.5).collect::<Vec<_>>())),
+            ]),
+            EpochStatistic::HierarchicalHeavyHitters(heavy) => entries.push((
+                "heavy_prefixes",
+                Value::Array(
+                    heavy
+                        .iter()
+                        .map(|prefix| {
+                            json!({
+                                "prefix": prefix.to_string(),
+                                "estimate": prefix.estimate,
+                                "discounted": prefix.discounted,
+                            })
+                        })
+                        .collect(),
+                ),
+            )),
+        }
+        lines.push(json_object(entries));
+    }
+    lines
+}
+
+fn parquet_column(name: &str, field_type: FieldType) -> Result<Type, String> {
+    let unsigned = |bit_width| Some(LogicalType::Integer { bit_width, is_signed: false });
+    let (physical_type, logical_type) = match field_type {
+        FieldType::U8 => (PhysicalType::INT32, unsigned(8)),
+        FieldType::U16 | FieldType::OptionU16 => (PhysicalType::INT32, unsigned(16)),
+        FieldType::U32 => (PhysicalType::INT32, unsigned(32)),
+        FieldType::U64 => (PhysicalType::INT64, unsigned(64)),
+        FieldType::Bool => (PhysicalType::BOOLEAN, None),
+        FieldType::String | FieldType::Ipv4 | FieldType::Ipv6 | FieldType::OptionTupleU16 => {
+            (PhysicalType::BYTE_ARRAY, Some(LogicalType::String))
+        }
+    };
+    Type::primitive_type_builder(name, physical_type)
+        .with_repetition(Repetition::OPTIONAL)
+        .with_logical_type(logical_type)
+        .build()
+        .map_err(|e| format!("Invalid Parquet column {}: {}", name, e))
+}
+
+// Columns of the Parquet file, in the order `parquet_row` fills them.
+fn parquet_schema(schema: &Schema) -> Result<Arc<Type>, String> {
+    let mut columns = vec![parquet_column("epoch", FieldType::U64)?, parquet_column("epoch_end", FieldType::U64)?];
+    for (name, field_type) in schema.fields() {
+        columns.push(parquet_column(name, *field_type)?);
+    }
+    for name in ["lower", "upper"] {
+        columns.push(parquet_column(name, FieldType::U64)?);
+    }
+    columns.push(parquet_column("status", FieldType::String)?);
+    let message = Type::group_type_builder("epoch_results")
+        .with_fields(columns.into_iter().map(Arc::new).collect())
+        .build()
+        .map_err(|e| e.to_string())?;
+    Ok(Arc::new(message))
+}
+
+fn parquet_row(report: &EpochReport, schema: &Schema, record: &Record, estimate: &Estimate) -> Vec<Option<PacketField>> {
+    let mut row = vec![Some(PacketField::U64(report.epoch as u64)), Some(PacketField::U64(report.epoch_end))];
+    row.extend((0..schema.len()).map(|slot| record.get(slot).and_then(present).cloned()));
+    row.push(estimate.bounds.map(|(lower, _)| PacketField::U64(lower)));
+    row.push(estimate.bounds.map(|(_, upper)| PacketField::U64(upper)));
+    row.push(Some(PacketField::String(estimate.status(report.threshold).to_string())));
+    row
+}
+
+fn write_cells<T: DataType>(
+    writer: &mut ColumnWriterImpl<T>,
+    cells: &[Option<PacketField>],
+    convert: impl Fn(&PacketField) -> Option<T::T>,
+) -> parquet::errors::Result<()> {
+    let values: Vec<Option<T::T>> = cells.iter().map(|cell| cell.as_ref().and_then(&convert)).collect();
+    let def_levels: Vec<i16> = values.iter().map(|value| value.is_some() as i16).collect();
+    let values: Vec<T::T> = values.into_iter().flatten().collect();
+    writer.write_batch(&values, Some(&def_levels), None).map(|_| ())
+}
+
+/// Writes one epoch as a row group; unsigned values are stored in their signed
+/// physical type, as the Parquet integer annotations specify.
+fn write_row_group(writer: &mut SerializedFileWriter<File>, schema: &Schema, report: &EpochReport) -> parquet::errors::Result<()> {
+    if report.rows.is_empty() {
+        return Ok(());
+    }
+    let rows: Vec<Vec<Option<PacketField>>> = report
+        .rows
+        .iter()
+        .map(|(_, record, estimate)| parquet_row(report, schema, record, estimate))
+        .collect();
+    let mut row_group = writer.next_row_group()?;
+    let mut column = 0;
+    while let Some(mut column_writer) = row_group.next_column()? {
+        let cells: Vec<Option<PacketField>> = rows.iter().map(|row| row[column].clone()).collect();
+        match column_writer.untyped() {
+            ColumnWriter::BoolColumnWriter(w) => write_cells::<BoolType>(w, &cells, |value| match value {
+                PacketField::Bool(v) => Some(*v),
+                _ => None,
+            })?,
+            ColumnWriter::Int32ColumnWriter(w) => write_cells::<Int32Type>(w, &cells, |value| match value {
+                PacketField::U8(v) => Some(*v as i32),
+                PacketField::U16(v) | PacketField::OptionU16(Some(v)) => Some(*v as i32),
+                PacketField::U32(v) => Some(*v as i32),
+                _ => None,
+            })?,
+            ColumnWriter::Int64ColumnWriter(w) => write_cells::<Int64Type>(w, &cells, |value| match value {
+                PacketField::U64(v) => Some(*v as i64),
+                _ => None,
+            })?,
+            ColumnWriter::ByteArrayColumnWriter(w) => {
+                write_cells::<ByteArrayType>(w, &cells, |value| Some(ByteArray::from(value.to_string().into_bytes())))?
+            }
+            _ => unreachable!("parquet_schema only declares boolean, int32, int64 and byte array columns"),
+        }
+        column_writer.close()?;
+        column += 1;
+    }
+    row_group.close()?;
+    Ok(())
+}
@@ -0,0 +1,820 @@
+// p4gen.rs
+// P4_16 source for the data-plane part of a query, for the v1model architecture (bmv2),
+// and the spec of what the switch hands to the control plane.
+
+use std::fmt::Write as _;
+use std::fs;
+use serde::Serialize;
+use crate::functions::{call_name, parse_call};
+use crate::query_executor::{map_items, parse_prefix, prefix_mask};
+use crate::dataplane::{partition, Location, Placement, TargetModel};
+use crate::query_plan::{Aggregation, Field, Operation, QueryPlan, ReduceType, StatisticType};
+
+const HEADERS: &str = "\
+header ethernet_t {
+    bit<48> dst_addr;
+    bit<48> src_addr;
+    bit<16> ether_type;
+}
+
+header ipv4_t {
+    bit<4>  version;
+    bit<4>  ihl;
+    bit<8>  diffserv;
+    bit<16> total_len;
+    bit<16> identification;
+    bit<3>  flags;
+    bit<13> frag_offset;
+    bit<8>  ttl;
+    bit<8>  protocol;
+    bit<16> hdr_checksum;
+    bit<32> src_addr;
+    bit<32> dst_addr;
+}
+
+header tcp_t {
+    bit<16> src_port;
+    bit<16> dst_port;
+    bit<32> seq_no;
+    bit<32> ack_no;
+    bit<4>  data_offset;
+    bit<4>  res;
+    bit<8>  flags;
+    bit<16> window;
+    bit<16> checksum;
+    bit<16> urgent_ptr;
+}
+
+struct headers_t {
+    ethernet_t ethernet;
+    ipv4_t     ipv4;
+    tcp_t      tcp;
+}
+";
+
+// Like the executor, only TCP over IPv4 reaches the query.
+const PARSER: &str = "\
+parser TelemetryParser(packet_in pkt, out headers_t hdr, inout metadata_t meta,
+                       inout standard_metadata_t standard_metadata) {
+    state start {
+        pkt.extract(hdr.ethernet);
+        transition select(hdr.ethernet.ether_type) {
+            16w0x0800: parse_ipv4;
+            default: accept;
+        }
+    }
+    state parse_ipv4 {
+        pkt.extract(hdr.ipv4);
+        transition select(hdr.ipv4.protocol) {
+            8w6: parse_tcp;
+            default: accept;
+        }
+    }
+    state parse_tcp {
+        pkt.extract(hdr.tcp);
+        transition accept;
+    }
+}
+";
+
+const TRAILER: &str = "\
+control TelemetryVerifyChecksum(inout headers_t hdr, inout metadata_t meta) {
+    apply { }
+}
+
+control TelemetryEgress(inout headers_t hdr, inout metadata_t meta,
+                        inout standard_metadata_t standard_metadata) {
+    apply { }
+}
+
+control TelemetryComputeChecksum(inout headers_t hdr, inout metadata_t meta) {
+    apply { }
+}
+
+control TelemetryDeparser(packet_out pkt, in headers_t hdr) {
+    apply {
+        pkt.emit(hdr.ethernet);
+        pkt.emit(hdr.ipv4);
+        pkt.emit(hdr.tcp);
+    }
+}
+
+V1Switch(TelemetryParser(), TelemetryVerifyChecksum(), TelemetryIngress(), TelemetryEgress(),
+         TelemetryComputeChecksum(), TelemetryDeparser()) main;
+";
+
+/// What the control plane must know to run the generated program.
+#[derive(Serialize)]
+pub struct ControlPlaneSpec {
+    pub query: String,
+    pub target: String,
+    /// Records the switch sends up, one digest per part of the query.
+    pub digests: Vec<DigestSpec>,
+    /// Parts of the query with no operator on the switch: their packets are mirrored whole.
+    pub mirror_sessions: Vec<MirrorSpec>,
+    /// Registers the control plane reads, for statistics, and clears at the end of every epoch.
+    pub epoch_registers: Vec<RegisterSpec>,
+    /// Operators left to the stream processor.
+    pub stream_processor: Vec<StreamOperatorSpec>,
+}
+
+#[derive(Serialize)]
+pub struct DigestSpec {
+    pub name: String,
+    pub part: String,
+    pub fields: Vec<FieldSpec>,
+    /// Condition under which a packet sends the digest, if the switch filters the results.
+    pub condition: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct FieldSpec {
+    pub name: String,
+    pub bits: usize,
+}
+
+#[derive(Serialize)]
+pub struct StreamOperatorSpec {
+    pub part: String,
+    pub position: usize,
+    pub operation: String,
+    /// Why the operator could not stay on the switch.
+    pub reason: String,
+}
+
+#[derive(Serialize)]
+pub struct MirrorSpec {
+    pub session: u32,
+    pub part: String,
+}
+
+#[derive(Serialize)]
+pub struct RegisterSpec {
+    pub name: String,
+    pub entries: usize,
+    pub bits: usize,
+}
+
+// A field of the record flowing through one part of the query: the P4 expression that
+// holds it and its width.
+#[derive(Clone)]
+struct RecordField {
+    name: String,
+    expr: String,
+    bits: usize,
+}
+
+// An unnamed Map call becomes a field named after it, such as "prefix(dst_ip, 24)",
+// which is no P4 identifier.
+fn identifier(name: &str) -> String {
+    let name: String = name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' }).collect();
+    name.trim_end_matches('_').to_string()
+}
+
+// Packet fields as the parser provides them; `dns_ns_type` is past the TCP header.
+fn packet_record() -> Vec<RecordField> {
+    [
+        ("src_ip", "hdr.ipv4.src_addr", 32),
+        ("dst_ip", "hdr.ipv4.dst_addr", 32),
+        ("src_port", "hdr.tcp.src_port", 16),
+        ("dst_port", "hdr.tcp.dst_port", 16),
+        ("tcp_flags", "hdr.tcp.flags", 8),
+        ("total_len", "(bit<32>)hdr.ipv4.total_len", 32),
+        ("protocol", "hdr.ipv4.protocol", 8),
+    ]
+    .iter()
+    .map(|(name, expr, bits)| RecordField { name: name.to_string(), expr: expr.to_string(), bits: *bits })
+    .collect()
+}
+
+fn filter_field_name(field: &Field) -> &'static str {
+    match field {
+        Field::TcpFlag => "tcp_flags",
+        Field::SourceIp => "src_ip",
+        Field::DestIp => "dst_ip",
+        Field::SourcePort => "src_port",
+        Field::DestPort => "dst_port",
+        Field::Protocol => "protocol",
+        Field::DnsNsType => "dns_ns_type",
+    }
+}
+
+// A filter value as a P4 literal of the field's width.
+fn literal(field: &Field, value: &str, bits: usize) -> Result<String, String> {
+    let number = match field {
+        Field::SourceIp | Field::DestIp => value.parse::<std::net::Ipv4Addr>().ok().map(|ip| u32::from(ip) as u64),
+        _ => value.parse::<u64>().ok(),
+    };
+    number
+        .map(|number| format!("{}w{}", bits, number))
+        .ok_or_else(|| format!("Filter: '{}' is not a {}-bit value", value, bits))
+}
+
+struct Generator {
+    metadata: Vec<(String, usize)>,
+    declarations: String,
+    apply: String,
+    spec: ControlPlaneSpec,
+}
+
+impl Generator {
+    fn declare(&mut self, name: &str, bits: usize) -> String {
+        if !self.metadata.iter().any(|(declared, _)| declared == name) {
+            self.metadata.push((name.to_string(), bits));
+        }
+        format!("meta.{}", name)
+    }
+
+    fn register(&mut self, name: &str, entries: usize, bits: usize) {
+        let _ = writeln!(self.declarations, "    register<bit<{}>>({}) {};", bits, entries, name);
+        self.spec.epoch_registers.push(RegisterSpec { name: name.to_string(), entries, bits });
+    }
+
+    // Hashes `key` into `index`, one of `entries` slots; `salt` tells the rows apart.
+    fn hash(&mut self, out: &mut String, index: &str, salt: u64, key: &str, entries: usize) {
+        let _ = writeln!(
+            out,
+            "hash({}, HashAlgorithm.crc32, 32w0, {{ 32w{}, {} }}, 32w{});",
+            index, salt, key, entries
+        );
+    }
+
+    // Adds `value` to a Count-Min row and leaves the counter in `count`.
+    fn count_min_row(&mut self, out: &mut String, row: &str, width: usize, salt: u64, key: &str, value: &str) -> String {
+        self.register(row, width, 32);
+        let index = self.declare(&format!("{}_index", row), 32);
+        let count = self.declare(&format!("{}_count", row), 32);
+        self.hash(out, &index, salt, key, width);
+        let _ = writeln!(out, "{}.read({}, {});", row, count, index);
+        let _ = writeln!(out, "{} = {} + {};", count, count, value);
+        let _ = writeln!(out, "{}.write({}, {});", row, index, count);
+        count
+    }
+
+    // Adds `value` to one FCM tree: a leaf counter that passes `threshold_l1` saturates and
+    // carries on into its layer-2 parent, and likewise into layer 3. Leaves the tree's
+    // estimate in `estimate`.
+    #[allow(clippy::too_many_arguments)]
+    fn fcm_tree(
+        &mut self,
+        out: &mut String,
+        tree: &str,
+        widths: (usize, usize, usize),
+        thresholds: (u32, u32),
+        salt: u64,
+        key: &str,
+        value: &str,
+    ) -> String {
+        let (l1, l2, l3) = (format!("{}_l1", tree), format!("{}_l2", tree), format!("{}_l3", tree));
+        self.register(&l1, widths.0, 8);
+        self.register(&l2, widths.1, 16);
+        self.register(&l3, widths.2, 32);
+        let index = self.declare(&format!("{}_index", tree), 32);
+        let c8 = self.declare(&format!("{}_c8", tree), 8);
+        let c16 = self.declare(&format!("{}_c16", tree), 16);
+        let c32 = self.declare(&format!("{}_c32", tree), 32);
+        let sum = self.declare(&format!("{}_sum", tree), 32);
+        let estimate = self.declare(&format!("{}_estimate", tree), 32);
+        let (t1, t2) = thresholds;
+
+        self.hash(out, &index, salt, key, widths.0);
+        let _ = write!(
+            out,
+            "{l1}.read({c8}, {index});
+{sum} = (bit<32>){c8} + {value};
+if ({sum} <= 32w{t1}) {{
+    {l1}.write({index}, (bit<8>){sum});
+    {estimate} = {sum};
+}} else {{
+    {l1}.write({index}, 8w255);
+    {index} = {index} >> 3;
+    {l2}.read({c16}, {index});
+    {sum} = (bit<32>){c16} + {value};
+    if ({sum} <= 32w{t2}) {{
+        {l2}.write({index}, (bit<16>){sum});
+        {estimate} = 32w{t1} + {sum};
+    }} else {{
+        {l2}.write({index}, 16w65535);
+        {index} = {index} >> 3;
+        {l3}.read({c32}, {index});
+        {c32} = {c32} + {value};
+        {l3}.write({index}, {c32});
+        {estimate} = 32w{t1} + 32w{t2} + {c32};
+    }}
+}}
+"
+        );
+        estimate
+    }
+
+    // A table letting through the packets that match one of `entries` and clearing the
+    // pass flag of the others.
+    fn match_table(&mut self, table: &str, pass: &str, keys: &[(String, &str)], entries: &[String]) {
+        let _ = write!(
+            self.declarations,
+            "
+    action {table}_miss() {{
+        {pass} = 1w0;
+    }}
+
+    table {table} {{
+        key = {{
+{keys}
+        }}
+        actions = {{
+            NoAction;
+            {table}_miss;
+        }}
+        const entries = {{
+{entries}
+        }}
+        default_action = {table}_miss();
+    }}
+",
+            keys = keys.iter().map(|(key, kind)| format!("            {} : {};", key, kind)).collect::<Vec<_>>().join("\n"),
+            entries = entries.iter().map(|entry| format!("            {} : NoAction();", entry)).collect::<Vec<_>>().join("\n"),
+        );
+    }
+
+    // Copies a field into metadata when its expression cannot be used as a table key or
+    // digest field.
+    fn lvalue(&mut self, out: &mut String, prefix: &str, field: &RecordField) -> String {
+        if !field.expr.starts_with('(') {
+            return field.expr.clone();
+        }
+        let target = self.declare(&format!("{}{}", prefix, field.name), field.bits);
+        let _ = writeln!(out, "{} = {};", target, field.expr);
+        target
+    }
+
+    // Emits the statements of one operator kept on the switch. `record` is updated to the
+    // operator's output.
+    fn operator(
+        &mut self,
+        op: &Operation,
+        position: usize,
+        prefix: &str,
+        record: &mut Vec<RecordField>,
+        condition: &mut Option<String>,
+    ) -> Result<String, String> {
+        let pass = format!("meta.{}pass", prefix);
+        let find = |record: &[RecordField], name: &str| {
+            record
+                .iter()
+                .find(|field| field.name == name)
+                .cloned()
+                .ok_or_else(|| format!("field '{}' is not available on the switch", name))
+        };
+        let key_tuple = |generator: &mut Generator, out: &mut String, record: &[RecordField], keys: &[String]| {
+            let mut exprs = Vec::new();
+            for key in keys {
+                let field = find(record, key)?;
+                exprs.push(generator.lvalue(out, prefix, &field));
+            }
+            Ok::<String, String>(exprs.join(", "))
+        };
+
+        let mut out = String::new();
+        match op {
+            Operation::Filter(conditions) => {
+                let table = format!("{}filter_{}", prefix, position);
+                let mut keys = Vec::new();
+                let mut values = Vec::new();
+                for (field, value) in conditions {
+                    let record_field = find(record, filter_field_name(field))?;
+                    keys.push(self.lvalue(&mut out, prefix, &record_field));
+                    values.push(literal(field, value, record_field.bits)?);
+                }
+                let entry = if values.len() == 1 { values[0].clone() } else { format!("({})", values.join(", ")) };
+                let keys: Vec<(String, &str)> = keys.into_iter().map(|key| (key, "exact")).collect();
+                self.match_table(&table, &pass, &keys, &[entry]);
+                let _ = writeln!(out, "{}.apply();", table);
+            }
+            Operation::Map(expr) => {
+                let mut mapped = Vec::new();
+                for item in map_items(expr) {
+                    let call = item.split_once('=').map_or(item, |(_, value)| value);
+                    if let Some((function, args)) = parse_call(call) {
+                        let (field, len) = match args.as_slice() {
+                            [field, len] if function == "prefix" => (find(record, field)?, len.parse::<u8>().ok()),
+                            _ => return Err(format!("Map: {}() has no switch implementation", function)),
+                        };
+                        let len = len.filter(|len| *len <= 32).ok_or_else(|| format!("Map: invalid prefix in '{}'", call))?;
+                        let name = item.split_once('=').map_or_else(|| call_name(item), |(name, _)| name.trim().to_string());
+                        let target = self.declare(&format!("{}{}", prefix, identifier(&name)), 32);
+                        let _ = writeln!(out, "{} = {} & 32w{:#010x};", target, field.expr, prefix_mask(len));
+                        mapped.push(RecordField { name, expr: target, bits: 32 });
+                        continue;
+                    }
+                    if let Some((name, value)) = item.split_once('=') {
+                        let (name, value) = (name.trim(), value.trim());
+                        let constant: u32 = value
+                            .parse()
+                            .map_err(|_| format!("Map: '{}' has no switch representation", value))?;
+                        let target = self.declare(&format!("{}{}", prefix, name), 32);
+                        let _ = writeln!(out, "{} = 32w{};", target, constant);
+                        mapped.push(RecordField { name: name.to_string(), expr: target, bits: 32 });
+                    } else {
+                        let field = find(record, item)?;
+                        let target = self.declare(&format!("{}{}", prefix, item), field.bits);
+                        if field.expr != target {
+                            let _ = writeln!(out, "{} = {};", target, field.expr);
+                        }
+                        mapped.push(RecordField { name: item.to_string(), expr: target, bits: field.bits });
+                    }
+                }
+                *record = mapped;
+            }
+            Operation::Reduce { keys, reduce_type, field_name, aggregation } => {
+                self.declarations.push('\n');
+                let key = key_tuple(self, &mut out, record, keys)?;
+                let value_field = find(record, field_name)?;
+                let value = self.declare(&format!("{}reduce_{}_value", prefix, position), 32);
+                match aggregation {
+                    Aggregation::Sum => {
+                        let _ = writeln!(out, "{} = {};", value, value_field.expr);
+                    }
+                    Aggregation::Count => {
+                        let _ = writeln!(out, "{} = 32w1;", value);
+                    }
+                    other => return Err(format!("P4 generation supports Sum and Count reduces, not {:?}", other)),
+                }
+                let sketch = format!("{}reduce_{}", prefix, position);
+                let estimates = match reduce_type {
+                    ReduceType::CMReduce { memory_in_bytes, depth, .. } => {
+                        let width = memory_in_bytes / 4 / depth;
+                        (0..*depth)
+                            .map(|row| self.count_min_row(&mut out, &format!("{}_row{}", sketch, row), width, 750 + row as u64, &key, &value))
+                            .collect::<Vec<_>>()
+                    }
+                    ReduceType::FCMFirstLayerOnly { depth, width_l1, seed } => (0..*depth)
+                        .map(|row| self.count_min_row(&mut out, &format!("{}_row{}", sketch, row), *width_l1, seed + row as u64, &key, &value))
+                        .collect(),
+                    ReduceType::FCMReduce { depth, width_l1, width_l2, width_l3, threshold_l1, threshold_l2, seed } => (0..*depth)
+                        .map(|tree| {
+                            self.fcm_tree(
+                                &mut out,
+                                &format!("{}_tree{}", sketch, tree),
+                                (*width_l1, *width_l2, *width_l3),
+                                (*threshold_l1, *threshold_l2),
+                                seed + tree as u64,
+                                &key,
+                                &value,
+                            )
+                        })
+                        .collect(),
+                    other => return Err(format!("P4 generation supports CM and FCM reduces, not {:?}", other)),
+                };
+                // The reduced field carries the estimate, as in the executor.
+                let target = self.declare(&format!("{}{}", prefix, field_name), 32);
+                let _ = writeln!(out, "{} = {};", target, estimates[0]);
+                for estimate in &estimates[1..] {
+                    let _ = writeln!(out, "if ({} < {}) {{\n    {} = {};\n}}", estimate, target, target, estimate);
+                }
+                for field in record.iter_mut().filter(|field| field.name == *field_name) {
+                    field.expr = target.clone();
+                }
+            }
+            Operation::Distinct { keys, distinct_type: ReduceType::BloomFilter { size, num_hashes, seed } } => {
+                self.declarations.push('\n');
+                let key = key_tuple(self, &mut out, record, keys)?;
+                let entries = size.div_ceil((*num_hashes).max(1));
+                let mut bits = Vec::new();
+                for hash in 0..*num_hashes {
+                    let array = format!("{}distinct_{}_bits{}", prefix, position, hash);
+                    self.register(&array, entries, 1);
+                    let index = self.declare(&format!("{}_index", array), 32);
+                    let bit = self.declare(&format!("{}_bit", array), 1);
+                    self.hash(&mut out, &index, seed + hash as u64, &key, entries);
+                    let _ = writeln!(out, "{}.read({}, {});", array, bit, index);
+                    bits.push((array, index, bit));
+                }
+                let seen = bits.iter().map(|(_, _, bit)| format!("{} == 1w1", bit)).collect::<Vec<_>>().join(" && ");
+                let _ = writeln!(out, "if ({}) {{\n    {} = 1w0;\n}} else {{", seen, pass);
+                for (array, index, _) in &bits {
+                    let _ = writeln!(out, "    {}.write({}, 1w1);", array, index);
+                }
+                let _ = writeln!(out, "}}");
+            }
+            Operation::RefinePrefix { field_name, prefix_len, within } => {
+                let field = find(record, field_name)?;
+                let mask = prefix_mask(*prefix_len);
+                match within {
+                    Some(prefixes) if prefixes.is_empty() => {
+                        let _ = writeln!(out, "{} = 1w0;", pass);
+                    }
+                    Some(prefixes) => {
+                        let table = format!("{}refine_{}", prefix, position);
+                        let mut entries = Vec::new();
+                        for text in prefixes {
+                            let (network, len) = parse_prefix(text)
+                                .ok_or_else(|| format!("RefinePrefix: '{}' is not an IPv4 prefix", text))?;
+                            entries.push(format!("32w{:#010x} &&& 32w{:#010x}", u32::from(network), prefix_mask(len)));
+                        }
+                        let key = self.lvalue(&mut out, prefix, &field);
+                        self.match_table(&table, &pass, &[(key, "lpm")], &entries);
+                        let _ = writeln!(out, "{}.apply();", table);
+                    }
+                    None => {}
+                }
+                let target = self.declare(&format!("{}{}", prefix, field_name), 32);
+                let _ = writeln!(out, "{} = {} & 32w{:#010x};", target, field.expr, mask);
+                for field in record.iter_mut().filter(|field| field.name == *field_name) {
+                    field.expr = target.clone();
+                }
+            }
+            Operation::FilterResult { threshold, field_name, .. } => {
+                let field = find(record, field_name)?;
+                let _ = writeln!(out, "if ({} < 32w{}) {{\n    {} = 1w0;\n}}", field.expr, threshold, pass);
+                *condition = Some(format!("{} >= {}", field_name, threshold));
+            }
+            Operation::Cardinality { keys, statistic_type, .. }
+            | Operation::Entropy { keys, statistic_type, .. }
+            | Operation::FlowSizeDistribution { keys, statistic_type, .. } => {
+                self.declarations.push('\n');
+                let key = key_tuple(self, &mut out, record, keys)?;
+                let sketch = format!("{}statistic_{}", prefix, position);
+                match statistic_type {
+                    // Byte counters, as in the light part, saturating at 255.
+                    StatisticType::LightPart { memory_in_bytes, seed } => {
+                        self.register(&sketch, *memory_in_bytes, 8);
+                        let index = self.declare(&format!("{}_index", sketch), 32);
+                        let count = self.declare(&format!("{}_count", sketch), 8);
+                        self.hash(&mut out, &index, *seed, &key, *memory_in_bytes);
+                        let _ = writeln!(out, "{}.read({}, {});", sketch, count, index);
+                        let _ = writeln!(out, "if ({} < 8w255) {{\n    {}.write({}, {} + 8w1);\n}}", count, sketch, index, count);
+                    }
+                    StatisticType::FCMSketch { depth, width_l1, width_l2, width_l3, threshold_l1, threshold_l2, seed, .. } => {
+                        for tree in 0..*depth {
+                            self.fcm_tree(
+                                &mut out,
+                                &format!("{}_tree{}", sketch, tree),
+                                (*width_l1, *width_l2, *width_l3),
+                                (*threshold_l1, *threshold_l2),
+                                seed + tree as u64,
+                                &key,
+                                "32w1",
+                            );
+                        }
+                    }
+                    StatisticType::Exact => return Err("exact statistics cannot run on the switch".to_string()),
+                }
+            }
+            other => return Err(format!("{:?} cannot run on the switch", other)),
+        }
+        Ok(out)
+    }
+
+    // Emits the switch part of one plan (the query, or one side of a join) and what it
+    // sends to the control plane. A side of a join starts from the record and pass flag
+    // of the operators before the join.
+    fn part<'a>(
+        &mut self,
+        query: &QueryPlan,
+        placements: &mut impl Iterator<Item = &'a Placement>,
+        prefix: &str,
+        input: Option<(&[RecordField], &str)>,
+    ) -> Result<(), String> {
+        let part = if prefix.is_empty() { "query".to_string() } else { prefix.trim_end_matches('_').to_string() };
+        let pass = format!("meta.{}pass", prefix);
+        let mut record = input.map_or_else(packet_record, |(record, _)| record.to_vec());
+        let mut condition = None;
+        let mut on_switch = 0;
+        let mut offloaded = false;
+        let mut joined = false;
+        // Written before the first statement of the part, so a query that starts with a
+        // join emits nothing of its own.
+        let mut started = false;
+        let mut start = |generator: &mut Generator| {
+            if !std::mem::replace(&mut started, true) {
+                generator.declare(&format!("{}pass", prefix), 1);
+                let initial = input.map_or("1w1", |(_, parent_pass)| parent_pass);
+                let _ = writeln!(generator.apply, "\n// Part: {}\n{} = {};", part, pass, initial);
+            }
+        };
+
+        for (i, op) in query.operations.iter().enumerate() {
+            let placement = placements.next().ok_or_else(|| "partition does not match the plan".to_string())?;
+            match &placement.location {
+                Location::DataPlane(_) if !offloaded => {
+                    start(self);
+                    let statements = self.operator(op, i + 1, prefix, &mut record, &mut condition)?;
+                    let _ = writeln!(self.apply, "// {}. {}", i + 1, placement.operation);
+                    if on_switch == 0 && input.is_none() {
+                        self.apply.push_str(&statements);
+                    } else {
+                        let _ = writeln!(self.apply, "if ({} == 1w1) {{\n{}}}", pass, indent(&statements, 4));
+                    }
+                    on_switch += 1;
+                }
+                Location::DataPlane(_) => unreachable!("operators after the stream processor stay there"),
+                Location::StreamProcessor(reason) => {
+                    let evaluated = !offloaded;
+                    offloaded = true;
+                    self.spec.stream_processor.push(StreamOperatorSpec {
+                        part: part.clone(),
+                        position: placement.position,
+                        operation: placement.operation.clone(),
+                        reason: reason.clone(),
+                    });
+                    if let (true, Operation::Join { left_query, right_query, .. }) = (evaluated, op) {
+                        let parent = (on_switch > 0 || input.is_some()).then_some((record.as_slice(), pass.as_str()));
+                        self.part(left_query, placements, &format!("{}left_", prefix), parent)?;
+                        self.part(right_query, placements, &format!("{}right_", prefix), parent)?;
+                        joined = true;
+                    }
+                }
+            }
+        }
+        // The join's sides send their own records; statistics finishing the query are read
+        // from their registers.
+        let statistics = matches!(
+            query.operations.last(),
+            Some(Operation::Cardinality { .. } | Operation::Entropy { .. } | Operation::FlowSizeDistribution { .. })
+        );
+        if joined || (statistics && !offloaded) {
+            return Ok(());
+        }
+
+        start(self);
+        if on_switch == 0 {
+            let session = self.spec.mirror_sessions.len() as u32 + 1;
+            match input {
+                Some(_) => {
+                    let _ = writeln!(self.apply, "if ({} == 1w1) {{\n    clone(CloneType.I2E, 32w{});\n}}", pass, session);
+                }
+                None => {
+                    let _ = writeln!(self.apply, "clone(CloneType.I2E, 32w{});", session);
+                }
+            }
+            self.spec.mirror_sessions.push(MirrorSpec { session, part });
+            return Ok(());
+        }
+
+        let name = format!("{}report_t", prefix);
+        let mut fields = Vec::new();
+        let mut exprs = String::new();
+        for field in &record {
+            let expr = self.lvalue(&mut exprs, prefix, field);
+            fields.push((field.name.clone(), field.bits, expr));
+        }
+        self.apply.push_str(&exprs);
+        let _ = writeln!(
+            self.apply,
+            "if ({} == 1w1) {{\n    digest<{}>(32w1, {{ {} }});\n}}",
+            pass,
+            name,
+            fields.iter().map(|(_, _, expr)| expr.as_str()).collect::<Vec<_>>().join(", ")
+        );
+        self.spec.digests.push(DigestSpec {
+            name,
+            part,
+            fields: fields.into_iter().map(|(name, bits, _)| FieldSpec { name, bits }).collect(),
+            condition,
+        });
+        Ok(())
+    }
+}
+
+fn indent(text: &str, spaces: usize) -> String {
+    let pad = " ".repeat(spaces);
+    text.lines()
+        .map(|line| if line.is_empty() { "\n".to_string() } else { format!("{}{}\n", pad, line) })
+        .collect()
+}
+
+/// P4_16 source for the data-plane part of `query` on `model`, and its control-plane spec.
+pub fn generate(query: &QueryPlan, query_name: &str, model: TargetModel) -> Result<(String, String), String> {
+    let partition = partition(query, model)?;
+    let mut generator = Generator {
+        metadata: Vec::new(),
+        declarations: String::new(),
+        apply: String::new(),
+        spec: ControlPlaneSpec {
+            query: query_name.to_string(),
+            target: "v1model".to_string(),
+            digests: Vec::new(),
+            mirror_sessions: Vec::new(),
+            epoch_registers: Vec::new(),
+            stream_processor: Vec::new(),
+        },
+    };
+    generator.part(query, &mut partition.placements.iter(), "", None)?;
+
+    let mut source = String::new();
+    let _ = writeln!(source, "// Data-plane part of query {}, generated for v1model.", query_name);
+    let _ = writeln!(
+        source,
+        "// Uses {} of {} stages and {} bytes of register memory. Registers are cleared by the",
+        partition.stages_used, model.stages, partition.sram_bytes
+    );
+    source.push_str("// control plane at the end of every epoch.\n\n");
+    source.push_str("#include <core.p4>\n#include <v1model.p4>\n\n");
+    source.push_str(HEADERS);
+    source.push_str("\nstruct metadata_t {\n");
+    for (name, bits) in &generator.metadata {
+        let _ = writeln!(source, "    bit<{}> {};", bits, name);
+    }
+    source.push_str("}\n");
+    for digest in &generator.spec.digests {
+        let _ = writeln!(source, "\nstruct {} {{", digest.name);
+        for field in &digest.fields {
+            let _ = writeln!(source, "    bit<{}> {};", field.bits, identifier(&field.name));
+        }
+        source.push_str("}\n");
+    }
+    source.push('\n');
+    source.push_str(PARSER);
+    source.push_str(
+        "\ncontrol TelemetryIngress(inout headers_t hdr, inout metadata_t meta,\n                         inout standard_metadata_t standard_metadata) {\n",
+    );
+    source.push_str(&generator.declarations);
+    source.push_str("\n    apply {\n        if (hdr.tcp.isValid()) {");
+    source.push_str(&indent(&generator.apply, 12));
+    source.push_str("        }\n    }\n}\n\n");
+    source.push_str(TRAILER);
+
+    let spec = serde_json::to_string_pretty(&generator.spec).map_err(|e| e.to_string())? + "\n";
+    Ok((source, spec))
+}
+
+/// Writes the generated program and spec, or with `check`, compares them with the files
+/// already at those paths.
+pub fn run_p4gen(
+    query: &QueryPlan,
+    query_name: &str,
+    model: TargetModel,
+    output: Option<&str>,
+    spec_path: Option<&str>,
+    check: bool,
+) -> Result<(), String> {
+    let (source, spec) = generate(query, query_name, model)?;
+    let outputs = [(output, source), (spec_path, spec)];
+    if check {
+        for (path, generated) in &outputs {
+            let Some(path) = path else { continue };
+            let expected = fs::read_to_string(path).map_err(|e| format!("Cannot read {}: {}", path, e))?;
+            if expected != *generated {
+                return Err(format!("{} differs from the generated output", path));
+            }
+            println!("{} matches", path);
+        }
+        return Ok(());
+    }
+    for (path, generated) in &outputs {
+        match path {
+            Some(path) => {
+                fs::write(path, generated).map_err(|e| format!("Cannot write {}: {}", path, e))?;
+                println!("Written to {}", path);
+            }
+            None if output.is_none() => print!("{}", generated),
+            None => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plan_file::load_plan;
+    use std::path::Path;
+
+    // The golden files are generated with the default switch of `src/.env`.
+    const GOLDEN_MODEL: TargetModel = TargetModel {
+        stages: 12,
+        sram_per_stage: 1_310_720,
+        stateful_alus_per_stage: 4,
+        hash_units_per_stage: 6,
+        phv_bits: 4096,
+    };
+
+    #[test]
+    fn golden_plans_generate_their_checked_in_output() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let mut plans: Vec<String> = fs::read_dir(root.join("golden/p4"))
+            .unwrap()
+            .filter_map(|entry| entry.unwrap().file_name().into_string().ok())
+            .filter(|name| name.ends_with(".plan.json"))
+            .collect();
+        plans.sort();
+        assert!(!plans.is_empty(), "no plans in golden/p4");
+
+        for plan in plans {
+            // Named as the README's loop names them, relative to the repository root.
+            let name = format!("golden/p4/{}", plan);
+            let query = load_plan(&root.join(&name).to_string_lossy()).unwrap();
+            let (source, spec) = generate(&query, &name, GOLDEN_MODEL).unwrap();
+            let stem = name.strip_suffix(".plan.json").unwrap();
+            for (extension, generated) in [("p4", source), ("json", spec)] {
+                let path = format!("{}.{}", stem, extension);
+                let expected = fs::read_to_string(root.join(&path)).unwrap();
+                assert!(
+                    expected == generated,
+                    "{} differs from the generated output; regenerate it with p4gen (see README)",
+                    path
+                );
+            }
+        }
+    }
+}
@@ -1,192 +1,340 @@
-use std::fs::OpenOptions;
-use std::io::Write;
 use lazy_static::lazy_static;
 use std::sync::Mutex;
 use std::time::Instant;
-use crate::sketch::Sketch;
-use crate::query_plan::QueryPlan;
-use crate::query_executor::{PacketField, execute_query};
+use crate::sketch::{Estimate, Sketch};
+use crate::query_executor::{PacketField, EpochStatistic, CompiledPlan, Sharding, execute_query, finalize_epoch};
+use crate::flow_key::encode_key;
+use crate::alerting::{AlertConfig, AlertManager};
+use crate::metrics_server;
+use crate::output_sink::{EpochReport, OutputFormat, OutputSink, PerformanceMetrics};
+use crate::record::Record;
+use crate::bobhash32::BOBHash32;
+use crate::sketch_store::save_epoch;
+use crate::checkpoint::{load_checkpoint, save_checkpoint, RunProgress};
 use pcap::Capture;
 use pnet::packet::{Packet, ethernet::EthernetPacket, ipv4::Ipv4Packet, tcp::TcpPacket};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread;
 
 lazy_static! {
-    pub static ref EPOCH_RESULTS: Mutex<Vec<HashMap<String, PacketField>>> = Mutex::new(Vec::new());
+    pub static ref EPOCH_RESULTS: Mutex<Vec<Record>> = Mutex::new(Vec::new());
 }
 
+// Packets handed to a worker per channel send.
+const WORKER_BATCH_SIZE: usize = 1024;
+// Seed of the shard hash, kept apart from the seeds the sketches use.
+const SHARD_HASH_SEED: u32 = 1000;
 
-/// Initializes and returns a writable log file.
-fn initialize_log_file(path: &str) -> std::fs::File {
-    OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(path)
-        .expect("Cannot open log file")
+enum WorkerMessage {
+    Packets { epoch_start: u64, packets: Vec<(Record, u64)> },
+    EndEpoch,
 }
 
-/// Extracts a packet tuple as a `HashMap`.
-fn extract_packet_tuple(packet: &pcap::Packet) -> Option<HashMap<String, PacketField>> {
+// Result map, sketches, whether the packet closing the epoch made it through the whole
+// plan (and so ran `FilterResult`, as it would in the single-threaded loop), and the keys
+// the last `Reduce` updated when the epoch is archived.
+type WorkerState = (HashMap<Vec<u8>, Record>, HashMap<String, Sketch>, bool, HashSet<Vec<u8>>);
+
+
+/// Extracts a packet tuple as a `Record` laid out according to `Schema::packet`.
+pub fn extract_packet_tuple(packet: &pcap::Packet) -> Option<Record> {
     let ethernet = EthernetPacket::new(packet.data)?;
     let ipv4 = Ipv4Packet::new(ethernet.payload())?;
 
     if ipv4.get_next_level_protocol() == pnet::packet::ip::IpNextHeaderProtocols::Tcp {
         let tcp = TcpPacket::new(ipv4.payload())?;
-        let mut packet_map = HashMap::new();
-        packet_map.insert("src_ip".to_string(), PacketField::String(ipv4.get_source().to_string()));
-        packet_map.insert("dst_ip".to_string(), PacketField::String(ipv4.get_destination().to_string()));
-        packet_map.insert("src_port".to_string(), PacketField::U16(tcp.get_source()));
-        packet_map.insert("dst_port".to_string(), PacketField::U16(tcp.get_destination()));
-        packet_map.insert("tcp_flags".to_string(), PacketField::U8(tcp.get_flags()));
-        packet_map.insert("total_len".to_string(), PacketField::U32(ipv4.get_total_length() as u32));
-        packet_map.insert("protocol".to_string(), PacketField::U8(ipv4.get_next_level_protocol().0));
-        packet_map.insert("dns_ns_type".to_string(), PacketField::OptionU16(None));
-        // println!("Extracted packet tuple: {:?}", packet_map); // Debugging statement
-        Some(packet_map)
+        let mut record = Record::default();
+        record.set(0, PacketField::Ipv4(ipv4.get_source()));
+        record.set(1, PacketField::Ipv4(ipv4.get_destination()));
+        record.set(2, PacketField::U16(tcp.get_source()));
+        record.set(3, PacketField::U16(tcp.get_destination()));
+        record.set(4, PacketField::U8(tcp.get_flags()));
+        record.set(5, PacketField::U32(ipv4.get_total_length() as u32));
+        record.set(6, PacketField::U8(ipv4.get_next_level_protocol().0));
+        record.set(7, PacketField::OptionU16(None));
+        // println!("Extracted packet tuple: {:?}", record); // Debugging statement
+        Some(record)
     } else {
         // println!("Non-TCP packet encountered, skipping.");
         None
     }
 }
 
-/// Prints and logs the epoch summary with all src_ip counts exceeding the threshold.
+/// Prints and writes the epoch summary with every key whose value exceeds the threshold,
+/// each with the bounds its sketch guarantees and whether it is definitely above it.
+#[allow(clippy::too_many_arguments)]
 fn print_epoch_summary(
+    epoch: usize,
     timestamp: u64,
     epoch_packets: usize,
     total_packets: usize,
-    result_map: &HashMap<String, HashMap<String, PacketField>>, // Updated type
-    log_file: &mut std::fs::File,
-    field_name: &str,
-    threshold: u32, // Add threshold as a parameter
+    result_map: &HashMap<Vec<u8>, Record>,
+    sketches: &HashMap<String, Sketch>,
+    plan: &CompiledPlan,
+    sink: &mut OutputSink,
+    threshold: u64,
+    statistics: &[(String, EpochStatistic)],
 ) {
     println!("Logging epoch summary...");
-    let mut summary = format!(
-        "\n=== EPOCH SUMMARY ===\nEpoch end timestamp: {}\nPackets processed this epoch: {}\nTotal packets processed: {}\n",
-        timestamp, epoch_packets, total_packets
-    );
+    let value_slot = plan.value_slot();
+    let bound = value_slot.and_then(|slot| plan.value_error_bound(slot, sketches));
 
-    // Add the header for the table
-    summary.push_str("flow_key, value\n");
-
-    // Collect entries, filter by threshold, and sort them by the specified field in descending order
-    let mut entries: Vec<(String, u32)> = result_map
+    // Collect entries above the threshold, sorted by value in descending order, then by key
+    let mut rows: Vec<(&[u8], &Record, Estimate)> = result_map
         .iter()
-        .filter_map(|(key, fields)| {
-            if let Some(PacketField::U32(value)) = fields.get(field_name) {
-                if *value > threshold as u32 {
-                    Some((key.clone(), *value)) // Include only if value > threshold
-                } else {
-                    None
-                }
-            } else {
-                None
-            }
+        .filter_map(|(key, record)| {
+            let value = record.get(value_slot?)?.as_u64()?;
+            (value > threshold).then(|| (key.as_slice(), record, Estimate::new(value, bound)))
         })
         .collect();
-
-    entries.sort_by(|a, b| b.1.cmp(&a.1)); // Sort by value in descending order
-
-    // Format the sorted entries
-    for (key, value) in &entries {
-        summary.push_str(&format!("{}, {}\n", key, value));
+    rows.sort_by(|a, b| b.2.value.cmp(&a.2.value).then_with(|| a.0.cmp(b.0)));
+
+    let report = EpochReport {
+        epoch,
+        epoch_end: timestamp,
+        epoch_packets,
+        total_packets,
+        threshold,
+        bound,
+        result_key: &plan.result_key,
+        rows,
+        statistics,
+    };
+    metrics_server::publish_epoch(&report, sketches);
+    match sink.write_epoch(&report) {
+        Ok(()) => println!("Successfully logged epoch summary."), // Debugging statement
+        Err(e) => eprintln!("Failed to write epoch summary: {}", e),
     }
+}
 
-    if entries.is_empty() {
-        summary.push_str("No entries exceeded the threshold.\n");
+/// Execution options that do not change the query's results.
+pub struct RunOptions {
+    pub num_workers: usize,
+    /// Directory for per-epoch sketch snapshots.
+    pub sketch_dir: Option<String>,
+    pub checkpoint_path: Option<String>,
+    /// Packets between two checkpoints.
+    pub checkpoint_interval: usize,
+    /// Continue from the checkpoint at `checkpoint_path` instead of starting over.
+    pub resume: bool,
+    pub output_format: OutputFormat,
+    pub output_path: String,
+    /// Alerting on the query's `FilterResult` output; `None` disables it.
+    pub alerts: Option<AlertConfig>,
+}
+
+// Opens the configured alert sinks; a sink that cannot be opened disables alerting.
+fn open_alerts(options: &RunOptions) -> Option<AlertManager> {
+    match AlertManager::new(options.alerts.as_ref()?) {
+        Ok(alerts) => Some(alerts),
+        Err(e) => {
+            eprintln!("Alerting disabled: {}", e);
+            None
+        }
     }
+}
 
-    // Write the summary to the log file
-    if let Err(e) = writeln!(log_file, "{}", summary.trim_end()) {
-        eprintln!("Failed to write to log file: {}", e);
-    } else {
-        println!("Successfully logged epoch summary."); // Debugging statement
+// Opens the configured sink; a resumed run appends to it.
+fn open_sink(query: &CompiledPlan, options: &RunOptions) -> Option<OutputSink> {
+    match OutputSink::open(options.output_format, &options.output_path, &query.result_schema, options.resume) {
+        Ok(sink) => Some(sink),
+        Err(e) => {
+            eprintln!("{}", e);
+            None
+        }
     }
 }
 
 /// Processes the PCAP file and executes the specified query in a feed forwarding manner.
-pub fn process_pcap(file_path: &str, epoch_size: u64, threshold: usize, query: QueryPlan) {
+pub fn process_pcap(file_path: &str, epoch_size: u64, threshold: usize, query: CompiledPlan, options: &RunOptions) {
+    let checkpoint = match (options.resume, &options.checkpoint_path) {
+        (false, _) => None,
+        (true, None) => {
+            eprintln!("--resume needs CHECKPOINT_PATH to be set");
+            return;
+        }
+        (true, Some(path)) => match load_checkpoint(path, file_path, &query) {
+            Ok(checkpoint) => Some(checkpoint),
+            Err(e) => {
+                eprintln!("Cannot resume from {}: {}", path, e);
+                return;
+            }
+        },
+    };
+
+    if options.num_workers > 1 {
+        match query.sharding() {
+            // Workers start empty, so only checkpoints without open epoch state can be sharded.
+            Ok(_) if checkpoint.as_ref().is_some_and(|c| !c.at_epoch_boundary()) => {
+                println!("Checkpoint holds an open epoch, running single-threaded.")
+            }
+            Ok(sharding) => {
+                let progress = checkpoint.map(|c| c.restore().0).unwrap_or_default();
+                return process_pcap_sharded(file_path, epoch_size, threshold, &query, options, sharding, progress);
+            }
+            Err(e) => println!("Query cannot be sharded ({}), running single-threaded.", e),
+        }
+    }
+
     println!("Starting packet processing...");
     let mut cap = Capture::from_file(file_path).expect("Failed to open PCAP file");
-    let mut sketches: HashMap<String, Sketch> = HashMap::new();
-    let mut log_file = initialize_log_file("telemetry_log.csv");
-    let mut result_map: HashMap<String, HashMap<String, PacketField>> = HashMap::new();
-
-    let mut total_packets = 0;
-    let mut epoch_packets = 0;
-    let mut current_epoch_start: Option<u64> = None;
-    let mut epoch_count = 0;
+    let mut sink = match open_sink(&query, options) {
+        Some(sink) => sink,
+        None => return,
+    };
+    let mut alerts = open_alerts(options);
+    let (mut progress, mut sketches, mut result_map) = match checkpoint {
+        Some(checkpoint) => checkpoint.restore(),
+        None => (RunProgress::default(), HashMap::new(), HashMap::new()),
+    };
+    skip_packets(&mut cap, progress.total_packets);
+    let sketch_dir = options.sketch_dir.as_deref();
+    // Keys archived with the epoch. A checkpoint is taken before the epoch's FilterResult,
+    // so every key updated before it is still in the result map.
+    let mut epoch_keys: HashSet<Vec<u8>> = result_map.keys().cloned().collect();
 
     // Start the timer
     let start_time = Instant::now();
+    let resumed = progress;
 
     while let Ok(packet) = cap.next_packet() {
         let packet_timestamp = packet.header.ts.tv_sec as u64;
-        current_epoch_start.get_or_insert(packet_timestamp);
+        progress.current_epoch_start.get_or_insert(packet_timestamp);
 
 
-        if let Some(packet_info) = extract_packet_tuple(&packet) {
-            execute_query(
+        let parsed = extract_packet_tuple(&packet);
+        metrics_server::record_packet(parsed.is_some());
+        if let Some(packet_info) = parsed {
+            let output = execute_query(
                 &query,
                 packet_info,
                 &mut sketches,
                 &mut result_map,
                 epoch_size,  
-                &mut current_epoch_start,  
+                &mut progress.current_epoch_start,  
                 packet_timestamp,         
             );
+            if let (Some(record), Some(_)) = (&output, sketch_dir) {
+                query.record_result_key(record, &mut epoch_keys);
+            }
         }
 
-        if packet_timestamp - current_epoch_start.unwrap() >= epoch_size {
+        if packet_timestamp - progress.current_epoch_start.unwrap() >= epoch_size {
             
             // Trigger the join operation before updating the epoch start time
-            epoch_count += 1;
+            progress.epoch_count += 1;
 
 
 
+            let statistics = finalize_epoch(&query, &sketches);
+
             // Print and log the epoch summary
             print_epoch_summary(
+                progress.epoch_count,
                 packet_timestamp,
-                epoch_packets,
-                total_packets,
+                progress.epoch_packets,
+                progress.total_packets,
                 &result_map,
-                &mut log_file,
-                "count", // Field name to check against the threshold
-                threshold as u32,
+                &sketches,
+                &query,
+                &mut sink,
+                threshold as u64,
+                &statistics,
             );
+            archive_epoch(sketch_dir, progress.epoch_count, packet_timestamp, &query, &epoch_keys, &result_map, &sketches);
+            if let Some(alerts) = alerts.as_mut() {
+                alerts.process_epoch(progress.epoch_count, packet_timestamp, &query, &result_map, &sketches);
+            }
 
             // Clear sketches and result map for the new epoch
             sketches.values_mut().for_each(|sketch| sketch.clear());
             result_map.clear();
-            epoch_packets = 0;
+            epoch_keys.clear();
+            progress.epoch_packets = 0;
 
             // Update the epoch start time
-            current_epoch_start = Some(packet_timestamp);
+            progress.current_epoch_start = Some(packet_timestamp);
         }
 
 
 
-        total_packets += 1;
-        epoch_packets += 1;
+        progress.total_packets += 1;
+        progress.epoch_packets += 1;
+
+        if let Some(path) = &options.checkpoint_path {
+            if progress.total_packets % options.checkpoint_interval == 0 {
+                checkpoint_run(path, file_path, &query, &progress, &sketches, &result_map);
+            }
+        }
     }
 
     // Final epoch summary for any remaining packets
-if let Some(epoch_start) = current_epoch_start {
-    if epoch_packets > 0 {
-        epoch_count += 1;
+if let Some(epoch_start) = progress.current_epoch_start {
+    if progress.epoch_packets > 0 {
+        progress.epoch_count += 1;
 
+        let statistics = finalize_epoch(&query, &sketches);
 
         print_epoch_summary(
+            progress.epoch_count,
             epoch_start,
-            epoch_packets,
-            total_packets,
+            progress.epoch_packets,
+            progress.total_packets,
             &result_map,
-            &mut log_file,
-            "count", // Field name to check against the threshold
-            threshold as u32,
+            &sketches,
+            &query,
+            &mut sink,
+            threshold as u64,
+            &statistics,
         );
+        archive_epoch(sketch_dir, progress.epoch_count, epoch_start, &query, &epoch_keys, &result_map, &sketches);
+        if let Some(alerts) = alerts.as_mut() {
+            alerts.process_epoch(progress.epoch_count, epoch_start, &query, &result_map, &sketches);
+        }
     }
 }
 
+    log_performance_metrics(
+        &mut sink,
+        progress.total_packets - resumed.total_packets,
+        progress.epoch_count - resumed.epoch_count,
+        start_time,
+    );
+    if let Err(e) = sink.finish() {
+        eprintln!("Failed to close {}: {}", options.output_path, e);
+    }
+}
+
+// Fast-forwards a resumed capture past the packets already processed.
+fn skip_packets(cap: &mut Capture<pcap::Offline>, count: usize) {
+    if count == 0 {
+        return;
+    }
+    println!("Resuming after packet {}...", count);
+    for _ in 0..count {
+        if cap.next_packet().is_err() {
+            eprintln!("Trace ended before the checkpoint offset");
+            break;
+        }
+    }
+}
+
+fn checkpoint_run(
+    path: &str,
+    file_path: &str,
+    query: &CompiledPlan,
+    progress: &RunProgress,
+    sketches: &HashMap<String, Sketch>,
+    result_map: &HashMap<Vec<u8>, Record>,
+) {
+    match save_checkpoint(path, file_path, query, progress, sketches, result_map) {
+        Ok(()) => println!("Checkpoint written after packet {}", progress.total_packets),
+        Err(e) => eprintln!("Failed to write checkpoint: {}", e),
+    }
+}
+
+fn log_performance_metrics(sink: &mut OutputSink, total_packets: usize, epoch_count: usize, start_time: Instant) {
     // Stop the timer
     let elapsed_time = start_time.elapsed();
     let elapsed_seconds = elapsed_time.as_secs_f64();
@@ -199,10 +347,433 @@ if let Some(epoch_start) = current_epoch_start {
     println!("Average packets per second: {:.2}", packets_per_second);
     println!("Average packets per epoch: {:.2}", average_packets_per_epoch);
 
-    if let Err(e) = writeln!(log_file, "\n=== PERFORMANCE METRICS ===\nTotal packets processed: {}\nElapsed time: {:.2} seconds\nAverage packets per second: {:.2}\nAverage packets per epoch: {:.2}\n",
-        total_packets, elapsed_seconds, packets_per_second, average_packets_per_epoch) {
-        eprintln!("Failed to write performance metrics to log file: {}", e);
-    } else {
-        println!("Successfully wrote performance metrics to log file.");
+    let metrics = PerformanceMetrics { total_packets, elapsed_seconds, packets_per_second, average_packets_per_epoch };
+    metrics_server::publish_run_metrics(&metrics);
+    match sink.write_metrics(&metrics) {
+        Ok(true) => println!("Successfully wrote performance metrics to log file."),
+        Ok(false) => {}
+        Err(e) => eprintln!("Failed to write performance metrics to log file: {}", e),
+    }
+}
+
+/// Runs the query on a pool of worker threads fed by this (reader) thread. At every
+/// epoch boundary the workers hand back their result maps and sketches, which are merged
+/// and summarized exactly as in the single-threaded loop.
+fn process_pcap_sharded(
+    file_path: &str,
+    epoch_size: u64,
+    threshold: usize,
+    query: &CompiledPlan,
+    options: &RunOptions,
+    sharding: Sharding,
+    mut progress: RunProgress,
+) {
+    let num_workers = options.num_workers;
+    let sketch_dir = options.sketch_dir.as_deref();
+    println!("Starting packet processing with {} workers ({:?})...", num_workers, sharding);
+    let mut cap = Capture::from_file(file_path).expect("Failed to open PCAP file");
+    let mut sink = match open_sink(query, options) {
+        Some(sink) => sink,
+        None => return,
+    };
+    let mut alerts = open_alerts(options);
+    let shard_hash = BOBHash32::new(SHARD_HASH_SEED);
+
+    skip_packets(&mut cap, progress.total_packets);
+
+    let start_time = Instant::now();
+    let resumed = progress;
+
+    thread::scope(|scope| {
+        let (state_tx, state_rx) = sync_channel::<(usize, WorkerState)>(num_workers);
+        let mut senders: Vec<SyncSender<WorkerMessage>> = Vec::with_capacity(num_workers);
+        for worker_id in 0..num_workers {
+            let (tx, rx) = sync_channel(64);
+            let state_tx = state_tx.clone();
+            let track_keys = sketch_dir.is_some();
+            scope.spawn(move || run_worker(worker_id, query, epoch_size, track_keys, rx, state_tx));
+            senders.push(tx);
+        }
+
+        let mut batches: Vec<Vec<(Record, u64)>> = vec![Vec::with_capacity(WORKER_BATCH_SIZE); num_workers];
+        let mut next_worker = 0;
+        let mut last_checkpoint = progress.total_packets;
+
+        while let Ok(packet) = cap.next_packet() {
+            let packet_timestamp = packet.header.ts.tv_sec as u64;
+            let epoch_start = *progress.current_epoch_start.get_or_insert(packet_timestamp);
+            let mut epoch_ended = false;
+
+            let parsed = extract_packet_tuple(&packet);
+            metrics_server::record_packet(parsed.is_some());
+            if let Some(packet_info) = parsed {
+                let worker = pick_worker(&sharding, &shard_hash, &packet_info, num_workers, &mut next_worker);
+                batches[worker].push((packet_info, packet_timestamp));
+                if batches[worker].len() == WORKER_BATCH_SIZE {
+                    send_batch(&senders[worker], epoch_start, &mut batches[worker]);
+                }
+            }
+
+            if packet_timestamp - epoch_start >= epoch_size {
+                progress.epoch_count += 1;
+
+                let (mut result_map, sketches, closed, epoch_keys) =
+                    collect_epoch(query, &senders, &state_rx, epoch_start, &mut batches, sketch_dir.is_some());
+                // The packet closing the epoch applies FilterResult to the whole map.
+                if closed {
+                    query.apply_epoch_filters(&mut result_map, &sketches);
+                }
+                let statistics = finalize_epoch(query, &sketches);
+
+                print_epoch_summary(
+                    progress.epoch_count,
+                    packet_timestamp,
+                    progress.epoch_packets,
+                    progress.total_packets,
+                    &result_map,
+                    &sketches,
+                    query,
+                    &mut sink,
+                    threshold as u64,
+                    &statistics,
+                );
+                archive_epoch(sketch_dir, progress.epoch_count, packet_timestamp, query, &epoch_keys, &result_map, &sketches);
+                if let Some(alerts) = alerts.as_mut() {
+                    alerts.process_epoch(progress.epoch_count, packet_timestamp, query, &result_map, &sketches);
+                }
+                progress.epoch_packets = 0;
+                progress.current_epoch_start = Some(packet_timestamp);
+                epoch_ended = true;
+            }
+
+            progress.total_packets += 1;
+            progress.epoch_packets += 1;
+
+            // Workers hold no state right after an epoch was collected, so checkpoints are
+            // taken there once the interval has passed.
+            if let Some(path) = &options.checkpoint_path {
+                if epoch_ended && progress.total_packets - last_checkpoint >= options.checkpoint_interval {
+                    checkpoint_run(path, file_path, query, &progress, &HashMap::new(), &HashMap::new());
+                    last_checkpoint = progress.total_packets;
+                }
+            }
+        }
+
+        // Final epoch summary for any remaining packets
+        if let Some(epoch_start) = progress.current_epoch_start {
+            if progress.epoch_packets > 0 {
+                progress.epoch_count += 1;
+
+                let (result_map, sketches, _, epoch_keys) =
+                    collect_epoch(query, &senders, &state_rx, epoch_start, &mut batches, sketch_dir.is_some());
+                let statistics = finalize_epoch(query, &sketches);
+
+                print_epoch_summary(
+                    progress.epoch_count,
+                    epoch_start,
+                    progress.epoch_packets,
+                    progress.total_packets,
+                    &result_map,
+                    &sketches,
+                    query,
+                    &mut sink,
+                    threshold as u64,
+                    &statistics,
+                );
+                archive_epoch(sketch_dir, progress.epoch_count, epoch_start, query, &epoch_keys, &result_map, &sketches);
+                if let Some(alerts) = alerts.as_mut() {
+                    alerts.process_epoch(progress.epoch_count, epoch_start, query, &result_map, &sketches);
+                }
+            }
+        }
+        // Dropping the senders stops the workers.
+    });
+
+    log_performance_metrics(
+        &mut sink,
+        progress.total_packets - resumed.total_packets,
+        progress.epoch_count - resumed.epoch_count,
+        start_time,
+    );
+    if let Err(e) = sink.finish() {
+        eprintln!("Failed to close {}: {}", options.output_path, e);
     }
-}
\ No newline at end of file
+}
+
+/// Worker a packet goes to: by the hash of its key under key sharding, in turn otherwise.
+fn pick_worker(
+    sharding: &Sharding,
+    shard_hash: &BOBHash32,
+    packet: &Record,
+    num_workers: usize,
+    next_worker: &mut usize,
+) -> usize {
+    match sharding {
+        Sharding::ByKey(key) => shard_hash.run(encode_key(key, packet).as_bytes()) as usize % num_workers,
+        Sharding::RoundRobin => {
+            *next_worker = (*next_worker + 1) % num_workers;
+            *next_worker
+        }
+    }
+}
+
+fn send_batch(sender: &SyncSender<WorkerMessage>, epoch_start: u64, batch: &mut Vec<(Record, u64)>) {
+    let packets = std::mem::replace(batch, Vec::with_capacity(WORKER_BATCH_SIZE));
+    sender
+        .send(WorkerMessage::Packets { epoch_start, packets })
+        .expect("Worker thread exited early");
+}
+
+/// Flushes pending packets, ends the epoch on every worker and merges what they return.
+/// Result maps are disjoint under key sharding. Only sketches read at the end of the
+/// epoch are merged, unless `merge_all` asks for every sketch (to archive the epoch).
+fn collect_epoch(
+    query: &CompiledPlan,
+    senders: &[SyncSender<WorkerMessage>],
+    state_rx: &Receiver<(usize, WorkerState)>,
+    epoch_start: u64,
+    batches: &mut [Vec<(Record, u64)>],
+    merge_all: bool,
+) -> WorkerState {
+    for (sender, batch) in senders.iter().zip(batches.iter_mut()) {
+        if !batch.is_empty() {
+            send_batch(sender, epoch_start, batch);
+        }
+        sender.send(WorkerMessage::EndEpoch).expect("Worker thread exited early");
+    }
+
+    let mut states: Vec<Option<WorkerState>> = (0..senders.len()).map(|_| None).collect();
+    for _ in 0..senders.len() {
+        let (worker_id, state) = state_rx.recv().expect("Worker thread exited early");
+        states[worker_id] = Some(state);
+    }
+
+    let mut result_map = HashMap::new();
+    let mut sketches: HashMap<String, Sketch> = HashMap::new();
+    let mut closed = false;
+    let mut epoch_keys = HashSet::new();
+    let epoch_sketch_keys = query.epoch_sketch_keys();
+    // Merge in worker order so the result does not depend on which worker finished first.
+    for (worker_results, worker_sketches, worker_closed, worker_keys) in states.into_iter().flatten() {
+        result_map.extend(worker_results);
+        closed |= worker_closed;
+        epoch_keys.extend(worker_keys);
+        for (sketch_key, sketch) in worker_sketches {
+            if !merge_all && !epoch_sketch_keys.contains(&sketch_key) {
+                continue;
+            }
+            match sketches.get_mut(&sketch_key) {
+                Some(merged) => {
+                    if let Err(e) = merged.merge(&sketch) {
+                        eprintln!("Error merging sketch '{}': {}", sketch_key, e);
+                    }
+                }
+                None => {
+                    sketches.insert(sketch_key, sketch);
+                }
+            }
+        }
+    }
+    (result_map, sketches, closed, epoch_keys)
+}
+
+fn run_worker(
+    worker_id: usize,
+    query: &CompiledPlan,
+    epoch_size: u64,
+    track_keys: bool,
+    rx: Receiver<WorkerMessage>,
+    state_tx: SyncSender<(usize, WorkerState)>,
+) {
+    let mut sketches: HashMap<String, Sketch> = HashMap::new();
+    // A cleared copy of every sketch built so far, which each epoch starts from instead
+    // of building its sketches again.
+    let mut empty_sketches: HashMap<String, Sketch> = HashMap::new();
+    let mut result_map: HashMap<Vec<u8>, Record> = HashMap::new();
+    let mut closed = false;
+    let mut epoch_keys: HashSet<Vec<u8>> = HashSet::new();
+
+    for message in rx {
+        match message {
+            WorkerMessage::Packets { epoch_start, packets } => {
+                let mut current_epoch_start = Some(epoch_start);
+                for (packet_info, packet_timestamp) in packets {
+                    let output = execute_query(
+                        query,
+                        packet_info,
+                        &mut sketches,
+                        &mut result_map,
+                        epoch_size,
+                        &mut current_epoch_start,
+                        packet_timestamp,
+                    );
+                    if let (Some(record), true) = (&output, track_keys) {
+                        query.record_result_key(record, &mut epoch_keys);
+                    }
+                    closed |= output.is_some() && packet_timestamp - epoch_start >= epoch_size;
+                }
+            }
+            WorkerMessage::EndEpoch => {
+                for (sketch_key, sketch) in &sketches {
+                    if !empty_sketches.contains_key(sketch_key) {
+                        let mut empty = sketch.clone();
+                        empty.clear();
+                        empty_sketches.insert(sketch_key.clone(), empty);
+                    }
+                }
+                let state = (
+                    std::mem::take(&mut result_map),
+                    std::mem::replace(&mut sketches, empty_sketches.clone()),
+                    std::mem::take(&mut closed),
+                    std::mem::take(&mut epoch_keys),
+                );
+                if state_tx.send((worker_id, state)).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Saves the epoch's sketches when an archive directory is configured, with the keys the
+/// last `Reduce` updated whether or not they passed `FilterResult`.
+fn archive_epoch(
+    sketch_dir: Option<&str>,
+    epoch: usize,
+    epoch_end: u64,
+    query: &CompiledPlan,
+    epoch_keys: &HashSet<Vec<u8>>,
+    result_map: &HashMap<Vec<u8>, Record>,
+    sketches: &HashMap<String, Sketch>,
+) {
+    if let Some(dir) = sketch_dir {
+        if let Err(e) = save_epoch(dir, epoch, epoch_end, query, epoch_keys, result_map, sketches) {
+            eprintln!("Failed to save epoch sketches: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query_executor::compile_plan;
+    use crate::query_plan::{Aggregation, FilterMode, Operation, QueryPlan, ReduceType};
+    use std::net::Ipv4Addr;
+
+    const EPOCH_SIZE: u64 = 10;
+
+    // Per epoch, the result map and the estimate of the reduce sketch for each key seen.
+    type EpochOutput = (HashMap<Vec<u8>, Record>, Vec<(Vec<u8>, u64)>);
+
+    fn plan() -> CompiledPlan {
+        compile_plan(&QueryPlan {
+            operations: vec![
+                Operation::Map("(dst_ip, src_ip, count = 1)".to_string()),
+                Operation::Reduce {
+                    keys: vec!["dst_ip".to_string()],
+                    reduce_type: ReduceType::DeterministicReduce,
+                    field_name: "count".to_string(),
+                    aggregation: Aggregation::Sum,
+                },
+                Operation::FilterResult { threshold: 30, field_name: "count".to_string(), mode: FilterMode::Estimate },
+            ],
+        })
+        .unwrap()
+    }
+
+    // Three epochs of packets to 40 destinations, a few of them heavy.
+    fn packets() -> Vec<(Record, u64)> {
+        (0..3000u32)
+            .map(|i| {
+                let dst = if i % 4 == 0 { i % 5 } else { i % 40 };
+                let mut record = Record::default();
+                record.set(0, PacketField::Ipv4(Ipv4Addr::from(0x0a00_0000 + i)));
+                record.set(1, PacketField::Ipv4(Ipv4Addr::new(172, 16, 0, dst as u8)));
+                record.set(3, PacketField::U16(80));
+                (record, 1_700_000_000 + (i / 100) as u64)
+            })
+            .collect()
+    }
+
+    fn epoch_output(plan: &CompiledPlan, result_map: HashMap<Vec<u8>, Record>, sketches: &HashMap<String, Sketch>) -> EpochOutput {
+        let sketch = &sketches[plan.reduce_sketch_key().unwrap()];
+        let mut estimates: Vec<(Vec<u8>, u64)> = (0..40u8)
+            .map(|dst| {
+                let key = [172, 16, 0, dst].to_vec();
+                let estimate = sketch.estimate(&key);
+                (key, estimate)
+            })
+            .collect();
+        estimates.sort();
+        (result_map, estimates)
+    }
+
+    // The loop of `process_pcap`.
+    fn run_single(plan: &CompiledPlan, packets: &[(Record, u64)]) -> Vec<EpochOutput> {
+        let mut sketches = HashMap::new();
+        let mut result_map = HashMap::new();
+        let mut current_epoch_start = None;
+        let mut epochs = Vec::new();
+        for (packet, timestamp) in packets {
+            current_epoch_start.get_or_insert(*timestamp);
+            execute_query(plan, packet.clone(), &mut sketches, &mut result_map, EPOCH_SIZE, &mut current_epoch_start, *timestamp);
+            if timestamp - current_epoch_start.unwrap() >= EPOCH_SIZE {
+                epochs.push(epoch_output(plan, result_map.clone(), &sketches));
+                sketches.values_mut().for_each(|sketch| sketch.clear());
+                result_map.clear();
+                current_epoch_start = Some(*timestamp);
+            }
+        }
+        epochs
+    }
+
+    // The loop of `process_pcap_sharded`, with its workers.
+    fn run_sharded(plan: &CompiledPlan, packets: &[(Record, u64)], num_workers: usize) -> Vec<EpochOutput> {
+        let sharding = plan.sharding().unwrap();
+        let shard_hash = BOBHash32::new(SHARD_HASH_SEED);
+        thread::scope(|scope| {
+            let (state_tx, state_rx) = sync_channel::<(usize, WorkerState)>(num_workers);
+            let senders: Vec<SyncSender<WorkerMessage>> = (0..num_workers)
+                .map(|worker_id| {
+                    let (tx, rx) = sync_channel(64);
+                    let state_tx = state_tx.clone();
+                    scope.spawn(move || run_worker(worker_id, plan, EPOCH_SIZE, false, rx, state_tx));
+                    tx
+                })
+                .collect();
+
+            let mut batches: Vec<Vec<(Record, u64)>> = vec![Vec::new(); num_workers];
+            let mut next_worker = 0;
+            let mut current_epoch_start = None;
+            let mut epochs = Vec::new();
+            for (packet, timestamp) in packets {
+                let epoch_start = *current_epoch_start.get_or_insert(*timestamp);
+                let worker = pick_worker(&sharding, &shard_hash, packet, num_workers, &mut next_worker);
+                batches[worker].push((packet.clone(), *timestamp));
+                if timestamp - epoch_start >= EPOCH_SIZE {
+                    let (mut result_map, sketches, closed, _) =
+                        collect_epoch(plan, &senders, &state_rx, epoch_start, &mut batches, false);
+                    if closed {
+                        plan.apply_epoch_filters(&mut result_map, &sketches);
+                    }
+                    epochs.push(epoch_output(plan, result_map, &sketches));
+                    current_epoch_start = Some(*timestamp);
+                }
+            }
+            epochs
+        })
+    }
+
+    #[test]
+    fn sharded_epochs_match_single_threaded() {
+        let plan = plan();
+        let packets = packets();
+        let single = run_single(&plan, &packets);
+        assert_eq!(single.len(), 2);
+        // FilterResult keeps the five heavy destinations.
+        assert!(single.iter().all(|(result_map, _)| result_map.len() == 5));
+        for num_workers in [1, 2, 4] {
+            assert!(run_sharded(&plan, &packets, num_workers) == single, "{} workers", num_workers);
+        }
+    }
+}
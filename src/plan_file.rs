@@ -0,0 +1,300 @@
+// plan_file.rs
+// Query plans stored as JSON or YAML, so they can be written and shared without
+// rebuilding the binary.
+
+use std::fs;
+use serde::{Deserialize, Serialize};
+use crate::query_plan::{Operation, QueryPlan};
+
+/// Version of the plan file layout. Bump it whenever `Operation`, `ReduceType`,
+/// `StatisticType` or `Field` change in a way old files cannot be read with.
+pub const PLAN_FORMAT_VERSION: u32 = 1;
+
+/// ```yaml
+/// version: 1
+/// operations:
+///   - !Filter [[TcpFlag, "2"]]
+///   - !Map (dst_ip, count = 1)
+///   - !Reduce
+///     keys: [dst_ip]
+///     reduce_type: !CMReduce { memory_in_bytes: 65536, depth: 3, seed: 42 }
+///     field_name: count
+///   - !FilterResult { threshold: 40, field_name: count, mode: Estimate }
+/// ```
+///
+/// In JSON, each operation is an object with the variant as its only key.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PlanFile {
+    version: u32,
+    operations: Vec<Operation>,
+}
+
+// Read first, so a file from another version is reported as such rather than as
+// whichever operation no longer parses.
+#[derive(Deserialize)]
+struct PlanFileVersion {
+    version: Option<u32>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum PlanFormat {
+    Json,
+    Yaml,
+}
+
+impl PlanFormat {
+    fn from_path(path: &str) -> Result<Self, String> {
+        if path.ends_with(".json") {
+            Ok(PlanFormat::Json)
+        } else if path.ends_with(".yaml") || path.ends_with(".yml") {
+            Ok(PlanFormat::Yaml)
+        } else {
+            Err(format!("Plan file {} must end in .json, .yaml or .yml", path))
+        }
+    }
+}
+
+pub fn load_plan(path: &str) -> Result<QueryPlan, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("Cannot read {}: {}", path, e))?;
+    let format = PlanFormat::from_path(path)?;
+    let version: PlanFileVersion = match format {
+        PlanFormat::Json => serde_json::from_str(&text).map_err(|e| format!("Invalid plan {}: {}", path, e))?,
+        PlanFormat::Yaml => serde_yaml::from_str(&text).map_err(|e| format!("Invalid plan {}: {}", path, e))?,
+    };
+    match version.version {
+        Some(PLAN_FORMAT_VERSION) => {}
+        Some(version) => {
+            return Err(format!("{} has plan format version {}, expected {}", path, version, PLAN_FORMAT_VERSION));
+        }
+        None => return Err(format!("{} has no plan format version", path)),
+    }
+    let file: PlanFile = match format {
+        PlanFormat::Json => serde_json::from_str(&text).map_err(|e| format!("Invalid plan {}: {}", path, e))?,
+        PlanFormat::Yaml => serde_yaml::from_str(&text).map_err(|e| format!("Invalid plan {}: {}", path, e))?,
+    };
+    Ok(QueryPlan { operations: file.operations })
+}
+
+/// `plan` as a versioned plan file, JSON unless `path` ends in `.yaml` or `.yml`.
+pub fn plan_to_string(plan: &QueryPlan, path: Option<&str>) -> Result<String, String> {
+    let format = match path {
+        Some(path) => PlanFormat::from_path(path)?,
+        None => PlanFormat::Json,
+    };
+    let file = PlanFile { version: PLAN_FORMAT_VERSION, operations: plan.operations.clone() };
+    match format {
+        PlanFormat::Json => serde_json::to_string_pretty(&file).map(|text| text + "\n").map_err(|e| e.to_string()),
+        PlanFormat::Yaml => serde_yaml::to_string(&file).map_err(|e| e.to_string()),
+    }
+}
+
+pub fn save_plan(path: &str, plan: &QueryPlan) -> Result<(), String> {
+    let text = plan_to_string(plan, Some(path))?;
+    fs::write(path, text).map_err(|e| format!("Cannot write {}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queries::query_by_id;
+    use crate::query_plan::{Aggregation, Field, FilterMode, ReduceType, StatisticType};
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("plan_file_{}_{}", std::process::id(), name))
+    }
+
+    fn round_trip(plan: &QueryPlan, name: &str) {
+        for extension in ["json", "yaml"] {
+            let path = temp_path(&format!("{}.{}", name, extension));
+            let path = path.to_string_lossy();
+            save_plan(&path, plan).unwrap();
+            let loaded = load_plan(&path);
+            fs::remove_file(path.as_ref()).unwrap();
+            assert_eq!(loaded.unwrap(), *plan, "{} as {}", name, extension);
+        }
+    }
+
+    fn reduce(reduce_type: ReduceType, aggregation: Aggregation) -> Operation {
+        Operation::Reduce {
+            keys: vec!["dst_ip".to_string(), "prefix(src_ip, 24)".to_string()],
+            reduce_type,
+            field_name: "count".to_string(),
+            aggregation,
+        }
+    }
+
+    // Every Reduce and statistic type, aggregation, filter mode and operator, with a join
+    // nested in a join's sub-plan.
+    fn every_variant_plan() -> QueryPlan {
+        let reduce_types = [
+            ReduceType::CMReduce { memory_in_bytes: 65536, depth: 3, seed: 42 },
+            ReduceType::FCMReduce {
+                depth: 2,
+                width_l1: 65536,
+                width_l2: 8192,
+                width_l3: 1024,
+                threshold_l1: 254,
+                threshold_l2: 65534,
+                seed: 7,
+            },
+            ReduceType::FCMFirstLayerOnly { depth: 2, width_l1: 4096, seed: 1 },
+            ReduceType::ElasticReduce { depth: 4, width: 1024, seed: 42 },
+            ReduceType::DeterministicReduce,
+            ReduceType::BeauCoupReduce { num_rows: 2, num_coupons: 32, d: 8, max_coupons_per_packet: 1, seed: 3 },
+        ];
+        let aggregations = [
+            Aggregation::Count,
+            Aggregation::Sum,
+            Aggregation::Min,
+            Aggregation::Max,
+            Aggregation::Mean,
+            Aggregation::First,
+            Aggregation::Last,
+            Aggregation::Quantile(0.95),
+        ];
+        let statistic_types = [
+            StatisticType::Exact,
+            StatisticType::LightPart { memory_in_bytes: 8192, seed: 5 },
+            StatisticType::FCMSketch {
+                depth: 2,
+                width_l1: 65536,
+                width_l2: 8192,
+                width_l3: 1024,
+                threshold_l1: 254,
+                threshold_l2: 65534,
+                seed: 42,
+                em_iterations: 10,
+            },
+        ];
+
+        let mut operations = vec![
+            Operation::Filter(vec![
+                (Field::TcpFlag, "2".to_string()),
+                (Field::SourceIp, "10.0.0.1".to_string()),
+                (Field::DestIp, "10.0.0.2".to_string()),
+                (Field::SourcePort, "1234".to_string()),
+                (Field::DestPort, "22".to_string()),
+                (Field::Protocol, "6".to_string()),
+                (Field::DnsNsType, "2".to_string()),
+            ]),
+            Operation::RefinePrefix { field_name: "dst_ip".to_string(), prefix_len: 16, within: None },
+            Operation::RefinePrefix {
+                field_name: "dst_ip".to_string(),
+                prefix_len: 24,
+                within: Some(vec!["10.0.0.0/8".to_string(), "172.16.0.0/16".to_string()]),
+            },
+            Operation::Map("(dst_ip, net = prefix(src_ip, 24), count = 1)".to_string()),
+            Operation::Distinct {
+                keys: vec!["dst_ip".to_string(), "src_ip".to_string()],
+                distinct_type: ReduceType::BloomFilter { size: 1 << 20, num_hashes: 4, seed: 9 },
+            },
+        ];
+        operations.extend(reduce_types.iter().cloned().map(|reduce_type| reduce(reduce_type, Aggregation::Sum)));
+        operations.extend(aggregations.iter().map(|aggregation| reduce(ReduceType::DeterministicReduce, *aggregation)));
+        for statistic_type in statistic_types {
+            let keys = vec!["src_ip".to_string()];
+            operations.push(Operation::Cardinality { keys: keys.clone(), statistic_type: statistic_type.clone() });
+            operations.push(Operation::Entropy { keys: keys.clone(), statistic_type: statistic_type.clone() });
+            operations.push(Operation::FlowSizeDistribution { keys, statistic_type });
+        }
+        operations.extend([
+            Operation::FCMEstimate { em_iterations: 15 },
+            Operation::HierarchicalHeavyHitters {
+                keys: vec!["src_ip".to_string(), "dst_ip".to_string()],
+                prefix_step: 8,
+                reduce_type: ReduceType::CMReduce { memory_in_bytes: 65536, depth: 3, seed: 42 },
+                field_name: "total_len".to_string(),
+                threshold: 1000,
+                randomized: true,
+            },
+        ]);
+        operations.extend(
+            [FilterMode::Estimate, FilterMode::Definitely, FilterMode::Possibly]
+                .map(|mode| Operation::FilterResult { threshold: 40, field_name: "count".to_string(), mode }),
+        );
+
+        let side = |field: &str| QueryPlan {
+            operations: vec![
+                Operation::Map(format!("({}, count = 1)", field)),
+                reduce(ReduceType::CMReduce { memory_in_bytes: 4096, depth: 2, seed: 1 }, Aggregation::Sum),
+            ],
+        };
+        let inner_join = QueryPlan {
+            operations: vec![
+                Operation::Join {
+                    left_query: Box::new(side("dst_ip")),
+                    right_query: Box::new(side("src_ip")),
+                    left_keys: vec!["dst_ip".to_string()],
+                    right_keys: vec!["src_ip".to_string()],
+                },
+                Operation::MapJoin("(dst_ip, count = left_count - right_count)".to_string()),
+            ],
+        };
+        operations.extend([
+            Operation::Join {
+                left_query: Box::new(inner_join),
+                right_query: Box::new(side("dst_ip")),
+                left_keys: vec!["dst_ip".to_string()],
+                right_keys: vec!["dst_ip".to_string()],
+            },
+            Operation::MapJoin("(dst_ip, count = left_count + right_count)".to_string()),
+            Operation::FilterJoin { threshold: 10, field_name: "count".to_string() },
+        ]);
+        QueryPlan { operations }
+    }
+
+    #[test]
+    fn builtin_queries_round_trip() {
+        let mut found = 0;
+        for query_id in 0..=u8::MAX {
+            if let Some(plan) = query_by_id(query_id) {
+                round_trip(&plan, &format!("query_{}", query_id));
+                found += 1;
+            }
+        }
+        assert!(found >= 11, "only {} built-in queries", found);
+    }
+
+    #[test]
+    fn every_operator_and_parameter_variant_round_trips() {
+        round_trip(&every_variant_plan(), "variants");
+    }
+
+    #[test]
+    fn other_or_missing_versions_are_rejected() {
+        let plan = QueryPlan { operations: vec![Operation::Map("(dst_ip, count = 1)".to_string())] };
+        for (extension, version) in [("json", PLAN_FORMAT_VERSION + 1), ("yaml", PLAN_FORMAT_VERSION + 1), ("yaml", 0)] {
+            let path = temp_path(&format!("v{}.{}", version, extension));
+            let path = path.to_string_lossy();
+            let written = plan_to_string(&plan, Some(&path)).unwrap();
+            let text = written
+                .replacen(&format!("\"version\": {}", PLAN_FORMAT_VERSION), &format!("\"version\": {}", version), 1)
+                .replacen(&format!("version: {}", PLAN_FORMAT_VERSION), &format!("version: {}", version), 1);
+            assert_ne!(text, written);
+            fs::write(path.as_ref(), text).unwrap();
+            let error = load_plan(&path).map(|_| ()).unwrap_err();
+            fs::remove_file(path.as_ref()).unwrap();
+            assert!(
+                error.contains(&format!("version {}, expected {}", version, PLAN_FORMAT_VERSION)),
+                "{}",
+                error
+            );
+        }
+
+        for extension in ["json", "yaml"] {
+            let path = temp_path(&format!("unversioned.{}", extension));
+            let path = path.to_string_lossy();
+            let text = if extension == "json" {
+                "{\"operations\": [{\"Map\": \"(dst_ip, count = 1)\"}]}\n"
+            } else {
+                "operations:\n- !Map (dst_ip, count = 1)\n"
+            };
+            fs::write(path.as_ref(), text).unwrap();
+            let error = load_plan(&path).map(|_| ()).unwrap_err();
+            fs::remove_file(path.as_ref()).unwrap();
+            assert!(error.contains("has no plan format version"), "{}", error);
+        }
+    }
+}
@@ -1,5 +1,5 @@
-use crate::query_plan::{QueryPlan, Operation, Field, ReduceType};
-use crate::config::{get_reduce_type_from_env, get_distinct_type_from_env, DistinctType};
+use crate::query_plan::{QueryPlan, Operation, Field, ReduceType, Aggregation};
+use crate::config::{get_reduce_type_from_env, get_distinct_type_from_env, get_fcm_reduce_type_from_env, get_statistic_type_from_env, get_filter_mode_from_env, get_hhh_prefix_step_from_env, get_hhh_randomized_from_env, DistinctType};
 
 
 /// Query 1: TCP New Connection
@@ -16,10 +16,12 @@ pub fn query_1() -> QueryPlan {
                 keys: vec!["dst_ip".to_string()],
                 reduce_type,
                 field_name: "count".to_string(),
+                aggregation: Aggregation::Sum,
             },
             Operation::FilterResult {
                 threshold: 2,
                 field_name: "count".to_string(),
+                mode: get_filter_mode_from_env(),
             },
         ],
     }
@@ -28,11 +30,8 @@ pub fn query_1() -> QueryPlan {
 // Query 2: SSH Brute
 pub fn query_2() -> QueryPlan {
     let reduce_type = get_reduce_type_from_env();
+    // Bloom filters can be sized from BF_EXPECTED_ITEMS and BF_FP_RATE.
     let distinct_type = get_distinct_type_from_env();
-    // distinct_type: ReduceType::BloomFilter {
-    //     expected_items: 10000,
-    //     false_positive_rate: 0.01, 
-    // },
     let distinct_op = match distinct_type {
         DistinctType::DeterministicReduce => ReduceType::DeterministicReduce,
         DistinctType::BloomFilter { size, num_hashes, seed } => {
@@ -55,10 +54,12 @@ pub fn query_2() -> QueryPlan {
                 keys: vec!["dst_ip".to_string(), "total_len".to_string()],
                 reduce_type,
                 field_name: "count".to_string(),
+                aggregation: Aggregation::Sum,
             },
             Operation::FilterResult {
                 threshold: 40,
                 field_name: "count".to_string(),
+                mode: get_filter_mode_from_env(),
             },
         ],
     }
@@ -90,10 +91,12 @@ pub fn query_3() -> QueryPlan {
                 keys: vec!["src_ip".to_string()],
                 reduce_type,
                 field_name: "count".to_string(),
+                aggregation: Aggregation::Sum,
             },
             Operation::FilterResult {
                 threshold: 40,
                 field_name: "count".to_string(),
+                mode: get_filter_mode_from_env(),
             },
         ],
     }
@@ -126,10 +129,12 @@ pub fn query_4() -> QueryPlan {
                 keys: vec!["src_ip".to_string()],
                 reduce_type,
                 field_name: "count".to_string(),
+                aggregation: Aggregation::Sum,
             },
             Operation::FilterResult {
                 threshold: 40,
                 field_name: "count".to_string(),
+                mode: get_filter_mode_from_env(),
             },
         ],
     }
@@ -146,10 +151,12 @@ pub fn query_5() -> QueryPlan {
                 keys: vec!["dst_ip".to_string(), "src_ip".to_string()],
                 reduce_type,
                 field_name: "total_len".to_string(),
+                aggregation: Aggregation::Sum,
             },
             Operation::FilterResult {
                 threshold: 1,
                 field_name: "total_len".to_string(),
+                mode: get_filter_mode_from_env(),
             },
         ],
     }
@@ -174,6 +181,7 @@ pub fn query_6() -> QueryPlan {
                 keys: vec!["dst_ip".to_string()],
                 reduce_type: reduce_type.clone(),
                 field_name: "left_count".to_string(),
+                aggregation: Aggregation::Sum,
             },
         ],
     };
@@ -190,6 +198,7 @@ pub fn query_6() -> QueryPlan {
                 keys: vec!["src_ip".to_string()],
                 reduce_type,
                 field_name: "right_count".to_string(),
+                aggregation: Aggregation::Sum,
             },
         ],
     };
@@ -207,6 +216,7 @@ pub fn query_6() -> QueryPlan {
             Operation::FilterResult {
                 threshold: 40,
                 field_name: "count".to_string(),
+                mode: get_filter_mode_from_env(),
             },
         ],
     }
@@ -225,6 +235,7 @@ pub fn query_7() -> QueryPlan {
                 keys: vec!["dst_ip".to_string()],
                 reduce_type: reduce_type.clone(),
                 field_name: "left_count".to_string(),
+                aggregation: Aggregation::Sum,
             },
         ],
     };
@@ -238,6 +249,7 @@ pub fn query_7() -> QueryPlan {
                 keys: vec!["src_ip".to_string()],
                 reduce_type,
                 field_name: "right_count".to_string(),
+                aggregation: Aggregation::Sum,
             },
         ],
     };
@@ -254,6 +266,7 @@ pub fn query_7() -> QueryPlan {
             Operation::FilterResult {
                 threshold: 1,
                 field_name: "diff".to_string(),
+                mode: get_filter_mode_from_env(),
             },
             Operation::Map("(dst_ip)".to_string()),
         ],
@@ -286,10 +299,12 @@ pub fn query_8() -> QueryPlan {
                 keys: vec!["dst_ip".to_string()],
                 reduce_type: reduce_type.clone(),
                 field_name: "count".to_string(),
+                aggregation: Aggregation::Sum,
             },
             Operation::FilterResult {
                 threshold: 5,
                 field_name: "count".to_string(),
+                mode: get_filter_mode_from_env(),
             },
         ],
     };
@@ -302,10 +317,12 @@ pub fn query_8() -> QueryPlan {
                 keys: vec!["dst_ip".to_string()],
                 reduce_type,
                 field_name: "total_len".to_string(),
+                aggregation: Aggregation::Sum,
             },
             Operation::FilterResult {
                 threshold: 500,
                 field_name: "total_len".to_string(),
+                mode: get_filter_mode_from_env(),
             },
         ],
     };
@@ -321,3 +338,113 @@ pub fn query_8() -> QueryPlan {
         ],
     }
 }
+
+// Query 9: Flow size distribution, entropy and cardinality of src/dst pairs (always FCM)
+pub fn query_9() -> QueryPlan {
+    QueryPlan {
+        operations: vec![
+            Operation::Map("(dst_ip, src_ip, count = 1)".to_string()),
+            Operation::Reduce {
+                keys: vec!["dst_ip".to_string(), "src_ip".to_string()],
+                reduce_type: get_fcm_reduce_type_from_env(),
+                field_name: "count".to_string(),
+                aggregation: Aggregation::Sum,
+            },
+            Operation::FCMEstimate { em_iterations: 10 },
+        ],
+    }
+}
+
+// Query 10: Entropy of source addresses and destination ports (DDoS / scan anomalies)
+pub fn query_10() -> QueryPlan {
+    let statistic_type = get_statistic_type_from_env();
+
+    QueryPlan {
+        operations: vec![
+            Operation::Filter(vec![(Field::Protocol, "6".to_string())]),
+            Operation::Cardinality {
+                keys: vec!["src_ip".to_string()],
+                statistic_type: statistic_type.clone(),
+            },
+            Operation::Entropy {
+                keys: vec!["src_ip".to_string()],
+                statistic_type: statistic_type.clone(),
+            },
+            Operation::Entropy {
+                keys: vec!["dst_port".to_string()],
+                statistic_type: statistic_type.clone(),
+            },
+            Operation::FlowSizeDistribution {
+                keys: vec!["src_ip".to_string()],
+                statistic_type,
+            },
+        ],
+    }
+}
+
+// Query 11: Destination subnets receiving the most bytes (hierarchical heavy hitters)
+pub fn query_11() -> QueryPlan {
+    QueryPlan {
+        operations: vec![
+            Operation::Map("(dst_ip, total_len)".to_string()),
+            Operation::HierarchicalHeavyHitters {
+                keys: vec!["dst_ip".to_string()],
+                prefix_step: get_hhh_prefix_step_from_env(),
+                reduce_type: get_reduce_type_from_env(),
+                field_name: "total_len".to_string(),
+                threshold: 1_000_000,
+                randomized: get_hhh_randomized_from_env(),
+            },
+        ],
+    }
+}
+
+// Query 12: Source/destination subnet pairs sending the most packets (2-D hierarchical heavy hitters)
+pub fn query_12() -> QueryPlan {
+    QueryPlan {
+        operations: vec![
+            Operation::Map("(src_ip, dst_ip, count = 1)".to_string()),
+            Operation::HierarchicalHeavyHitters {
+                keys: vec!["src_ip".to_string(), "dst_ip".to_string()],
+                prefix_step: get_hhh_prefix_step_from_env(),
+                reduce_type: get_reduce_type_from_env(),
+                field_name: "count".to_string(),
+                threshold: 1000,
+                randomized: get_hhh_randomized_from_env(),
+            },
+        ],
+    }
+}
+
+/// Built-in queries by id, with what they detect.
+pub const QUERIES: [(u8, &str); 11] = [
+    (1, "TCP new connections per destination"),
+    (2, "SSH brute force"),
+    (3, "Super-spreaders"),
+    (4, "Port scans"),
+    (5, "Heavy hitters by bytes"),
+    (6, "SYN floods"),
+    (8, "Slowloris attacks"),
+    (9, "Flow size distribution, entropy and cardinality of src/dst pairs"),
+    (10, "Entropy of source addresses and destination ports"),
+    (11, "Hierarchical heavy hitters of destination subnets by bytes"),
+    (12, "Hierarchical heavy hitters of source/destination subnet pairs"),
+];
+
+pub fn query_by_id(query_id: u8) -> Option<QueryPlan> {
+    let query = match query_id {
+        1 => query_1(),
+        2 => query_2(),
+        3 => query_3(),
+        4 => query_4(),
+        5 => query_5(),
+        6 => query_6(),
+        8 => query_8(),
+        9 => query_9(),
+        10 => query_10(),
+        11 => query_11(),
+        12 => query_12(),
+        _ => return None,
+    };
+    Some(query)
+}
@@ -1,430 +1,1229 @@
-use crate::query_plan::{QueryPlan, Operation, Field, ReduceType};
-use crate::sketch::Sketch;
-use std::collections::{HashMap};
+use crate::query_plan::{QueryPlan, Operation, Field, FilterMode, ReduceType, StatisticType, Aggregation};
+use crate::sketch::{ErrorBound, Estimate, Sketch};
+use crate::fcm_em::FlowSizeEstimate;
+use crate::hhh::{HHHSketch, HeavyPrefix};
+use crate::aggregation::{AggregateSketch, QUANTILE_BUCKETS};
+use crate::functions::{call_name, parse_call, FieldFunction};
+use crate::flow_key::{encode_key, max_encoded_width, MAX_KEY_BYTES, MAX_KEY_STRING_BYTES};
+use crate::record::{FieldType, Record, Schema};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
 use crate::pcap_processor::EPOCH_RESULTS;
 use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
 use std::sync::Mutex;
 
 
 
 
 lazy_static! {
-    static ref LEFT_RESULTS: Mutex<HashMap<String, HashMap<String, PacketField>>> = Mutex::new(HashMap::new());
-    static ref RIGHT_RESULTS: Mutex<HashMap<String, HashMap<String, PacketField>>> = Mutex::new(HashMap::new());
+    pub static ref LEFT_RESULTS: Mutex<HashMap<Vec<u8>, Record>> = Mutex::new(HashMap::new());
+    pub static ref RIGHT_RESULTS: Mutex<HashMap<Vec<u8>, Record>> = Mutex::new(HashMap::new());
 }
 
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum PacketField {
     String(String),
     U16(u16),
     U32(u32),
     U8(u8),
+    U64(u64),
+    Bool(bool),
+    Ipv4(Ipv4Addr),
+    Ipv6(Ipv6Addr),
     OptionU16(Option<u16>),
     OptionTupleU16(Option<(u16, u16)>),
 }
-fn join_packets(
-    left_packet: &HashMap<String, PacketField>,
-    right_packet: &HashMap<String, PacketField>,
-    left_keys: &[String],
-    right_keys: &[String],
-) -> Option<HashMap<String, PacketField>> {
-    // Ensure the number of keys matches
-    if left_keys.len() != right_keys.len() {
-        eprintln!("Error: Mismatched number of left and right keys.");
-        return None;
-    }
-    
-    // Iterate over the key pairs and check if they match
-    if left_keys.iter().zip(right_keys.iter()).all(|(left_key, right_key)| {
-        match (left_packet.get(left_key), right_packet.get(right_key)) {
-            (Some(left_value), Some(right_value)) => {
-                // println!(
-                //     "Checking left_key: {}, right_key: {}, left_value: {:?}, right_value: {:?}",
-                //     left_key, right_key, left_value, right_value
-                // );
-                left_value == right_value
-            }
-            _ => {
-                println!(
-                    "Key mismatch: left_key: {}, right_key: {}, left_packet: {:?}, right_packet: {:?}",
-                    left_key, right_key, left_packet, right_packet
-                );
-                false
-            }
+
+impl PacketField {
+    pub fn field_type(&self) -> FieldType {
+        match self {
+            PacketField::String(_) => FieldType::String,
+            PacketField::U16(_) => FieldType::U16,
+            PacketField::U32(_) => FieldType::U32,
+            PacketField::U8(_) => FieldType::U8,
+            PacketField::U64(_) => FieldType::U64,
+            PacketField::Bool(_) => FieldType::Bool,
+            PacketField::Ipv4(_) => FieldType::Ipv4,
+            PacketField::Ipv6(_) => FieldType::Ipv6,
+            PacketField::OptionU16(_) => FieldType::OptionU16,
+            PacketField::OptionTupleU16(_) => FieldType::OptionTupleU16,
         }
-    }) {
-        // If all keys match, create the joined packet
-        let mut joined_packet = left_packet.clone();
-        for (key, value) in right_packet {
-            joined_packet.entry(key.clone()).or_insert(value.clone());
+    }
+
+    /// Value of an unsigned integer field, as reported and compared against thresholds.
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            PacketField::U8(v) => Some(*v as u64),
+            PacketField::U16(v) => Some(*v as u64),
+            PacketField::U32(v) => Some(*v as u64),
+            PacketField::U64(v) => Some(*v),
+            _ => None,
         }
-        println!("Join successful. Joined packet: {:?}", joined_packet);
-        Some(joined_packet)
-    } else {
-        // println!(
-        //     "Join failed. left_packet: {:?}, right_packet: {:?}, left_keys: {:?}, right_keys: {:?}",
-        //     left_packet, right_packet, left_keys, right_keys
-        // );
-        None
     }
 }
 
-fn evaluate_expression(expr: &str, result: &HashMap<String, PacketField>) -> Option<PacketField> {
-    let tokens: Vec<&str> = expr.split_whitespace().collect();
-    if tokens.len() == 3 {
-        let left_operand = tokens[0];
-        let operator = tokens[1];
-        let right_operand = tokens[2];
+impl fmt::Display for PacketField {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PacketField::String(s) => write!(f, "{}", s),
+            PacketField::U16(v) => write!(f, "{}", v),
+            PacketField::U32(v) => write!(f, "{}", v),
+            PacketField::U8(v) => write!(f, "{}", v),
+            PacketField::U64(v) => write!(f, "{}", v),
+            PacketField::Bool(v) => write!(f, "{}", v),
+            PacketField::Ipv4(ip) => write!(f, "{}", ip),
+            PacketField::Ipv6(ip) => write!(f, "{}", ip),
+            PacketField::OptionU16(Some(v)) => write!(f, "{}", v),
+            PacketField::OptionTupleU16(Some((a, b))) => write!(f, "({}, {})", a, b),
+            PacketField::OptionU16(None) | PacketField::OptionTupleU16(None) => write!(f, "-"),
+        }
+    }
+}
 
-        // Retrieve the values of the operands from the result map
-        let left_value = get_field_value(left_operand, result)?;
-        let right_value = get_field_value(right_operand, result)?;
+/// A per-epoch result of an epoch-level operator.
+#[derive(Clone, Debug)]
+pub enum EpochStatistic {
+    Cardinality(f64),
+    Entropy(f64),
+    FlowSizeDistribution(FlowSizeEstimate),
+    HierarchicalHeavyHitters(Vec<HeavyPrefix>),
+}
 
-        // Perform the operation
-        match (left_value, right_value) {
-            (PacketField::U16(left), PacketField::U16(right)) => match operator {
-                "+" => Some(PacketField::U16(left + right)),
-                "-" => Some(PacketField::U16(left.saturating_sub(right))),
-                "*" => Some(PacketField::U16(left * right)),
-                "/" => Some(PacketField::U16(if right != 0 { left / right } else { 0 })),
-                _ => None,
-            },
-            (PacketField::U8(left), PacketField::U8(right)) => match operator {
-                "+" => Some(PacketField::U8(left + right)),
-                "-" => Some(PacketField::U8(left.saturating_sub(right))),
-                "*" => Some(PacketField::U8(left * right)),
-                "/" => Some(PacketField::U8(if right != 0 { left / right } else { 0 })),
-                _ => None,
-            },
-            _ => None,
-        }
-    } else {
-        eprintln!("Invalid expression format: {}", expr);
-        None
+/// A `QueryPlan` with every field name resolved to a record slot.
+#[derive(Debug)]
+pub struct CompiledPlan {
+    /// The plan this was compiled from.
+    pub query: QueryPlan,
+    operations: Vec<CompiledOperation>,
+    /// Layout of the records stored in the result map.
+    pub result_schema: Schema,
+    /// Names and types of the fields encoded in the result map keys.
+    pub result_key: Vec<(String, FieldType)>,
+    /// Layout of the records leaving each operation.
+    output_schemas: Vec<Schema>,
+}
+
+#[derive(Debug)]
+enum CompiledOperation {
+    Filter(Vec<(usize, PacketField)>),
+    Map(Vec<MapOutput>),
+    Reduce {
+        key: Vec<(usize, FieldType)>,
+        // Key fields computed by functions, appended to the record.
+        computed: Vec<(usize, FieldFunction)>,
+        reduce_type: ReduceType,
+        aggregation: Aggregation,
+        sketch_key: String,
+        value_slot: usize,
+    },
+    FilterResult { threshold: u64, value_slot: usize, mode: FilterMode },
+    Distinct {
+        key: Vec<(usize, FieldType)>,
+        distinct_type: ReduceType,
+        sketch_key: String,
+    },
+    Join {
+        left_query: Box<CompiledPlan>,
+        right_query: Box<CompiledPlan>,
+        key_slots: Vec<(usize, usize)>,
+        // Right-hand slots appended after the left record's fields.
+        right_fields: Vec<usize>,
+    },
+    MapJoin(Vec<MapJoinOutput>),
+    FilterJoin { threshold: u16, value_slot: usize },
+    FCMEstimate { sketch_key: String, em_iterations: usize },
+    Statistic {
+        kind: StatisticKind,
+        key: Vec<(usize, FieldType)>,
+        statistic_type: StatisticType,
+        sketch_key: String,
+    },
+    RefinePrefix {
+        slot: usize,
+        mask: u32,
+        // Allowed networks, grouped by prefix mask.
+        within: Option<Vec<(u32, HashSet<u32>)>>,
+    },
+    HierarchicalHeavyHitters {
+        key: Vec<(usize, FieldType)>,
+        value_slot: usize,
+        prefix_step: u8,
+        reduce_type: ReduceType,
+        threshold: u64,
+        randomized: bool,
+        sketch_key: String,
+    },
+}
+
+#[derive(Debug)]
+enum MapOutput {
+    Field(usize),
+    Constant(PacketField),
+    Function(FieldFunction),
+}
+
+#[derive(Debug)]
+enum MapJoinOutput {
+    Field(usize),
+    Expression { left: usize, operator: String, right: usize },
+}
+
+#[derive(Clone, Copy, Debug)]
+enum StatisticKind {
+    Cardinality,
+    Entropy,
+    FlowSizeDistribution,
+}
+
+fn filter_field_name(field: &Field) -> &'static str {
+    match field {
+        Field::TcpFlag => "tcp_flags",
+        Field::SourceIp => "src_ip",
+        Field::DestIp => "dst_ip",
+        Field::SourcePort => "src_port",
+        Field::DestPort => "dst_port",
+        Field::Protocol => "protocol",
+        Field::DnsNsType => "dns_ns_type",
     }
 }
 
-fn get_field_value(field: &str, result: &HashMap<String, PacketField>) -> Option<PacketField> {
-    if let Some(value) = result.get(field) {
-        Some(value.clone())
-    } else {
-        eprintln!("Field not found: {}", field);
-        None
+pub fn resolve(schema: &Schema, name: &str, op: &str) -> Result<usize, String> {
+    schema
+        .slot(name)
+        .ok_or_else(|| format!("{}: field '{}' not found in {:?}", op, name, schema.fields()))
+}
+
+pub fn resolve_key(schema: &Schema, keys: &[String], op: &str) -> Result<Vec<(usize, FieldType)>, String> {
+    let key = keys
+        .iter()
+        .map(|k| resolve(schema, k, op).map(|slot| (slot, schema.field_type(slot))))
+        .collect::<Result<Vec<_>, String>>()?;
+    let width: usize = key.iter().map(|(_, t)| max_encoded_width(*t)).sum();
+    if width > MAX_KEY_BYTES {
+        return Err(format!("{}: key {:?} can be {} bytes, longer than {}", op, keys, width, MAX_KEY_BYTES));
     }
+    Ok(key)
 }
 
-pub fn execute_query(
-    query: &QueryPlan,
-    packet: HashMap<String, PacketField>,
-    sketches: &mut HashMap<String, Sketch>,
-    result_map: &mut HashMap<String, HashMap<String, PacketField>>,
-    epoch_size: u64,
-    current_epoch_start: &mut Option<u64>,
-    timestamp: u64,
-) -> Option<HashMap<String, PacketField>>  {
-    let mut current_packet = packet;
+/// Network mask of a prefix length (at most 32).
+pub fn prefix_mask(prefix_len: u8) -> u32 {
+    u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0)
+}
+
+/// Parses "10.0.0.0/8" into its network and length; host bits must be zero.
+pub fn parse_prefix(text: &str) -> Option<(Ipv4Addr, u8)> {
+    let (address, prefix_len) = text.split_once('/')?;
+    let address: Ipv4Addr = address.trim().parse().ok()?;
+    let prefix_len: u8 = prefix_len.trim().parse().ok().filter(|len| *len <= 32)?;
+    (u32::from(address) & !prefix_mask(prefix_len) == 0).then_some((address, prefix_len))
+}
+
+// Splits "(a, b, c = 1, d = prefix(a, 24))" into its comma-separated items, leaving the
+// commas of function calls alone.
+pub fn map_items(expr: &str) -> Vec<&str> {
+    let expr = expr.trim();
+    let expr = expr.strip_prefix('(').and_then(|inner| inner.strip_suffix(')')).unwrap_or(expr);
+    let mut items = Vec::new();
+    let (mut depth, mut start) = (0, 0);
+    for (i, c) in expr.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                items.push(expr[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(expr[start..].trim());
+    items
+}
+
+/// Resolves all field references of `query` against the packet schema.
+pub fn compile_plan(query: &QueryPlan) -> Result<CompiledPlan, String> {
+    compile_with_schema(query, Schema::packet())
+}
+
+fn compile_with_schema(query: &QueryPlan, input: Schema) -> Result<CompiledPlan, String> {
+    let mut schema = input;
+    let mut result_schema = schema.clone();
+    let mut result_key = Vec::new();
+    let mut last_reduce_sketch: Option<String> = None;
+    let mut after_join = false;
+    let mut operations = Vec::with_capacity(query.operations.len());
+    let mut output_schemas = Vec::with_capacity(query.operations.len());
 
     for op in &query.operations {
-        if current_packet.is_empty() {
-            eprintln!("Warning: Received an empty packet. Ignoring it.");
-            return None;
+        let per_packet = matches!(
+            op,
+            Operation::Filter(_)
+                | Operation::Map(_)
+                | Operation::Reduce { .. }
+                | Operation::Distinct { .. }
+                | Operation::RefinePrefix { .. }
+        );
+        if per_packet && after_join {
+            return Err(format!("{:?} cannot follow a Join", op));
         }
-        match op {
+
+        let compiled = match op {
             Operation::Filter(conditions) => {
-                let mut pass = true;
+                let mut compiled_conditions = Vec::with_capacity(conditions.len());
                 for (field, value) in conditions {
-                    let field_key = match field {
-                        Field::TcpFlag => "tcp_flags",
-                        Field::SourceIp => "src_ip",
-                        Field::DestIp => "dst_ip",
-                        Field::SourcePort => "src_port",
-                        Field::DestPort => "dst_port",
-                        Field::Protocol => "protocol",
-                        Field::DnsNsType => "DnsNsType"
-                    };
-            
-                    pass &= match current_packet.get(field_key) {
-                        Some(PacketField::String(s)) => s == value,
-                        Some(PacketField::U16(v)) => v.to_string() == *value,
-                        Some(PacketField::U8(v)) => v.to_string() == *value,
-                        Some(PacketField::OptionU16(Some(v))) => v.to_string() == *value,
-                        _ => false,
-                    };
-            
-                    // Break early if a condition fails
-                    if !pass {
-                        break;
+                    let slot = resolve(&schema, filter_field_name(field), "Filter")?;
+                    let expected = schema.field_type(slot).parse(value).ok_or_else(|| {
+                        format!("Filter: '{}' is not a valid {:?} value", value, schema.field_type(slot))
+                    })?;
+                    compiled_conditions.push((slot, expected));
+                }
+                CompiledOperation::Filter(compiled_conditions)
+            }
+            Operation::Map(expr) => {
+                let mut outputs = Vec::new();
+                let mut new_schema = Schema::default();
+                for item in map_items(expr) {
+                    if item.contains('=') {
+                        let parts: Vec<&str> = item.split('=').map(|s| s.trim()).collect();
+                        if parts.len() != 2 {
+                            return Err(format!("Map: invalid assignment '{}'", item));
+                        }
+                        if parse_call(parts[1]).is_some() {
+                            let (function, field_type) =
+                                FieldFunction::compile(parts[1], &schema).map_err(|e| format!("Map: {}", e))?;
+                            new_schema.push(parts[0], field_type)?;
+                            outputs.push(MapOutput::Function(function));
+                            continue;
+                        }
+                        let value = match parts[1].parse::<u32>() {
+                            Ok(v) => PacketField::U32(v),
+                            Err(_) if parts[1].len() > MAX_KEY_STRING_BYTES => {
+                                return Err(format!(
+                                    "Map: constant '{}' is longer than {} bytes",
+                                    parts[1], MAX_KEY_STRING_BYTES
+                                ));
+                            }
+                            Err(_) => PacketField::String(parts[1].to_string()),
+                        };
+                        new_schema.push(parts[0], value.field_type())?;
+                        outputs.push(MapOutput::Constant(value));
+                    } else if parse_call(item).is_some() {
+                        let (function, field_type) =
+                            FieldFunction::compile(item, &schema).map_err(|e| format!("Map: {}", e))?;
+                        new_schema.push(&call_name(item), field_type)?;
+                        outputs.push(MapOutput::Function(function));
+                    } else {
+                        let slot = resolve(&schema, item, "Map")?;
+                        new_schema.push(item, schema.field_type(slot))?;
+                        outputs.push(MapOutput::Field(slot));
                     }
                 }
-            
-                if !pass {
-                    return None;
+                schema = new_schema;
+                CompiledOperation::Map(outputs)
+            }
+            Operation::Reduce { keys, reduce_type, field_name, aggregation } => {
+                // Keys such as "prefix(src_ip, 24)" become fields of their own.
+                let mut computed = Vec::new();
+                for name in keys {
+                    if schema.slot(name).is_none() && parse_call(name).is_some() {
+                        let (function, field_type) =
+                            FieldFunction::compile(name, &schema).map_err(|e| format!("Reduce: {}", e))?;
+                        computed.push((schema.push(name, field_type)?, function));
+                    }
+                }
+                let key = resolve_key(&schema, keys, "Reduce")?;
+                let value_slot = resolve(&schema, field_name, "Reduce")?;
+                if schema.field_type(value_slot) != FieldType::U32 {
+                    return Err(format!("Reduce: field '{}' must be a U32", field_name));
+                }
+                let sketch_key = aggregate_sketch_key(reduce_type, *aggregation)
+                    .ok_or_else(|| "BloomFilter is not supported in Reduce operation".to_string())?;
+                match (aggregation, reduce_type) {
+                    (Aggregation::Quantile(q), _) if !(0.0..=1.0).contains(q) => {
+                        return Err(format!("Reduce: quantile {} must lie between 0 and 1", q));
+                    }
+                    (Aggregation::Sum | Aggregation::Count, _) => {}
+                    (_, ReduceType::BeauCoupReduce { .. }) => {
+                        return Err(format!("Reduce: BeauCoupReduce counts distinct keys and cannot compute {:?}", aggregation));
+                    }
+                    _ => {}
+                }
+                last_reduce_sketch = Some(sketch_key.clone());
+                result_schema = schema.clone();
+                result_key = keys.iter().zip(&key).map(|(k, (_, t))| (k.clone(), *t)).collect();
+                CompiledOperation::Reduce {
+                    key,
+                    computed,
+                    reduce_type: reduce_type.clone(),
+                    aggregation: *aggregation,
+                    sketch_key,
+                    value_slot,
                 }
-                // println!("Packet passed filter: {:?}", current_packet);
             }
-            Operation::Map(expr) => {
-                // Parse the `expr` string into individual operations
+            Operation::FilterResult { threshold, field_name, mode } => CompiledOperation::FilterResult {
+                threshold: *threshold,
+                value_slot: resolve(&result_schema, field_name, "FilterResult")?,
+                mode: *mode,
+            },
+            Operation::Distinct { keys, distinct_type } => {
+                let sketch_key = match distinct_type {
+                    ReduceType::BloomFilter { size, num_hashes, .. } => {
+                        format!("DistinctBloomFilter_{}_{}", size, num_hashes)
+                    }
+                    ReduceType::DeterministicReduce => "DistinctDeterministicSketch".to_string(),
+                    _ => return Err("Unsupported distinct type".to_string()),
+                };
+                CompiledOperation::Distinct {
+                    key: resolve_key(&schema, keys, "Distinct")?,
+                    distinct_type: distinct_type.clone(),
+                    sketch_key,
+                }
+            }
+            Operation::Join { left_query, right_query, left_keys, right_keys } => {
+                if left_keys.len() != right_keys.len() {
+                    return Err("Join: mismatched number of left and right keys".to_string());
+                }
+                let left = compile_with_schema(left_query, schema.clone())?;
+                let right = compile_with_schema(right_query, schema.clone())?;
+                let key_slots = left_keys
+                    .iter()
+                    .zip(right_keys)
+                    .map(|(l, r)| {
+                        Ok((
+                            resolve(&left.result_schema, l, "Join")?,
+                            resolve(&right.result_schema, r, "Join")?,
+                        ))
+                    })
+                    .collect::<Result<Vec<_>, String>>()?;
 
-                if current_packet.is_empty() {
-                    return None;
+                let mut joined = left.result_schema.clone();
+                let mut right_fields = Vec::new();
+                for (slot, (name, field_type)) in right.result_schema.fields().iter().enumerate() {
+                    if joined.slot(name).is_none() {
+                        joined.push(name, *field_type)?;
+                        right_fields.push(slot);
+                    }
                 }
 
-                let operations: Vec<&str> = expr
-                    .trim_matches(|c| c == '(' || c == ')') // Remove parentheses
-                    .split(',')                             // Split by commas
-                    .map(|s| s.trim())                      // Trim whitespace
-                    .collect();
-            
-                let mut new_packet: HashMap<String, PacketField> = HashMap::new();
-            
-                for operation in operations {
-                    if operation.contains('=') {
-                        let parts: Vec<&str> = operation.split('=').map(|s| s.trim()).collect();
-                        if parts.len() == 2 {
-                            let key = parts[0];
-                            let value = parts[1];
-            
-                            if let Ok(parsed_value) = value.parse::<u32>() {
-                                new_packet.insert(key.to_string(), PacketField::U32(parsed_value));
-                            } else if let Ok(parsed_value) = value.parse::<u32>() {
-                                new_packet.insert(key.to_string(), PacketField::U32(parsed_value));
-                            } else {
-                                new_packet.insert(key.to_string(), PacketField::String(value.to_string()));
-                            }
+                result_key = left.result_key.clone();
+                result_schema = joined.clone();
+                schema = joined;
+                after_join = true;
+                CompiledOperation::Join {
+                    left_query: Box::new(left),
+                    right_query: Box::new(right),
+                    key_slots,
+                    right_fields,
+                }
+            }
+            Operation::MapJoin(expr) => {
+                let mut outputs = Vec::new();
+                let mut new_schema = Schema::default();
+                for item in map_items(expr) {
+                    if item.contains('=') {
+                        let parts: Vec<&str> = item.split('=').map(|s| s.trim()).collect();
+                        let tokens: Vec<&str> = parts.get(1).map_or(Vec::new(), |e| e.split_whitespace().collect());
+                        if parts.len() != 2 || tokens.len() != 3 {
+                            return Err(format!("Invalid expression format: {}", item));
                         }
-                        
+                        let left = resolve(&result_schema, tokens[0], "MapJoin")?;
+                        let right = resolve(&result_schema, tokens[2], "MapJoin")?;
+                        new_schema.push(parts[0], result_schema.field_type(left))?;
+                        outputs.push(MapJoinOutput::Expression { left, operator: tokens[1].to_string(), right });
                     } else {
-                        if let Some(value) = current_packet.get(operation) {
-                            new_packet.insert(operation.to_string(), value.clone());
+                        let slot = resolve(&result_schema, item, "MapJoin")?;
+                        new_schema.push(item, result_schema.field_type(slot))?;
+                        outputs.push(MapJoinOutput::Field(slot));
+                    }
+                }
+                result_schema = new_schema;
+                CompiledOperation::MapJoin(outputs)
+            }
+            Operation::FilterJoin { threshold, field_name } => CompiledOperation::FilterJoin {
+                threshold: *threshold,
+                value_slot: resolve(&result_schema, field_name, "FilterJoin")?,
+            },
+            Operation::FCMEstimate { em_iterations } => CompiledOperation::FCMEstimate {
+                sketch_key: last_reduce_sketch
+                    .clone()
+                    .ok_or_else(|| "FCMEstimate must follow a Reduce operation".to_string())?,
+                em_iterations: *em_iterations,
+            },
+            Operation::Cardinality { keys, statistic_type }
+            | Operation::Entropy { keys, statistic_type }
+            | Operation::FlowSizeDistribution { keys, statistic_type } => {
+                let kind = match op {
+                    Operation::Cardinality { .. } => StatisticKind::Cardinality,
+                    Operation::Entropy { .. } => StatisticKind::Entropy,
+                    _ => StatisticKind::FlowSizeDistribution,
+                };
+                CompiledOperation::Statistic {
+                    kind,
+                    key: resolve_key(&schema, keys, "Statistic")?,
+                    statistic_type: statistic_type.clone(),
+                    sketch_key: statistic_sketch_key(kind, keys, statistic_type),
+                }
+            }
+            Operation::RefinePrefix { field_name, prefix_len, within } => {
+                let slot = resolve(&schema, field_name, "RefinePrefix")?;
+                if schema.field_type(slot) != FieldType::Ipv4 {
+                    return Err(format!("RefinePrefix: field '{}' must be an Ipv4", field_name));
+                }
+                if *prefix_len > 32 {
+                    return Err(format!("RefinePrefix: /{} is longer than an IPv4 address", prefix_len));
+                }
+                let within = match within {
+                    Some(prefixes) => {
+                        let mut groups: Vec<(u32, HashSet<u32>)> = Vec::new();
+                        for prefix in prefixes {
+                            let (network, len) = parse_prefix(prefix)
+                                .ok_or_else(|| format!("RefinePrefix: '{}' is not an IPv4 prefix", prefix))?;
+                            let mask = prefix_mask(len);
+                            match groups.iter_mut().find(|(group_mask, _)| *group_mask == mask) {
+                                Some((_, networks)) => {
+                                    networks.insert(u32::from(network));
+                                }
+                                None => groups.push((mask, HashSet::from([u32::from(network)]))),
+                            }
                         }
+                        Some(groups)
                     }
+                    None => None,
+                };
+                CompiledOperation::RefinePrefix { slot, mask: prefix_mask(*prefix_len), within }
+            }
+            Operation::HierarchicalHeavyHitters { keys, prefix_step, reduce_type, field_name, threshold, randomized } => {
+                let key = resolve_key(&schema, keys, "HierarchicalHeavyHitters")?;
+                if key.is_empty() || key.len() > 2 || key.iter().any(|(_, field_type)| *field_type != FieldType::Ipv4) {
+                    return Err(format!("HierarchicalHeavyHitters: keys {:?} must be one or two Ipv4 fields", keys));
+                }
+                if ![1, 2, 4, 8].contains(prefix_step) {
+                    return Err(format!("HierarchicalHeavyHitters: prefix step {} must be 1, 2, 4 or 8", prefix_step));
+                }
+                let value_slot = resolve(&schema, field_name, "HierarchicalHeavyHitters")?;
+                if schema.field_type(value_slot) != FieldType::U32 {
+                    return Err(format!("HierarchicalHeavyHitters: field '{}' must be a U32", field_name));
+                }
+                let sketch_key = match reduce_type {
+                    ReduceType::BloomFilter { .. } | ReduceType::BeauCoupReduce { .. } => {
+                        return Err("HierarchicalHeavyHitters needs a Reduce type that sums values".to_string())
+                    }
+                    _ => hhh_sketch_key(keys, *prefix_step, reduce_type, *randomized),
+                };
+                CompiledOperation::HierarchicalHeavyHitters {
+                    key,
+                    value_slot,
+                    prefix_step: *prefix_step,
+                    reduce_type: reduce_type.clone(),
+                    threshold: *threshold,
+                    randomized: *randomized,
+                    sketch_key,
                 }
-                // println!("old packet: {:?}. \n new packet: {:?}", current_packet, new_packet);
-                current_packet = new_packet;
             }
+        };
+        // Per-packet operators pass records on; the others act on the result map.
+        let epoch_level = matches!(
+            compiled,
+            CompiledOperation::Statistic { .. } | CompiledOperation::HierarchicalHeavyHitters { .. }
+        );
+        output_schemas.push(if per_packet || epoch_level {
+            schema.clone()
+        } else {
+            result_schema.clone()
+        });
+        operations.push(compiled);
+    }
 
+    Ok(CompiledPlan { query: query.clone(), operations, result_schema, result_key, output_schemas })
+}
 
-            Operation::Reduce { keys, reduce_type, field_name } => {
-                let key = keys
-                .iter()
-                .map(|k| {
-                    current_packet.get(k).map_or(format!("{}: <missing>", k), |v| match v {
-                        PacketField::String(s) => format!("{}: {}", k, s),
-                        PacketField::U16(v) => format!("{}: {}", k, v),
-                        PacketField::U8(v) => format!("{}: {}", k, v),
-                        PacketField::OptionU16(Some(v)) => format!("{}: {}", k, v),
-                        _ => format!("{}: <invalid>", k),
-                    })
-                })
-                .collect::<Vec<_>>()
-                .join(", ");
-                let sketch_key = match reduce_type {
-                    ReduceType::CMReduce { memory_in_bytes, depth, seed } => {
-                        format!("CMSketch_{}_{}", memory_in_bytes, depth)
-                    }
+fn evaluate_expression(left: &PacketField, operator: &str, right: &PacketField) -> Option<PacketField> {
+    macro_rules! arithmetic {
+        ($variant:ident, $l:expr, $r:expr) => {
+            match operator {
+                "+" => Some(PacketField::$variant($l.saturating_add(*$r))),
+                "-" => Some(PacketField::$variant($l.saturating_sub(*$r))),
+                "*" => Some(PacketField::$variant($l.saturating_mul(*$r))),
+                "/" => Some(PacketField::$variant($l.checked_div(*$r).unwrap_or(0))),
+                _ => None,
+            }
+        };
+    }
+    match (left, right) {
+        (PacketField::U8(l), PacketField::U8(r)) => arithmetic!(U8, l, r),
+        (PacketField::U16(l), PacketField::U16(r)) => arithmetic!(U16, l, r),
+        (PacketField::U32(l), PacketField::U32(r)) => arithmetic!(U32, l, r),
+        (PacketField::U64(l), PacketField::U64(r)) => arithmetic!(U64, l, r),
+        _ => None,
+    }
+}
+
+/// Returns the name under which the sketch backing a `Reduce` is kept in the sketch map.
+pub fn reduce_sketch_key(reduce_type: &ReduceType) -> Option<String> {
+    let sketch_key = match reduce_type {
+        ReduceType::CMReduce { memory_in_bytes, depth, .. } => {
+            format!("CMSketch_{}_{}", memory_in_bytes, depth)
+        }
+        ReduceType::FCMFirstLayerOnly { depth, width_l1, .. } => {
+            format!("FCMFirstLayerOnly_{}_{}", depth, width_l1)
+        }
+        ReduceType::FCMReduce { depth, width_l1, .. } => {
+            format!("FCMSketch_{}_{}", depth, width_l1)
+        }
+        ReduceType::ElasticReduce { depth, width, .. } => {
+            format!("ElasticSketch_{}_{}", depth, width)
+        }
+        ReduceType::BeauCoupReduce {
+            num_rows,
+            num_coupons,
+            d,
+            max_coupons_per_packet,
+            seed,
+        } => {
+            format!(
+                "BeauCoup_rows{}_coupons{}_d{}_cpp{}_seed{}",
+                num_rows, num_coupons, d, max_coupons_per_packet, seed
+            )
+        }
+        ReduceType::DeterministicReduce => "DeterministicSketch".to_string(),
+        ReduceType::BloomFilter { .. } => return None,
+    };
+    Some(sketch_key)
+}
+
+/// Name of the sketch behind a `Reduce` aggregating with `aggregation`; sums keep the name
+/// of their Reduce type.
+fn aggregate_sketch_key(reduce_type: &ReduceType, aggregation: Aggregation) -> Option<String> {
+    let sketch_key = reduce_sketch_key(reduce_type)?;
+    Some(match aggregation {
+        Aggregation::Sum => sketch_key,
+        Aggregation::Quantile(q) => format!("{}_Quantile{}", sketch_key, q),
+        other => format!("{}_{:?}", sketch_key, other),
+    })
+}
+
+pub fn new_reduce_sketch(reduce_type: &ReduceType) -> Sketch {
+    match reduce_type {
+        ReduceType::CMReduce { memory_in_bytes, depth, seed } => {
+            Sketch::new_cm_sketch(*memory_in_bytes, *depth, *seed)
+        }
+        ReduceType::BeauCoupReduce {
+            num_rows,
+            num_coupons,
+            d,
+            max_coupons_per_packet,
+            seed,
+        } => Sketch::new_beaucoup(*num_rows, *num_coupons, *d, *max_coupons_per_packet, *seed),
+        ReduceType::FCMFirstLayerOnly {
+            depth,
+            width_l1,
+            seed,
+        } => Sketch::new_fcm_first_layer_only(*depth, *width_l1, *seed),
+        ReduceType::FCMReduce {
+            depth,
+            width_l1,
+            width_l2,
+            width_l3,
+            threshold_l1,
+            threshold_l2,
+            seed,
+        } => Sketch::new_fcm_sketch(
+            *depth,
+            *width_l1,
+            *width_l2,
+            *width_l3,
+            *threshold_l1,
+            *threshold_l2,
+            *seed,
+        ),
+        ReduceType::ElasticReduce { depth, width, seed } => {
+            Sketch::new_elastic_sketch(*depth, *width, *seed)
+        }
+        ReduceType::DeterministicReduce => Sketch::new_deterministic_sketch(),
+        ReduceType::BloomFilter { .. } => panic!("BloomFilter should not reach here"),
+    }
+}
+
+fn reduce_depth(reduce_type: &ReduceType) -> usize {
+    match reduce_type {
+        ReduceType::CMReduce { depth, .. }
+        | ReduceType::FCMReduce { depth, .. }
+        | ReduceType::FCMFirstLayerOnly { depth, .. }
+        | ReduceType::ElasticReduce { depth, .. } => *depth,
+        _ => 1,
+    }
+}
+
+// Counts and sums increment a plain Reduce sketch. Exact Reduces keep the other
+// aggregates per key; the sketches keep a mean in two sketches of their type, quantiles in
+// one keyed by value bucket, and the rest in registers taking as much memory as the type.
+fn new_aggregate_sketch(reduce_type: &ReduceType, aggregation: Aggregation) -> Sketch {
+    let aggregate = match (aggregation, reduce_type) {
+        (Aggregation::Sum | Aggregation::Count, _) => return new_reduce_sketch(reduce_type),
+        (Aggregation::Mean, _) => AggregateSketch::mean(new_reduce_sketch(reduce_type), new_reduce_sketch(reduce_type)),
+        (_, ReduceType::DeterministicReduce) => AggregateSketch::exact(aggregation),
+        (Aggregation::Quantile(q), _) => AggregateSketch::quantile(q, new_reduce_sketch(reduce_type)),
+        _ => AggregateSketch::registers(
+            aggregation,
+            new_reduce_sketch(reduce_type).get_memory_usage(),
+            reduce_depth(reduce_type),
+        ),
+    };
+    Sketch::Aggregate(aggregate)
+}
+
+fn statistic_name(kind: StatisticKind) -> &'static str {
+    match kind {
+        StatisticKind::Cardinality => "Cardinality",
+        StatisticKind::Entropy => "Entropy",
+        StatisticKind::FlowSizeDistribution => "FlowSizeDistribution",
+    }
+}
+
+fn statistic_sketch_key(kind: StatisticKind, keys: &[String], statistic_type: &StatisticType) -> String {
+    let backend = match statistic_type {
+        StatisticType::Exact => "Exact".to_string(),
+        StatisticType::LightPart { memory_in_bytes, .. } => format!("LightPart_{}", memory_in_bytes),
+        StatisticType::FCMSketch { depth, width_l1, .. } => format!("FCMSketch_{}_{}", depth, width_l1),
+    };
+    format!("{}({})_{}", statistic_name(kind), keys.join(", "), backend)
+}
+
+fn new_statistic_sketch(statistic_type: &StatisticType) -> Sketch {
+    match statistic_type {
+        StatisticType::Exact => Sketch::new_deterministic_sketch(),
+        StatisticType::LightPart { memory_in_bytes, seed } => Sketch::new_light_part(*memory_in_bytes, *seed),
+        StatisticType::FCMSketch {
+            depth,
+            width_l1,
+            width_l2,
+            width_l3,
+            threshold_l1,
+            threshold_l2,
+            seed,
+            ..
+        } => Sketch::new_fcm_sketch(*depth, *width_l1, *width_l2, *width_l3, *threshold_l1, *threshold_l2, *seed),
+    }
+}
+
+fn hhh_sketch_key(keys: &[String], prefix_step: u8, reduce_type: &ReduceType, randomized: bool) -> String {
+    let backend = reduce_sketch_key(reduce_type).unwrap_or_default();
+    let sampling = if randomized { "_RHHH" } else { "" };
+    format!("HierarchicalHeavyHitters({})_{}_{}{}", keys.join(", "), prefix_step, backend, sampling)
+}
+
+fn new_hhh_sketch(dimensions: usize, prefix_step: u8, reduce_type: &ReduceType, randomized: bool) -> Sketch {
+    Sketch::HierarchicalHeavyHitters(HHHSketch::new(dimensions, prefix_step, randomized, || new_reduce_sketch(reduce_type)))
+}
+
+fn statistic_estimate(sketch: &Sketch, statistic_type: &StatisticType) -> Option<FlowSizeEstimate> {
+    match (sketch, statistic_type) {
+        (Sketch::DeterministicSketch(sketch), StatisticType::Exact) => Some(sketch.get_distribution()),
+        (Sketch::LightPart(sketch), StatisticType::LightPart { .. }) => Some(sketch.get_flow_size_estimate()),
+        (Sketch::FCMSketch(sketch), StatisticType::FCMSketch { em_iterations, .. }) => {
+            Some(sketch.get_distribution(*em_iterations))
+        }
+        _ => None,
+    }
+}
+
+// Looks up a sketch by name, creating it on first use without allocating on later calls.
+fn sketch_entry<'a>(
+    sketches: &'a mut HashMap<String, Sketch>,
+    sketch_key: &str,
+    create: impl FnOnce() -> Sketch,
+) -> &'a mut Sketch {
+    if !sketches.contains_key(sketch_key) {
+        sketches.insert(sketch_key.to_string(), create());
+    }
+    sketches.get_mut(sketch_key).unwrap()
+}
+
+/// Per-packet work of a sketch update, as the fewest and most over the paths a packet can
+/// take (an FCM counter overflowing into the upper layers, a Bloom filter hit or miss).
+#[derive(Clone, Copy, Debug)]
+pub struct SketchCost {
+    /// Counter memory as configured; `None` for exact sketches, which grow with the keys.
+    pub memory_bytes: Option<usize>,
+    pub hashes: (usize, usize),
+    /// Counter reads and writes, a read-modify-write counting once.
+    pub memory_accesses: (usize, usize),
+}
+
+fn configured_memory(sketch: Sketch) -> Option<usize> {
+    match sketch {
+        Sketch::DeterministicSketch(_) => None,
+        Sketch::Aggregate(sketch) if sketch.is_exact() => None,
+        Sketch::HierarchicalHeavyHitters(sketch) if sketch.get_memory_usage() == 0 => None,
+        sketch => Some(sketch.get_memory_usage()),
+    }
+}
+
+// `Reduce` increments the sketch and then reads the estimate back. A mean does so in two
+// sketches, a quantile reads back every bucket of the key, and registers hash the key once
+// per row (and once more for the fingerprint of `First` and `Last`).
+fn reduce_cost(reduce_type: &ReduceType, aggregation: Aggregation) -> SketchCost {
+    let (hashes, memory_accesses) = match reduce_type {
+        ReduceType::CMReduce { depth, .. } | ReduceType::FCMFirstLayerOnly { depth, .. } => {
+            ((2 * depth, 2 * depth), (2 * depth, 2 * depth))
+        }
+        ReduceType::FCMReduce { depth, .. } => ((2 * depth, 2 * depth), (2 * depth, 6 * depth)),
+        ReduceType::ElasticReduce { depth, .. } => ((2 * depth, 2 * depth), (2 * depth, 2 * depth + 2)),
+        ReduceType::BeauCoupReduce { d, max_coupons_per_packet, .. } => {
+            let accesses = d * max_coupons_per_packet + d;
+            ((2 * d, 2 * d), (accesses, accesses))
+        }
+        ReduceType::DeterministicReduce | ReduceType::BloomFilter { .. } => ((2, 2), (2, 2)),
+    };
+    let depth = reduce_depth(reduce_type);
+    let twice = |(fewest, most): (usize, usize)| (2 * fewest, 2 * most);
+    let every_bucket = |(fewest, most): (usize, usize)| (fewest, most.div_ceil(2) * (1 + QUANTILE_BUCKETS));
+    let (hashes, memory_accesses) = match (aggregation, reduce_type) {
+        (Aggregation::Sum | Aggregation::Count, _) | (_, ReduceType::DeterministicReduce) => (hashes, memory_accesses),
+        (Aggregation::Mean, _) => (twice(hashes), twice(memory_accesses)),
+        (Aggregation::Quantile(_), _) => (every_bucket(hashes), every_bucket(memory_accesses)),
+        (Aggregation::First | Aggregation::Last, _) => ((2 * depth + 2, 2 * depth + 2), (2 * depth, 2 * depth)),
+        _ => ((2 * depth, 2 * depth), (2 * depth, 2 * depth)),
+    };
+    let memory_bytes = match reduce_type {
+        ReduceType::BloomFilter { .. } => None,
+        _ => configured_memory(new_aggregate_sketch(reduce_type, aggregation)),
+    };
+    SketchCost { memory_bytes, hashes, memory_accesses }
+}
+
+// `Distinct` looks the key up and inserts it when new.
+fn distinct_cost(distinct_type: &ReduceType) -> SketchCost {
+    match distinct_type {
+        ReduceType::BloomFilter { size, num_hashes, seed } => SketchCost {
+            memory_bytes: configured_memory(Sketch::new_bloom_filter(*size, *num_hashes, *seed)),
+            hashes: (*num_hashes, 2 * num_hashes),
+            memory_accesses: (*num_hashes, 2 * num_hashes),
+        },
+        _ => SketchCost { memory_bytes: None, hashes: (1, 2), memory_accesses: (1, 2) },
+    }
+}
+
+// Statistics only insert; the light part also moves the key between two histogram buckets.
+fn statistic_cost(statistic_type: &StatisticType) -> SketchCost {
+    let (hashes, memory_accesses) = match statistic_type {
+        StatisticType::Exact => ((1, 1), (1, 1)),
+        StatisticType::LightPart { .. } => ((1, 1), (3, 3)),
+        StatisticType::FCMSketch { depth, .. } => ((*depth, *depth), (*depth, 3 * depth)),
+    };
+    SketchCost { memory_bytes: configured_memory(new_statistic_sketch(statistic_type)), hashes, memory_accesses }
+}
+
+// Every lattice node, or one random node with RHHH, is updated like a `Reduce` without
+// the read-back.
+fn hhh_cost(dimensions: usize, prefix_step: u8, reduce_type: &ReduceType, randomized: bool) -> SketchCost {
+    let node = reduce_cost(reduce_type, Aggregation::Sum);
+    let nodes = if randomized { 1 } else { (32 / prefix_step as usize + 1).pow(dimensions as u32) };
+    let per_packet = |(fewest, most): (usize, usize)| (nodes * fewest.div_ceil(2), nodes * most.div_ceil(2));
+    SketchCost {
+        memory_bytes: configured_memory(new_hhh_sketch(dimensions, prefix_step, reduce_type, randomized)),
+        hashes: per_packet(node.hashes),
+        memory_accesses: per_packet(node.memory_accesses),
+    }
+}
+
+/// One operation of a compiled plan, as `explain` describes it.
+pub struct OperatorProfile<'a> {
+    /// Layout of the records the operation passes on.
+    pub output_schema: &'a Schema,
+    /// Sketch updated for every packet reaching the operation, and what that costs.
+    pub sketch: Option<(&'a str, SketchCost)>,
+    /// Left and right plans of a `Join`.
+    pub sub_plans: Option<(&'a CompiledPlan, &'a CompiledPlan)>,
+}
+
+/// Whether `FilterResult` keeps a key with this estimate.
+pub fn passes_filter(estimate: &Estimate, threshold: u64, mode: FilterMode) -> bool {
+    let compared = match mode {
+        FilterMode::Estimate => estimate.value,
+        FilterMode::Definitely => estimate.lower(),
+        FilterMode::Possibly => estimate.upper(),
+    };
+    compared >= threshold
+}
+
+fn filter_results(
+    result_map: &mut HashMap<Vec<u8>, Record>,
+    threshold: u64,
+    value_slot: usize,
+    mode: FilterMode,
+    bound: Option<ErrorBound>,
+) {
+    result_map.retain(|key, fields| {
+        if let Some(PacketField::U32(value)) = fields.get(value_slot) {
+            passes_filter(&Estimate::new(*value as u64, bound), threshold, mode)
+        } else {
+            eprintln!(
+                "Error filter result: Field slot {} not found or invalid in entry '{:?}'",
+                value_slot, key
+            );
+            false // Remove the entry if the field is missing or invalid
+        }
+    });
+}
+
+/// How packets may be spread over parallel workers without changing the result.
+#[derive(Debug)]
+pub enum Sharding {
+    /// Packets with equal values in these packet slots must go to the same worker.
+    ByKey(Vec<(usize, FieldType)>),
+    /// Any worker may take any packet; only the epoch-level sketches are merged.
+    RoundRobin,
+}
+
+impl CompiledPlan {
+    /// Works out how this plan can be sharded. Every `Distinct` and `Reduce` key must
+    /// contain the shard key so that each group is seen by a single worker; sketches read
+    /// at the end of the epoch are merged across workers.
+    pub fn sharding(&self) -> Result<Sharding, String> {
+        // For each slot of the current record, the packet slot it was copied from.
+        let mut origins: Vec<Option<usize>> = (0..Schema::packet().len()).map(Some).collect();
+        let mut group_keys: Vec<Vec<(Option<usize>, FieldType)>> = Vec::new();
 
-                    ReduceType::FCMFirstLayerOnly { depth, width_l1, seed } => {
-                        format!("FCMFirstLayerOnly_{}_{}", depth, width_l1)
+        for op in &self.operations {
+            match op {
+                CompiledOperation::Filter(_)
+                | CompiledOperation::FilterResult { .. }
+                | CompiledOperation::FCMEstimate { .. }
+                | CompiledOperation::Statistic { .. }
+                | CompiledOperation::HierarchicalHeavyHitters { .. } => {}
+                CompiledOperation::Map(outputs) => {
+                    origins = outputs
+                        .iter()
+                        .map(|output| match output {
+                            MapOutput::Field(source) => origins.get(*source).copied().flatten(),
+                            MapOutput::Constant(_) | MapOutput::Function(_) => None,
+                        })
+                        .collect();
+                }
+                // A truncated address no longer identifies the packets sharing it.
+                CompiledOperation::RefinePrefix { slot, .. } => origins[*slot] = None,
+                CompiledOperation::Reduce { key, computed, .. } => {
+                    // Computed key fields do not trace back to a packet field.
+                    for (slot, _) in computed {
+                        origins.resize(origins.len().max(slot + 1), None);
                     }
-                    ReduceType::FCMReduce {
-                        depth,
-                        width_l1,
-                        width_l2,
-                        width_l3,
-                        threshold_l1,
-                        threshold_l2,
-                        seed,
-                    } => {
-                        format!("FCMSketch_{}_{}", depth, width_l1)
+                    group_keys.push(key.iter().map(|(slot, field_type)| (origins[*slot], *field_type)).collect());
+                }
+                CompiledOperation::Distinct { key, .. } => {
+                    group_keys.push(key.iter().map(|(slot, field_type)| (origins[*slot], *field_type)).collect());
+                }
+                CompiledOperation::Join { .. }
+                | CompiledOperation::MapJoin(_)
+                | CompiledOperation::FilterJoin { .. } => {
+                    return Err("joins are evaluated on shared state".to_string());
+                }
+            }
+        }
+
+        if group_keys.is_empty() {
+            return Ok(Sharding::RoundRobin);
+        }
+        // The shard key is a grouping key, fully traceable to packet fields, that every
+        // other grouping key contains.
+        let contains = |outer: &[(Option<usize>, FieldType)], inner: &[(Option<usize>, FieldType)]| {
+            inner.iter().all(|(origin, _)| outer.iter().any(|(o, _)| o == origin))
+        };
+        let shard_key = group_keys
+            .iter()
+            .filter(|key| key.iter().all(|(origin, _)| origin.is_some()))
+            .find(|key| group_keys.iter().all(|other| contains(other, key)))
+            .ok_or_else(|| "grouping keys do not share a common shard key".to_string())?
+            .iter()
+            .map(|(origin, field_type)| (origin.unwrap(), *field_type))
+            .collect();
+        Ok(Sharding::ByKey(shard_key))
+    }
+
+    /// Name of the sketch behind the last `Reduce`, whose keys make up the result map.
+    pub fn reduce_sketch_key(&self) -> Option<&str> {
+        self.operations.iter().rev().find_map(|op| match op {
+            CompiledOperation::Reduce { sketch_key, .. } => Some(sketch_key.as_str()),
+            _ => None,
+        })
+    }
+
+    /// Adds the result map key of a record returned by `execute_query` to `keys`, so the
+    /// keys the last `Reduce` updated in an epoch are known after `FilterResult` drops
+    /// some of them. Plans that rebuild records after their last `Reduce` add nothing.
+    pub fn record_result_key(&self, record: &Record, keys: &mut HashSet<Vec<u8>>) {
+        let last = self.operations.iter().rev().find(|op| {
+            !matches!(
+                op,
+                CompiledOperation::FilterResult { .. } | CompiledOperation::FCMEstimate { .. } | CompiledOperation::Statistic { .. }
+            )
+        });
+        if let Some(CompiledOperation::Reduce { key, .. }) = last {
+            let key = encode_key(key, record);
+            if !keys.contains(key.as_bytes()) {
+                keys.insert(key.as_bytes().to_vec());
+            }
+        }
+    }
+
+    /// Slot of the result map holding each key's value: the field of the last `Reduce`,
+    /// or `count` once a join has rebuilt the records.
+    pub fn value_slot(&self) -> Option<usize> {
+        for op in self.operations.iter().rev() {
+            match op {
+                CompiledOperation::Reduce { value_slot, .. } => return Some(*value_slot),
+                CompiledOperation::Join { .. } | CompiledOperation::MapJoin(_) => break,
+                _ => {}
+            }
+        }
+        self.result_schema.slot("count")
+    }
+
+    /// Sketch whose estimates fill `slot` of the result map, or `None` if its values are
+    /// exact or computed after a join.
+    pub fn estimate_sketch_key(&self, slot: usize) -> Option<&str> {
+        for op in self.operations.iter().rev() {
+            match op {
+                CompiledOperation::Reduce { sketch_key, value_slot, .. } => {
+                    return (*value_slot == slot).then_some(sketch_key.as_str());
+                }
+                CompiledOperation::Join { .. } | CompiledOperation::MapJoin(_) => return None,
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// Error bound on the values in `slot` of the result map; `None` if the sketch behind
+    /// them gives no guarantee.
+    pub fn value_error_bound(&self, slot: usize, sketches: &HashMap<String, Sketch>) -> Option<ErrorBound> {
+        match self.estimate_sketch_key(slot) {
+            Some(sketch_key) => sketches.get(sketch_key)?.error_bound(),
+            None => Some(ErrorBound::EXACT),
+        }
+    }
+
+    /// Names of the sketches read at the end of the epoch, by `finalize_epoch` and for
+    /// error bounds, which workers must merge.
+    pub fn epoch_sketch_keys(&self) -> Vec<String> {
+        let mut keys = Vec::new();
+        for op in &self.operations {
+            match op {
+                CompiledOperation::FCMEstimate { sketch_key, .. }
+                | CompiledOperation::Statistic { sketch_key, .. }
+                | CompiledOperation::HierarchicalHeavyHitters { sketch_key, .. } => keys.push(sketch_key.clone()),
+                CompiledOperation::Join { left_query, right_query, .. } => {
+                    keys.extend(left_query.epoch_sketch_keys());
+                    keys.extend(right_query.epoch_sketch_keys());
+                }
+                _ => {}
+            }
+        }
+        keys.extend(self.reduce_sketch_key().map(str::to_string));
+        keys
+    }
+
+    /// One profile per operation, in plan order.
+    pub fn operator_profiles(&self) -> Vec<OperatorProfile<'_>> {
+        self.operations
+            .iter()
+            .zip(&self.output_schemas)
+            .map(|(op, output_schema)| {
+                let sketch = match op {
+                    CompiledOperation::Reduce { reduce_type, aggregation, sketch_key, .. } => {
+                        Some((sketch_key.as_str(), reduce_cost(reduce_type, *aggregation)))
                     }
-                    ReduceType::ElasticReduce { depth, width, seed } => {
-                        format!("ElasticSketch_{}_{}", depth, width)
+                    CompiledOperation::Distinct { distinct_type, sketch_key, .. } => {
+                        Some((sketch_key.as_str(), distinct_cost(distinct_type)))
                     }
-
-                    ReduceType::BeauCoupReduce {
-                        num_rows,
-                        num_coupons,
-                        d,
-                        max_coupons_per_packet,
-                        seed,
-                    } => {
-                        format!(
-                            "BeauCoup_rows{}_coupons{}_d{}_cpp{}_seed{}",
-                            num_rows, num_coupons, d, max_coupons_per_packet, seed
-                        )
+                    CompiledOperation::Statistic { statistic_type, sketch_key, .. } => {
+                        Some((sketch_key.as_str(), statistic_cost(statistic_type)))
                     }
-                    ReduceType::DeterministicReduce => "DeterministicSketch".to_string(),
-                    ReduceType::BloomFilter { .. } => {
-                        eprintln!("Error: BloomFilter is not supported in Reduce operation");
-                        return None;
+                    CompiledOperation::HierarchicalHeavyHitters { key, prefix_step, reduce_type, randomized, sketch_key, .. } => {
+                        Some((sketch_key.as_str(), hhh_cost(key.len(), *prefix_step, reduce_type, *randomized)))
                     }
+                    _ => None,
                 };
-            
-                let sketch = sketches.entry(sketch_key.clone()).or_insert_with(|| match reduce_type {
-                    ReduceType::CMReduce { memory_in_bytes, depth, seed } => {
-                        Sketch::new_cm_sketch(*memory_in_bytes, *depth, *seed)
-                    }
-                    ReduceType::BeauCoupReduce {
-                        num_rows,
-                        num_coupons,
-                        d,
-                        max_coupons_per_packet,
-                        seed,
-                    } => Sketch::new_beaucoup(*num_rows, *num_coupons, *d, *max_coupons_per_packet, *seed),
-                    ReduceType::FCMFirstLayerOnly {
-                        depth,
-                        width_l1,
-                        seed,
-                    } => Sketch::new_fcm_first_layer_only(*depth, *width_l1, *seed),
-                    ReduceType::FCMReduce {
-                        depth,
-                        width_l1,
-                        width_l2,
-                        width_l3,
-                        threshold_l1,
-                        threshold_l2,
-                        seed,
-                    } => Sketch::new_fcm_sketch(
-                        *depth,
-                        *width_l1,
-                        *width_l2,
-                        *width_l3,
-                        *threshold_l1,
-                        *threshold_l2,
-                        *seed,
-                    ),
-                    ReduceType::ElasticReduce { depth, width, seed } => {
-                        Sketch::new_elastic_sketch(*depth, *width, *seed)
+                let sub_plans = match op {
+                    CompiledOperation::Join { left_query, right_query, .. } => Some((&**left_query, &**right_query)),
+                    _ => None,
+                };
+                OperatorProfile { output_schema, sketch, sub_plans }
+            })
+            .collect()
+    }
+
+    /// Threshold, value slot and mode of the last `FilterResult`, whose output raises alerts.
+    pub fn filter_result(&self) -> Option<(u64, usize, FilterMode)> {
+        self.operations.iter().rev().find_map(|op| match op {
+            CompiledOperation::FilterResult { threshold, value_slot, mode } => Some((*threshold, *value_slot, *mode)),
+            _ => None,
+        })
+    }
+
+    /// Applies the end-of-epoch `FilterResult` operations to a merged result map.
+    pub fn apply_epoch_filters(&self, result_map: &mut HashMap<Vec<u8>, Record>, sketches: &HashMap<String, Sketch>) {
+        for op in &self.operations {
+            if let CompiledOperation::FilterResult { threshold, value_slot, mode } = op {
+                filter_results(result_map, *threshold, *value_slot, *mode, self.value_error_bound(*value_slot, sketches));
+            }
+        }
+    }
+}
+
+/// Runs the epoch-level operators of a plan against the sketches built during the epoch.
+/// Must be called before the sketches are cleared for the next epoch.
+pub fn finalize_epoch(plan: &CompiledPlan, sketches: &HashMap<String, Sketch>) -> Vec<(String, EpochStatistic)> {
+    let mut statistics = Vec::new();
+
+    for op in &plan.operations {
+        match op {
+            CompiledOperation::FCMEstimate { sketch_key, em_iterations } => match sketches.get(sketch_key) {
+                Some(Sketch::FCMSketch(sketch)) => {
+                    let estimate = sketch.get_distribution(*em_iterations);
+                    statistics.push((sketch_key.clone(), EpochStatistic::FlowSizeDistribution(estimate)));
+                }
+                Some(_) => eprintln!("Error: FCMEstimate requires an FCMReduce, found '{}'", sketch_key),
+                None => {}
+            },
+            CompiledOperation::Statistic { kind, statistic_type, sketch_key, .. } => {
+                let estimate = match sketches.get(sketch_key).and_then(|s| statistic_estimate(s, statistic_type)) {
+                    Some(estimate) => estimate,
+                    None => continue,
+                };
+                let statistic = match kind {
+                    StatisticKind::Cardinality => EpochStatistic::Cardinality(estimate.cardinality),
+                    StatisticKind::Entropy => EpochStatistic::Entropy(estimate.entropy),
+                    StatisticKind::FlowSizeDistribution => EpochStatistic::FlowSizeDistribution(estimate),
+                };
+                statistics.push((sketch_key.clone(), statistic));
+            }
+            CompiledOperation::HierarchicalHeavyHitters { threshold, sketch_key, .. } => {
+                if let Some(Sketch::HierarchicalHeavyHitters(sketch)) = sketches.get(sketch_key) {
+                    let heavy = sketch.heavy_prefixes(*threshold);
+                    statistics.push((sketch_key.clone(), EpochStatistic::HierarchicalHeavyHitters(heavy)));
+                }
+            }
+            CompiledOperation::Join { left_query, right_query, .. } => {
+                statistics.extend(finalize_epoch(left_query, sketches));
+                statistics.extend(finalize_epoch(right_query, sketches));
+            }
+            _ => {}
+        }
+    }
+    statistics
+}
+
+pub fn execute_query(
+    plan: &CompiledPlan,
+    packet: Record,
+    sketches: &mut HashMap<String, Sketch>,
+    result_map: &mut HashMap<Vec<u8>, Record>,
+    epoch_size: u64,
+    current_epoch_start: &mut Option<u64>,
+    timestamp: u64,
+) -> Option<Record>  {
+    let mut current_packet = packet;
+
+    for op in &plan.operations {
+        match op {
+            CompiledOperation::Filter(conditions) => {
+                // Break early if a condition fails
+                if !conditions.iter().all(|(slot, value)| current_packet.get(*slot) == Some(value)) {
+                    return None;
+                }
+            }
+            CompiledOperation::Map(outputs) => {
+                let mut new_packet = Record::default();
+                for (slot, output) in outputs.iter().enumerate() {
+                    match output {
+                        MapOutput::Field(source) => {
+                            if let Some(value) = current_packet.get(*source) {
+                                new_packet.set(slot, value.clone());
+                            }
+                        }
+                        MapOutput::Constant(value) => new_packet.set(slot, value.clone()),
+                        MapOutput::Function(function) => {
+                            if let Some(value) = function.evaluate(&current_packet) {
+                                new_packet.set(slot, value);
+                            }
+                        }
                     }
-                    ReduceType::DeterministicReduce => Sketch::new_deterministic_sketch(),
-                    ReduceType::BloomFilter { .. } => panic!("BloomFilter should not reach here"),
-                });
-            
-                if let Some(PacketField::U32(current_value)) = current_packet.get(field_name) {
-                    let normalized_value = *current_value as u32; 
+                }
+                current_packet = new_packet;
+            }
 
-                    
-                    // let raw = (*current_value).max(2) as f64; // to avoid ln(0), ln(1)
-                    // let loglog_value = raw.ln().ln();
-                    // let normalized_value = loglog_value.max(1.0).floor() as u32;
 
+            CompiledOperation::Reduce { key, computed, reduce_type, aggregation, sketch_key, value_slot } => {
+                for (slot, function) in computed {
+                    if let Some(value) = function.evaluate(&current_packet) {
+                        current_packet.set(*slot, value);
+                    }
+                }
+                let key = encode_key(key, &current_packet);
+                let sketch = sketch_entry(sketches, sketch_key, || new_aggregate_sketch(reduce_type, *aggregation));
 
-                    current_packet.insert(
-                        field_name.to_string(),
-                        PacketField::U32(normalized_value),
-                    );
+                if let Some(PacketField::U32(current_value)) = current_packet.get(*value_slot) {
+                    let value = if *aggregation == Aggregation::Count { 1 } else { *current_value as u64 };
+                    sketch.increment(key.as_bytes(), value);
+                    let estimated_count = sketch.estimate(key.as_bytes());
 
-                    // println!("current packet: {:?}", current_packet);
-                    sketch.increment(&key, normalized_value as u64);
-                    let estimated_count = sketch.estimate(&key);
-                    // if key == "dst_ip: 35.26.185.176, src_ip: 163.27.199.6" {
-                    //     println!("Estimated count for key '{}': {}", key, estimated_count);
-                    // }
+                    current_packet.set(*value_slot, PacketField::U32(estimated_count as u32));
 
-                    current_packet.insert(
-                        field_name.to_string(),
-                        PacketField::U32(estimated_count as u32),
-                    );
-                    // if key == "dst_ip: 35.26.185.176, src_ip: 163.27.199.6" {
-                    //     println!("current packet field : {:?},  field value : {:?} and estimated count: {:?}", field_name.to_string(), current_packet.get(field_name), estimated_count);
-                    // }
-
-                    
-                    
-                    result_map.insert(key.clone(), current_packet.clone());
-            
+                    // A key allocates once per epoch, when first seen; later packets copy
+                    // into its record in place. Copying a record still allocates for each
+                    // String field it holds (string constants and port_range).
+                    match result_map.get_mut(key.as_bytes()) {
+                        Some(result) => result.clone_from(&current_packet),
+                        None => {
+                            result_map.insert(key.as_bytes().to_vec(), current_packet.clone());
+                        }
+                    }
                 } else {
                     return None;
                 }
-                // println!("packet after reduce operation: {:?}", current_packet);
             }
-            
-            
-            Operation::FilterResult { threshold, field_name } => {
-                // println!("timestamp: {}, current_epoch_start: {:?}", timestamp, current_epoch_start);
-                // println!("result map before filtering: {:?}", result_map);
+
+
+            CompiledOperation::FilterResult { threshold, value_slot, mode } => {
                 if timestamp - current_epoch_start.unwrap() >= epoch_size  {
-                    // println!("result map start filtering: {:?}", result_map);
-                    result_map.retain(|key, fields| {
-                        // println!("Checking key: '{}', fields: {:?}", key, fields);
-                        if let Some(PacketField::U32(value)) = fields.get(field_name) {
-                            if *value >= *threshold as u32 {
-                                // println!(
-                                //     "Keeping entry: '{}' with field '{}' value: {}",
-                                //     key, field_name, value
-                                // );
-                                true // Keep the entry
-                            } else {
-                                false // Remove the entry
-                            }
-                        } else {
-                            eprintln!(
-                                "Error filter result: Field '{}' not found or invalid in entry '{}'",
-                                field_name, key
-                            );
-                            false // Remove the entry if the field is missing or invalid
-                        }
-                    });
-                    println!("result map after filtering: {:?}", result_map);
-                
+                    let bound = plan.value_error_bound(*value_slot, sketches);
+                    filter_results(result_map, *threshold, *value_slot, *mode, bound);
+                    println!("result map after filtering: {} entries", result_map.len());
+
                 }
             },
-            Operation::Distinct { keys, distinct_type } => {
+            CompiledOperation::Distinct { key, distinct_type, sketch_key } => {
                 // Generate a unique key for the group based on the specified keys
-                let key = keys
-                .iter()
-                .map(|k| {
-                    current_packet.get(k).map_or(format!("{}: <missing>", k), |v| match v {
-                        PacketField::String(s) => format!("{}: {}", k, s),
-                        PacketField::U16(v) => format!("{}: {}", k, v),
-                        PacketField::U8(v) => format!("{}: {}", k, v),
-                        PacketField::OptionU16(Some(v)) => format!("{}: {}", k, v),
-                        _ => format!("{}: <invalid>", k),
-                    })
-                })
-                .collect::<Vec<_>>()
-                .join(", ");
+                let key = encode_key(key, &current_packet);
                 match distinct_type {
                     ReduceType::BloomFilter { size, num_hashes, seed } => {
-                        let sketch_key = format!("DistinctBloomFilter_{}_{}", size, num_hashes);
-                        let bloom_filter = sketches.entry(sketch_key.clone()).or_insert_with(|| {
+                        let bloom_filter = sketch_entry(sketches, sketch_key, || {
                             Sketch::new_bloom_filter(*size, *num_hashes, *seed)
                         });
-            
-                        if bloom_filter.contains(&key) {
-                            current_packet.clear();
 
+                        if bloom_filter.contains(key.as_bytes()) {
                             return None;
                         } else {
-                            bloom_filter.insert(&key);
+                            bloom_filter.insert(key.as_bytes());
                         }
                     }
-                    ReduceType::DeterministicReduce => {
-                        let sketch_key = "DistinctDeterministicSketch".to_string();
-                        let deterministic_sketch = sketches.entry(sketch_key.clone()).or_insert_with(|| {
-                            Sketch::new_deterministic_sketch()
-                        });
-            
-                        let count = deterministic_sketch.estimate(&key);
+                    _ => {
+                        let deterministic_sketch = sketch_entry(sketches, sketch_key, Sketch::new_deterministic_sketch);
+
+                        let count = deterministic_sketch.estimate(key.as_bytes());
                         if count > 0 {
-                            current_packet.clear();
                             return None;
                         } else {
-                            deterministic_sketch.increment(&key, 1);
+                            deterministic_sketch.increment(key.as_bytes(), 1);
                         }
                     }
-                    _ => {
-                        eprintln!("Unsupported distinct type");
-                        return None;
-                    }
                 }
             }
-            Operation::Join { left_query, right_query, left_keys, right_keys } => {
-
-                let mut join_timestamp: Option<u64> = None;
-                join_timestamp.get_or_insert(timestamp);
-            
+            CompiledOperation::Join { left_query, right_query, key_slots, right_fields } => {
                 // Execute the left query
                 {
                     let mut left_results = LEFT_RESULTS.lock().unwrap();
@@ -432,13 +1231,13 @@ pub fn execute_query(
                         left_query,
                         current_packet.clone(),
                         sketches,
-                        &mut left_results, 
+                        &mut left_results,
                         epoch_size,
                         current_epoch_start,
                         timestamp,
                     );
                 }
-            
+
                 // Execute the right query
                 {
                     let mut right_results = RIGHT_RESULTS.lock().unwrap();
@@ -453,160 +1252,269 @@ pub fn execute_query(
                     );
                 }
 
-            
+
                 if timestamp - current_epoch_start.unwrap() >= epoch_size -1 {
                     println!("Join operation triggered at timestamp: {}", timestamp);
 
-                    let mut joined_results: HashMap<String, HashMap<String, PacketField>> = HashMap::new();
-                        
+                    let mut joined_results: HashMap<Vec<u8>, Record> = HashMap::new();
+
                     // Lock both LEFT_RESULTS and RIGHT_RESULTS in a consistent order
-                    let (left_results, right_results) = {
-                        let left_results = LEFT_RESULTS.lock().unwrap();
-                        let right_results = RIGHT_RESULTS.lock().unwrap();
-                        (left_results.clone(), right_results.clone()) // Clone to avoid holding locks during iteration
-                    };
-            
-                    for (left_key, left_fields) in &left_results {
-                        for (right_key, right_fields) in &right_results {
-
-                            if left_keys.iter().zip(right_keys.iter()).all(|(left_field, right_field)| {
-                                match (left_fields.get(left_field), right_fields.get(right_field)) {
-                                    (Some(left_value), Some(right_value)) => {
-                                        left_value == right_value
-                                    }
-                                    _ => {
-                                        println!(
-                                            "Fields do not match - left_field: {}, right_field: {}, left_value: {:?}, right_value: {:?}",
-                                            left_field, right_field, left_fields.get(left_field), right_fields.get(right_field)
-                                        );
-                                        false
-                                    }
+                    let left_results = LEFT_RESULTS.lock().unwrap();
+                    let right_results = RIGHT_RESULTS.lock().unwrap();
+                    let left_len = left_query.result_schema.len();
+
+                    for (left_key, left_fields) in left_results.iter() {
+                        for right_record in right_results.values() {
+                            let matched = key_slots.iter().all(|(left_slot, right_slot)| {
+                                match (left_fields.get(*left_slot), right_record.get(*right_slot)) {
+                                    (Some(left_value), Some(right_value)) => left_value == right_value,
+                                    _ => false,
                                 }
-                            }) {
-                                // println!("Fields matched for left_key: {} and right_key: {}", left_key, right_key);
-            
+                            });
+                            if matched {
                                 // Perform the join operation
                                 let mut joined_fields = left_fields.clone();
-                                for (key, value) in right_fields {
-                                    // println!("Adding field to joined_fields - key: {}, value: {:?}", key, value);
-                                    joined_fields.entry(key.clone()).or_insert(value.clone());
+                                for (i, right_slot) in right_fields.iter().enumerate() {
+                                    if let Some(value) = right_record.get(*right_slot) {
+                                        joined_fields.set(left_len + i, value.clone());
+                                    }
                                 }
-            
-                                // Add the joined result to the final results
                                 joined_results.insert(left_key.clone(), joined_fields);
-                                // println!("join result: {:?}", joined_results);
-
                             }
                         }
                     }
-
-                    // println!("join result :{:?}" , joined_results);
-
+                    drop(left_results);
+                    drop(right_results);
 
                     result_map.clear();
                     result_map.extend(joined_results);
-                    // println!("result map: {:?}", result_map);
-            
+
                     // Clear for the next epoch
-                    {
-                        let mut left_results = LEFT_RESULTS.lock().unwrap();
-                        let mut right_results = RIGHT_RESULTS.lock().unwrap();
-                        left_results.clear();
-                        right_results.clear();
-                    }
-            
-                    join_timestamp = Some(timestamp);
+                    LEFT_RESULTS.lock().unwrap().clear();
+                    RIGHT_RESULTS.lock().unwrap().clear();
                 } else {
                     return None;
                 }
             }
-            Operation::MapJoin(expr) => {
+            CompiledOperation::MapJoin(outputs) => {
                 if timestamp - current_epoch_start.unwrap_or(0) >= epoch_size {
-            
-                    let mut mapped_results = HashMap::new();
-            
-                    for (key, result) in result_map.iter() {
-                        // println!("Processing result for key: {:?}, value: {:?}", key, result);
-            
-                        let mut new_result = HashMap::new();
-                        let operations: Vec<&str> = expr
-                            .trim_matches(|c| c == '(' || c == ')')
-                            .split(',')
-                            .map(|s| s.trim())
-                            .collect();
-            
-                        for operation in operations {
-                            if operation.contains('=') {
-                                let parts: Vec<&str> = operation.split('=').map(|s| s.trim()).collect();
-                                if parts.len() == 2 {
-                                    let key = parts[0];
-                                    let value_expr = parts[1];
-            
-                                    // Evaluate the expression (e.g., "left.count_left + right.count_right")
-                                    let evaluated_value = evaluate_expression(value_expr, result);
-            
+
+                    for result in result_map.values_mut() {
+                        let mut new_result = Record::default();
+                        for (slot, output) in outputs.iter().enumerate() {
+                            match output {
+                                MapJoinOutput::Field(source) => {
+                                    if let Some(value) = result.get(*source) {
+                                        new_result.set(slot, value.clone());
+                                    }
+                                }
+                                MapJoinOutput::Expression { left, operator, right } => {
+                                    let evaluated_value = match (result.get(*left), result.get(*right)) {
+                                        (Some(l), Some(r)) => evaluate_expression(l, operator, r),
+                                        _ => None,
+                                    };
                                     if let Some(evaluated_value) = evaluated_value {
-                                        new_result.insert(key.to_string(), evaluated_value);
+                                        new_result.set(slot, evaluated_value);
                                     } else {
-                                        eprintln!("Failed to evaluate expression: {}", value_expr);
+                                        eprintln!("Failed to evaluate expression: slots {} {} {}", left, operator, right);
                                     }
                                 }
-                            } else {
-                                if let Some(value) = result.get(operation) {
-                                    new_result.insert(operation.to_string(), value.clone());
-                                }
                             }
                         }
-                        mapped_results.insert(key.clone(), new_result);
-
+                        *result = new_result;
                     }
 
-                    // println!("result map: {:?}", result_map);
-            
-                    // Clear and update result_map with the mapped results
-                    result_map.clear();
-                    result_map.extend(mapped_results);
-            
                 } else {
                     println!("Epoch not reached. Skipping MapJoin operation.");
                 }
             }
-            Operation::FilterJoin { threshold, field_name } => {
+            CompiledOperation::FilterJoin { threshold, value_slot } => {
                 let mut epoch_results = EPOCH_RESULTS.lock().unwrap();
                 if epoch_results.is_empty() {
-                    // println!("EPOCH_RESULTS is empty. Skipping FilterJoin operation.");
                     continue;
                 }
 
-                // println!("EPOCH_RESULTS : {:?}", epoch_results);    
-                // println!("------------------------");
-            
                 epoch_results.retain(|result| {
-                    if let Some(PacketField::U16(count)) = result.get(field_name) {
-                        if *count >= *threshold {
-                            true 
-                        } else {
-                            // println!(
-                            //     "Filtered out result: {:?} (count: {}, threshold: {})",
-                            //     result, count, threshold
-                            // );
-                            false
-                        }
+                    if let Some(PacketField::U16(count)) = result.get(*value_slot) {
+                        *count >= *threshold
                     } else {
                         println!(
-                            "Field '{}' not found or invalid in result: {:?}",
-                            field_name, result
+                            "Field slot {} not found or invalid in result: {:?}",
+                            value_slot, result
                         );
                         false
                     }
                 });
-            
-                // println!("Filtered EPOCH_RESULTS: {:?}", epoch_results);
             }
-            
-            
+            CompiledOperation::FCMEstimate { .. } => {
+                // Evaluated once per epoch by `finalize_epoch`.
+            }
+            CompiledOperation::Statistic { key, statistic_type, sketch_key, .. } => {
+                let key = encode_key(key, &current_packet);
+                let sketch = sketch_entry(sketches, sketch_key, || new_statistic_sketch(statistic_type));
+                sketch.increment(key.as_bytes(), 1);
+            }
+            CompiledOperation::RefinePrefix { slot, mask, within } => {
+                let address = match current_packet.get(*slot) {
+                    Some(PacketField::Ipv4(address)) => u32::from(*address),
+                    _ => return None,
+                };
+                if let Some(within) = within {
+                    if !within.iter().any(|(mask, networks)| networks.contains(&(address & mask))) {
+                        return None;
+                    }
+                }
+                current_packet.set(*slot, PacketField::Ipv4(Ipv4Addr::from(address & mask)));
+            }
+            CompiledOperation::HierarchicalHeavyHitters {
+                key,
+                value_slot,
+                prefix_step,
+                reduce_type,
+                randomized,
+                sketch_key,
+                ..
+            } => {
+                let value = match current_packet.get(*value_slot) {
+                    Some(PacketField::U32(value)) => *value as u64,
+                    _ => return None,
+                };
+                let sketch = sketch_entry(sketches, sketch_key, || {
+                    new_hhh_sketch(key.len(), *prefix_step, reduce_type, *randomized)
+                });
+                sketch.increment(encode_key(key, &current_packet).as_bytes(), value);
+            }
         }
     }
 
     Some(current_packet)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            ALLOCATIONS.with(|count| count.set(count.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    fn allocations() -> usize {
+        ALLOCATIONS.with(|count| count.get())
+    }
+
+    fn reduce_plan(map: &str, keys: &[&str]) -> QueryPlan {
+        QueryPlan {
+            operations: vec![
+                Operation::Map(map.to_string()),
+                Operation::Reduce {
+                    keys: keys.iter().map(|key| key.to_string()).collect(),
+                    reduce_type: ReduceType::DeterministicReduce,
+                    field_name: "count".to_string(),
+                    aggregation: Aggregation::Sum,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn keys_that_could_exceed_the_key_buffer_are_rejected() {
+        // Three strings can take 3 * 32 bytes.
+        let plan = reduce_plan("(a = port_range(src_port), b = port_range(dst_port), c = x, count = 1)", &["a", "b", "c"]);
+        let error = compile_plan(&plan).unwrap_err();
+        assert!(error.contains("can be 96 bytes, longer than 64"), "{}", error);
+
+        // Two fit in 64.
+        assert!(compile_plan(&reduce_plan("(a = port_range(src_port), b = port_range(dst_port), count = 1)", &["a", "b"])).is_ok());
+    }
+
+    #[test]
+    fn string_constants_longer_than_a_key_string_are_rejected() {
+        let long = "x".repeat(MAX_KEY_STRING_BYTES + 1);
+        let error = compile_plan(&reduce_plan(&format!("(label = {}, count = 1)", long), &["label"])).unwrap_err();
+        assert!(error.contains("is longer than 31 bytes"), "{}", error);
+
+        let longest = "x".repeat(MAX_KEY_STRING_BYTES);
+        let plan = compile_plan(&reduce_plan(&format!("(label = {}, count = 1)", longest), &["label"])).unwrap();
+        let mut sketches = HashMap::new();
+        let mut result_map = HashMap::new();
+        let mut record = Record::default();
+        record.set(2, PacketField::U16(1));
+        execute_query(&plan, record, &mut sketches, &mut result_map, 10, &mut Some(0), 0).unwrap();
+        let key = result_map.keys().next().unwrap();
+        assert_eq!(key.len(), 1 + MAX_KEY_STRING_BYTES);
+        assert_eq!(&key[1..], longest.as_bytes());
+    }
+
+    #[test]
+    fn packets_of_known_keys_do_not_allocate() {
+        let reduce_types = [
+            ReduceType::DeterministicReduce,
+            ReduceType::CMReduce { memory_in_bytes: 4096, depth: 3, seed: 1 },
+            ReduceType::FCMReduce {
+                depth: 2,
+                width_l1: 1024,
+                width_l2: 128,
+                width_l3: 16,
+                threshold_l1: 254,
+                threshold_l2: 65534,
+                seed: 1,
+            },
+        ];
+        for reduce_type in reduce_types {
+            let query = QueryPlan {
+                operations: vec![
+                    Operation::Map("(src_ip, dst_port, count = 1)".to_string()),
+                    Operation::Reduce {
+                        keys: vec!["src_ip".to_string(), "dst_port".to_string()],
+                        reduce_type: reduce_type.clone(),
+                        field_name: "count".to_string(),
+                        aggregation: Aggregation::Sum,
+                    },
+                ],
+            };
+            let plan = compile_plan(&query).unwrap();
+            let mut sketches = HashMap::new();
+            let mut result_map = HashMap::new();
+            let packets: Vec<Record> = (0..50u8)
+                .map(|i| {
+                    let mut record = Record::default();
+                    record.set(0, PacketField::Ipv4(Ipv4Addr::new(10, 0, 0, i)));
+                    record.set(3, PacketField::U16(80 + (i % 3) as u16));
+                    record
+                })
+                .collect();
+
+            for packet in &packets {
+                execute_query(&plan, packet.clone(), &mut sketches, &mut result_map, 10, &mut Some(0), 1);
+            }
+            assert_eq!(result_map.len(), 50);
+
+            let mut allocated = 0;
+            for packet in &packets {
+                let packet = packet.clone();
+                let before = allocations();
+                execute_query(&plan, packet, &mut sketches, &mut result_map, 10, &mut Some(0), 1);
+                allocated += allocations() - before;
+            }
+            assert_eq!(allocated, 0, "{:?}", reduce_type);
+        }
+    }
+}
@@ -1,4 +1,6 @@
-#[derive(Clone, Debug)]
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Field {
     SourceIp,
     DestIp,
@@ -8,16 +10,19 @@ pub enum Field {
     Protocol,
     DnsNsType,
 }
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Operation {
     Filter(Vec<(Field, String)>),
     Map(String),
+    /// Aggregates `field_name` per key; plans without an `aggregation` sum it.
     Reduce {
         keys: Vec<String>,
         reduce_type: ReduceType,
         field_name: String,
+        #[serde(default)]
+        aggregation: Aggregation,
     },
-    FilterResult { threshold: u64, field_name: String },
+    FilterResult { threshold: u64, field_name: String, mode: FilterMode },
     Distinct {
         keys: Vec<String>,
         distinct_type: ReduceType,
@@ -30,8 +35,63 @@ pub enum Operation {
     },
     MapJoin(String),
     FilterJoin { threshold: u16, field_name: String },
+    /// Estimates the flow size distribution, entropy and cardinality of the preceding
+    /// FCM `Reduce` at the end of every epoch.
+    FCMEstimate { em_iterations: usize },
+    /// Number of distinct keys seen this epoch.
+    Cardinality { keys: Vec<String>, statistic_type: StatisticType },
+    /// Entropy of the per-key packet counts this epoch.
+    Entropy { keys: Vec<String>, statistic_type: StatisticType },
+    /// Histogram of per-key packet counts this epoch.
+    FlowSizeDistribution { keys: Vec<String>, statistic_type: StatisticType },
+    /// Keeps packets whose IPv4 field lies in one of the `within` prefixes (such as
+    /// "10.0.0.0/8"; every packet when `None`) and truncates the field to its first
+    /// `prefix_len` bits. The refinement controller rewrites it between epochs.
+    RefinePrefix { field_name: String, prefix_len: u8, within: Option<Vec<String>> },
+    /// Hierarchical heavy hitters over the prefixes of one or two IPv4 fields (a 1-D or
+    /// 2-D lattice whose prefixes grow by `prefix_step` bits), summing `field_name` in a
+    /// `reduce_type` sketch per lattice node. Reports every epoch the prefixes whose sum,
+    /// less that of the heavy hitters below them, reaches `threshold`. `randomized`
+    /// updates one random node per packet, as RHHH does, instead of all of them.
+    HierarchicalHeavyHitters {
+        keys: Vec<String>,
+        prefix_step: u8,
+        reduce_type: ReduceType,
+        field_name: String,
+        threshold: u64,
+        randomized: bool,
+    },
+}
+/// Which side of a sketch estimate's error interval `FilterResult` compares with its threshold.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FilterMode {
+    /// The point estimate.
+    Estimate,
+    /// Keeps only keys whose lower bound reaches the threshold.
+    Definitely,
+    /// Keeps every key whose upper bound reaches the threshold.
+    Possibly,
 }
-#[derive(Clone, Debug)]
+/// What a `Reduce` keeps of the values of each key. `Min`, `Max`, `First`, `Last` and
+/// quantiles are exact with `DeterministicReduce`; the other Reduce types keep them in
+/// registers (the quantiles in a histogram of value buckets), and `Mean` divides a sum
+/// sketch by a count sketch.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Aggregation {
+    /// Number of packets, whatever the field holds.
+    Count,
+    #[default]
+    Sum,
+    Min,
+    Max,
+    Mean,
+    First,
+    Last,
+    /// The value below which this fraction of the key's values lies, such as 0.5 for the
+    /// median.
+    Quantile(f64),
+}
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum ReduceType {
     CMReduce { memory_in_bytes: usize, depth: usize, seed: u64 },
     FCMReduce {
@@ -60,7 +120,100 @@ pub enum ReduceType {
         seed: u64,
     },
 }
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum StatisticType {
+    Exact,
+    LightPart { memory_in_bytes: usize, seed: u64 },
+    FCMSketch {
+        depth: usize,
+        width_l1: usize,
+        width_l2: usize,
+        width_l3: usize,
+        threshold_l1: u32,
+        threshold_l2: u32,
+        seed: u64,
+        em_iterations: usize,
+    },
+}
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct QueryPlan {
     pub operations: Vec<Operation>,
-}
\ No newline at end of file
+}
+impl QueryPlan {
+    /// The same plan with every `Reduce`, `Distinct` and hierarchical heavy hitter lattice
+    /// made exact, used as ground truth.
+    pub fn exact(&self) -> QueryPlan {
+        let operations = self
+            .operations
+            .iter()
+            .map(|op| match op {
+                Operation::Reduce { keys, field_name, aggregation, .. } => Operation::Reduce {
+                    keys: keys.clone(),
+                    reduce_type: ReduceType::DeterministicReduce,
+                    field_name: field_name.clone(),
+                    aggregation: *aggregation,
+                },
+                Operation::Distinct { keys, .. } => Operation::Distinct {
+                    keys: keys.clone(),
+                    distinct_type: ReduceType::DeterministicReduce,
+                },
+                Operation::HierarchicalHeavyHitters { keys, prefix_step, field_name, threshold, .. } => {
+                    Operation::HierarchicalHeavyHitters {
+                        keys: keys.clone(),
+                        prefix_step: *prefix_step,
+                        reduce_type: ReduceType::DeterministicReduce,
+                        field_name: field_name.clone(),
+                        threshold: *threshold,
+                        randomized: false,
+                    }
+                }
+                Operation::Join { left_query, right_query, left_keys, right_keys } => Operation::Join {
+                    left_query: Box::new(left_query.exact()),
+                    right_query: Box::new(right_query.exact()),
+                    left_keys: left_keys.clone(),
+                    right_keys: right_keys.clone(),
+                },
+                other => other.clone(),
+            })
+            .collect();
+        QueryPlan { operations }
+    }
+
+    /// The same plan with every `FilterResult` and hierarchical heavy hitter lattice
+    /// (including those of joined sub-plans) comparing against `threshold`.
+    pub fn with_threshold(&self, threshold: u64) -> QueryPlan {
+        let operations = self
+            .operations
+            .iter()
+            .map(|op| match op {
+                Operation::FilterResult { field_name, mode, .. } => Operation::FilterResult {
+                    threshold,
+                    field_name: field_name.clone(),
+                    mode: *mode,
+                },
+                Operation::HierarchicalHeavyHitters { keys, prefix_step, reduce_type, field_name, randomized, .. } => {
+                    Operation::HierarchicalHeavyHitters {
+                        keys: keys.clone(),
+                        prefix_step: *prefix_step,
+                        reduce_type: reduce_type.clone(),
+                        field_name: field_name.clone(),
+                        threshold,
+                        randomized: *randomized,
+                    }
+                }
+                Operation::Join { left_query, right_query, left_keys, right_keys } => Operation::Join {
+                    left_query: Box::new(left_query.with_threshold(threshold)),
+                    right_query: Box::new(right_query.with_threshold(threshold)),
+                    left_keys: left_keys.clone(),
+                    right_keys: right_keys.clone(),
+                },
+                other => other.clone(),
+            })
+            .collect();
+        QueryPlan { operations }
+    }
+
+    pub fn has_join(&self) -> bool {
+        self.operations.iter().any(|op| matches!(op, Operation::Join { .. }))
+    }
+}
@@ -0,0 +1,106 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+use crate::query_executor::PacketField;
+use serde::{Deserialize, Serialize};
+
+/// Upper bound on the number of fields a record can carry at any point of a plan.
+pub const MAX_FIELDS: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FieldType {
+    String,
+    U8,
+    U16,
+    U32,
+    U64,
+    Bool,
+    Ipv4,
+    Ipv6,
+    OptionU16,
+    OptionTupleU16,
+}
+
+impl FieldType {
+    /// Parses a literal from a plan (a filter value, a Map constant) as a value of this type.
+    pub fn parse(&self, value: &str) -> Option<PacketField> {
+        match self {
+            FieldType::String => Some(PacketField::String(value.to_string())),
+            FieldType::U8 => value.parse().ok().map(PacketField::U8),
+            FieldType::U16 => value.parse().ok().map(PacketField::U16),
+            FieldType::U32 => value.parse().ok().map(PacketField::U32),
+            FieldType::U64 => value.parse().ok().map(PacketField::U64),
+            FieldType::Bool => value.parse().ok().map(PacketField::Bool),
+            FieldType::Ipv4 => value.parse::<Ipv4Addr>().ok().map(PacketField::Ipv4),
+            FieldType::Ipv6 => value.parse::<Ipv6Addr>().ok().map(PacketField::Ipv6),
+            FieldType::OptionU16 => value.parse().ok().map(|v| PacketField::OptionU16(Some(v))),
+            FieldType::OptionTupleU16 => None,
+        }
+    }
+}
+
+/// Names and types of the fields of a record at one edge of a plan. Field names are
+/// resolved to slot indices against it once, when the plan is compiled.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Schema {
+    fields: Vec<(String, FieldType)>,
+}
+
+impl Schema {
+    pub fn new(fields: Vec<(String, FieldType)>) -> Self {
+        Self { fields }
+    }
+
+    /// Layout of the records produced by the packet parser.
+    pub fn packet() -> Self {
+        Self::new(vec![
+            ("src_ip".to_string(), FieldType::Ipv4),
+            ("dst_ip".to_string(), FieldType::Ipv4),
+            ("src_port".to_string(), FieldType::U16),
+            ("dst_port".to_string(), FieldType::U16),
+            ("tcp_flags".to_string(), FieldType::U8),
+            ("total_len".to_string(), FieldType::U32),
+            ("protocol".to_string(), FieldType::U8),
+            ("dns_ns_type".to_string(), FieldType::OptionU16),
+        ])
+    }
+
+    pub fn slot(&self, name: &str) -> Option<usize> {
+        self.fields.iter().position(|(n, _)| n == name)
+    }
+
+    pub fn field_type(&self, slot: usize) -> FieldType {
+        self.fields[slot].1
+    }
+
+    pub fn fields(&self) -> &[(String, FieldType)] {
+        &self.fields
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn push(&mut self, name: &str, field_type: FieldType) -> Result<usize, String> {
+        if self.fields.len() == MAX_FIELDS {
+            return Err(format!("Too many fields in record (max {}): {}", MAX_FIELDS, name));
+        }
+        self.fields.push((name.to_string(), field_type));
+        Ok(self.fields.len() - 1)
+    }
+}
+
+/// A packet or intermediate result laid out according to a `Schema`. Stored inline so
+/// that building, projecting and copying records does not touch the heap.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    fields: [Option<PacketField>; MAX_FIELDS],
+}
+
+impl Record {
+    pub fn get(&self, slot: usize) -> Option<&PacketField> {
+        self.fields[slot].as_ref()
+    }
+
+    pub fn set(&mut self, slot: usize, value: PacketField) {
+        self.fields[slot] = Some(value);
+    }
+}
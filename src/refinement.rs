@@ -0,0 +1,200 @@
+// refinement.rs
+// Iterative refinement over IP prefixes, as in Sonata: the query runs on addresses
+// truncated to a coarse prefix, and each finer level only within the prefixes the level
+// above reported in the previous epoch, down to full addresses.
+
+use std::fs::File;
+use std::io::Write;
+use pcap::Capture;
+use crate::evaluation::PlanRun;
+use crate::flow_key::{decode_key, format_key};
+use crate::pcap_processor::extract_packet_tuple;
+use crate::query_executor::{compile_plan, PacketField};
+use crate::query_plan::{Operation, QueryPlan};
+use crate::record::FieldType;
+
+/// `query` for one refinement level: `field` truncated to `prefix_len` bits and, below the
+/// first level, restricted to the prefixes `within`.
+pub fn refine_plan(query: &QueryPlan, field: &str, prefix_len: u8, within: Option<Vec<String>>) -> QueryPlan {
+    let mut operations = vec![Operation::RefinePrefix { field_name: field.to_string(), prefix_len, within }];
+    operations.extend(query.operations.iter().cloned());
+    QueryPlan { operations }
+}
+
+// One level's plan and state, and what it reported over the run.
+struct Level {
+    prefix_len: u8,
+    run: PlanRun,
+    reported: usize,
+    peak_memory: usize,
+}
+
+// What the level reported this epoch: each key as text, its value, and the prefix of the
+// refined field the next level is restricted to.
+fn reported_keys(level: &Level, field: &str) -> Vec<(String, Option<u64>, String)> {
+    let plan = &level.run.plan;
+    let value_slot = plan.value_slot();
+    let mut keys: Vec<(String, Option<u64>, String)> = level
+        .run
+        .result_map
+        .iter()
+        .filter_map(|(key, record)| {
+            let address = decode_key(&plan.result_key, key)?
+                .into_iter()
+                .find_map(|(name, value)| match value {
+                    PacketField::Ipv4(address) if name == field => Some(address),
+                    _ => None,
+                })?;
+            let value = value_slot.and_then(|slot| record.get(slot)?.as_u64());
+            Some((format_key(&plan.result_key, key), value, format!("{}/{}", address, level.prefix_len)))
+        })
+        .collect();
+    keys.sort();
+    keys
+}
+
+fn check_query(query: &QueryPlan, field: &str, levels: &[u8]) -> Result<(), String> {
+    if query.has_join() {
+        return Err("refinement does not support queries with joins".to_string());
+    }
+    if levels.is_empty() || levels.windows(2).any(|pair| pair[0] >= pair[1]) || levels.iter().any(|len| *len > 32) {
+        return Err(format!("refinement levels {:?} must be increasing prefix lengths up to 32", levels));
+    }
+    let plan = compile_plan(&refine_plan(query, field, levels[0], None))?;
+    if !plan.result_key.iter().any(|(name, field_type)| name == field && *field_type == FieldType::Ipv4) {
+        return Err(format!("the query's result key has no IPv4 field '{}'", field));
+    }
+    if plan.filter_result().is_none() {
+        return Err("refinement follows the keys passing a FilterResult, which the query lacks".to_string());
+    }
+    Ok(())
+}
+
+/// `refine` mode: runs `query` at every level of `levels` and, unrefined, as a baseline,
+/// in one pass over the trace. Between epochs each level's plan is rewritten to the
+/// prefixes the coarser level reported. Writes every reported key, with its level, to
+/// `report_path` as CSV.
+pub fn run_refinement(file_path: &str, epoch_size: u64, query: &QueryPlan, field: &str, levels: &[u8], report_path: &str) {
+    if let Err(e) = check_query(query, field, levels) {
+        eprintln!("Cannot refine query: {}", e);
+        return;
+    }
+    let mut report = match File::create(report_path) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("Cannot create {}: {}", report_path, e);
+            return;
+        }
+    };
+    let compile = |prefix_len: u8, within: Option<Vec<String>>| {
+        compile_plan(&refine_plan(query, field, prefix_len, within)).expect("refined plans compile like the first level")
+    };
+    // Until a coarser level has reported, the finer ones see no packets.
+    let mut levels: Vec<Level> = levels
+        .iter()
+        .enumerate()
+        .map(|(i, prefix_len)| Level {
+            prefix_len: *prefix_len,
+            run: PlanRun::new(compile(*prefix_len, (i > 0).then(Vec::new))),
+            reported: 0,
+            peak_memory: 0,
+        })
+        .collect();
+    let mut baseline = PlanRun::new(compile_plan(query).expect("checked with the first level"));
+    let (mut baseline_reported, mut baseline_found, mut baseline_peak) = (0, 0, 0);
+
+    let names: Vec<String> = levels.iter().map(|level| format!("/{}", level.prefix_len)).collect();
+    println!("Refining {} through {}", field, names.join(", "));
+    let mut csv = String::from("epoch,epoch_end,level,key,value,prefix\n");
+    let mut epochs = 0;
+
+    let mut end_epoch = |levels: &mut Vec<Level>, baseline: &mut PlanRun, epoch_end: u64| {
+        epochs += 1;
+        println!("Epoch {} (ending {}):", epochs, epoch_end);
+        let mut within = Vec::new();
+        for level in levels.iter_mut() {
+            let run = &mut level.run;
+            run.plan.apply_epoch_filters(&mut run.result_map, &run.sketches);
+            let memory: usize = run.sketches.values().map(|sketch| sketch.get_memory_usage()).sum();
+            level.peak_memory = level.peak_memory.max(memory);
+            let keys = reported_keys(level, field);
+            level.reported += keys.len();
+            println!("  /{}: {} keys, {} bytes of sketch state", level.prefix_len, keys.len(), memory);
+            for (key, value, prefix) in &keys {
+                let value = value.map_or_else(String::new, |value| value.to_string());
+                println!("    {} ({})", key, value);
+                csv.push_str(&format!("{},{},{},\"{}\",{},{}\n", epochs, epoch_end, level.prefix_len, key, value, prefix));
+            }
+            within.push(keys.into_iter().map(|(_, _, prefix)| prefix).collect::<Vec<_>>());
+        }
+
+        let run = &mut *baseline;
+        run.plan.apply_epoch_filters(&mut run.result_map, &run.sketches);
+        baseline_peak = baseline_peak.max(run.sketches.values().map(|sketch| sketch.get_memory_usage()).sum());
+        baseline_reported += run.result_map.len();
+        // Full addresses at the finest level are keyed exactly as the unrefined query's.
+        let finest = levels.last().expect("at least one level");
+        if finest.prefix_len == 32 {
+            let found = run.result_map.keys().filter(|key| finest.run.result_map.contains_key(*key)).count();
+            baseline_found += found;
+            println!("  Unrefined query: {} keys, {} found by the refinement", run.result_map.len(), found);
+        } else {
+            println!("  Unrefined query: {} keys", run.result_map.len());
+        }
+
+        // Each finer level now looks only inside what the coarser one reported.
+        for (i, level) in levels.iter_mut().enumerate() {
+            if i > 0 {
+                level.run = PlanRun::new(compile(level.prefix_len, Some(std::mem::take(&mut within[i - 1]))));
+            }
+            level.run.end_epoch(epoch_end);
+        }
+        baseline.end_epoch(epoch_end);
+    };
+
+    let mut cap = Capture::from_file(file_path).expect("Failed to open PCAP file");
+    let mut current_epoch_start: Option<u64> = None;
+    let mut epoch_packets = 0;
+    while let Ok(packet) = cap.next_packet() {
+        let packet_timestamp = packet.header.ts.tv_sec as u64;
+        current_epoch_start.get_or_insert(packet_timestamp);
+
+        if let Some(record) = extract_packet_tuple(&packet) {
+            for level in levels.iter_mut() {
+                level.run.execute(record.clone(), epoch_size, packet_timestamp);
+            }
+            baseline.execute(record, epoch_size, packet_timestamp);
+        }
+
+        if packet_timestamp - current_epoch_start.unwrap() >= epoch_size {
+            end_epoch(&mut levels, &mut baseline, packet_timestamp);
+            current_epoch_start = Some(packet_timestamp);
+            epoch_packets = 0;
+        }
+        epoch_packets += 1;
+    }
+    if let (Some(epoch_start), true) = (current_epoch_start, epoch_packets > 0) {
+        end_epoch(&mut levels, &mut baseline, epoch_start);
+    }
+
+    let per_level: Vec<String> = levels.iter().map(|level| format!("/{}: {}", level.prefix_len, level.reported)).collect();
+    println!("Keys reported per level: {}", per_level.join(", "));
+    if levels.last().is_some_and(|level| level.prefix_len == 32) {
+        println!("Unrefined query reported {} keys; the refinement found {} of them", baseline_reported, baseline_found);
+    }
+    let refined: usize = levels.iter().map(|level| level.peak_memory).sum();
+    let per_level: Vec<String> = levels.iter().map(|level| format!("/{}: {}", level.prefix_len, level.peak_memory)).collect();
+    println!("Peak sketch state: {} bytes ({}) against {} for the unrefined query", refined, per_level.join(", "), baseline_peak);
+    if refined <= baseline_peak {
+        let saved = baseline_peak - refined;
+        println!("Memory saved: {} bytes ({:.1}%)", saved, 100.0 * saved as f64 / baseline_peak.max(1) as f64);
+    } else {
+        println!("Memory saved: none, the levels use {} bytes more", refined - baseline_peak);
+    }
+
+    if let Err(e) = report.write_all(csv.as_bytes()) {
+        eprintln!("Failed to write {}: {}", report_path, e);
+        return;
+    }
+    println!("Report written to {}", report_path);
+}
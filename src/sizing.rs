@@ -0,0 +1,82 @@
+// sizing.rs
+// Sketch dimensions derived from accuracy targets or memory budgets, so configurations
+// can state what they need rather than raw widths.
+
+use std::f64::consts::{E, LN_2};
+
+// Bytes per counter at each FCM layer (8, 16 and 32-bit registers), and the tree's fan-out.
+const FCM_COUNTER_BYTES: [usize; 3] = [1, 2, 4];
+const FCM_K_ARY: usize = 8;
+
+/// Count-Min memory and depth such that, with probability at least `1 - delta`, every
+/// estimate exceeds the true count by at most `epsilon` times the epoch's total count.
+/// Width is `e / epsilon` and depth `ln(1 / delta)`, with 32-bit counters.
+pub fn cm_from_accuracy(epsilon: f64, delta: f64) -> Option<(usize, usize)> {
+    if !(epsilon > 0.0 && epsilon < 1.0 && delta > 0.0 && delta < 1.0) {
+        return None;
+    }
+    let width = (E / epsilon).ceil() as usize;
+    let depth = ((1.0 / delta).ln().ceil() as usize).max(1);
+    Some((width * depth * size_of::<u32>(), depth))
+}
+
+/// Bloom filter size in bits and number of hashes for `expected_items` distinct keys at a
+/// false positive rate of `false_positive_rate`: `m = -n ln p / ln^2 2` and `k = m/n ln 2`.
+pub fn bloom_from_accuracy(expected_items: usize, false_positive_rate: f64) -> Option<(usize, usize)> {
+    if expected_items == 0 || !(false_positive_rate > 0.0 && false_positive_rate < 1.0) {
+        return None;
+    }
+    let n = expected_items as f64;
+    let size = (-n * false_positive_rate.ln() / (LN_2 * LN_2)).ceil() as usize;
+    let num_hashes = ((size as f64 / n) * LN_2).round().max(1.0) as usize;
+    Some((size, num_hashes))
+}
+
+/// FCM layer widths that fit `memory_in_bytes` across `depth` trees. Each layer is an
+/// eighth of the one below, so the layer-1 width is rounded down to a multiple of 64.
+pub fn fcm_from_memory(memory_in_bytes: usize, depth: usize) -> Option<(usize, usize, usize)> {
+    // Bytes per layer-1 counter once its share of the upper layers is included, times 64.
+    let bytes_per_64 = FCM_COUNTER_BYTES[0] * 64 + FCM_COUNTER_BYTES[1] * 8 + FCM_COUNTER_BYTES[2];
+    let groups = memory_in_bytes / depth.max(1) / bytes_per_64;
+    if depth == 0 || groups == 0 {
+        return None;
+    }
+    let width_l1 = groups * FCM_K_ARY * FCM_K_ARY;
+    Some((width_l1, width_l1 / FCM_K_ARY, width_l1 / FCM_K_ARY / FCM_K_ARY))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cm_is_sized_from_epsilon_and_delta() {
+        // 27183 counters wide and 5 deep, at 4 bytes each.
+        assert_eq!(cm_from_accuracy(0.0001, 0.01), Some((543_660, 5)));
+        assert_eq!(cm_from_accuracy(0.5, 0.5), Some((24, 1)));
+    }
+
+    #[test]
+    fn bloom_is_sized_from_items_and_false_positive_rate() {
+        assert_eq!(bloom_from_accuracy(30_000, 0.01), Some((287_552, 7)));
+    }
+
+    #[test]
+    fn default_fcm_memory_gives_the_default_widths() {
+        assert_eq!(fcm_from_memory(1_376_256, 2), Some((524_288, 65_536, 8_192)));
+        // Rounded down to whole groups of 64 leaves.
+        assert_eq!(fcm_from_memory(1_376_256 + 167, 2), Some((524_288, 65_536, 8_192)));
+    }
+
+    #[test]
+    fn out_of_range_targets_are_rejected() {
+        for (epsilon, delta) in [(0.0, 0.01), (1.0, 0.01), (-0.1, 0.01), (0.01, 0.0), (0.01, 1.0), (f64::NAN, 0.01)] {
+            assert_eq!(cm_from_accuracy(epsilon, delta), None, "epsilon {} delta {}", epsilon, delta);
+        }
+        for (items, rate) in [(0, 0.01), (1000, 0.0), (1000, 1.0), (1000, f64::NAN)] {
+            assert_eq!(bloom_from_accuracy(items, rate), None, "{} items at {}", items, rate);
+        }
+        assert_eq!(fcm_from_memory(1_376_256, 0), None);
+        assert_eq!(fcm_from_memory(83 * 2, 2), None);
+    }
+}
@@ -5,7 +5,102 @@ use crate::deterministic_sketch::DeterministicSketch;
 use crate::bloom_filter::BloomFilter;
 use crate::fcm_first_layer_sketch::FCMFirstLayerOnly;
 use crate::beaucoup::BeauCoupSketch;
+use crate::light_part::LightPart;
+use crate::hhh::HHHSketch;
+use crate::aggregation::AggregateSketch;
+use serde::{Deserialize, Serialize};
+use std::f64::consts::E;
 
+/// How far a sketch's estimates may exceed the true count, and the probability that the
+/// bound holds. The sketches that have one never underestimate.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ErrorBound {
+    pub overestimate: u64,
+    pub confidence: f64,
+}
+
+/// A point estimate and the interval its sketch guarantees for the true value.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Estimate {
+    pub value: u64,
+    /// `None` when the sketch gives no guarantee, so the true value may be anything.
+    pub bounds: Option<(u64, u64)>,
+    pub confidence: f64,
+}
+
+/// Where an estimate stands relative to a threshold, given its bounds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ThresholdStatus {
+    DefinitelyAbove,
+    PossiblyAbove,
+    Below,
+}
+
+impl ErrorBound {
+    pub const EXACT: ErrorBound = ErrorBound { overestimate: 0, confidence: 1.0 };
+
+    /// Count-Min guarantee: with probability `1 - e^-depth`, an estimate exceeds the true
+    /// count by at most `e / width` times the `total` inserted.
+    fn count_min(width: usize, depth: usize, total: u64) -> ErrorBound {
+        ErrorBound {
+            overestimate: (E * total as f64 / width.max(1) as f64).ceil() as u64,
+            confidence: 1.0 - (-(depth as f64)).exp(),
+        }
+    }
+}
+
+impl Estimate {
+    pub fn new(value: u64, bound: Option<ErrorBound>) -> Self {
+        match bound {
+            Some(bound) => Estimate {
+                value,
+                bounds: Some((value.saturating_sub(bound.overestimate), value)),
+                confidence: bound.confidence,
+            },
+            None => Estimate { value, bounds: None, confidence: 0.0 },
+        }
+    }
+
+    pub fn lower(&self) -> u64 {
+        self.bounds.map_or(0, |(lower, _)| lower)
+    }
+
+    pub fn upper(&self) -> u64 {
+        self.bounds.map_or(u64::MAX, |(_, upper)| upper)
+    }
+
+    /// Lower and upper bound as printed in reports, `-` when unknown.
+    pub fn bound_strings(&self) -> (String, String) {
+        match self.bounds {
+            Some((lower, upper)) => (lower.to_string(), upper.to_string()),
+            None => ("-".to_string(), "-".to_string()),
+        }
+    }
+
+    /// "Definitely above" when even the lower bound exceeds `threshold`, "possibly above"
+    /// when only the upper bound does.
+    pub fn status(&self, threshold: u64) -> ThresholdStatus {
+        if self.lower() > threshold {
+            ThresholdStatus::DefinitelyAbove
+        } else if self.upper() > threshold {
+            ThresholdStatus::PossiblyAbove
+        } else {
+            ThresholdStatus::Below
+        }
+    }
+}
+
+impl std::fmt::Display for ThresholdStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ThresholdStatus::DefinitelyAbove => write!(f, "definitely above"),
+            ThresholdStatus::PossiblyAbove => write!(f, "possibly above"),
+            ThresholdStatus::Below => write!(f, "below"),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub enum Sketch {
     CMSketch(CMSketch),
     FCMSketch(FCMSketch),
@@ -14,7 +109,9 @@ pub enum Sketch {
     BloomFilter(BloomFilter),
     FCMFirstLayerOnly(FCMFirstLayerOnly),
     BeauCoup(BeauCoupSketch), 
-    
+    LightPart(LightPart),
+    HierarchicalHeavyHitters(HHHSketch),
+    Aggregate(AggregateSketch),
 }
 
 impl Sketch {
@@ -68,65 +165,122 @@ pub fn new_beaucoup(
     ))
 }
 
-    pub fn contains(&self, item: &str) -> bool {
+    pub fn new_light_part(memory_in_bytes: usize, seed: u64) -> Self {
+        Sketch::LightPart(LightPart::new(memory_in_bytes, seed))
+    }
+
+    pub fn contains(&self, item: &[u8]) -> bool {
         match self {
             Sketch::CMSketch(_) => panic!("CMSketch does not support contains"),
             Sketch::FCMSketch(_) => panic!("FCMSketch does not support contains"),
             Sketch::ElasticSketch(_) => panic!("ElasticSketch does not support contains"),
             Sketch::DeterministicSketch(_) => panic!("DeterministicSketch does not support contains"),
             Sketch::FCMFirstLayerOnly(_) => panic!("FCMFirstLayerOnly does not support contains"),
+            Sketch::LightPart(_) => panic!("LightPart does not support contains"),
+            Sketch::HierarchicalHeavyHitters(_) => panic!("HierarchicalHeavyHitters does not support contains"),
+            Sketch::Aggregate(_) => panic!("Aggregate does not support contains"),
             Sketch::BeauCoup(sketch) => sketch.contains(item),
             Sketch::BloomFilter(bloom) => bloom.contains(item),
         }
     }
 
-    pub fn increment(&mut self, item: &str, count: u64) {
+    pub fn increment(&mut self, item: &[u8], count: u64) {
         match self {
-            Sketch::CMSketch(sketch) => sketch.insert(item.as_bytes(), count as i32),
-            Sketch::FCMSketch(sketch) => sketch.insert(item.as_bytes(), count as u32),
-            Sketch::FCMFirstLayerOnly(sketch) => sketch.insert(item.as_bytes(), count as u32), // Handle FCMFirstLayerOnly
-            Sketch::ElasticSketch(sketch) => sketch.insert(item.as_bytes(), count as u32),
+            Sketch::CMSketch(sketch) => sketch.insert(item, count as i32),
+            Sketch::FCMSketch(sketch) => sketch.insert(item, count as u32),
+            Sketch::FCMFirstLayerOnly(sketch) => sketch.insert(item, count as u32), // Handle FCMFirstLayerOnly
+            Sketch::ElasticSketch(sketch) => sketch.insert(item, count as u32),
             Sketch::DeterministicSketch(sketch) => sketch.insert(item, count),
             Sketch::BeauCoup(sketch) => sketch.insert(item),
+            Sketch::LightPart(sketch) => sketch.insert(item, count as i32),
+            Sketch::HierarchicalHeavyHitters(sketch) => sketch.insert(item, count),
+            Sketch::Aggregate(sketch) => sketch.insert(item, count as u32),
             Sketch::BloomFilter(_) => panic!("BloomFilter does not support increment operation"),
         }
     }
     
-    pub fn estimate(&self, item: &str) -> u64 {
-        // Define the target item
-        let target_item = "dst_ip: 35.26.185.176, src_ip: 163.27.199.6";
-        let is_target_item = item == target_item;
-    
-        // Perform the estimate
-        let estimate = match self {
-            Sketch::CMSketch(sketch) => sketch.query(item.as_bytes()) as u64,
-            Sketch::FCMSketch(sketch) => sketch.query(item.as_bytes()) as u64,
-            Sketch::FCMFirstLayerOnly(sketch) => sketch.query(item.as_bytes()) as u64, // Handle FCMFirstLayerOnly
-            Sketch::ElasticSketch(sketch) => sketch.query(item.as_bytes()) as u64,
+    pub fn estimate(&self, item: &[u8]) -> u64 {
+        match self {
+            Sketch::CMSketch(sketch) => sketch.query(item) as u64,
+            Sketch::FCMSketch(sketch) => sketch.query(item) as u64,
+            Sketch::FCMFirstLayerOnly(sketch) => sketch.query(item) as u64, // Handle FCMFirstLayerOnly
+            Sketch::ElasticSketch(sketch) => sketch.query(item) as u64,
             Sketch::DeterministicSketch(sketch) => sketch.query(item),
             Sketch::BeauCoup(sketch) => sketch.estimate(item),
+            Sketch::LightPart(sketch) => sketch.query(item) as u64,
+            Sketch::HierarchicalHeavyHitters(sketch) => sketch.query(item),
+            Sketch::Aggregate(sketch) => sketch.query(item),
             Sketch::BloomFilter(_) => panic!("BloomFilter does not support estimate operation"),
-        };
-    
-        // if is_target_item {
-        //     println!("[DEBUG] Estimate for target item '{}': {}", item, estimate);
-        // }
-    
-        estimate
+        }
     }
 
-    pub fn insert(&mut self, item: &str) {
+    /// Guarantee on `estimate` given everything inserted so far, or `None` for sketches
+    /// without one (Elastic, BeauCoup, aggregates kept in registers). FCM has none either:
+    /// an overflowed leaf is read through the layer-2 and layer-3 counters it shares with
+    /// its siblings, which the Count-Min bound of the leaf layer does not cover.
+    pub fn error_bound(&self) -> Option<ErrorBound> {
+        match self {
+            Sketch::CMSketch(sketch) => {
+                let total = sketch.counters.first().map_or(0, |row| row.iter().map(|&c| c as u64).sum());
+                Some(ErrorBound::count_min(sketch.width, sketch.depth, total))
+            }
+            Sketch::FCMFirstLayerOnly(sketch) => {
+                let total = sketch.counters_l1.first().map_or(0, |row| row.iter().map(|&c| c as u64).sum());
+                Some(ErrorBound::count_min(sketch.width_l1, sketch.depth, total))
+            }
+            Sketch::DeterministicSketch(_) => Some(ErrorBound::EXACT),
+            Sketch::Aggregate(sketch) => sketch.is_exact().then_some(ErrorBound::EXACT),
+            Sketch::FCMSketch(_)
+            | Sketch::ElasticSketch(_)
+            | Sketch::BeauCoup(_)
+            | Sketch::LightPart(_)
+            | Sketch::BloomFilter(_)
+            | Sketch::HierarchicalHeavyHitters(_) => None,
+        }
+    }
+
+    pub fn estimate_with_bounds(&self, item: &[u8]) -> Estimate {
+        Estimate::new(self.estimate(item), self.error_bound())
+    }
+
+    pub fn insert(&mut self, item: &[u8]) {
         match self {
             Sketch::CMSketch(_) => panic!("CMSketch does not support insert"),
             Sketch::FCMSketch(_) => panic!("FCMSketch does not support insert"),
             Sketch::ElasticSketch(_) => panic!("ElasticSketch does not support insert"),
             Sketch::DeterministicSketch(_) => panic!("DeterministicSketch does not support insert"),
             Sketch::FCMFirstLayerOnly(_) => panic!("FCMFirstLayerOnly does not support insert"),
+            Sketch::LightPart(_) => panic!("LightPart does not support insert"),
+            Sketch::HierarchicalHeavyHitters(_) => panic!("HierarchicalHeavyHitters does not support insert"),
+            Sketch::Aggregate(_) => panic!("Aggregate does not support insert"),
             Sketch::BeauCoup(sketch) => sketch.insert(item),
             Sketch::BloomFilter(bloom) => bloom.insert(item),
         }
     }
 
+    /// True if nothing was inserted since the sketch was created or cleared.
+    pub fn is_empty(&self) -> bool {
+        fn all_zero(rows: &[Vec<u32>]) -> bool {
+            rows.iter().all(|row| row.iter().all(|&counter| counter == 0))
+        }
+        match self {
+            Sketch::CMSketch(sketch) => sketch.counters.iter().all(|row| row.iter().all(|&counter| counter == 0)),
+            Sketch::FCMSketch(sketch) => {
+                all_zero(&sketch.counters_l1) && all_zero(&sketch.counters_l2) && all_zero(&sketch.counters_l3)
+            }
+            Sketch::FCMFirstLayerOnly(sketch) => all_zero(&sketch.counters_l1),
+            Sketch::ElasticSketch(sketch) => {
+                all_zero(&sketch.light_counters) && sketch.heavy_counters.iter().all(|&counter| counter == 0)
+            }
+            Sketch::DeterministicSketch(sketch) => sketch.is_empty(),
+            Sketch::BloomFilter(bloom) => bloom.is_empty(),
+            Sketch::BeauCoup(sketch) => sketch.is_empty(),
+            Sketch::LightPart(sketch) => sketch.is_empty(),
+            Sketch::HierarchicalHeavyHitters(sketch) => sketch.is_empty(),
+            Sketch::Aggregate(sketch) => sketch.is_empty(),
+        }
+    }
+
     pub fn clear(&mut self) {
         match self {
             Sketch::CMSketch(sketch) => sketch.counters.iter_mut().for_each(|row| row.fill(0)),
@@ -145,7 +299,49 @@ pub fn new_beaucoup(
             Sketch::DeterministicSketch(sketch) => sketch.clear(),
             Sketch::BloomFilter(bloom) => bloom.clear(),
             Sketch::BeauCoup(sketch) => sketch.clear(),
+            Sketch::LightPart(sketch) => sketch.clear(),
+            Sketch::HierarchicalHeavyHitters(sketch) => sketch.clear(),
+            Sketch::Aggregate(sketch) => sketch.clear(),
+        }
+    }
+
+    /// Bytes of counter state, as the sketch would occupy in data-plane registers. Bit
+    /// arrays count one bit per entry; the exact sketch counts its keys and 8-byte counts.
+    pub fn get_memory_usage(&self) -> usize {
+        match self {
+            Sketch::CMSketch(sketch) => sketch.depth * sketch.width * size_of::<i32>(),
+            Sketch::FCMSketch(sketch) => sketch.get_memory_usage(),
+            Sketch::FCMFirstLayerOnly(sketch) => sketch.counters_l1.iter().map(|row| row.len() * size_of::<u32>()).sum(),
+            Sketch::ElasticSketch(sketch) => {
+                (sketch.depth * sketch.width + sketch.heavy_counters.len()) * size_of::<u32>()
+            }
+            Sketch::DeterministicSketch(sketch) => sketch.get_memory_usage(),
+            Sketch::BloomFilter(bloom) => bloom.get_memory_usage(),
+            Sketch::BeauCoup(sketch) => sketch.get_memory_usage(),
+            Sketch::LightPart(sketch) => sketch.get_memory_usage(),
+            Sketch::HierarchicalHeavyHitters(sketch) => sketch.get_memory_usage(),
+            Sketch::Aggregate(sketch) => sketch.get_memory_usage(),
         }
     }
-}
 
+    /// Folds another sketch built with the same parameters into this one, so that the
+    /// result summarizes both input streams.
+    pub fn merge(&mut self, other: &Sketch) -> Result<(), String> {
+        match (self, other) {
+            (Sketch::CMSketch(sketch), Sketch::CMSketch(other)) => sketch.merge(other),
+            (Sketch::FCMSketch(sketch), Sketch::FCMSketch(other)) => sketch.merge(other),
+            (Sketch::FCMFirstLayerOnly(sketch), Sketch::FCMFirstLayerOnly(other)) => sketch.merge(other),
+            (Sketch::ElasticSketch(sketch), Sketch::ElasticSketch(other)) => sketch.merge(other),
+            (Sketch::DeterministicSketch(sketch), Sketch::DeterministicSketch(other)) => {
+                sketch.merge(other);
+                Ok(())
+            }
+            (Sketch::BloomFilter(bloom), Sketch::BloomFilter(other)) => bloom.merge(other),
+            (Sketch::BeauCoup(sketch), Sketch::BeauCoup(other)) => sketch.merge(other),
+            (Sketch::LightPart(sketch), Sketch::LightPart(other)) => sketch.merge(other),
+            (Sketch::HierarchicalHeavyHitters(sketch), Sketch::HierarchicalHeavyHitters(other)) => sketch.merge(other),
+            (Sketch::Aggregate(sketch), Sketch::Aggregate(other)) => sketch.merge(other),
+            _ => Err("Cannot merge sketches of different types".to_string()),
+        }
+    }
+}
@@ -0,0 +1,368 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::checkpoint::{read_versioned, write_versioned};
+use crate::flow_key::{format_key, key_names, parse_key};
+use crate::query_executor::CompiledPlan;
+use crate::record::{FieldType, Record};
+use crate::sketch::{Estimate, Sketch};
+
+const SNAPSHOT_MAGIC: [u8; 4] = *b"SKEP";
+
+/// The state kept from one epoch: every sketch, plus the keys the final `Reduce` updated,
+/// including those its `FilterResult` dropped. Sketches cannot enumerate their keys, so
+/// these are the candidates checked when looking for heavy hitters after merging.
+#[derive(Serialize, Deserialize)]
+pub struct EpochSnapshot {
+    pub epoch: usize,
+    pub epoch_end: u64,
+    pub result_key: Vec<(String, FieldType)>,
+    pub reduce_sketch_key: Option<String>,
+    pub keys: Vec<Vec<u8>>,
+    pub sketches: HashMap<String, Sketch>,
+}
+
+// Borrowed form of `EpochSnapshot` with the same field order, so saving does not copy the sketches.
+#[derive(Serialize)]
+struct EpochSnapshotRef<'a> {
+    epoch: usize,
+    epoch_end: u64,
+    result_key: &'a [(String, FieldType)],
+    reduce_sketch_key: Option<&'a str>,
+    keys: Vec<&'a Vec<u8>>,
+    sketches: &'a HashMap<String, Sketch>,
+}
+
+/// Writes the state of one epoch to `<dir>/epoch_<n>.sketch`. The candidate keys are
+/// `epoch_keys` and any other key left in the result map (such as a join's).
+pub fn save_epoch(
+    dir: &str,
+    epoch: usize,
+    epoch_end: u64,
+    plan: &CompiledPlan,
+    epoch_keys: &HashSet<Vec<u8>>,
+    result_map: &HashMap<Vec<u8>, Record>,
+    sketches: &HashMap<String, Sketch>,
+) -> Result<(), String> {
+    fs::create_dir_all(dir).map_err(|e| format!("Cannot create {}: {}", dir, e))?;
+    let path = Path::new(dir).join(format!("epoch_{:05}.sketch", epoch));
+    let snapshot = EpochSnapshotRef {
+        epoch,
+        epoch_end,
+        result_key: &plan.result_key,
+        reduce_sketch_key: plan.reduce_sketch_key(),
+        keys: epoch_keys.iter().chain(result_map.keys().filter(|key| !epoch_keys.contains(*key))).collect(),
+        sketches,
+    };
+    write_versioned(&path.to_string_lossy(), SNAPSHOT_MAGIC, &snapshot)
+}
+
+pub fn load_snapshot(path: &str) -> Result<EpochSnapshot, String> {
+    read_versioned(path, SNAPSHOT_MAGIC)
+}
+
+pub fn save_snapshot(path: &str, snapshot: &EpochSnapshot) -> Result<(), String> {
+    write_versioned(path, SNAPSHOT_MAGIC, snapshot)
+}
+
+/// Merges snapshots of the same query taken at different vantage points. Sketches are
+/// merged by name and candidate keys are unioned.
+pub fn merge_snapshots(snapshots: Vec<EpochSnapshot>) -> Result<EpochSnapshot, String> {
+    let mut snapshots = snapshots.into_iter();
+    let mut merged = snapshots.next().ok_or_else(|| "No snapshots to merge".to_string())?;
+    let mut keys: HashSet<Vec<u8>> = merged.keys.drain(..).collect();
+
+    for snapshot in snapshots {
+        if snapshot.result_key != merged.result_key || snapshot.reduce_sketch_key != merged.reduce_sketch_key {
+            return Err(format!(
+                "Snapshots come from different queries: {:?} vs {:?}",
+                merged.reduce_sketch_key, snapshot.reduce_sketch_key
+            ));
+        }
+        for (name, sketch) in snapshot.sketches {
+            match merged.sketches.get_mut(&name) {
+                Some(existing) => existing.merge(&sketch).map_err(|e| format!("{}: {}", name, e))?,
+                None => {
+                    merged.sketches.insert(name, sketch);
+                }
+            }
+        }
+        keys.extend(snapshot.keys);
+        merged.epoch_end = merged.epoch_end.max(snapshot.epoch_end);
+    }
+    merged.keys = keys.into_iter().collect();
+    Ok(merged)
+}
+
+fn reduce_sketch(snapshot: &EpochSnapshot) -> Option<&Sketch> {
+    snapshot.reduce_sketch_key.as_ref().and_then(|name| snapshot.sketches.get(name))
+}
+
+/// Candidate keys whose estimate in the reduce sketch exceeds `threshold`, largest first.
+pub fn heavy_hitters(snapshot: &EpochSnapshot, threshold: u64) -> Vec<(Vec<u8>, u64)> {
+    let sketch = match reduce_sketch(snapshot) {
+        Some(sketch) => sketch,
+        None => return Vec::new(),
+    };
+    let mut hitters: Vec<(Vec<u8>, u64)> = snapshot
+        .keys
+        .iter()
+        .map(|key| (key.clone(), sketch.estimate(key)))
+        .filter(|(_, estimate)| *estimate > threshold)
+        .collect();
+    hitters.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    hitters
+}
+
+/// The `k` candidate keys with the largest estimates, ranked over every key the final
+/// `Reduce` updated in the epoch rather than only those that passed `FilterResult`.
+pub fn top_keys(snapshot: &EpochSnapshot, k: usize) -> Vec<(Vec<u8>, u64)> {
+    let mut hitters = heavy_hitters(snapshot, 0);
+    hitters.truncate(k);
+    hitters
+}
+
+/// `merge` mode: merges saved epoch snapshots and prints the network-wide heavy hitters.
+pub fn run_merge(inputs: &[String], threshold: u64, output: Option<&str>) {
+    let snapshots = match inputs.iter().map(|path| load_snapshot(path)).collect::<Result<Vec<_>, String>>() {
+        Ok(snapshots) => snapshots,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    let merged = match merge_snapshots(snapshots) {
+        Ok(merged) => merged,
+        Err(e) => {
+            eprintln!("Merge failed: {}", e);
+            return;
+        }
+    };
+
+    println!("Merged {} snapshots ({} candidate keys)", inputs.len(), merged.keys.len());
+    println!("flow_key, value");
+    for (key, estimate) in heavy_hitters(&merged, threshold) {
+        println!("{}, {}", format_key(&merged.result_key, &key), estimate);
+    }
+
+    if let Some(path) = output {
+        match save_snapshot(path, &merged) {
+            Ok(()) => println!("Merged snapshot written to {}", path),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+}
+
+/// Snapshots saved by `save_epoch` in `dir` for epochs `from..=to`, in epoch order.
+pub fn archived_epochs(dir: &str, from: usize, to: usize) -> Result<Vec<(usize, PathBuf)>, String> {
+    let entries = fs::read_dir(dir).map_err(|e| format!("Cannot read {}: {}", dir, e))?;
+    let mut epochs: Vec<(usize, PathBuf)> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter_map(|path| {
+            let epoch = path
+                .file_name()?
+                .to_str()?
+                .strip_prefix("epoch_")?
+                .strip_suffix(".sketch")?
+                .parse()
+                .ok()?;
+            Some((epoch, path))
+        })
+        .filter(|(epoch, _)| (from..=to).contains(epoch))
+        .collect();
+    epochs.sort();
+    Ok(epochs)
+}
+
+/// `archive count` mode: estimated count of one key in each archived epoch of a range.
+pub fn run_archive_count(dir: &str, key: &str, from: usize, to: usize) {
+    let epochs = match archived_epochs(dir, from, to) {
+        Ok(epochs) if !epochs.is_empty() => epochs,
+        Ok(_) => {
+            eprintln!("No archived epochs between {} and {} in {}", from, to, dir);
+            return;
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+
+    // The key is parsed against the first snapshot; later ones must come from the same query.
+    let mut key_bytes: Option<Vec<u8>> = None;
+    let mut result_key = Vec::new();
+    let mut total = 0u64;
+    for (epoch, path) in &epochs {
+        let snapshot = match load_snapshot(&path.to_string_lossy()) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                eprintln!("{}", e);
+                return;
+            }
+        };
+        if key_bytes.is_none() {
+            match parse_key(&snapshot.result_key, key) {
+                Ok(bytes) => {
+                    println!("Key {} in epochs {}-{}", format_key(&snapshot.result_key, &bytes), from, to);
+                    println!("epoch, epoch_end, estimate, lower, upper");
+                    result_key = snapshot.result_key.clone();
+                    key_bytes = Some(bytes);
+                }
+                Err(e) => {
+                    eprintln!("Invalid key: {}", e);
+                    return;
+                }
+            }
+        }
+        if snapshot.result_key != result_key {
+            eprintln!("Epoch {} was archived by a different query ({})", epoch, key_names(&snapshot.result_key));
+            return;
+        }
+        let estimate = match reduce_sketch(&snapshot) {
+            Some(sketch) => sketch.estimate_with_bounds(key_bytes.as_deref().unwrap_or_default()),
+            None => {
+                eprintln!("Epoch {} has no reduce sketch", epoch);
+                return;
+            }
+        };
+        let (lower, upper) = estimate.bound_strings();
+        total += estimate.value;
+        println!("{}, {}, {}, {}, {}", epoch, snapshot.epoch_end, estimate.value, lower, upper);
+    }
+
+    let missing = (to - from + 1) - epochs.len();
+    println!("Total: {} over {} archived epochs", total, epochs.len());
+    if missing > 0 {
+        println!("{} epochs in range are not in the archive", missing);
+    }
+}
+
+/// `archive top` mode: the `k` candidate keys with the largest estimates in one epoch.
+pub fn run_archive_top(dir: &str, k: usize, epoch: usize) {
+    let path = Path::new(dir).join(format!("epoch_{:05}.sketch", epoch));
+    let snapshot = match load_snapshot(&path.to_string_lossy()) {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    let bound = match reduce_sketch(&snapshot) {
+        Some(sketch) => sketch.error_bound(),
+        None => {
+            eprintln!("Epoch {} has no reduce sketch", epoch);
+            return;
+        }
+    };
+
+    println!("Top {} in epoch {} (ending at {}, {} candidate keys)", k, epoch, snapshot.epoch_end, snapshot.keys.len());
+    println!("flow_key, value, lower, upper");
+    for (key, value) in top_keys(&snapshot, k) {
+        let (lower, upper) = Estimate::new(value, bound).bound_strings();
+        println!("{}, {}, {}, {}", format_key(&snapshot.result_key, &key), value, lower, upper);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query_executor::{compile_plan, execute_query, PacketField};
+    use crate::query_plan::{Aggregation, Field, FilterMode, Operation, QueryPlan, ReduceType};
+    use std::net::Ipv4Addr;
+
+    fn syn_packet(dst: [u8; 4]) -> Record {
+        let mut record = Record::default();
+        record.set(0, PacketField::Ipv4(Ipv4Addr::new(10, 0, 0, 100)));
+        record.set(1, PacketField::Ipv4(Ipv4Addr::from(dst)));
+        record.set(2, PacketField::U16(40000));
+        record.set(3, PacketField::U16(80));
+        record.set(4, PacketField::U8(2));
+        record.set(5, PacketField::U32(60));
+        record.set(6, PacketField::U8(6));
+        record.set(7, PacketField::OptionU16(None));
+        record
+    }
+
+    // Runs one epoch of a heavy-hitter query and archives it in a fresh directory: 10.0.0.1
+    // passes FilterResult; the others, including the key of the packet closing the epoch at
+    // t = 5, do not. Returns the directory and the snapshot's path.
+    fn archive_test_epoch(name: &str) -> (String, String) {
+        let plan = compile_plan(&QueryPlan {
+            operations: vec![
+                Operation::Filter(vec![(Field::TcpFlag, "2".to_string())]),
+                Operation::Map("(dst_ip, count = 1)".to_string()),
+                Operation::Reduce {
+                    keys: vec!["dst_ip".to_string()],
+                    reduce_type: ReduceType::DeterministicReduce,
+                    field_name: "count".to_string(),
+                    aggregation: Aggregation::Sum,
+                },
+                Operation::FilterResult { threshold: 3, field_name: "count".to_string(), mode: FilterMode::Estimate },
+            ],
+        })
+        .unwrap();
+
+        let packets = [([10, 0, 0, 1], 5), ([10, 0, 0, 2], 2), ([10, 0, 0, 3], 1)];
+        let mut sketches = HashMap::new();
+        let mut result_map = HashMap::new();
+        let mut epoch_start = Some(0);
+        let mut epoch_keys = HashSet::new();
+        let timed = packets
+            .iter()
+            .flat_map(|(dst, n)| std::iter::repeat_n((*dst, 1), *n))
+            .chain(std::iter::once(([10, 0, 0, 4], 5)));
+        for (dst, timestamp) in timed {
+            if let Some(record) =
+                execute_query(&plan, syn_packet(dst), &mut sketches, &mut result_map, 5, &mut epoch_start, timestamp)
+            {
+                plan.record_result_key(&record, &mut epoch_keys);
+            }
+        }
+        assert_eq!(result_map.len(), 1);
+
+        let dir = std::env::temp_dir().join(format!("sketch_store_{}_{}", name, std::process::id()));
+        let dir = dir.to_string_lossy().into_owned();
+        save_epoch(&dir, 1, 5, &plan, &epoch_keys, &result_map, &sketches).unwrap();
+        let path = Path::new(&dir).join("epoch_00001.sketch").to_string_lossy().into_owned();
+        (dir, path)
+    }
+
+    #[test]
+    fn snapshot_keeps_keys_dropped_by_filter_result() {
+        let (dir, path) = archive_test_epoch("merge");
+        let snapshot = load_snapshot(&path).unwrap();
+        assert_eq!(snapshot.keys.len(), 4);
+
+        // Two vantage points seeing the same epoch: 10.0.0.2 reaches the threshold only once merged.
+        let merged = merge_snapshots(vec![snapshot, load_snapshot(&path).unwrap()]).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let hitters: Vec<(String, u64)> = heavy_hitters(&merged, 3)
+            .into_iter()
+            .map(|(key, value)| (format_key(&merged.result_key, &key), value))
+            .collect();
+        assert_eq!(
+            hitters,
+            vec![("dst_ip: 10.0.0.1".to_string(), 10), ("dst_ip: 10.0.0.2".to_string(), 4)]
+        );
+    }
+
+    #[test]
+    fn top_ranks_keys_dropped_by_filter_result() {
+        let (dir, path) = archive_test_epoch("top");
+        let snapshot = load_snapshot(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let top: Vec<(String, u64)> = top_keys(&snapshot, 3)
+            .into_iter()
+            .map(|(key, value)| (format_key(&snapshot.result_key, &key), value))
+            .collect();
+        assert_eq!(
+            top,
+            vec![
+                ("dst_ip: 10.0.0.1".to_string(), 5),
+                ("dst_ip: 10.0.0.2".to_string(), 2),
+                ("dst_ip: 10.0.0.3".to_string(), 1),
+            ]
+        );
+    }
+}
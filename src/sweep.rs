@@ -0,0 +1,145 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::Write;
+use crate::evaluation::{compile_with_exact, evaluate_plans};
+use crate::query_executor::{compile_plan, CompiledPlan};
+use crate::query_plan::QueryPlan;
+
+/// Environment variables applied while building one configuration's plans.
+type SweepConfig = Vec<(String, String)>;
+
+/// Expands a grid file into configurations. Each line is a family of `NAME=values`
+/// settings, and comma-separated values are expanded into every combination, e.g.
+///
+/// ```text
+/// REDUCE_TYPE=cms CM_MEMORY=16384,65536,262144 CM_DEPTH=2,3
+/// REDUCE_TYPE=fcm FCM_WIDTH_L1=8192,32768
+/// ```
+///
+/// Blank lines and lines starting with `#` are ignored.
+fn parse_grid(text: &str) -> Result<Vec<SweepConfig>, String> {
+    let mut configs = Vec::new();
+    for (line_number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut family: Vec<SweepConfig> = vec![Vec::new()];
+        for setting in line.split_whitespace() {
+            let (name, values) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("line {}: expected NAME=values, found '{}'", line_number + 1, setting))?;
+            family = family
+                .into_iter()
+                .flat_map(|config| {
+                    values.split(',').map(move |value| {
+                        let mut config = config.clone();
+                        config.push((name.to_string(), value.to_string()));
+                        config
+                    })
+                })
+                .collect();
+        }
+        configs.extend(family);
+    }
+    Ok(configs)
+}
+
+// Builds a plan with the configuration's variables set, then restores the environment.
+fn with_env<T>(config: &SweepConfig, build: impl FnOnce() -> T) -> T {
+    let saved: Vec<(String, Option<String>)> = config.iter().map(|(name, _)| (name.clone(), env::var(name).ok())).collect();
+    for (name, value) in config {
+        env::set_var(name, value);
+    }
+    let result = build();
+    for (name, value) in saved.into_iter().rev() {
+        match value {
+            Some(value) => env::set_var(&name, value),
+            None => env::remove_var(&name),
+        }
+    }
+    result
+}
+
+fn describe(config: &SweepConfig) -> String {
+    config.iter().map(|(name, value)| format!("{}={}", name, value)).collect::<Vec<_>>().join(" ")
+}
+
+/// `sweep` mode: evaluates every configuration of the grid for every query in a single
+/// pass over the trace, and writes memory against accuracy to `report_path` as CSV.
+pub fn run_sweep(
+    file_path: &str,
+    epoch_size: u64,
+    threshold: u64,
+    query_ids: &[u8],
+    grid_path: &str,
+    report_path: &str,
+    select_query: impl Fn(u8) -> Option<QueryPlan>,
+) {
+    let configs = match fs::read_to_string(grid_path)
+        .map_err(|e| format!("Cannot read {}: {}", grid_path, e))
+        .and_then(|text| parse_grid(&text))
+    {
+        Ok(configs) if !configs.is_empty() => configs,
+        Ok(_) => {
+            eprintln!("{} has no configurations", grid_path);
+            return;
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+
+    // One group per query: its exact plan and the plan built under each configuration.
+    let mut groups: Vec<(CompiledPlan, Vec<CompiledPlan>)> = Vec::new();
+    let mut labels: Vec<(u8, Vec<&SweepConfig>)> = Vec::new();
+    for &query_id in query_ids {
+        let exact = match select_query(query_id).ok_or_else(|| "unknown query".to_string()).and_then(|q| compile_with_exact(&q)) {
+            Ok((_, exact)) => exact,
+            Err(e) => {
+                eprintln!("Skipping query {}: {}", query_id, e);
+                continue;
+            }
+        };
+        let mut candidates = Vec::new();
+        let mut query_configs = Vec::new();
+        for config in &configs {
+            match with_env(config, || select_query(query_id)).map(|query| compile_plan(&query)) {
+                Some(Ok(plan)) => {
+                    candidates.push(plan);
+                    query_configs.push(config);
+                }
+                Some(Err(e)) => eprintln!("Skipping query {} with {}: {}", query_id, describe(config), e),
+                None => {}
+            }
+        }
+        groups.push((exact, candidates));
+        labels.push((query_id, query_configs));
+    }
+    if groups.is_empty() {
+        eprintln!("No query to sweep");
+        return;
+    }
+
+    println!("Sweeping {} configurations over {} queries...", configs.len(), groups.len());
+    let results = evaluate_plans(file_path, epoch_size, threshold, groups);
+
+    let mut csv = String::from("query,config,memory_bytes,precision,recall,f1,are,aae\n");
+    println!("query, memory_bytes, f1, are, config");
+    for ((query_id, query_configs), query_results) in labels.iter().zip(&results) {
+        for (config, result) in query_configs.iter().zip(query_results) {
+            let (precision, recall, f1, are, aae) = result.mean();
+            csv.push_str(&format!(
+                "{},{},{},{:.6},{:.6},{:.6},{:.6},{:.4}\n",
+                query_id, describe(config), result.peak_memory, precision, recall, f1, are, aae
+            ));
+            println!("{}, {}, {:.4}, {:.4}, {}", query_id, result.peak_memory, f1, are, describe(config));
+        }
+    }
+
+    match File::create(report_path).and_then(|mut file| file.write_all(csv.as_bytes())) {
+        Ok(()) => println!("Report written to {}", report_path),
+        Err(e) => eprintln!("Failed to write {}: {}", report_path, e),
+    }
+}